@@ -0,0 +1,274 @@
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+// Which field of a CSV row holds the address: a header name or a 0-based index
+#[derive(Debug, Clone)]
+pub enum Column {
+    Name(String),
+    Index(usize),
+}
+
+impl FromStr for Column {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(index) => Column::Index(index),
+            Err(_) => Column::Name(s.to_string()),
+        })
+    }
+}
+
+// Addresses in input order, without duplicates and (with `skip_invalid`) invalid entries
+#[derive(Debug)]
+pub struct AddressList {
+    pub addresses: Vec<String>,
+    pub duplicates: usize,
+    pub invalid: usize,
+}
+
+// Read addresses from `path`, or from stdin if it's `-`. Plain text has one
+// address per line, blank lines and `#` comments are ignored. Files ending in
+// .csv, or any input when `column` is given, are read as CSV with a header row
+pub fn read_addresses(
+    path: &str,
+    column: Option<&Column>,
+    skip_invalid: bool,
+) -> Result<AddressList, Box<dyn Error>> {
+    read_addresses_from(path, io::stdin(), column, skip_invalid)
+}
+
+// `read_addresses` with `stdin` standing in for the process's stdin
+fn read_addresses_from(
+    path: &str,
+    mut stdin: impl Read,
+    column: Option<&Column>,
+    skip_invalid: bool,
+) -> Result<AddressList, Box<dyn Error>> {
+    let (source, contents) = if path == "-" {
+        let mut contents = String::new();
+        stdin
+            .read_to_string(&mut contents)
+            .map_err(|e| format!("Failed to read addresses from stdin: {}", e))?;
+        ("stdin", contents)
+    } else {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read addresses {}: {}", path, e))?;
+        (path, contents)
+    };
+
+    let is_csv = column.is_some()
+        || Path::new(path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+    let entries = if is_csv {
+        csv_entries(&contents, column).map_err(|e| format!("{} in {}", e, source))?
+    } else {
+        text_entries(&contents)
+    };
+
+    parse_entries(entries, source, skip_invalid)
+}
+
+// (line number, entry) for every non-blank, non-comment line
+fn text_entries(contents: &str) -> Vec<(usize, String)> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_no, line)| (line_no, line.to_string()))
+        .collect()
+}
+
+// (line number, field) of the address column in every record. Without a
+// column, the one named `address` is used, or else the first
+fn csv_entries(contents: &str, column: Option<&Column>) -> Result<Vec<(usize, String)>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(contents.as_bytes());
+    let headers = reader
+        .headers()
+        .map_err(|e| format!("Invalid CSV header: {}", e))?
+        .clone();
+    let find = |name: &str| {
+        headers
+            .iter()
+            .position(|header| header.trim().eq_ignore_ascii_case(name))
+    };
+    let index = match column {
+        Some(Column::Index(index)) => *index,
+        Some(Column::Name(name)) => find(name).ok_or_else(|| format!("No column {}", name))?,
+        None => find("address").unwrap_or(0),
+    };
+
+    let mut entries = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| format!("Invalid CSV: {}", e))?;
+        let line_no = record
+            .position()
+            .map_or(0, |position| position.line() as usize);
+        entries.push((
+            line_no,
+            record.get(index).unwrap_or_default().trim().to_string(),
+        ));
+    }
+    Ok(entries)
+}
+
+fn parse_entries(
+    entries: Vec<(usize, String)>,
+    source: &str,
+    skip_invalid: bool,
+) -> Result<AddressList, Box<dyn Error>> {
+    let mut seen = HashSet::new();
+    let mut list = AddressList {
+        addresses: Vec::new(),
+        duplicates: 0,
+        invalid: 0,
+    };
+
+    for (line_no, entry) in entries {
+        if let Err(e) = Pubkey::from_str(&entry) {
+            let message = format!(
+                "Invalid pubkey {:?} on line {} of {}: {}",
+                entry, line_no, source, e
+            );
+            if !skip_invalid {
+                return Err(format!("{} (pass --skip-invalid to skip it)", message).into());
+            }
+            eprintln!("Skipping {}", message);
+            list.invalid += 1;
+            continue;
+        }
+        if seen.insert(entry.clone()) {
+            list.addresses.push(entry);
+        } else {
+            list.duplicates += 1;
+        }
+    }
+
+    Ok(list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pubkeys(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| Pubkey::new_unique().to_string())
+            .collect()
+    }
+
+    fn temp_file(name: &str, contents: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("balance-checker-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn read(path: &str, column: Option<&Column>) -> Result<AddressList, Box<dyn Error>> {
+        read_addresses_from(path, io::empty(), column, false)
+    }
+
+    #[test]
+    fn reads_one_address_per_line() {
+        let keys = pubkeys(3);
+        let path = temp_file(
+            "addresses.txt",
+            &format!(
+                "# team wallets\n{}\n\n  {}  \n{}\n{}\n",
+                keys[0], keys[1], keys[0], keys[2]
+            ),
+        );
+
+        let list = read(&path, None).unwrap();
+        assert_eq!(list.addresses, keys);
+        assert_eq!(list.duplicates, 1);
+        assert_eq!(list.invalid, 0);
+    }
+
+    #[test]
+    fn reads_the_address_column_of_a_csv() {
+        let keys = pubkeys(2);
+        let path = temp_file(
+            "wallets.csv",
+            &format!("label,Address\nalice,{}\nbob,{}\n", keys[0], keys[1]),
+        );
+        assert_eq!(read(&path, None).unwrap().addresses, keys);
+
+        // Without an `address` header the first column is used
+        let path = temp_file(
+            "first.csv",
+            &format!("wallet,label\n{},alice\n{},bob\n", keys[0], keys[1]),
+        );
+        assert_eq!(read(&path, None).unwrap().addresses, keys);
+    }
+
+    #[test]
+    fn reads_the_given_csv_column() {
+        let keys = pubkeys(2);
+        let contents = format!(
+            "label,owner,wallet\nalice,x,{}\nbob,y,{}\n",
+            keys[0], keys[1]
+        );
+        // Any file is read as CSV once a column is given
+        let path = temp_file("columns.txt", &contents);
+
+        let by_name = Column::from_str("wallet").unwrap();
+        assert_eq!(read(&path, Some(&by_name)).unwrap().addresses, keys);
+        let by_index = Column::from_str("2").unwrap();
+        assert_eq!(read(&path, Some(&by_index)).unwrap().addresses, keys);
+
+        let missing = Column::from_str("pubkey").unwrap();
+        let error = read(&path, Some(&missing)).unwrap_err().to_string();
+        assert!(error.contains("No column pubkey"), "{}", error);
+    }
+
+    #[test]
+    fn reads_addresses_from_stdin() {
+        let keys = pubkeys(2);
+        let stdin = format!("{}\n{}\n", keys[0], keys[1]);
+        let list = read_addresses_from("-", stdin.as_bytes(), None, false).unwrap();
+        assert_eq!(list.addresses, keys);
+
+        let stdin = format!("address\n{}\n", keys[0]);
+        let column = Column::Name("address".to_string());
+        let list = read_addresses_from("-", stdin.as_bytes(), Some(&column), false).unwrap();
+        assert_eq!(list.addresses, vec![keys[0].clone()]);
+    }
+
+    #[test]
+    fn invalid_addresses_fail_unless_skipped() {
+        let keys = pubkeys(1);
+        let stdin = format!("not-a-pubkey\n{}\n", keys[0]);
+
+        let error = read_addresses_from("-", stdin.as_bytes(), None, false)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains(r#"Invalid pubkey "not-a-pubkey" on line 1 of stdin"#),
+            "{}",
+            error
+        );
+
+        let list = read_addresses_from("-", stdin.as_bytes(), None, true).unwrap();
+        assert_eq!(list.addresses, keys);
+        assert_eq!(list.invalid, 1);
+    }
+
+    #[test]
+    fn reports_a_missing_file() {
+        let error = read("/nonexistent/addresses.txt", None)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("Failed to read addresses /nonexistent/addresses.txt"));
+    }
+}
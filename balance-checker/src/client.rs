@@ -0,0 +1,139 @@
+use common::rate_limit::RateLimiter;
+use common::retry::is_rate_limited;
+use solana_client::client_error::ClientError;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+// Retries of a rate-limited request before its wallets are given up
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+// Wait before the first retry of a rate-limited request, doubled per retry
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_millis(500);
+
+// Every RPC request goes through here: at most `max_concurrent` in flight,
+// optionally paced to `requests_per_second` by a token bucket that slows down
+// further while the RPC rate limits, and retried when rate limited. Requests
+// rotate through the endpoints, the limits cover all of them together
+pub struct LimitedClient {
+    clients: Vec<RpcClient>,
+    // Endpoint the next request starts at
+    next: AtomicUsize,
+    permits: Semaphore,
+    limiter: Option<RateLimiter>,
+    // Set whenever a request was rate limited, cleared by `take_rate_limited`
+    rate_limited: AtomicBool,
+    // Requests that moved on to another endpoint because one was unreachable
+    failovers: AtomicUsize,
+}
+
+impl LimitedClient {
+    pub fn new(
+        clients: Vec<RpcClient>,
+        max_concurrent: usize,
+        requests_per_second: Option<f64>,
+    ) -> Self {
+        Self {
+            clients,
+            next: AtomicUsize::new(0),
+            permits: Semaphore::new(max_concurrent.max(1)),
+            limiter: requests_per_second
+                .filter(|rps| *rps > 0.0)
+                .map(RateLimiter::new),
+            rate_limited: AtomicBool::new(false),
+            failovers: AtomicUsize::new(0),
+        }
+    }
+
+    async fn acquire(&self) -> SemaphorePermit<'_> {
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("the semaphore is never closed");
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire().await;
+        }
+        permit
+    }
+
+    // Run one request, retrying with backoff while the RPC rate limits it. It
+    // starts at the next endpoint in turn and moves on to the following ones
+    // while they can't be reached
+    pub async fn call<'a, T, F, Fut>(&'a self, mut request: F) -> Result<T, ClientError>
+    where
+        F: FnMut(&'a RpcClient) -> Fut,
+        Fut: Future<Output = Result<T, ClientError>>,
+    {
+        let mut endpoint = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut tried = 1;
+        let mut retries = 0;
+        loop {
+            let client = &self.clients[endpoint % self.clients.len()];
+            let result = {
+                let _permit = self.acquire().await;
+                request(client).await
+            };
+            match result {
+                Err(e) if tried < self.clients.len() && common::rpc::is_connection_error(&e) => {
+                    self.failovers.fetch_add(1, Ordering::Relaxed);
+                    endpoint += 1;
+                    tried += 1;
+                }
+                Err(e) if is_rate_limited(&e) && retries < MAX_RATE_LIMIT_RETRIES => {
+                    self.on_rate_limited();
+                    tokio::time::sleep(RATE_LIMIT_BACKOFF * 2u32.pow(retries)).await;
+                    retries += 1;
+                }
+                Err(e) => {
+                    if is_rate_limited(&e) {
+                        self.on_rate_limited();
+                    }
+                    return Err(e);
+                }
+                Ok(value) => return Ok(value),
+            }
+        }
+    }
+
+    // Slows the pacing down and lets the batch concurrency back off
+    fn on_rate_limited(&self) {
+        self.rate_limited.store(true, Ordering::Relaxed);
+        if let Some(limiter) = &self.limiter {
+            limiter.on_rate_limited();
+        }
+    }
+
+    pub fn take_rate_limited(&self) -> bool {
+        self.rate_limited.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn failovers(&self) -> usize {
+        self.failovers.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rate_limits_slow_the_pacing_down() {
+        let client = LimitedClient::new(Vec::new(), 4, Some(8.0));
+        client.on_rate_limited();
+
+        assert!(client.take_rate_limited());
+        assert!(!client.take_rate_limited());
+        assert_eq!(client.limiter.as_ref().unwrap().rate(), 4.0);
+    }
+
+    #[test]
+    fn no_pacing_without_a_positive_rate() {
+        assert!(LimitedClient::new(Vec::new(), 4, None).limiter.is_none());
+        assert!(
+            LimitedClient::new(Vec::new(), 4, Some(0.0))
+                .limiter
+                .is_none()
+        );
+    }
+}
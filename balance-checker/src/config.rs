@@ -0,0 +1,236 @@
+use crate::Args;
+use common::RpcUrls;
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::str::FromStr;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub wallets: Vec<WalletEntry>,
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    // One endpoint or a list that requests rotate through
+    #[serde(default = "default_rpc_url")]
+    pub rpc_url: RpcUrls,
+    #[serde(default)]
+    pub mode: FetchMode,
+    // Bounds for the number of batches fetched in parallel, tuned from latency
+    #[serde(default = "default_min_concurrency")]
+    pub min_concurrency: usize,
+    #[serde(default = "default_max_concurrency")]
+    pub max_concurrency: usize,
+    // RPC requests in flight across all batches
+    #[serde(default = "default_max_concurrent")]
+    pub max_concurrent: usize,
+    // Upper bound for RPC requests started per second, unlimited if unset
+    #[serde(default)]
+    pub requests_per_second: Option<f64>,
+    // Wallets below this balance are flagged as low, empty ones always are
+    #[serde(default, alias = "min_balance_sol")]
+    pub alert_threshold_sol: Option<f64>,
+    // SPL token mints whose balances are fetched alongside SOL
+    #[serde(default)]
+    pub token_mints: Vec<String>,
+    // Owner program every wallet without its own `expected_owner` must have
+    #[serde(default)]
+    pub expected_owner: Option<String>,
+}
+
+// A wallet is either just its address or an address with the program it
+// must be owned by, e.g. for vault PDAs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "WalletEntryRepr")]
+pub struct WalletEntry {
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected_owner: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WalletEntryRepr {
+    Address(String),
+    WithOwner {
+        address: String,
+        expected_owner: Option<String>,
+    },
+}
+
+impl From<WalletEntryRepr> for WalletEntry {
+    fn from(repr: WalletEntryRepr) -> Self {
+        match repr {
+            WalletEntryRepr::Address(address) => WalletEntry {
+                address,
+                expected_owner: None,
+            },
+            WalletEntryRepr::WithOwner {
+                address,
+                expected_owner,
+            } => WalletEntry {
+                address,
+                expected_owner,
+            },
+        }
+    }
+}
+
+impl WalletEntry {
+    fn new(address: String) -> Self {
+        WalletEntry {
+            address,
+            expected_owner: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn addresses(&self) -> Vec<String> {
+        self.wallets
+            .iter()
+            .map(|wallet| wallet.address.clone())
+            .collect()
+    }
+
+    // Address -> owner program it must have, for every wallet that has one
+    pub fn expected_owners(&self) -> HashMap<String, String> {
+        self.wallets
+            .iter()
+            .filter_map(|wallet| {
+                let owner = wallet
+                    .expected_owner
+                    .as_ref()
+                    .or(self.expected_owner.as_ref())?;
+                Some((wallet.address.clone(), owner.clone()))
+            })
+            .collect()
+    }
+
+    pub fn checks_owners(&self) -> bool {
+        self.expected_owner.is_some()
+            || self
+                .wallets
+                .iter()
+                .any(|wallet| wallet.expected_owner.is_some())
+    }
+}
+
+// How balances are requested from the RPC
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FetchMode {
+    // One getMultipleAccounts call per batch
+    #[default]
+    Batch,
+    // One getBalance call per wallet, for RPCs without getMultipleAccounts
+    Individual,
+}
+
+fn default_batch_size() -> usize {
+    25
+}
+
+fn default_min_concurrency() -> usize {
+    1
+}
+
+fn default_max_concurrency() -> usize {
+    8
+}
+
+fn default_max_concurrent() -> usize {
+    25
+}
+
+fn default_rpc_url() -> RpcUrls {
+    RpcUrls::One("https://api.mainnet-beta.solana.com".to_string())
+}
+
+// Load and validate config, merged with the command line and the
+// addresses read from `--addresses`
+pub async fn read_config(args: &Args, listed: &[String]) -> Result<Config, Box<dyn Error>> {
+    // Without ad-hoc wallets there's nothing to check unless the default file exists
+    let config_path = match &args.config {
+        Some(path) => Some(path.as_str()),
+        None if args.wallets.is_empty() && listed.is_empty() => Some("config.yaml"),
+        None => None,
+    };
+
+    let mut config = match config_path {
+        Some(path) => {
+            let contents = fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read config {}: {}", path, e))?;
+            let config: Config = serde_yaml::from_str(&contents)
+                .map_err(|e| format!("Failed to parse config {}: {}", path, e))?;
+
+            // Validate pubkeys upfront
+            let owners = config
+                .wallets
+                .iter()
+                .filter_map(|wallet| wallet.expected_owner.as_ref())
+                .chain(&config.expected_owner);
+            for addr in config
+                .wallets
+                .iter()
+                .map(|wallet| &wallet.address)
+                .chain(&config.token_mints)
+                .chain(owners)
+            {
+                Pubkey::from_str(addr)
+                    .map_err(|e| format!("Invalid pubkey {} in {}: {}", addr, path, e))?;
+            }
+            config
+        }
+        None => Config {
+            wallets: Vec::new(),
+            batch_size: default_batch_size(),
+            rpc_url: default_rpc_url(),
+            mode: FetchMode::default(),
+            min_concurrency: default_min_concurrency(),
+            max_concurrency: default_max_concurrency(),
+            max_concurrent: default_max_concurrent(),
+            requests_per_second: None,
+            alert_threshold_sol: None,
+            token_mints: Vec::new(),
+            expected_owner: None,
+        },
+    };
+
+    for addr in &args.wallets {
+        Pubkey::from_str(addr)
+            .map_err(|e| format!("Invalid pubkey {} from --wallet: {}", addr, e))?;
+        config.wallets.push(WalletEntry::new(addr.clone()));
+    }
+    // Already validated while reading them
+    config
+        .wallets
+        .extend(listed.iter().cloned().map(WalletEntry::new));
+    // Keep the first occurrence so the output follows the config's order
+    let mut seen = HashSet::new();
+    config
+        .wallets
+        .retain(|wallet| seen.insert(wallet.address.clone()));
+
+    if !args.rpc_url.is_empty() {
+        config.rpc_url = RpcUrls::Many(args.rpc_url.clone());
+    }
+    if config.rpc_url.urls().is_empty() {
+        return Err("No RPC endpoint in config, set rpc_url".into());
+    }
+    if let Some(batch_size) = args.batch_size {
+        config.batch_size = batch_size;
+    }
+    if let Some(min_balance) = args.min_balance {
+        config.alert_threshold_sol = Some(min_balance);
+    }
+
+    // Check for empty wallet list
+    if config.wallets.is_empty() {
+        return Err("No wallet addresses specified in config, with --wallet or --addresses".into());
+    }
+
+    Ok(config)
+}
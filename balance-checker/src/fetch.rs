@@ -0,0 +1,568 @@
+use crate::client::LimitedClient;
+use crate::config::{Config, FetchMode};
+use chrono::{DateTime, Utc};
+use common::lamports_to_sol;
+use serde::Serialize;
+use solana_client::client_error::ClientError;
+use solana_sdk::account::Account;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::program_pack::Pack;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::system_program;
+use spl_associated_token_account_client::address::get_associated_token_address;
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+
+// Wallets that failed to fetch are left out and listed in the summary instead
+#[derive(Debug, Clone, Serialize)]
+pub struct WalletBalance {
+    pub address: String,
+    pub lamports: u64,
+    pub balance_sol: f64,
+    // Mint -> balance in UI units, 0 without an associated token account
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub token_balances: BTreeMap<String, f64>,
+    // Program owning the account, nonexistent accounts belong to the system
+    // program. In `mode: individual` only fetched when owners are checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    pub fetch_time_ms: u64,
+    pub fetched_at: DateTime<Utc>,
+}
+
+// A configured mint with the decimals read from chain
+struct TokenMint {
+    address: String,
+    pubkey: Pubkey,
+    decimals: u8,
+}
+
+// A wallet whose balance couldn't be fetched, listed in the summary
+#[derive(Debug, Clone)]
+pub struct FailedWallet {
+    pub address: String,
+    pub error: String,
+}
+
+fn fail_all(addresses: Vec<String>, error: &str) -> Vec<FailedWallet> {
+    addresses
+        .into_iter()
+        .map(|address| FailedWallet {
+            address,
+            error: error.to_string(),
+        })
+        .collect()
+}
+
+// Fetch a whole batch with a single getMultipleAccounts call.
+// `fetch_time_ms` of every entry is its share of the batch call
+async fn fetch_batch_balances(
+    rpc: &LimitedClient,
+    addresses: &[String],
+) -> Result<Vec<WalletBalance>, String> {
+    let start_time = Instant::now();
+    let pubkeys = addresses
+        .iter()
+        .map(|addr| Pubkey::from_str(addr).map_err(|e| format!("Invalid pubkey {}: {}", addr, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let accounts = rpc
+        .call(|client| client.get_multiple_accounts(&pubkeys))
+        .await
+        .map_err(|e| {
+            format!(
+                "RPC error for batch starting at {}: {}",
+                addresses.first().map_or("", String::as_str),
+                e
+            )
+        })?;
+    let elapsed = start_time.elapsed().as_millis() as u64 / addresses.len().max(1) as u64;
+    batch_balances(addresses, accounts, elapsed, Utc::now())
+}
+
+// Match the getMultipleAccounts response, in request order, to its addresses.
+// Accounts that don't exist yet simply hold no SOL
+fn batch_balances(
+    addresses: &[String],
+    accounts: Vec<Option<Account>>,
+    fetch_time_ms: u64,
+    fetched_at: DateTime<Utc>,
+) -> Result<Vec<WalletBalance>, String> {
+    if accounts.len() != addresses.len() {
+        return Err(format!(
+            "RPC returned {} accounts for a batch of {} starting at {}",
+            accounts.len(),
+            addresses.len(),
+            addresses.first().map_or("", String::as_str)
+        ));
+    }
+    Ok(addresses
+        .iter()
+        .zip(accounts)
+        .map(|(address, account)| {
+            let lamports = account.as_ref().map_or(0, |account| account.lamports);
+            let owner = account.map_or(system_program::id(), |account| account.owner);
+            WalletBalance {
+                address: address.clone(),
+                lamports,
+                balance_sol: lamports_to_sol(lamports),
+                token_balances: BTreeMap::new(),
+                owner: Some(owner.to_string()),
+                fetch_time_ms,
+                fetched_at,
+            }
+        })
+        .collect())
+}
+
+// Read the decimals of every configured mint
+async fn fetch_token_mints(
+    rpc: &LimitedClient,
+    mints: &[String],
+) -> Result<Vec<TokenMint>, String> {
+    let mut token_mints = Vec::with_capacity(mints.len());
+    for address in mints {
+        let pubkey = Pubkey::from_str(address)
+            .map_err(|e| format!("Invalid token mint {}: {}", address, e))?;
+        let account = rpc
+            .call(|client| client.get_account(&pubkey))
+            .await
+            .map_err(|e| format!("Failed to fetch token mint {}: {}", address, e))?;
+        let mint = spl_token::state::Mint::unpack(&account.data)
+            .map_err(|e| format!("{} is not an SPL token mint: {}", address, e))?;
+        token_mints.push(TokenMint {
+            address: address.clone(),
+            pubkey,
+            decimals: mint.decimals,
+        });
+    }
+    Ok(token_mints)
+}
+
+// Token balances of every address for every mint, in the order of `addresses`.
+// Wallets without an associated token account hold 0
+async fn fetch_token_balances(
+    rpc: &LimitedClient,
+    addresses: &[String],
+    mints: &[TokenMint],
+    mode: FetchMode,
+) -> Result<Vec<BTreeMap<String, f64>>, String> {
+    let owners = addresses
+        .iter()
+        .map(|addr| Pubkey::from_str(addr).map_err(|e| format!("Invalid pubkey {}: {}", addr, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut balances = vec![BTreeMap::new(); owners.len()];
+    for mint in mints {
+        let token_accounts: Vec<_> = owners
+            .iter()
+            .map(|owner| get_associated_token_address(owner, &mint.pubkey))
+            .collect();
+        let rpc_error = |e: ClientError| format!("RPC error for token {}: {}", mint.address, e);
+        let accounts = match mode {
+            FetchMode::Batch => rpc
+                .call(|client| client.get_multiple_accounts(&token_accounts))
+                .await
+                .map_err(rpc_error)?,
+            FetchMode::Individual => {
+                let mut accounts = Vec::with_capacity(token_accounts.len());
+                for account in &token_accounts {
+                    let response = rpc
+                        .call(|client| {
+                            client.get_account_with_commitment(account, client.commitment())
+                        })
+                        .await
+                        .map_err(rpc_error)?;
+                    accounts.push(response.value);
+                }
+                accounts
+            }
+        };
+
+        for (balance, account) in balances.iter_mut().zip(accounts) {
+            let amount = match account {
+                Some(account) => {
+                    spl_token::state::Account::unpack(&account.data)
+                        .map_err(|e| format!("Invalid token account for {}: {}", mint.address, e))?
+                        .amount
+                }
+                None => 0,
+            };
+            balance.insert(
+                mint.address.clone(),
+                amount as f64 / 10f64.powi(mint.decimals as i32),
+            );
+        }
+    }
+    Ok(balances)
+}
+
+// Fetch single wallet balance. With `fetch_owner` the whole account is read
+// instead, to also get its owner
+async fn fetch_wallet_balance(
+    rpc: Arc<LimitedClient>,
+    address: String,
+    fetch_owner: bool,
+) -> Result<WalletBalance, String> {
+    let start_time = Instant::now();
+    let pubkey = Pubkey::from_str(&address).map_err(|e| e.to_string())?;
+    // Include address in RPC error for clarity
+    let rpc_error = |e: ClientError| format!("RPC error for {}: {}", address, e);
+    let (lamports, owner) = if fetch_owner {
+        let account = rpc
+            .call(|client| client.get_account_with_commitment(&pubkey, client.commitment()))
+            .await
+            .map_err(rpc_error)?
+            .value;
+        match account {
+            Some(account) => (account.lamports, Some(account.owner)),
+            None => (0, Some(system_program::id())),
+        }
+    } else {
+        let lamports = rpc
+            .call(|client| client.get_balance(&pubkey))
+            .await
+            .map_err(rpc_error)?;
+        (lamports, None)
+    };
+    let elapsed = start_time.elapsed().as_millis() as u64;
+
+    Ok(WalletBalance {
+        address,
+        lamports,
+        balance_sol: lamports_to_sol(lamports),
+        token_balances: BTreeMap::new(),
+        owner: owner.map(|owner| owner.to_string()),
+        fetch_time_ms: elapsed,
+        fetched_at: Utc::now(),
+    })
+}
+
+// Fetch every wallet of a batch with its own getBalance call, in parallel
+async fn fetch_individual_balances(
+    rpc: Arc<LimitedClient>,
+    addresses: &[String],
+    fetch_owner: bool,
+) -> (Vec<WalletBalance>, Vec<FailedWallet>) {
+    let tasks: Vec<_> = addresses
+        .iter()
+        .map(|addr| {
+            tokio::spawn(fetch_wallet_balance(
+                Arc::clone(&rpc),
+                addr.clone(),
+                fetch_owner,
+            ))
+        })
+        .collect();
+
+    let mut balances = Vec::new();
+    let mut failed = Vec::new();
+    for (address, task) in addresses.iter().zip(tasks) {
+        let error = match task.await {
+            Ok(Ok(balance)) => {
+                balances.push(balance);
+                continue;
+            }
+            Ok(Err(e)) => e,
+            Err(e) => format!("Task panicked: {}", e),
+        };
+        failed.push(FailedWallet {
+            address: address.clone(),
+            error,
+        });
+    }
+    (balances, failed)
+}
+
+// Fetch SOL and token balances of one batch. A wallet is only reported with
+// all of its balances, otherwise it's failed
+async fn fetch_batch(
+    rpc: Arc<LimitedClient>,
+    addresses: Vec<String>,
+    mints: Arc<Vec<TokenMint>>,
+    mode: FetchMode,
+    fetch_owner: bool,
+) -> (Vec<WalletBalance>, Vec<FailedWallet>) {
+    let (mut balances, mut failed) = match mode {
+        // One RPC round-trip for the whole batch
+        FetchMode::Batch => match fetch_batch_balances(&rpc, &addresses).await {
+            Ok(balances) => (balances, Vec::new()),
+            Err(e) => return (Vec::new(), fail_all(addresses, &e)),
+        },
+        FetchMode::Individual => {
+            fetch_individual_balances(Arc::clone(&rpc), &addresses, fetch_owner).await
+        }
+    };
+
+    if !mints.is_empty() && !balances.is_empty() {
+        let fetched: Vec<_> = balances
+            .iter()
+            .map(|balance| balance.address.clone())
+            .collect();
+        let token_balances = match fetch_token_balances(&rpc, &fetched, &mints, mode).await {
+            Ok(token_balances) => token_balances,
+            Err(e) => {
+                let error = format!("Failed to fetch token balances: {}", e);
+                failed.extend(fail_all(fetched, &error));
+                return (Vec::new(), failed);
+            }
+        };
+        for (balance, token_balances) in balances.iter_mut().zip(token_balances) {
+            balance.token_balances = token_balances;
+        }
+    }
+
+    (balances, failed)
+}
+
+// Split the addresses into the batches fetched at once
+fn batches(addresses: &[String], mode: FetchMode, batch_size: usize) -> Vec<Vec<String>> {
+    // getMultipleAccounts accepts at most this many pubkeys per call
+    let batch_size = match mode {
+        FetchMode::Batch => batch_size.clamp(1, MAX_MULTIPLE_ACCOUNTS),
+        FetchMode::Individual => batch_size.max(1),
+    };
+    addresses
+        .chunks(batch_size)
+        .map(<[String]>::to_vec)
+        .collect()
+}
+
+// Batches faster than this let the concurrency grow
+const TARGET_BATCH_LATENCY: Duration = Duration::from_secs(1);
+
+// AIMD limit for batches in flight: every fast batch adds one, rate limiting
+// halves it, always within the configured bounds
+struct ConcurrencyController {
+    limit: usize,
+    min: usize,
+    max: usize,
+}
+
+impl ConcurrencyController {
+    fn new(min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        Self {
+            limit: min,
+            min,
+            max: max.max(min),
+        }
+    }
+
+    fn on_success(&mut self, latency: Duration) {
+        if latency <= TARGET_BATCH_LATENCY {
+            self.limit = (self.limit + 1).min(self.max);
+        }
+    }
+
+    fn on_rate_limited(&mut self) {
+        self.limit = (self.limit / 2).max(self.min);
+    }
+}
+
+pub struct FetchReport {
+    // In config order
+    pub balances: Vec<WalletBalance>,
+    pub failed: Vec<FailedWallet>,
+}
+
+// Fetch balances in batches, `verbose` prints a progress counter
+pub async fn fetch_wallet_balances(
+    config: &Config,
+    verbose: bool,
+) -> Result<FetchReport, Box<dyn Error>> {
+    if verbose {
+        println!("Loading {} wallet addresses", config.wallets.len());
+    }
+
+    // Added timeout to avoid hanging RPC calls
+    let clients = config
+        .rpc_url
+        .urls()
+        .iter()
+        .map(|url| {
+            common::make_rpc_client(url, CommitmentConfig::confirmed(), Duration::from_secs(30))
+        })
+        .collect();
+    let rpc = Arc::new(LimitedClient::new(
+        clients,
+        config.max_concurrent,
+        config.requests_per_second,
+    ));
+
+    let mints = Arc::new(fetch_token_mints(&rpc, &config.token_mints).await?);
+    let total_start = Instant::now();
+
+    let fetch_owner = config.checks_owners();
+    let mut pending: VecDeque<_> = batches(&config.addresses(), config.mode, config.batch_size)
+        .into_iter()
+        .enumerate()
+        .collect();
+    let mut controller = ConcurrencyController::new(config.min_concurrency, config.max_concurrency);
+    let mut in_flight = JoinSet::new();
+    let mut batches = Vec::new();
+    let mut done = 0;
+
+    loop {
+        while in_flight.len() < controller.limit {
+            let Some((batch_idx, chunk)) = pending.pop_front() else {
+                break;
+            };
+            let rpc = Arc::clone(&rpc);
+            let mints = Arc::clone(&mints);
+            let mode = config.mode;
+            in_flight.spawn(async move {
+                let batch_start = Instant::now();
+                let (balances, failed) = fetch_batch(rpc, chunk, mints, mode, fetch_owner).await;
+                (batch_idx, batch_start.elapsed(), balances, failed)
+            });
+        }
+
+        let Some(joined) = in_flight.join_next().await else {
+            break;
+        };
+        let (batch_idx, elapsed, balances, failed) =
+            joined.map_err(|e| format!("Task panicked: {}", e))?;
+
+        if rpc.take_rate_limited() {
+            controller.on_rate_limited();
+        } else {
+            controller.on_success(elapsed);
+        }
+        done += balances.len() + failed.len();
+        if verbose {
+            print!("\r{}/{} fetched", done, config.wallets.len());
+            io::stdout().flush()?;
+        }
+        batches.push((batch_idx, balances, failed));
+    }
+
+    if verbose {
+        println!(
+            "\nFetched all balances in {:.2}s",
+            total_start.elapsed().as_secs_f64()
+        );
+    }
+    if rpc.failovers() > 0 {
+        eprintln!(
+            "Warning: {} requests failed over to another RPC endpoint",
+            rpc.failovers()
+        );
+    }
+
+    // Report the wallets in config order regardless of which batch finished first
+    batches.sort_by_key(|(batch_idx, _, _)| *batch_idx);
+    let mut report = FetchReport {
+        balances: Vec::new(),
+        failed: Vec::new(),
+    };
+    for (_, balances, failed) in batches {
+        report.balances.extend(balances);
+        report.failed.extend(failed);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| Pubkey::new_unique().to_string())
+            .collect()
+    }
+
+    fn batch_sizes(batches: &[Vec<String>]) -> Vec<usize> {
+        batches.iter().map(Vec::len).collect()
+    }
+
+    #[test]
+    fn batches_stay_within_the_get_multiple_accounts_limit() {
+        let exactly_one_call = addresses(MAX_MULTIPLE_ACCOUNTS);
+        let batched = batches(&exactly_one_call, FetchMode::Batch, 250);
+        assert_eq!(batch_sizes(&batched), vec![MAX_MULTIPLE_ACCOUNTS]);
+        assert_eq!(batched.concat(), exactly_one_call);
+
+        let one_over = addresses(MAX_MULTIPLE_ACCOUNTS + 1);
+        let batched = batches(&one_over, FetchMode::Batch, 250);
+        assert_eq!(batch_sizes(&batched), vec![MAX_MULTIPLE_ACCOUNTS, 1]);
+        assert_eq!(batched.concat(), one_over);
+
+        // Only getMultipleAccounts has the limit
+        assert_eq!(
+            batch_sizes(&batches(&one_over, FetchMode::Individual, 250)),
+            vec![MAX_MULTIPLE_ACCOUNTS + 1]
+        );
+    }
+
+    #[test]
+    fn fewer_addresses_than_the_batch_size_make_one_batch() {
+        let wallets = addresses(7);
+        assert_eq!(batches(&wallets, FetchMode::Batch, 25), vec![
+            wallets.clone()
+        ]);
+        assert_eq!(batch_sizes(&batches(&wallets, FetchMode::Batch, 3)), vec![
+            3, 3, 1
+        ]);
+        // A batch size of 0 still makes progress
+        assert_eq!(batch_sizes(&batches(&wallets, FetchMode::Batch, 0)), vec![
+            1;
+            7
+        ]);
+        assert!(batches(&[], FetchMode::Batch, 25).is_empty());
+    }
+
+    #[test]
+    fn accounts_missing_from_the_response_hold_no_sol() {
+        let wallets = addresses(3);
+        let token_program = Pubkey::new_unique();
+        let accounts = vec![
+            Some(Account::new(1_500_000_000, 0, &system_program::id())),
+            None,
+            Some(Account::new(2_039_280, 165, &token_program)),
+        ];
+        let balances = batch_balances(&wallets, accounts, 4, Utc::now()).unwrap();
+
+        let reported: Vec<(&str, u64, String)> = balances
+            .iter()
+            .map(|balance| {
+                (
+                    balance.address.as_str(),
+                    balance.lamports,
+                    balance.owner.clone().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(reported, vec![
+            (
+                wallets[0].as_str(),
+                1_500_000_000,
+                system_program::id().to_string()
+            ),
+            (wallets[1].as_str(), 0, system_program::id().to_string()),
+            (wallets[2].as_str(), 2_039_280, token_program.to_string()),
+        ]);
+        assert_eq!(balances[0].balance_sol, 1.5);
+        assert_eq!(balances[1].balance_sol, 0.0);
+    }
+
+    #[test]
+    fn a_short_response_fails_the_batch() {
+        let wallets = addresses(3);
+        let error = batch_balances(&wallets, vec![None, None], 0, Utc::now()).unwrap_err();
+        assert!(
+            error.contains("returned 2 accounts for a batch of 3"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,406 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use common::{lamport_change_to_sol, lamports_to_sol};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+
+// One line of the history file. Every wallet of a run shares its timestamp
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub timestamp: DateTime<Utc>,
+    pub address: String,
+    pub lamports: u64,
+}
+
+// Append the balances of one run to the JSONL file at `path`. The whole run
+// goes out in a single write while holding an exclusive lock, so concurrent
+// runs never interleave their lines
+pub fn append<'a>(
+    path: &str,
+    balances: impl IntoIterator<Item = (&'a str, u64)>,
+) -> Result<usize, Box<dyn Error>> {
+    let timestamp = Utc::now();
+    let mut lines = String::new();
+    let mut count = 0;
+    for (address, lamports) in balances {
+        let record = Record {
+            timestamp,
+            address: address.to_string(),
+            lamports,
+        };
+        lines.push_str(&serde_json::to_string(&record)?);
+        lines.push('\n');
+        count += 1;
+    }
+    if count == 0 {
+        return Ok(0);
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open history {}: {}", path, e))?;
+    file.lock()
+        .map_err(|e| format!("Failed to lock history {}: {}", path, e))?;
+    file.write_all(lines.as_bytes())
+        .and_then(|_| file.flush())
+        .map_err(|e| format!("Failed to write history {}: {}", path, e))?;
+    Ok(count)
+}
+
+// Every record of the history file, oldest run first. Lines that don't parse,
+// e.g. cut off by a crash mid-write, are skipped with a warning
+pub fn read(path: &str) -> Result<Vec<Record>, Box<dyn Error>> {
+    let file = File::open(path).map_err(|e| format!("Failed to open history {}: {}", path, e))?;
+    file.lock_shared()
+        .map_err(|e| format!("Failed to lock history {}: {}", path, e))?;
+
+    let mut records = Vec::new();
+    let mut skipped = 0;
+    for line in BufReader::new(&file).lines() {
+        let line = line.map_err(|e| format!("Failed to read history {}: {}", path, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Record>(&line) {
+            Ok(record) => records.push(record),
+            Err(_) => skipped += 1,
+        }
+    }
+    if skipped > 0 {
+        eprintln!("Warning: skipped {} unreadable lines in {}", skipped, path);
+    }
+    records.sort_by_key(|record| record.timestamp);
+    Ok(records)
+}
+
+// Parse a window like `90s`, `30m`, `24h`, `7d` or `2w`
+pub fn parse_window(s: &str) -> Result<TimeDelta, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("{:?} has no unit, use s, m, h, d or w", s))?;
+    let (count, unit) = s.split_at(split);
+    let count: i64 = count
+        .parse()
+        .map_err(|_| format!("{:?} doesn't start with a number", s))?;
+    let window = match unit {
+        "s" => TimeDelta::try_seconds(count),
+        "m" => TimeDelta::try_minutes(count),
+        "h" => TimeDelta::try_hours(count),
+        "d" => TimeDelta::try_days(count),
+        "w" => TimeDelta::try_weeks(count),
+        _ => return Err(format!("Unknown unit {:?}, use s, m, h, d or w", unit)),
+    };
+    window.ok_or_else(|| format!("{:?} is too long", s))
+}
+
+// How one wallet's balance moved over the report window
+pub struct WalletChange {
+    pub address: String,
+    pub start_lamports: u64,
+    pub end_lamports: u64,
+    // Not in the first run of the window
+    pub appeared: bool,
+    // Not in the last run of the window, `end_lamports` is its last known balance
+    pub disappeared: bool,
+}
+
+impl WalletChange {
+    pub fn delta(&self) -> i128 {
+        self.end_lamports as i128 - self.start_lamports as i128
+    }
+
+    pub fn went_to_zero(&self) -> bool {
+        self.start_lamports > 0 && self.end_lamports == 0
+    }
+}
+
+pub struct HistoryReport {
+    pub first_run: DateTime<Utc>,
+    pub last_run: DateTime<Utc>,
+    pub runs: usize,
+    // Sorted by address
+    pub wallets: Vec<WalletChange>,
+}
+
+// Per-wallet change between the start of the window and the latest run. The
+// start is the last run before `since`, so the change spans the whole window,
+// or the first run after it if the history doesn't reach back that far.
+// Wallets are compared between their own first and last balance within those
+// runs, so ones added or dropped between runs still show up. `None` if no run
+// falls within the window
+pub fn report(records: &[Record], since: DateTime<Utc>) -> Option<HistoryReport> {
+    let mut runs: BTreeMap<DateTime<Utc>, Vec<&Record>> = BTreeMap::new();
+    for record in records {
+        runs.entry(record.timestamp).or_default().push(record);
+    }
+    let baseline = runs
+        .range(..=since)
+        .next_back()
+        .map(|(timestamp, _)| *timestamp);
+    let start = baseline.unwrap_or(since);
+    let runs: Vec<(&DateTime<Utc>, &Vec<&Record>)> = runs.range(start..).collect();
+
+    let (&first_run, first_records) = *runs.first()?;
+    let (&last_run, last_records) = *runs.last()?;
+    if baseline.is_some() && runs.len() == 1 {
+        // Nothing was recorded within the window itself
+        return None;
+    }
+    let in_first: BTreeSet<&str> = first_records.iter().map(|r| r.address.as_str()).collect();
+    let in_last: BTreeSet<&str> = last_records.iter().map(|r| r.address.as_str()).collect();
+
+    let mut wallets: BTreeMap<&str, WalletChange> = BTreeMap::new();
+    for (_, records) in &runs {
+        for record in records.iter() {
+            wallets
+                .entry(record.address.as_str())
+                .and_modify(|change| change.end_lamports = record.lamports)
+                .or_insert_with(|| WalletChange {
+                    address: record.address.clone(),
+                    start_lamports: record.lamports,
+                    end_lamports: record.lamports,
+                    appeared: !in_first.contains(record.address.as_str()),
+                    disappeared: !in_last.contains(record.address.as_str()),
+                });
+        }
+    }
+
+    Some(HistoryReport {
+        first_run,
+        last_run,
+        runs: runs.len(),
+        wallets: wallets.into_values().collect(),
+    })
+}
+
+pub fn print_report(report: &HistoryReport, limit: usize) {
+    println!(
+        "{} runs between {} and {}, {} wallets",
+        report.runs,
+        report.first_run.format("%Y-%m-%d %H:%M:%S"),
+        report.last_run.format("%Y-%m-%d %H:%M:%S"),
+        report.wallets.len()
+    );
+
+    println!(
+        "\n{:<44} | {:<15} | {:<15} | {:<16} | {:<8}",
+        "Address", "Start (SOL)", "End (SOL)", "Change (SOL)", "Note"
+    );
+    println!("{}", "-".repeat(110));
+    for wallet in &report.wallets {
+        let note = if wallet.appeared {
+            "new"
+        } else if wallet.disappeared {
+            "missing"
+        } else {
+            ""
+        };
+        println!(
+            "{:<44} | {:<15.5} | {:<15.5} | {:<+16.9} | {:<8}",
+            wallet.address,
+            lamports_to_sol(wallet.start_lamports),
+            lamports_to_sol(wallet.end_lamports),
+            lamport_change_to_sol(wallet.delta()),
+            note
+        );
+    }
+
+    let mut gainers: Vec<&WalletChange> = report.wallets.iter().filter(|w| w.delta() > 0).collect();
+    gainers.sort_by_key(|wallet| -wallet.delta());
+    print_movers("LARGEST GAINERS", &gainers, limit);
+
+    let mut drainers: Vec<&WalletChange> =
+        report.wallets.iter().filter(|w| w.delta() < 0).collect();
+    drainers.sort_by_key(|wallet| wallet.delta());
+    print_movers("LARGEST DRAINERS", &drainers, limit);
+
+    let zeroed: Vec<&WalletChange> = report.wallets.iter().filter(|w| w.went_to_zero()).collect();
+    if !zeroed.is_empty() {
+        println!("\n{:-^80}", " WENT TO ZERO ");
+        for wallet in zeroed {
+            println!(
+                "  {} (was {:.9} SOL)",
+                wallet.address,
+                lamports_to_sol(wallet.start_lamports)
+            );
+        }
+    }
+
+    let inflow: i128 = gainers.iter().map(|wallet| wallet.delta()).sum();
+    let outflow: i128 = drainers.iter().map(|wallet| -wallet.delta()).sum();
+    println!(
+        "\n{} wallets changed: inflow {:.9} SOL, outflow {:.9} SOL",
+        gainers.len() + drainers.len(),
+        lamport_change_to_sol(inflow),
+        lamport_change_to_sol(outflow)
+    );
+}
+
+fn print_movers(title: &str, wallets: &[&WalletChange], limit: usize) {
+    if wallets.is_empty() || limit == 0 {
+        return;
+    }
+    println!("\n{:-^80}", format!(" {} ", title));
+    for wallet in wallets.iter().take(limit) {
+        println!(
+            "  {:<44} {:+.9} SOL",
+            wallet.address,
+            lamport_change_to_sol(wallet.delta())
+        );
+    }
+}
+
+// The `report` subcommand: the change over the last `window` of the history at `path`
+pub fn run_report(path: &str, window: TimeDelta, limit: usize) -> Result<(), Box<dyn Error>> {
+    let records = read(path)?;
+    let since = Utc::now() - window;
+    let report = report(&records, since).ok_or_else(|| {
+        format!(
+            "No balances recorded in {} since {}",
+            path,
+            since.format("%Y-%m-%d %H:%M:%S")
+        )
+    })?;
+    print_report(&report, limit);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(minutes: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000, 0).unwrap() + TimeDelta::minutes(minutes)
+    }
+
+    // A run at `minutes` with the given balances
+    fn run(minutes: i64, balances: &[(&str, u64)]) -> Vec<Record> {
+        balances
+            .iter()
+            .map(|(address, lamports)| Record {
+                timestamp: at(minutes),
+                address: address.to_string(),
+                lamports: *lamports,
+            })
+            .collect()
+    }
+
+    // (address, start, end, appeared, disappeared) of every wallet
+    fn changes(report: &HistoryReport) -> Vec<(&str, u64, u64, bool, bool)> {
+        report
+            .wallets
+            .iter()
+            .map(|w| {
+                (
+                    w.address.as_str(),
+                    w.start_lamports,
+                    w.end_lamports,
+                    w.appeared,
+                    w.disappeared,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compares_two_snapshots() {
+        let mut records = run(0, &[("a", 100), ("b", 50), ("c", 10)]);
+        records.extend(run(60, &[("a", 150), ("c", 0), ("d", 20)]));
+
+        let report = report(&records, at(30)).unwrap();
+        assert_eq!(report.runs, 2);
+        assert_eq!((report.first_run, report.last_run), (at(0), at(60)));
+        assert_eq!(changes(&report), vec![
+            ("a", 100, 150, false, false),
+            // Missing from the last run, its last known balance stands
+            ("b", 50, 50, false, true),
+            ("c", 10, 0, false, false),
+            ("d", 20, 20, true, false),
+        ]);
+        let deltas: Vec<i128> = report.wallets.iter().map(WalletChange::delta).collect();
+        assert_eq!(deltas, vec![50, 0, -10, 0]);
+        let zeroed: Vec<bool> = report
+            .wallets
+            .iter()
+            .map(WalletChange::went_to_zero)
+            .collect();
+        assert_eq!(zeroed, vec![false, false, true, false]);
+    }
+
+    #[test]
+    fn the_window_starts_at_the_last_run_before_since() {
+        let mut records = run(0, &[("a", 100)]);
+        records.extend(run(60, &[("a", 200)]));
+        records.extend(run(120, &[("a", 250)]));
+
+        // The run at 60 is the baseline of a window starting at 90
+        let window = report(&records, at(90)).unwrap();
+        assert_eq!((window.runs, window.first_run), (2, at(60)));
+        assert_eq!(changes(&window), vec![("a", 200, 250, false, false)]);
+
+        // A window reaching back before the history starts at its first run
+        let window = report(&records, at(-60)).unwrap();
+        assert_eq!((window.runs, window.first_run), (3, at(0)));
+        assert_eq!(changes(&window), vec![("a", 100, 250, false, false)]);
+
+        // Nothing was recorded after the last run
+        assert!(report(&records, at(150)).is_none());
+        assert!(report(&[], at(0)).is_none());
+    }
+
+    #[test]
+    fn parses_windows() {
+        assert_eq!(parse_window("90s"), Ok(TimeDelta::seconds(90)));
+        assert_eq!(parse_window("30m"), Ok(TimeDelta::minutes(30)));
+        assert_eq!(parse_window(" 24h "), Ok(TimeDelta::hours(24)));
+        assert_eq!(parse_window("7d"), Ok(TimeDelta::days(7)));
+        assert_eq!(parse_window("2w"), Ok(TimeDelta::weeks(2)));
+
+        assert!(parse_window("7").unwrap_err().contains("no unit"));
+        assert!(
+            parse_window("d")
+                .unwrap_err()
+                .contains("doesn't start with a number")
+        );
+        assert!(parse_window("3y").unwrap_err().contains("Unknown unit"));
+        assert!(
+            parse_window("99999999999999w")
+                .unwrap_err()
+                .contains("too long")
+        );
+    }
+
+    #[test]
+    fn reads_back_appended_runs() {
+        let path = std::env::temp_dir().join(format!(
+            "balance-checker-{}-history.jsonl",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        assert_eq!(append(path, [("a", 1), ("b", 2)]).unwrap(), 2);
+        // A line cut off mid-write is skipped
+        OpenOptions::new()
+            .append(true)
+            .open(path)
+            .unwrap()
+            .write_all(b"{\"timestamp\": \"2024-")
+            .unwrap();
+        assert_eq!(append(path, std::iter::empty()).unwrap(), 0);
+
+        let records = read(path).unwrap();
+        let balances: Vec<(&str, u64)> = records
+            .iter()
+            .map(|record| (record.address.as_str(), record.lamports))
+            .collect();
+        assert_eq!(balances, vec![("a", 1), ("b", 2)]);
+        assert_eq!(records[0].timestamp, records[1].timestamp);
+        std::fs::remove_file(path).unwrap();
+    }
+}
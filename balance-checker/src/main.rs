@@ -1,159 +1,323 @@
-use futures::future::join_all;
-use serde::{Deserialize, Serialize};
-use solana_client::rpc_client::RpcClient;
-use solana_sdk::commitment_config::CommitmentConfig;
-use solana_sdk::pubkey::Pubkey;
+mod addresses;
+mod client;
+mod config;
+mod fetch;
+mod history;
+mod output;
+mod reconcile;
+mod watch;
+
+use addresses::Column;
+use chrono::TimeDelta;
+use clap::{Parser, Subcommand, ValueEnum};
+use config::read_config;
+use fetch::{FetchReport, WalletBalance, fetch_wallet_balances};
+use output::{print_alerts, print_balances, print_failures, print_owner_mismatches, write_output};
+use reconcile::{print_reconciliation, read_expected};
+use std::collections::HashMap;
 use std::error::Error;
-use std::fs;
-use std::str::FromStr;
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::Duration;
+use watch::{Snapshot, print_change_summary, print_changes};
+
+#[derive(Parser, Debug)]
+#[command(
+    about = "Solana wallet balance checker",
+    args_conflicts_with_subcommands = true
+)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Config file with the wallets to check. Defaults to config.yaml, optional
+    /// when `--wallet` is given
+    #[clap(short, long)]
+    config: Option<String>,
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Config {
+    /// Check this address too, can be repeated. Merged with the config's wallets
+    #[clap(long = "wallet", value_name = "PUBKEY")]
     wallets: Vec<String>,
-    #[serde(default = "default_batch_size")]
-    batch_size: usize,
-    #[serde(default = "default_rpc_url")]
-    rpc_url: String,
-}
 
-fn default_batch_size() -> usize {
-    25
-}
+    /// Text file with one address per line, or a CSV file, to check as well.
+    /// `-` reads them from stdin
+    #[clap(long, value_name = "PATH")]
+    addresses: Option<String>,
 
-fn default_rpc_url() -> String {
-    "https://api.mainnet-beta.solana.com".to_string()
-}
+    /// CSV column with the addresses, a header name or a 0-based index.
+    /// Defaults to the `address` column, or the first one
+    #[clap(long, value_name = "COLUMN", requires = "addresses")]
+    address_column: Option<Column>,
 
-#[derive(Debug, Clone)]
-struct WalletBalance {
-    address: String,
-    balance_sol: f64,
-    fetch_time_ms: u64,
-}
+    /// Skip invalid lines of `--addresses` instead of aborting
+    #[clap(long, requires = "addresses")]
+    skip_invalid: bool,
 
-// Load and validate config
-async fn read_config(config_path: &str) -> Result<Config, Box<dyn Error>> {
-    let contents = fs::read_to_string(config_path)?;
-    let config: Config = serde_yaml::from_str(&contents)?;
+    /// Overrides `rpc_url` from the config. Repeat it or separate several with
+    /// commas to rotate through them
+    #[clap(long, value_name = "URL", value_delimiter = ',')]
+    rpc_url: Vec<String>,
 
-    // Check for empty wallet list
-    if config.wallets.is_empty() {
-        return Err("No wallet addresses specified in config".into());
-    }
+    /// Overrides `batch_size` from the config
+    #[clap(long)]
+    batch_size: Option<usize>,
 
-    // Validate pubkeys upfront
-    for addr in &config.wallets {
-        Pubkey::from_str(addr).map_err(|e| format!("Invalid pubkey {}: {}", addr, e))?;
-    }
+    /// Keep re-checking the balances every `--interval` seconds
+    #[clap(long)]
+    watch: bool,
+
+    /// Seconds between checks in watch mode
+    #[clap(long, default_value = "30")]
+    interval: u64,
+
+    /// In watch mode, print only wallets whose balance changed since the previous check
+    #[clap(long, requires = "watch")]
+    only_changes: bool,
+
+    /// In watch mode, keep the last balances in this JSON file so changes are
+    /// reported against them after a restart
+    #[clap(long, requires = "watch")]
+    snapshot: Option<String>,
 
-    Ok(config)
+    /// YAML or JSON file mapping addresses to their expected balance in SOL.
+    /// Exits with an error if any wallet deviates by more than `--tolerance`
+    #[clap(long, conflicts_with = "watch")]
+    expected: Option<String>,
+
+    /// Allowed difference from the expected balance in SOL
+    #[clap(long, default_value = "0", requires = "expected")]
+    tolerance: f64,
+
+    /// Also write the fetched balances to this file
+    #[clap(long, conflicts_with = "watch")]
+    output: Option<String>,
+
+    /// Format of the `--output` file
+    #[clap(
+        long,
+        visible_alias = "format",
+        value_enum,
+        default_value = "json",
+        requires = "output"
+    )]
+    output_format: OutputFormat,
+
+    /// Only list the N wallets with the highest balance, combinable with `--bottom`.
+    /// The summary still covers every wallet
+    #[clap(long, value_name = "N")]
+    top: Option<usize>,
+
+    /// Only list the N wallets with the lowest balance, combinable with `--top`
+    #[clap(long, value_name = "N")]
+    bottom: Option<usize>,
+
+    /// Overrides `alert_threshold_sol` (alias `min_balance_sol`) from the config
+    #[clap(long, value_name = "SOL")]
+    min_balance: Option<f64>,
+
+    /// Append every check's balances to this JSONL file, for `report`
+    #[clap(long, value_name = "PATH")]
+    history: Option<String>,
 }
 
-// Fetch single wallet balance
-async fn fetch_wallet_balance(
-    client: Arc<RpcClient>,
-    address: String,
-) -> Result<WalletBalance, String> {
-    let start_time = Instant::now();
-    let pubkey = Pubkey::from_str(&address).map_err(|e| e.to_string())?;
-    // Include address in RPC error for clarity
-    let balance = client
-        .get_balance(&pubkey)
-        .map_err(|e| format!("RPC error for {}: {}", address, e))?;
-    let elapsed = start_time.elapsed().as_millis() as u64;
-
-    Ok(WalletBalance {
-        address,
-        balance_sol: balance as f64 / 1_000_000_000.0,
-        fetch_time_ms: elapsed,
-    })
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Per-wallet balance change over a window of the `--history` file
+    Report {
+        /// History file written by `--history`
+        #[clap(long, value_name = "PATH")]
+        history: String,
+
+        /// How far back to look, e.g. `30m`, `24h` or `7d`
+        #[clap(long, value_name = "WINDOW", default_value = "24h", value_parser = history::parse_window)]
+        since: TimeDelta,
+
+        /// Number of largest gainers and drainers to list
+        #[clap(long, value_name = "N", default_value = "5")]
+        limit: usize,
+    },
 }
 
-// Fetch balances in batches
-async fn fetch_wallet_balances(config_path: &str) -> Result<Vec<WalletBalance>, Box<dyn Error>> {
-    let config = read_config(config_path).await?;
-    println!("Loading {} wallet addresses", config.wallets.len());
-
-    // Added timeout to avoid hanging RPC calls
-    let client = Arc::new(RpcClient::new_with_timeout_and_commitment(
-        config.rpc_url,
-        Duration::from_secs(30),
-        CommitmentConfig::confirmed(),
-    ));
-
-    let mut all_results = Vec::new();
-    let total_start = Instant::now();
-
-    for (batch_idx, chunk) in config.wallets.chunks(config.batch_size).enumerate() {
-        println!(
-            "Processing batch {} ({} addresses)",
-            batch_idx + 1,
-            chunk.len()
-        );
-        let batch_start = Instant::now();
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    Json,
+    Csv,
+}
 
-        // Parallel tasks for each wallet in batch
-        let tasks: Vec<_> = chunk
+// Wallets that failed to fetch are left out, the report treats them as
+// missing from this run
+fn record_history(path: &str, balances: &[WalletBalance]) -> Result<usize, Box<dyn Error>> {
+    history::append(
+        path,
+        balances
             .iter()
-            .map(|addr| {
-                let client_clone = Arc::clone(&client);
-                let addr_clone = addr.clone();
-                tokio::spawn(async move { fetch_wallet_balance(client_clone, addr_clone).await })
-            })
-            .collect();
-
-        let results = join_all(tasks).await;
-
-        // Log errors, collect successes
-        for result in results {
-            match result {
-                Ok(Ok(balance)) => all_results.push(balance),
-                Ok(Err(e)) => eprintln!("Failed to fetch balance: {}", e),
-                Err(e) => eprintln!("Task panicked: {}", e),
+            .map(|balance| (balance.address.as_str(), balance.lamports)),
+    )
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+
+    if let Some(Command::Report {
+        history,
+        since,
+        limit,
+    }) = &args.command
+    {
+        return history::run_report(history, *since, *limit);
+    }
+
+    // Read once, stdin can't be read again on every check in watch mode
+    let listed = match &args.addresses {
+        Some(path) => {
+            let list =
+                addresses::read_addresses(path, args.address_column.as_ref(), args.skip_invalid)?;
+            if list.duplicates > 0 {
+                eprintln!(
+                    "Warning: ignored {} duplicate addresses in {}",
+                    list.duplicates, path
+                );
             }
+            if list.invalid > 0 {
+                eprintln!(
+                    "Warning: skipped {} invalid addresses in {}",
+                    list.invalid, path
+                );
+            }
+            list.addresses
         }
+        None => Vec::new(),
+    };
 
-        println!(
-            "Batch {} completed in {:.2}s",
-            batch_idx + 1,
-            batch_start.elapsed().as_secs_f64()
+    if !args.watch {
+        // Fail on a bad expected file before spending time on RPC calls
+        let expected = args.expected.as_deref().map(read_expected).transpose()?;
+        let config = read_config(&args, &listed).await?;
+        let FetchReport { balances, failed } = fetch_wallet_balances(&config, true).await?;
+        if let Some(path) = &args.history {
+            let count = record_history(path, &balances)?;
+            println!("Appended {} balances to {}", count, path);
+        }
+        print_balances(
+            &balances,
+            &failed,
+            &config.token_mints,
+            config.alert_threshold_sol,
+            args.top,
+            args.bottom,
         );
+        if let Some(path) = &args.output {
+            write_output(
+                path,
+                args.output_format,
+                &balances,
+                config.alert_threshold_sol,
+            )?;
+        }
 
-        // Delay to avoid rate limits—semaphores too complex for this
-        if batch_idx < config.wallets.chunks(config.batch_size).len() - 1 {
-            tokio::time::sleep(Duration::from_millis(100)).await;
+        let alerts = print_alerts(&balances, config.alert_threshold_sol);
+        let mismatches = print_owner_mismatches(&balances, &config.expected_owners());
+
+        if let Some(expected) = expected {
+            let deviations = print_reconciliation(&balances, &expected, args.tolerance);
+            if deviations > 0 {
+                return Err(format!(
+                    "{} of {} wallets deviate from their expected balance",
+                    deviations,
+                    expected.len()
+                )
+                .into());
+            }
+            println!(
+                "\nAll {} wallets match their expected balance",
+                expected.len()
+            );
         }
+
+        if mismatches > 0 {
+            return Err(format!(
+                "{} wallets are not owned by their expected program",
+                mismatches
+            )
+            .into());
+        }
+        // Lets cron jobs notice low wallets through the exit code
+        if alerts > 0 {
+            return Err(format!("{} wallets are below the alert threshold", alerts).into());
+        }
+        return Ok(());
     }
 
-    println!(
-        "Fetched all balances in {:.2}s",
-        total_start.elapsed().as_secs_f64()
-    );
+    // Balances from the previous check, only filled once the full table was
+    // shown or loaded from the snapshot of an earlier run
+    let mut previous = match &args.snapshot {
+        Some(path) => Snapshot::load(path)?.map(|snapshot| snapshot.lamports),
+        None => None,
+    };
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        let verbose = previous.is_none() || !args.only_changes;
+        // Re-read every time so config edits apply without a restart
+        let fetch = async {
+            let config = read_config(&args, &listed).await?;
+            let report = fetch_wallet_balances(&config, verbose).await?;
+            Ok::<_, Box<dyn Error>>((config, report))
+        };
+        let fetched = tokio::select! {
+            fetched = fetch => fetched,
+            _ = &mut ctrl_c => break,
+        };
 
-    Ok(all_results)
-}
+        match fetched {
+            Ok((config, FetchReport { balances, failed })) => {
+                if let Some(path) = &args.history
+                    && let Err(e) = record_history(path, &balances)
+                {
+                    eprintln!("{}", e);
+                }
+                match &previous {
+                    Some(previous) if args.only_changes => {
+                        print_changes(&balances, previous);
+                        print_failures(&failed);
+                    }
+                    _ => print_balances(
+                        &balances,
+                        &failed,
+                        &config.token_mints,
+                        config.alert_threshold_sol,
+                        args.top,
+                        args.bottom,
+                    ),
+                }
+                if let Some(previous) = &previous {
+                    print_change_summary(&balances, previous);
+                }
+                print_alerts(&balances, config.alert_threshold_sol);
+                print_owner_mismatches(&balances, &config.expected_owners());
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let config_path = "config.yaml";
-    let balances = fetch_wallet_balances(config_path).await?;
-
-    println!("\nWallet Balance Results:");
-    println!(
-        "{:<44} | {:<15} | {:<8}",
-        "Address", "Balance (SOL)", "Time (ms)"
-    );
-    println!("{}", "-".repeat(75));
-
-    for balance in &balances {
-        println!(
-            "{:<44} | {:<15.5} | {:<8}",
-            balance.address, balance.balance_sol, balance.fetch_time_ms
-        );
+                // Wallets that failed this time keep their last known balance
+                let snapshot = previous.get_or_insert_with(HashMap::new);
+                for balance in &balances {
+                    snapshot.insert(balance.address.clone(), balance.lamports);
+                }
+                if let Some(path) = &args.snapshot
+                    && let Err(e) = Snapshot::save(path, snapshot)
+                {
+                    eprintln!("{}", e);
+                }
+            }
+            Err(e) => eprintln!("Failed to fetch balances: {}", e),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(args.interval)) => {}
+            _ = &mut ctrl_c => break,
+        }
     }
 
-    println!("\nSummary: Fetched {} balances", balances.len());
+    if let (Some(path), Some(previous)) = (&args.snapshot, &previous) {
+        Snapshot::save(path, previous)?;
+        println!("\nSaved the last balances to {}", path);
+    }
     Ok(())
 }
@@ -0,0 +1,309 @@
+use crate::OutputFormat;
+use crate::fetch::{FailedWallet, WalletBalance};
+use crate::reconcile::sol_to_lamports;
+use common::lamports_to_sol;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+
+pub fn print_balances(
+    balances: &[WalletBalance],
+    failed: &[FailedWallet],
+    mints: &[String],
+    threshold_sol: Option<f64>,
+    top: Option<usize>,
+    bottom: Option<usize>,
+) {
+    println!("\nWallet Balance Results:");
+    let token_headers: String = mints
+        .iter()
+        .map(|mint| format!(" | {:<15}", short_mint(mint)))
+        .collect();
+    println!(
+        "{:<44} | {:<15}{} | {:<9} | {:<5}",
+        "Address", "Balance (SOL)", token_headers, "Time (ms)", "Flag"
+    );
+    println!("{}", "-".repeat(85 + 18 * mints.len()));
+
+    for (group_idx, group) in quick_view(balances, top, bottom).iter().enumerate() {
+        if group_idx > 0 {
+            println!("{:<44} |", "...");
+        }
+        for balance in group {
+            print_balance_row(balance, mints, threshold_sol);
+        }
+    }
+
+    println!("\nSummary: Fetched {} balances", balances.len());
+    println!("Total: {:.9} SOL", total_sol(balances));
+    for (mint, total) in token_totals(balances) {
+        println!("Total: {} of token {}", total, mint);
+    }
+    print_failures(failed);
+}
+
+// Every wallet in config order, or with `--top`/`--bottom` the richest and
+// the emptiest wallets sorted by balance, as groups split by a separator
+fn quick_view(
+    balances: &[WalletBalance],
+    top: Option<usize>,
+    bottom: Option<usize>,
+) -> Vec<Vec<&WalletBalance>> {
+    if top.is_none() && bottom.is_none() {
+        return vec![balances.iter().collect()];
+    }
+
+    let mut sorted: Vec<_> = balances.iter().collect();
+    sorted.sort_by_key(|balance| Reverse(balance.lamports));
+    let (top, bottom) = (top.unwrap_or(0), bottom.unwrap_or(0));
+    // Overlapping views would list wallets twice
+    if top + bottom >= sorted.len() {
+        return vec![sorted];
+    }
+
+    let bottom_rows = sorted.split_off(sorted.len() - bottom);
+    sorted.truncate(top);
+    [sorted, bottom_rows]
+        .into_iter()
+        .filter(|group| !group.is_empty())
+        .collect()
+}
+
+fn print_balance_row(balance: &WalletBalance, mints: &[String], threshold_sol: Option<f64>) {
+    let token_columns: String = mints
+        .iter()
+        .map(|mint| {
+            format!(
+                " | {:<15.5}",
+                balance
+                    .token_balances
+                    .get(mint)
+                    .copied()
+                    .unwrap_or_default()
+            )
+        })
+        .collect();
+    println!(
+        "{:<44} | {:<15.5}{} | {:<9} | {:<5}",
+        balance.address,
+        balance.balance_sol,
+        token_columns,
+        balance.fetch_time_ms,
+        low_balance_flag(balance, threshold_sol).unwrap_or_default()
+    );
+}
+
+pub fn print_failures(failed: &[FailedWallet]) {
+    if failed.is_empty() {
+        return;
+    }
+    println!("\nFailed to fetch {} wallets:", failed.len());
+    for wallet in failed {
+        println!("{:<44} | {}", wallet.address, wallet.error);
+    }
+}
+
+fn total_sol(balances: &[WalletBalance]) -> f64 {
+    lamports_to_sol(balances.iter().map(|balance| balance.lamports).sum())
+}
+
+// Mint -> sum over all wallets in UI units
+fn token_totals(balances: &[WalletBalance]) -> BTreeMap<String, f64> {
+    let mut totals = BTreeMap::new();
+    for balance in balances {
+        for (mint, amount) in &balance.token_balances {
+            *totals.entry(mint.clone()).or_default() += amount;
+        }
+    }
+    totals
+}
+
+// First and last characters of a mint, to fit into a table column
+fn short_mint(mint: &str) -> String {
+    if mint.len() > 11 {
+        format!("{}..{}", &mint[..4], &mint[mint.len() - 4..])
+    } else {
+        mint.to_string()
+    }
+}
+
+// Balance statistics across all fetched wallets, in SOL
+#[derive(Serialize)]
+struct Aggregate {
+    total_sol: f64,
+    min_sol: Option<f64>,
+    max_sol: Option<f64>,
+    mean_sol: Option<f64>,
+    threshold_sol: Option<f64>,
+    below_threshold: usize,
+}
+
+impl Aggregate {
+    fn new(balances: &[WalletBalance], threshold_sol: Option<f64>) -> Self {
+        let min = balances.iter().map(|balance| balance.lamports).min();
+        let max = balances.iter().map(|balance| balance.lamports).max();
+        let total = total_sol(balances);
+        Aggregate {
+            total_sol: total,
+            min_sol: min.map(lamports_to_sol),
+            max_sol: max.map(lamports_to_sol),
+            mean_sol: (!balances.is_empty()).then(|| total / balances.len() as f64),
+            threshold_sol,
+            below_threshold: balances
+                .iter()
+                .filter(|balance| low_balance_flag(balance, threshold_sol).is_some())
+                .count(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    total_sol: f64,
+    aggregate: Aggregate,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    token_totals: BTreeMap<String, f64>,
+    wallets: &'a [WalletBalance],
+}
+
+// Write the balances for other tools to consume
+pub fn write_output(
+    path: &str,
+    format: OutputFormat,
+    balances: &[WalletBalance],
+    threshold_sol: Option<f64>,
+) -> Result<(), Box<dyn Error>> {
+    let file = fs::File::create(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+    match format {
+        OutputFormat::Json => {
+            let report = JsonReport {
+                total_sol: total_sol(balances),
+                aggregate: Aggregate::new(balances, threshold_sol),
+                token_totals: token_totals(balances),
+                wallets: balances,
+            };
+            serde_json::to_writer_pretty(file, &report)?;
+        }
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(file);
+            // One column per token mint, named after the mint
+            let mints: Vec<&String> = balances
+                .first()
+                .map(|balance| balance.token_balances.keys().collect())
+                .unwrap_or_default();
+            let mut header = vec!["address", "lamports", "balance_sol"];
+            header.extend(mints.iter().map(|mint| mint.as_str()));
+            header.extend(["fetch_time_ms", "fetched_at", "flag"]);
+            writer.write_record(&header)?;
+            for balance in balances {
+                let mut record = vec![
+                    balance.address.clone(),
+                    balance.lamports.to_string(),
+                    balance.balance_sol.to_string(),
+                ];
+                record.extend(mints.iter().map(|mint| {
+                    balance
+                        .token_balances
+                        .get(*mint)
+                        .copied()
+                        .unwrap_or_default()
+                        .to_string()
+                }));
+                record.push(balance.fetch_time_ms.to_string());
+                record.push(balance.fetched_at.to_rfc3339());
+                record.push(
+                    low_balance_flag(balance, threshold_sol)
+                        .unwrap_or_default()
+                        .to_string(),
+                );
+                writer.write_record(&record)?;
+            }
+            writer.flush()?;
+        }
+    }
+    println!("Wrote {} balances to {}", balances.len(), path);
+    Ok(())
+}
+
+// EMPTY or LOW if the wallet holds less than `threshold_sol`.
+// Empty and nonexistent accounts are flagged even without a threshold
+fn low_balance_flag(balance: &WalletBalance, threshold_sol: Option<f64>) -> Option<&'static str> {
+    if balance.lamports == 0 {
+        Some("EMPTY")
+    } else if (balance.lamports as i128) < threshold_sol.map_or(0, sol_to_lamports) {
+        Some("LOW")
+    } else {
+        None
+    }
+}
+
+// List wallets below the alert threshold, returns how many there are
+pub fn print_alerts(balances: &[WalletBalance], threshold_sol: Option<f64>) -> usize {
+    let low: Vec<_> = balances
+        .iter()
+        .filter_map(|balance| Some((balance, low_balance_flag(balance, threshold_sol)?)))
+        .collect();
+
+    if low.is_empty() {
+        return 0;
+    }
+
+    match threshold_sol {
+        Some(threshold_sol) => println!(
+            "\n!!! LOW BALANCE ALERT: {} wallets below {} SOL !!!",
+            low.len(),
+            threshold_sol
+        ),
+        None => println!("\n!!! LOW BALANCE ALERT: {} empty wallets !!!", low.len()),
+    }
+    println!(
+        "{:<44} | {:<15} | {:<6}",
+        "Address", "Balance (SOL)", "Status"
+    );
+    println!("{}", "-".repeat(70));
+    for (balance, status) in &low {
+        println!(
+            "{:<44} | {:<15.5} | {:<6}",
+            balance.address, balance.balance_sol, status
+        );
+    }
+
+    low.len()
+}
+
+// List wallets not owned by the program they're expected to be, e.g. an
+// address that was replaced, returns how many there are
+pub fn print_owner_mismatches(
+    balances: &[WalletBalance],
+    expected_owners: &HashMap<String, String>,
+) -> usize {
+    let mismatched: Vec<_> = balances
+        .iter()
+        .filter_map(|balance| {
+            let expected = expected_owners.get(&balance.address)?;
+            let owner = balance.owner.as_ref()?;
+            (owner != expected).then_some((balance, owner, expected))
+        })
+        .collect();
+
+    if mismatched.is_empty() {
+        return 0;
+    }
+
+    println!(
+        "\n!!! OWNER MISMATCH: {} wallets not owned by their expected program !!!",
+        mismatched.len()
+    );
+    println!(
+        "{:<44} | {:<44} | {:<44}",
+        "Address", "Owner", "Expected owner"
+    );
+    println!("{}", "-".repeat(136));
+    for (balance, owner, expected) in &mismatched {
+        println!("{:<44} | {:<44} | {:<44}", balance.address, owner, expected);
+    }
+
+    mismatched.len()
+}
@@ -0,0 +1,79 @@
+use crate::fetch::WalletBalance;
+use common::lamport_change_to_sol;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::str::FromStr;
+
+// Load the expected balances, address -> SOL
+pub fn read_expected(path: &str) -> Result<HashMap<String, f64>, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read expected balances {}: {}", path, e))?;
+    // JSON is valid YAML, so one parser covers both
+    let expected: HashMap<String, f64> = serde_yaml::from_str(&contents)
+        .map_err(|e| format!("Failed to parse expected balances {}: {}", path, e))?;
+
+    for addr in expected.keys() {
+        Pubkey::from_str(addr).map_err(|e| format!("Invalid pubkey {}: {}", addr, e))?;
+    }
+
+    Ok(expected)
+}
+
+// Signed, to compare against balance differences
+pub fn sol_to_lamports(sol: f64) -> i128 {
+    common::sol_to_lamports(sol) as i128
+}
+
+// Compare actual balances against the expected ones, returns the number of
+// wallets that are off by more than `tolerance_sol` or couldn't be checked
+pub fn print_reconciliation(
+    balances: &[WalletBalance],
+    expected: &HashMap<String, f64>,
+    tolerance_sol: f64,
+) -> usize {
+    let tolerance = sol_to_lamports(tolerance_sol);
+    let mut deviations = 0;
+
+    println!("\nReconciliation against expected balances:");
+    println!(
+        "{:<44} | {:<15} | {:<15} | {:<16} | {:<6}",
+        "Address", "Balance (SOL)", "Expected (SOL)", "Diff (SOL)", "Status"
+    );
+    println!("{}", "-".repeat(110));
+
+    for balance in balances {
+        let Some(&expected_sol) = expected.get(&balance.address) else {
+            continue;
+        };
+        let diff = balance.lamports as i128 - sol_to_lamports(expected_sol);
+        let status = if diff.abs() > tolerance {
+            deviations += 1;
+            "DEVIATES"
+        } else {
+            "OK"
+        };
+        println!(
+            "{:<44} | {:<15.5} | {:<15.5} | {:<+16.9} | {:<6}",
+            balance.address,
+            balance.balance_sol,
+            expected_sol,
+            lamport_change_to_sol(diff),
+            status
+        );
+    }
+
+    // Expected wallets without a fetched balance can't be verified
+    for address in expected.keys() {
+        if !balances.iter().any(|balance| &balance.address == address) {
+            deviations += 1;
+            println!(
+                "{:<44} | {:<15} | {:<15.5} | {:<16} | {:<6}",
+                address, "-", expected[address], "-", "MISSING"
+            );
+        }
+    }
+
+    deviations
+}
@@ -0,0 +1,109 @@
+use crate::fetch::WalletBalance;
+use chrono::{DateTime, Utc};
+use common::{lamport_change_to_sol, lamports_to_sol};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+
+// Print only wallets whose balance differs from the previous check
+pub fn print_changes(balances: &[WalletBalance], previous: &HashMap<String, u64>) {
+    let changed: Vec<_> = balances
+        .iter()
+        .filter_map(|balance| {
+            let before = *previous.get(&balance.address)?;
+            (before != balance.lamports).then_some((balance, before))
+        })
+        .collect();
+
+    if changed.is_empty() {
+        println!("No changes across {} wallets", balances.len());
+        return;
+    }
+
+    println!(
+        "\n{:<44} | {:<15} | {:<16} | {:<9}",
+        "Address", "Balance (SOL)", "Change (SOL)", "Direction"
+    );
+    println!("{}", "-".repeat(92));
+    for (balance, before) in changed {
+        let delta = balance.lamports as i128 - before as i128;
+        println!(
+            "{:<44} | {:<15.5} | {:<+16.9} | {:<9}",
+            balance.address,
+            balance.balance_sol,
+            lamport_change_to_sol(delta),
+            if delta > 0 { "IN" } else { "OUT" }
+        );
+    }
+}
+
+// One line with the number of changed wallets and the SOL that moved in and
+// out of them since the previous check
+pub fn print_change_summary(balances: &[WalletBalance], previous: &HashMap<String, u64>) {
+    let mut changed = 0;
+    let mut inflow: u64 = 0;
+    let mut outflow: u64 = 0;
+    for balance in balances {
+        let Some(&before) = previous.get(&balance.address) else {
+            continue;
+        };
+        if balance.lamports > before {
+            inflow += balance.lamports - before;
+        } else if balance.lamports < before {
+            outflow += before - balance.lamports;
+        } else {
+            continue;
+        }
+        changed += 1;
+    }
+
+    println!(
+        "{} changed since last check: {} wallets, inflow {:.9} SOL, outflow {:.9} SOL",
+        Utc::now().format("%Y-%m-%d %H:%M:%S"),
+        changed,
+        lamports_to_sol(inflow),
+        lamports_to_sol(outflow)
+    );
+}
+
+// Last balances of a watch run, address -> lamports
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub taken_at: DateTime<Utc>,
+    pub lamports: HashMap<String, u64>,
+}
+
+impl Snapshot {
+    // `None` if there's no snapshot yet
+    pub fn load(path: &str) -> Result<Option<Snapshot>, Box<dyn Error>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Failed to read snapshot {}: {}", path, e).into()),
+        };
+        let snapshot: Snapshot = serde_json::from_str(&contents)
+            .map_err(|e| format!("Failed to parse snapshot {}: {}", path, e))?;
+        println!(
+            "Loaded the balances of {} wallets from {} (taken at {})",
+            snapshot.lamports.len(),
+            path,
+            snapshot.taken_at.to_rfc3339()
+        );
+        Ok(Some(snapshot))
+    }
+
+    // Written to a temporary file first so a crash never leaves half a snapshot
+    pub fn save(path: &str, lamports: &HashMap<String, u64>) -> Result<(), Box<dyn Error>> {
+        let snapshot = Snapshot {
+            taken_at: Utc::now(),
+            lamports: lamports.clone(),
+        };
+        let tmp_path = format!("{}.tmp", path);
+        let json = serde_json::to_string_pretty(&snapshot)?;
+        fs::write(&tmp_path, json)
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(|e| format!("Failed to save snapshot {}: {}", path, e).into())
+    }
+}
@@ -0,0 +1,61 @@
+use anyhow::{Context, Result, anyhow, bail};
+use solana_sdk::signer::{
+    Signer,
+    keypair::{Keypair, read_keypair, read_keypair_file},
+};
+
+/// Prefix of a keypair given inline as its base58 encoded secret key
+const BASE58_PREFIX: &str = "base58:";
+/// Prefix of a keypair read from an environment variable
+const ENV_PREFIX: &str = "env:";
+
+/// Loads the keypair `source` refers to:
+/// - `base58:<secret>`, the 64 byte secret key in base58 as wallets export it
+/// - `env:<VAR>`, a variable holding a base58 secret or the JSON byte array of a keypair file
+/// - anything else is the path of a keypair file
+///
+/// Errors never contain the secret itself
+pub fn load_keypair(source: &str) -> Result<Keypair> {
+    if let Some(secret) = source.strip_prefix(BASE58_PREFIX) {
+        return from_base58(secret);
+    }
+
+    if let Some(var) = source.strip_prefix(ENV_PREFIX) {
+        let value = std::env::var(var)
+            .with_context(|| format!("Keypair environment variable {} is not set", var))?;
+        let value = value.trim();
+        return if value.starts_with('[') {
+            read_keypair(&mut value.as_bytes())
+                .map_err(|e| anyhow!("Invalid keypair JSON in {}: {}", var, e))
+        } else {
+            from_base58(value).with_context(|| format!("Invalid keypair in {}", var))
+        };
+    }
+
+    read_keypair_file(source).map_err(|e| anyhow!("Failed to read keypair file: {}", e))
+}
+
+fn from_base58(secret: &str) -> Result<Keypair> {
+    let bytes = bs58::decode(secret.trim())
+        .into_vec()
+        .map_err(|e| anyhow!("Malformed base58 keypair secret: {}", e))?;
+    if bytes.len() != 64 {
+        bail!(
+            "Base58 keypair secret decodes to {} bytes, expected 64",
+            bytes.len()
+        );
+    }
+    Keypair::from_bytes(&bytes).map_err(|e| anyhow!("Invalid base58 keypair secret: {}", e))
+}
+
+/// `source` as it may appear in logs, reports and the state file: inline
+/// secrets are replaced by the pubkey they belong to
+pub fn describe(source: &str) -> String {
+    match source.strip_prefix(BASE58_PREFIX) {
+        Some(secret) => match from_base58(secret) {
+            Ok(keypair) => format!("base58:<secret of {}>", keypair.pubkey()),
+            Err(_) => "base58:<invalid secret>".to_string(),
+        },
+        None => source.to_string(),
+    }
+}
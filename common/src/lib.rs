@@ -0,0 +1,86 @@
+//! Solana helpers shared by balance-checker, multi-transfer-cli and
+//! yellowstone-watcher
+
+pub mod keypair;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod rate_limit;
+pub mod retry;
+pub mod rpc;
+
+pub use keypair::load_keypair;
+pub use rpc::{RoundRobinClient, RpcUrls, SolanaRpc};
+
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{commitment_config::CommitmentConfig, native_token::LAMPORTS_PER_SOL};
+use std::time::Duration;
+
+/// `sol` in lamports, rounded to the nearest lamport so amounts like 0.1 SOL
+/// don't lose one to floating point error. Negative amounts become 0
+pub fn sol_to_lamports(sol: f64) -> u64 {
+    (sol * LAMPORTS_PER_SOL as f64).round() as u64
+}
+
+/// `lamports` in SOL, for display
+pub fn lamports_to_sol(lamports: u64) -> f64 {
+    lamports as f64 / LAMPORTS_PER_SOL as f64
+}
+
+/// A signed amount of lamports, e.g. a balance change, in SOL, for display
+pub fn lamport_change_to_sol(lamports: i128) -> f64 {
+    lamports as f64 / LAMPORTS_PER_SOL as f64
+}
+
+/// Nonblocking RPC client for `url`, every request gives up after `timeout`
+pub fn make_rpc_client(
+    url: impl Into<String>,
+    commitment: CommitmentConfig,
+    timeout: Duration,
+) -> RpcClient {
+    RpcClient::new_with_timeout_and_commitment(url.into(), timeout, commitment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sol_to_lamports_rounds_to_the_nearest_lamport() {
+        assert_eq!(sol_to_lamports(0.1), 100_000_000);
+        assert_eq!(sol_to_lamports(0.3), 300_000_000);
+        assert_eq!(sol_to_lamports(1e-9), 1);
+        assert_eq!(sol_to_lamports(1.4e-9), 1);
+        assert_eq!(sol_to_lamports(0.4e-9), 0);
+        assert_eq!(sol_to_lamports(0.0), 0);
+    }
+
+    #[test]
+    fn sol_to_lamports_handles_large_amounts() {
+        assert_eq!(sol_to_lamports(5_000_000.0), 5_000_000_000_000_000);
+        assert_eq!(sol_to_lamports(1_000_000.123456789), 1_000_000_123_456_789);
+        // Beyond u64, saturates instead of wrapping
+        assert_eq!(sol_to_lamports(1e12), u64::MAX);
+    }
+
+    #[test]
+    fn sol_to_lamports_turns_negatives_into_zero() {
+        assert_eq!(sol_to_lamports(-1.0), 0);
+        assert_eq!(sol_to_lamports(-1e-9), 0);
+        assert_eq!(sol_to_lamports(f64::NAN), 0);
+    }
+
+    #[test]
+    fn lamports_to_sol_round_trips() {
+        assert_eq!(lamports_to_sol(100_000_000), 0.1);
+        assert_eq!(sol_to_lamports(lamports_to_sol(123_456_789)), 123_456_789);
+    }
+
+    #[test]
+    fn lamport_changes_keep_their_sign() {
+        assert_eq!(lamport_change_to_sol(-1_500_000_000), -1.5);
+        assert_eq!(
+            lamport_change_to_sol(100_000_000),
+            lamports_to_sol(100_000_000)
+        );
+    }
+}
@@ -0,0 +1,358 @@
+//! In-memory `SolanaRpc` for tests, so the send, retry and confirmation paths
+//! can run against scripted RPC behaviour instead of a live node.
+//!
+//! Time is measured with `tokio::time`, so tests can pause the clock and let
+//! blockhashes expire or slow sends finish without waiting
+
+use crate::SolanaRpc;
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind, Result as ClientResult},
+    rpc_config::{RpcSendTransactionConfig, RpcSimulateTransactionConfig, RpcTransactionConfig},
+    rpc_response::{
+        Response, RpcPrioritizationFee, RpcResponseContext, RpcResult, RpcSimulateTransactionResult,
+    },
+};
+use solana_sdk::{
+    account::Account,
+    commitment_config::CommitmentConfig,
+    hash::Hash,
+    message::Message,
+    pubkey::Pubkey,
+    signature::Signature,
+    transaction::{self, Transaction, TransactionError},
+};
+use solana_transaction_status_client_types::{
+    EncodedConfirmedTransactionWithStatusMeta, TransactionConfirmationStatus, TransactionStatus,
+};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::time::{Instant, sleep};
+
+/// Slot every response of the mock claims to be from
+const SLOT: u64 = 1;
+
+/// One call of `send_transaction_with_config` or `send_and_confirm_transaction`
+#[derive(Debug, Clone)]
+pub struct SentTransaction {
+    pub transaction: Transaction,
+    pub started: Instant,
+    pub finished: Instant,
+    /// Whether the node took the transaction, even if the call then failed
+    pub accepted: bool,
+}
+
+/// What the mock node knows and how it answers. Every field can be changed
+/// between calls through `MockRpc::state`
+pub struct MockState {
+    /// Balances `get_balance` reports, 0 for anything else
+    pub balances: HashMap<Pubkey, u64>,
+    pub accounts: HashMap<Pubkey, Account>,
+    /// `get_fee_for_message` charges this per required signature
+    pub fee_per_signature: u64,
+    pub prioritization_fees: Vec<u64>,
+    /// How long a blockhash stays valid after `get_latest_blockhash` handed it
+    /// out. Sends signed with an older or unknown one fail with `BlockhashNotFound`
+    pub blockhash_ttl: Duration,
+    /// How long every send takes
+    pub send_delay: Duration,
+    /// Whether accepted transactions land right away. Otherwise their status
+    /// stays unknown until it's set in `statuses`
+    pub land_sent: bool,
+    /// Final status of the transactions that landed
+    pub statuses: HashMap<Signature, transaction::Result<()>>,
+    /// Every send, in the order they started
+    pub sends: Vec<SentTransaction>,
+    blockhashes: HashMap<Hash, Instant>,
+    errors: HashMap<&'static str, VecDeque<ClientError>>,
+    lost_responses: VecDeque<ClientError>,
+    calls: HashMap<&'static str, usize>,
+}
+
+pub struct MockRpc {
+    state: Mutex<MockState>,
+}
+
+impl Default for MockRpc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockRpc {
+    /// A node where sends land right away, blockhashes live for 60 seconds and
+    /// every wallet is empty
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(MockState {
+                balances: HashMap::new(),
+                accounts: HashMap::new(),
+                fee_per_signature: 5000,
+                prioritization_fees: Vec::new(),
+                blockhash_ttl: Duration::from_secs(60),
+                send_delay: Duration::ZERO,
+                land_sent: true,
+                statuses: HashMap::new(),
+                sends: Vec::new(),
+                blockhashes: HashMap::new(),
+                errors: HashMap::new(),
+                lost_responses: VecDeque::new(),
+                calls: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Makes the next call of `method`, named like the `SolanaRpc` method,
+    /// fail with `error`. Queued errors are used up in order. Both send
+    /// methods count as `send_transaction`
+    pub fn fail_next(&self, method: &'static str, error: impl Into<ClientError>) {
+        self.state()
+            .errors
+            .entry(method)
+            .or_default()
+            .push_back(error.into());
+    }
+
+    /// Makes the next send reach the node, and land if `land_sent` is set,
+    /// but fail with `error` as if the response got lost on the way back
+    pub fn lose_next_send_response(&self, error: impl Into<ClientError>) {
+        self.state().lost_responses.push_back(error.into());
+    }
+
+    /// How often `method` was called, errors included
+    pub fn calls(&self, method: &str) -> usize {
+        self.state().calls.get(method).copied().unwrap_or(0)
+    }
+
+    /// Counts the call and takes the error queued for it, if any
+    fn begin(&self, method: &'static str) -> Option<ClientError> {
+        let mut state = self.state();
+        *state.calls.entry(method).or_default() += 1;
+        state.errors.get_mut(method).and_then(VecDeque::pop_front)
+    }
+
+    fn statuses(state: &MockState, signatures: &[Signature]) -> Vec<Option<TransactionStatus>> {
+        signatures
+            .iter()
+            .map(|signature| {
+                state
+                    .statuses
+                    .get(signature)
+                    .map(|status| TransactionStatus {
+                        slot: SLOT,
+                        confirmations: None,
+                        status: status.clone(),
+                        err: status.clone().err(),
+                        confirmation_status: Some(TransactionConfirmationStatus::Finalized),
+                    })
+            })
+            .collect()
+    }
+
+    fn blockhash_is_valid(state: &MockState, blockhash: &Hash) -> bool {
+        state
+            .blockhashes
+            .get(blockhash)
+            .is_some_and(|issued| issued.elapsed() < state.blockhash_ttl)
+    }
+
+    async fn send(&self, transaction: &Transaction) -> ClientResult<Signature> {
+        let started = Instant::now();
+        let scripted = self.begin("send_transaction");
+        let delay = self.state().send_delay;
+        sleep(delay).await;
+
+        let mut state = self.state();
+        let signature = transaction.signatures[0];
+        let rejected = scripted.or_else(|| {
+            (!Self::blockhash_is_valid(&state, &transaction.message.recent_blockhash))
+                .then(|| TransactionError::BlockhashNotFound.into())
+        });
+        let accepted = rejected.is_none();
+        if accepted && state.land_sent {
+            state.statuses.insert(signature, Ok(()));
+        }
+        let error = rejected.or_else(|| state.lost_responses.pop_front());
+        state.sends.push(SentTransaction {
+            transaction: transaction.clone(),
+            started,
+            finished: Instant::now(),
+            accepted,
+        });
+        match error {
+            Some(error) => Err(error),
+            None => Ok(signature),
+        }
+    }
+}
+
+fn response<T>(value: T) -> Response<T> {
+    Response {
+        context: RpcResponseContext {
+            slot: SLOT,
+            api_version: None,
+        },
+        value,
+    }
+}
+
+impl SolanaRpc for MockRpc {
+    fn url(&self) -> String {
+        "mock".to_string()
+    }
+
+    fn commitment(&self) -> CommitmentConfig {
+        CommitmentConfig::confirmed()
+    }
+
+    async fn get_balance(&self, pubkey: &Pubkey) -> ClientResult<u64> {
+        if let Some(error) = self.begin("get_balance") {
+            return Err(error);
+        }
+        Ok(self.state().balances.get(pubkey).copied().unwrap_or(0))
+    }
+
+    /// A new blockhash on every call
+    async fn get_latest_blockhash(&self) -> ClientResult<Hash> {
+        if let Some(error) = self.begin("get_latest_blockhash") {
+            return Err(error);
+        }
+        let blockhash = Hash::new_unique();
+        self.state().blockhashes.insert(blockhash, Instant::now());
+        Ok(blockhash)
+    }
+
+    async fn is_blockhash_valid(
+        &self,
+        blockhash: &Hash,
+        _commitment: CommitmentConfig,
+    ) -> ClientResult<bool> {
+        if let Some(error) = self.begin("is_blockhash_valid") {
+            return Err(error);
+        }
+        Ok(Self::blockhash_is_valid(&self.state(), blockhash))
+    }
+
+    async fn get_account_with_commitment(
+        &self,
+        pubkey: &Pubkey,
+        _commitment: CommitmentConfig,
+    ) -> RpcResult<Option<Account>> {
+        if let Some(error) = self.begin("get_account_with_commitment") {
+            return Err(error);
+        }
+        Ok(response(self.state().accounts.get(pubkey).cloned()))
+    }
+
+    async fn get_fee_for_message(&self, message: &Message) -> ClientResult<u64> {
+        if let Some(error) = self.begin("get_fee_for_message") {
+            return Err(error);
+        }
+        Ok(self.state().fee_per_signature * message.header.num_required_signatures as u64)
+    }
+
+    async fn get_recent_prioritization_fees(
+        &self,
+        _addresses: &[Pubkey],
+    ) -> ClientResult<Vec<RpcPrioritizationFee>> {
+        if let Some(error) = self.begin("get_recent_prioritization_fees") {
+            return Err(error);
+        }
+        Ok(self
+            .state()
+            .prioritization_fees
+            .iter()
+            .enumerate()
+            .map(|(slot, fee)| RpcPrioritizationFee {
+                slot: slot as u64,
+                prioritization_fee: *fee,
+            })
+            .collect())
+    }
+
+    fn send_transaction_with_config(
+        &self,
+        transaction: &Transaction,
+        _config: RpcSendTransactionConfig,
+    ) -> impl Future<Output = ClientResult<Signature>> + Send {
+        self.send(transaction)
+    }
+
+    /// Sends like `send_transaction_with_config`, confirmed right away if the
+    /// transaction landed
+    async fn send_and_confirm_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> ClientResult<Signature> {
+        let signature = self.send(transaction).await?;
+        match self.state().statuses.get(&signature) {
+            Some(Err(e)) => Err(e.clone().into()),
+            Some(Ok(())) => Ok(signature),
+            None => Err(ClientErrorKind::Custom(format!(
+                "Transaction {} was not confirmed",
+                signature
+            ))
+            .into()),
+        }
+    }
+
+    /// Not scripted, always fails
+    async fn simulate_transaction_with_config(
+        &self,
+        _transaction: &Transaction,
+        _config: RpcSimulateTransactionConfig,
+    ) -> RpcResult<RpcSimulateTransactionResult> {
+        if let Some(error) = self.begin("simulate_transaction_with_config") {
+            return Err(error);
+        }
+        Err(ClientErrorKind::Custom("MockRpc doesn't simulate transactions".to_string()).into())
+    }
+
+    async fn get_signature_status(
+        &self,
+        signature: &Signature,
+    ) -> ClientResult<Option<transaction::Result<()>>> {
+        if let Some(error) = self.begin("get_signature_status") {
+            return Err(error);
+        }
+        Ok(self.state().statuses.get(signature).cloned())
+    }
+
+    async fn get_signature_statuses(
+        &self,
+        signatures: &[Signature],
+    ) -> RpcResult<Vec<Option<TransactionStatus>>> {
+        if let Some(error) = self.begin("get_signature_statuses") {
+            return Err(error);
+        }
+        Ok(response(Self::statuses(&self.state(), signatures)))
+    }
+
+    /// Same as `get_signature_statuses`, the mock keeps every status
+    async fn get_signature_statuses_with_history(
+        &self,
+        signatures: &[Signature],
+    ) -> RpcResult<Vec<Option<TransactionStatus>>> {
+        if let Some(error) = self.begin("get_signature_statuses_with_history") {
+            return Err(error);
+        }
+        Ok(response(Self::statuses(&self.state(), signatures)))
+    }
+
+    /// Not scripted, every transaction is reported as not found
+    async fn get_transaction_with_config(
+        &self,
+        signature: &Signature,
+        _config: RpcTransactionConfig,
+    ) -> ClientResult<EncodedConfirmedTransactionWithStatusMeta> {
+        if let Some(error) = self.begin("get_transaction_with_config") {
+            return Err(error);
+        }
+        Err(ClientErrorKind::Custom(format!("Transaction {} not found", signature)).into())
+    }
+}
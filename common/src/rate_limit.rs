@@ -0,0 +1,182 @@
+//! Token bucket pacing RPC requests, which backs off when the RPC rate limits
+//! them anyway
+
+use std::{sync::Mutex, time::Duration};
+use tokio::time::{Instant, sleep};
+use tracing::{info, warn};
+
+/// How long the rate stays reduced after the RPC rate limited a request
+const BACKOFF_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// A rate limit halves the rate at most this often, so the burst of
+/// concurrent requests failing together only counts once
+const MIN_HALVING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The rate never drops below this many requests per second
+const MIN_RATE: f64 = 0.1;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    /// Current requests per second, below the configured one while backing off
+    rate: f64,
+    /// Until when the rate stays reduced, `None` at the configured rate
+    backoff_until: Option<Instant>,
+    last_halved: Option<Instant>,
+}
+
+/// Token bucket pacing requests to `rps` per second, allowing bursts of up to
+/// one second's worth. Whenever the RPC rate limits a request anyway, the rate
+/// is halved until no rate limit was seen for a cooldown
+pub struct RateLimiter {
+    rps: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rps: f64) -> Self {
+        let rps = rps.max(MIN_RATE);
+        Self {
+            rps,
+            bucket: Mutex::new(Bucket {
+                tokens: rps.max(1.0),
+                last_refill: Instant::now(),
+                rate: rps,
+                backoff_until: None,
+                last_halved: None,
+            }),
+        }
+    }
+
+    /// Waits until a request may start
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                self.refill(&mut bucket, Instant::now());
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / bucket.rate)
+            };
+            sleep(wait).await;
+        }
+    }
+
+    /// Current requests per second, below the configured one while backing off
+    pub fn rate(&self) -> f64 {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Halves the rate for the cooldown, called when the RPC rate limited a request
+    pub fn on_rate_limited(&self) {
+        let now = Instant::now();
+        let mut bucket = self.bucket.lock().unwrap();
+        self.refill(&mut bucket, now);
+        bucket.backoff_until = Some(now + BACKOFF_COOLDOWN);
+        if bucket
+            .last_halved
+            .is_some_and(|halved| now.duration_since(halved) < MIN_HALVING_INTERVAL)
+        {
+            return;
+        }
+        bucket.last_halved = Some(now);
+        bucket.rate = (bucket.rate / 2.0).max(MIN_RATE);
+        bucket.tokens = bucket.tokens.min(bucket.rate.max(1.0));
+        warn!(
+            "RPC rate limited, lowering to {:.1} requests per second for {}s",
+            bucket.rate,
+            BACKOFF_COOLDOWN.as_secs()
+        );
+    }
+
+    /// Adds the tokens earned since the last refill and restores the configured
+    /// rate once the cooldown passed
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * bucket.rate).min(bucket.rate.max(1.0));
+        bucket.last_refill = now;
+
+        if bucket.backoff_until.is_some_and(|until| now >= until) {
+            bucket.backoff_until = None;
+            bucket.rate = self.rps;
+            info!(
+                "No rate limits for {}s, back to {:.1} requests per second",
+                BACKOFF_COOLDOWN.as_secs(),
+                self.rps
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::advance;
+
+    #[tokio::test(start_paused = true)]
+    async fn paces_requests_after_a_burst() {
+        let limiter = RateLimiter::new(2.0);
+        let start = Instant::now();
+        limiter.acquire().await;
+        limiter.acquire().await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        limiter.acquire().await;
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refill_never_exceeds_one_second_of_requests() {
+        let limiter = RateLimiter::new(2.0);
+        advance(Duration::from_secs(10)).await;
+        let mut bucket = limiter.bucket.lock().unwrap();
+        limiter.refill(&mut bucket, Instant::now());
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limits_halve_the_rate_at_most_once_a_second() {
+        let limiter = RateLimiter::new(8.0);
+        limiter.on_rate_limited();
+        assert_eq!(limiter.rate(), 4.0);
+
+        // Requests failing together count once
+        advance(Duration::from_millis(500)).await;
+        limiter.on_rate_limited();
+        assert_eq!(limiter.rate(), 4.0);
+
+        advance(Duration::from_millis(500)).await;
+        limiter.on_rate_limited();
+        assert_eq!(limiter.rate(), 2.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_never_drops_below_the_minimum() {
+        let limiter = RateLimiter::new(0.2);
+        limiter.on_rate_limited();
+        advance(MIN_HALVING_INTERVAL).await;
+        limiter.on_rate_limited();
+        assert_eq!(limiter.rate(), MIN_RATE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restores_the_rate_after_a_quiet_cooldown() {
+        let limiter = RateLimiter::new(8.0);
+        limiter.on_rate_limited();
+        advance(Duration::from_secs(10)).await;
+        // Restarts the cooldown
+        limiter.on_rate_limited();
+        assert_eq!(limiter.rate(), 2.0);
+
+        advance(BACKOFF_COOLDOWN - Duration::from_secs(1)).await;
+        limiter.acquire().await;
+        assert_eq!(limiter.rate(), 2.0);
+
+        advance(Duration::from_secs(1)).await;
+        limiter.acquire().await;
+        assert_eq!(limiter.rate(), 8.0);
+        assert_eq!(limiter.bucket.lock().unwrap().backoff_until, None);
+    }
+}
@@ -0,0 +1,115 @@
+//! Tells rate limits and transient RPC problems apart from errors retrying
+//! won't fix
+
+use solana_client::client_error::{ClientError, ClientErrorKind};
+
+/// Fragments of error messages that point at rate limiting or a transient
+/// network problem rather than something wrong with the transaction. Status
+/// codes only count with their reason phrase, a bare number also turns up in
+/// base58 pubkeys and signatures
+const RETRYABLE_MESSAGES: &[&str] = &[
+    "too many requests",
+    "rate limit",
+    "timed out",
+    "timeout",
+    "connection reset",
+    "connection refused",
+    "connection closed",
+    "error sending request",
+    "502 bad gateway",
+    "503 service unavailable",
+    "504 gateway timeout",
+];
+
+/// Fragments of error messages that mean the RPC rate limited the request
+const RATE_LIMIT_MESSAGES: &[&str] = &["too many requests", "rate limit"];
+
+/// HTTP status of a rate limited request
+const TOO_MANY_REQUESTS: u16 = 429;
+
+/// Whether the RPC rejected the call that produced `e` for exceeding its rate limit
+pub fn is_rate_limited(e: &ClientError) -> bool {
+    if let ClientErrorKind::Reqwest(e) = e.kind()
+        && e.status().map(|status| status.as_u16()) == Some(TOO_MANY_REQUESTS)
+    {
+        return true;
+    }
+    let message = e.to_string().to_lowercase();
+    RATE_LIMIT_MESSAGES
+        .iter()
+        .any(|fragment| message.contains(fragment))
+}
+
+/// Whether an error message looks like rate limiting or a transient RPC problem.
+/// Anything else, e.g. "insufficient funds", is treated as terminal
+fn is_retryable_message(message: &str) -> bool {
+    let message = message.to_lowercase();
+    RETRYABLE_MESSAGES
+        .iter()
+        .any(|fragment| message.contains(fragment))
+}
+
+/// Whether retrying the RPC call that produced `e` has a chance to succeed
+pub fn is_retryable(e: &ClientError) -> bool {
+    match e.kind() {
+        // Rejected by the cluster, sending it again won't change that
+        ClientErrorKind::TransactionError(_) | ClientErrorKind::SigningError(_) => false,
+        ClientErrorKind::Io(_) => true,
+        ClientErrorKind::Reqwest(e) if e.is_timeout() || e.is_connect() => true,
+        ClientErrorKind::Reqwest(e)
+            if e.status().map(|status| status.as_u16()) == Some(TOO_MANY_REQUESTS) =>
+        {
+            true
+        }
+        _ => is_retryable_message(&e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::{pubkey::Pubkey, transaction::TransactionError};
+    use std::io;
+
+    fn custom(message: &str) -> ClientError {
+        ClientErrorKind::Custom(message.to_string()).into()
+    }
+
+    /// A pubkey whose base58 form contains "429"
+    fn pubkey_with_429() -> String {
+        std::iter::repeat_with(|| Pubkey::new_unique().to_string())
+            .find(|pubkey| pubkey.contains("429"))
+            .unwrap()
+    }
+
+    #[test]
+    fn rate_limits_are_recognized_by_their_reason_phrase() {
+        let e = custom("HTTP status client error (429 Too Many Requests) for url (http://rpc)");
+        assert!(is_rate_limited(&e));
+        assert!(is_retryable(&e));
+        assert!(is_rate_limited(&custom("Rate limit exceeded")));
+    }
+
+    #[test]
+    fn a_429_inside_an_address_is_not_a_rate_limit() {
+        let e = custom(&format!(
+            "Account {} has insufficient funds",
+            pubkey_with_429()
+        ));
+        assert!(!is_rate_limited(&e));
+        assert!(!is_retryable(&e));
+    }
+
+    #[test]
+    fn classifies_transient_and_terminal_errors() {
+        assert!(is_retryable(
+            &io::Error::new(io::ErrorKind::TimedOut, "send timed out").into()
+        ));
+        assert!(is_retryable(&custom("503 Service Unavailable")));
+        assert!(is_retryable(&custom("connection reset by peer")));
+        assert!(!is_retryable(
+            &TransactionError::InsufficientFundsForFee.into()
+        ));
+        assert!(!is_retryable(&custom("invalid transaction")));
+    }
+}
@@ -0,0 +1,397 @@
+use serde::{Deserialize, Serialize};
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind, Result as ClientResult},
+    nonblocking::rpc_client::RpcClient,
+    rpc_config::{RpcSendTransactionConfig, RpcSimulateTransactionConfig, RpcTransactionConfig},
+    rpc_response::{RpcPrioritizationFee, RpcResult, RpcSimulateTransactionResult},
+};
+use solana_sdk::{
+    account::Account,
+    commitment_config::CommitmentConfig,
+    hash::Hash,
+    message::Message,
+    pubkey::Pubkey,
+    signature::Signature,
+    transaction::{self, Transaction},
+};
+use solana_transaction_status_client_types::{
+    EncodedConfirmedTransactionWithStatusMeta, TransactionStatus,
+};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// One RPC endpoint, or a list of them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RpcUrls {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl RpcUrls {
+    pub fn urls(&self) -> &[String] {
+        match self {
+            RpcUrls::One(url) => std::slice::from_ref(url),
+            RpcUrls::Many(urls) => urls,
+        }
+    }
+}
+
+/// Whether `error` means the endpoint couldn't serve the request at all
+/// (unreachable, timed out or a 5xx), so another endpoint may still succeed.
+/// Errors the node returned, rate limits included, don't count
+pub fn is_connection_error(error: &ClientError) -> bool {
+    match error.kind() {
+        ClientErrorKind::Io(_) => true,
+        ClientErrorKind::Reqwest(e) => {
+            e.is_connect()
+                || e.is_timeout()
+                || e.status().is_some_and(|status| status.is_server_error())
+        }
+        _ => false,
+    }
+}
+
+/// The RPC calls the binaries send transactions with, so the send, confirm and
+/// balance logic can run against something other than a live node. Every
+/// method does what the `RpcClient` method of the same name does
+pub trait SolanaRpc: Send + Sync {
+    fn url(&self) -> String;
+
+    fn commitment(&self) -> CommitmentConfig;
+
+    fn get_balance(&self, pubkey: &Pubkey) -> impl Future<Output = ClientResult<u64>> + Send;
+
+    fn get_latest_blockhash(&self) -> impl Future<Output = ClientResult<Hash>> + Send;
+
+    fn is_blockhash_valid(
+        &self,
+        blockhash: &Hash,
+        commitment: CommitmentConfig,
+    ) -> impl Future<Output = ClientResult<bool>> + Send;
+
+    fn get_account_with_commitment(
+        &self,
+        pubkey: &Pubkey,
+        commitment: CommitmentConfig,
+    ) -> impl Future<Output = RpcResult<Option<Account>>> + Send;
+
+    fn get_fee_for_message(
+        &self,
+        message: &Message,
+    ) -> impl Future<Output = ClientResult<u64>> + Send;
+
+    fn get_recent_prioritization_fees(
+        &self,
+        addresses: &[Pubkey],
+    ) -> impl Future<Output = ClientResult<Vec<RpcPrioritizationFee>>> + Send;
+
+    fn send_transaction_with_config(
+        &self,
+        transaction: &Transaction,
+        config: RpcSendTransactionConfig,
+    ) -> impl Future<Output = ClientResult<Signature>> + Send;
+
+    fn send_and_confirm_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> impl Future<Output = ClientResult<Signature>> + Send;
+
+    fn simulate_transaction_with_config(
+        &self,
+        transaction: &Transaction,
+        config: RpcSimulateTransactionConfig,
+    ) -> impl Future<Output = RpcResult<RpcSimulateTransactionResult>> + Send;
+
+    fn get_signature_status(
+        &self,
+        signature: &Signature,
+    ) -> impl Future<Output = ClientResult<Option<transaction::Result<()>>>> + Send;
+
+    fn get_signature_statuses(
+        &self,
+        signatures: &[Signature],
+    ) -> impl Future<Output = RpcResult<Vec<Option<TransactionStatus>>>> + Send;
+
+    fn get_signature_statuses_with_history(
+        &self,
+        signatures: &[Signature],
+    ) -> impl Future<Output = RpcResult<Vec<Option<TransactionStatus>>>> + Send;
+
+    fn get_transaction_with_config(
+        &self,
+        signature: &Signature,
+        config: RpcTransactionConfig,
+    ) -> impl Future<Output = ClientResult<EncodedConfirmedTransactionWithStatusMeta>> + Send;
+}
+
+impl SolanaRpc for RpcClient {
+    fn url(&self) -> String {
+        RpcClient::url(self)
+    }
+
+    fn commitment(&self) -> CommitmentConfig {
+        RpcClient::commitment(self)
+    }
+
+    fn get_balance(&self, pubkey: &Pubkey) -> impl Future<Output = ClientResult<u64>> + Send {
+        RpcClient::get_balance(self, pubkey)
+    }
+
+    fn get_latest_blockhash(&self) -> impl Future<Output = ClientResult<Hash>> + Send {
+        RpcClient::get_latest_blockhash(self)
+    }
+
+    fn is_blockhash_valid(
+        &self,
+        blockhash: &Hash,
+        commitment: CommitmentConfig,
+    ) -> impl Future<Output = ClientResult<bool>> + Send {
+        RpcClient::is_blockhash_valid(self, blockhash, commitment)
+    }
+
+    fn get_account_with_commitment(
+        &self,
+        pubkey: &Pubkey,
+        commitment: CommitmentConfig,
+    ) -> impl Future<Output = RpcResult<Option<Account>>> + Send {
+        RpcClient::get_account_with_commitment(self, pubkey, commitment)
+    }
+
+    fn get_fee_for_message(
+        &self,
+        message: &Message,
+    ) -> impl Future<Output = ClientResult<u64>> + Send {
+        RpcClient::get_fee_for_message(self, message)
+    }
+
+    fn get_recent_prioritization_fees(
+        &self,
+        addresses: &[Pubkey],
+    ) -> impl Future<Output = ClientResult<Vec<RpcPrioritizationFee>>> + Send {
+        RpcClient::get_recent_prioritization_fees(self, addresses)
+    }
+
+    fn send_transaction_with_config(
+        &self,
+        transaction: &Transaction,
+        config: RpcSendTransactionConfig,
+    ) -> impl Future<Output = ClientResult<Signature>> + Send {
+        RpcClient::send_transaction_with_config(self, transaction, config)
+    }
+
+    fn send_and_confirm_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> impl Future<Output = ClientResult<Signature>> + Send {
+        RpcClient::send_and_confirm_transaction(self, transaction)
+    }
+
+    fn simulate_transaction_with_config(
+        &self,
+        transaction: &Transaction,
+        config: RpcSimulateTransactionConfig,
+    ) -> impl Future<Output = RpcResult<RpcSimulateTransactionResult>> + Send {
+        RpcClient::simulate_transaction_with_config(self, transaction, config)
+    }
+
+    fn get_signature_status(
+        &self,
+        signature: &Signature,
+    ) -> impl Future<Output = ClientResult<Option<transaction::Result<()>>>> + Send {
+        RpcClient::get_signature_status(self, signature)
+    }
+
+    fn get_signature_statuses(
+        &self,
+        signatures: &[Signature],
+    ) -> impl Future<Output = RpcResult<Vec<Option<TransactionStatus>>>> + Send {
+        RpcClient::get_signature_statuses(self, signatures)
+    }
+
+    fn get_signature_statuses_with_history(
+        &self,
+        signatures: &[Signature],
+    ) -> impl Future<Output = RpcResult<Vec<Option<TransactionStatus>>>> + Send {
+        RpcClient::get_signature_statuses_with_history(self, signatures)
+    }
+
+    fn get_transaction_with_config(
+        &self,
+        signature: &Signature,
+        config: RpcTransactionConfig,
+    ) -> impl Future<Output = ClientResult<EncodedConfirmedTransactionWithStatusMeta>> + Send {
+        RpcClient::get_transaction_with_config(self, signature, config)
+    }
+}
+
+/// Several endpoints behind one client. Every call starts at the next endpoint
+/// in turn and, when that one can't be reached, moves on to the following ones
+/// before giving up. Each endpoint's own rate limits still apply
+pub struct RoundRobinClient {
+    clients: Vec<RpcClient>,
+    next: AtomicUsize,
+}
+
+impl RoundRobinClient {
+    /// # Panics
+    /// If `urls` is empty
+    pub fn new(urls: &[String], commitment: CommitmentConfig, timeout: Duration) -> Self {
+        assert!(!urls.is_empty(), "RoundRobinClient needs at least one URL");
+        Self {
+            clients: urls
+                .iter()
+                .map(|url| crate::make_rpc_client(url, commitment, timeout))
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// URL of the first endpoint that can be reached, for the calls that don't
+    /// go through the rotation. The first endpoint if none can
+    pub async fn first_reachable_url(&self) -> String {
+        if self.clients.len() > 1 {
+            for client in &self.clients {
+                match client.get_version().await {
+                    Err(e) if is_connection_error(&e) => {
+                        warn!("RPC {} is not reachable: {}", client.url(), e);
+                    }
+                    _ => return client.url(),
+                }
+            }
+        }
+        self.clients[0].url()
+    }
+
+    async fn call<'a, T, F, Fut>(&'a self, request: F) -> ClientResult<T>
+    where
+        F: Fn(&'a RpcClient) -> Fut,
+        Fut: Future<Output = ClientResult<T>>,
+    {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut tried = 0;
+        loop {
+            let client = &self.clients[(start + tried) % self.clients.len()];
+            tried += 1;
+            match request(client).await {
+                Err(e) if tried < self.clients.len() && is_connection_error(&e) => {
+                    debug!(
+                        "RPC {} failed, trying the next endpoint: {}",
+                        client.url(),
+                        e
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl SolanaRpc for RoundRobinClient {
+    /// Every endpoint, comma separated
+    fn url(&self) -> String {
+        self.clients
+            .iter()
+            .map(RpcClient::url)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn commitment(&self) -> CommitmentConfig {
+        self.clients[0].commitment()
+    }
+
+    fn get_balance(&self, pubkey: &Pubkey) -> impl Future<Output = ClientResult<u64>> + Send {
+        self.call(move |client| client.get_balance(pubkey))
+    }
+
+    fn get_latest_blockhash(&self) -> impl Future<Output = ClientResult<Hash>> + Send {
+        self.call(|client| client.get_latest_blockhash())
+    }
+
+    fn is_blockhash_valid(
+        &self,
+        blockhash: &Hash,
+        commitment: CommitmentConfig,
+    ) -> impl Future<Output = ClientResult<bool>> + Send {
+        self.call(move |client| client.is_blockhash_valid(blockhash, commitment))
+    }
+
+    fn get_account_with_commitment(
+        &self,
+        pubkey: &Pubkey,
+        commitment: CommitmentConfig,
+    ) -> impl Future<Output = RpcResult<Option<Account>>> + Send {
+        self.call(move |client| client.get_account_with_commitment(pubkey, commitment))
+    }
+
+    fn get_fee_for_message(
+        &self,
+        message: &Message,
+    ) -> impl Future<Output = ClientResult<u64>> + Send {
+        self.call(move |client| client.get_fee_for_message(message))
+    }
+
+    fn get_recent_prioritization_fees(
+        &self,
+        addresses: &[Pubkey],
+    ) -> impl Future<Output = ClientResult<Vec<RpcPrioritizationFee>>> + Send {
+        self.call(move |client| client.get_recent_prioritization_fees(addresses))
+    }
+
+    fn send_transaction_with_config(
+        &self,
+        transaction: &Transaction,
+        config: RpcSendTransactionConfig,
+    ) -> impl Future<Output = ClientResult<Signature>> + Send {
+        self.call(move |client| client.send_transaction_with_config(transaction, config))
+    }
+
+    fn send_and_confirm_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> impl Future<Output = ClientResult<Signature>> + Send {
+        self.call(move |client| client.send_and_confirm_transaction(transaction))
+    }
+
+    fn simulate_transaction_with_config(
+        &self,
+        transaction: &Transaction,
+        config: RpcSimulateTransactionConfig,
+    ) -> impl Future<Output = RpcResult<RpcSimulateTransactionResult>> + Send {
+        self.call(move |client| {
+            client.simulate_transaction_with_config(transaction, config.clone())
+        })
+    }
+
+    fn get_signature_status(
+        &self,
+        signature: &Signature,
+    ) -> impl Future<Output = ClientResult<Option<transaction::Result<()>>>> + Send {
+        self.call(move |client| client.get_signature_status(signature))
+    }
+
+    fn get_signature_statuses(
+        &self,
+        signatures: &[Signature],
+    ) -> impl Future<Output = RpcResult<Vec<Option<TransactionStatus>>>> + Send {
+        self.call(move |client| client.get_signature_statuses(signatures))
+    }
+
+    fn get_signature_statuses_with_history(
+        &self,
+        signatures: &[Signature],
+    ) -> impl Future<Output = RpcResult<Vec<Option<TransactionStatus>>>> + Send {
+        self.call(move |client| client.get_signature_statuses_with_history(signatures))
+    }
+
+    fn get_transaction_with_config(
+        &self,
+        signature: &Signature,
+        config: RpcTransactionConfig,
+    ) -> impl Future<Output = ClientResult<EncodedConfirmedTransactionWithStatusMeta>> + Send {
+        self.call(move |client| client.get_transaction_with_config(signature, config))
+    }
+}
@@ -0,0 +1,232 @@
+use serde::{Deserialize, Deserializer, de::Error as _};
+use std::fmt;
+
+/// Decimals of SOL, 1 SOL = 10^9 lamports
+pub const SOL_DECIMALS: u8 = 9;
+
+/// An amount as written in the config: either a decimal in SOL (or token units)
+/// or an exact number of lamports (or raw token units).
+///
+/// Decimals are kept as digits rather than `f64`, so `0.1` stays exactly
+/// 100_000_000 lamports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Amount {
+    /// `mantissa * 10^-scale`
+    Decimal {
+        mantissa: u128,
+        scale: u32,
+    },
+    BaseUnits(u64),
+}
+
+impl Amount {
+    /// Parses a plain non-negative decimal such as `1`, `0.25` or `.5`.
+    /// Exponents and signs are rejected
+    pub fn parse_decimal(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+        if whole.is_empty() && fraction.is_empty()
+            || !whole
+                .chars()
+                .chain(fraction.chars())
+                .all(|c| c.is_ascii_digit())
+        {
+            return Err(format!(
+                "Invalid amount {:?}, expected a decimal number",
+                text
+            ));
+        }
+
+        let digits = format!("{}{}", whole, fraction);
+        let mantissa = digits
+            .parse::<u128>()
+            .map_err(|_| format!("Amount {:?} has too many digits", text))?;
+        Ok(Amount::Decimal {
+            mantissa,
+            scale: fraction.len() as u32,
+        })
+    }
+
+    /// Converts to base units (lamports for SOL) of an asset with `decimals`.
+    /// Extra fractional digits are rounded half up; zero and amounts below one
+    /// base unit are rejected
+    pub fn to_base_units(self, decimals: u8) -> Result<u64, String> {
+        let units = match self {
+            Amount::BaseUnits(units) => units as u128,
+            Amount::Decimal { mantissa, scale } => {
+                let decimals = decimals as u32;
+                if scale <= decimals {
+                    10u128
+                        .checked_pow(decimals - scale)
+                        .and_then(|factor| mantissa.checked_mul(factor))
+                        .ok_or_else(|| format!("Amount {} is too large", self))?
+                } else {
+                    match 10u128.checked_pow(scale - decimals) {
+                        Some(divisor) => mantissa.saturating_add(divisor / 2) / divisor,
+                        None => 0,
+                    }
+                }
+            }
+        };
+
+        if units == 0 {
+            return Err(if self.is_zero() {
+                "Amount must be greater than zero".to_string()
+            } else {
+                format!("Amount {} is less than one base unit", self)
+            });
+        }
+        u64::try_from(units).map_err(|_| format!("Amount {} is too large", self))
+    }
+
+    fn is_zero(&self) -> bool {
+        matches!(
+            self,
+            Amount::Decimal { mantissa: 0, .. } | Amount::BaseUnits(0)
+        )
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Amount::BaseUnits(units) => write!(f, "{} lamports", units),
+            Amount::Decimal { mantissa, scale } => {
+                let digits = format!("{:0>width$}", mantissa, width = scale as usize + 1);
+                let (whole, fraction) = digits.split_at(digits.len() - scale as usize);
+                if fraction.is_empty() {
+                    write!(f, "{}", whole)
+                } else {
+                    write!(f, "{}.{}", whole, fraction)
+                }
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    /// Accepts a number, a decimal string, or `{ lamports: <integer> }`
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum RawAmount {
+            BaseUnits { lamports: u64 },
+            Integer(u64),
+            Float(f64),
+            Text(String),
+        }
+
+        match RawAmount::deserialize(deserializer)? {
+            RawAmount::BaseUnits { lamports } => Ok(Amount::BaseUnits(lamports)),
+            RawAmount::Integer(whole) => Ok(Amount::Decimal {
+                mantissa: whole as u128,
+                scale: 0,
+            }),
+            // `Display` for f64 prints the shortest representation that
+            // round-trips, e.g. `0.1` rather than `0.1000000000000000055`
+            RawAmount::Float(value) => {
+                Amount::parse_decimal(&value.to_string()).map_err(D::Error::custom)
+            }
+            RawAmount::Text(text) => Amount::parse_decimal(&text).map_err(D::Error::custom),
+        }
+    }
+}
+
+/// Base units as a decimal number of whole units, for display only
+pub fn to_ui_amount(units: u64, decimals: u8) -> f64 {
+    units as f64 / 10f64.powi(decimals as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lamports(text: &str) -> Result<u64, String> {
+        Amount::parse_decimal(text)?.to_base_units(SOL_DECIMALS)
+    }
+
+    fn parse_yaml(yaml: &str) -> Amount {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn converts_decimals_exactly() {
+        assert_eq!(lamports("0.1"), Ok(100_000_000));
+        assert_eq!(lamports(".5"), Ok(500_000_000));
+        assert_eq!(lamports("1"), Ok(1_000_000_000));
+        assert_eq!(lamports("0.000000001"), Ok(1));
+        assert_eq!(
+            Amount::parse_decimal("1.5").unwrap().to_base_units(6),
+            Ok(1_500_000)
+        );
+    }
+
+    #[test]
+    fn rounds_digits_beyond_the_decimals_half_up() {
+        assert_eq!(lamports("0.0000000014"), Ok(1));
+        assert_eq!(lamports("0.0000000015"), Ok(2));
+        assert_eq!(lamports("1.0000000005"), Ok(1_000_000_001));
+        assert_eq!(
+            Amount::parse_decimal("0.5").unwrap().to_base_units(0),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn rejects_zero_and_sub_lamport_amounts() {
+        assert_eq!(
+            lamports("0.000"),
+            Err("Amount must be greater than zero".to_string())
+        );
+        assert_eq!(
+            Amount::BaseUnits(0).to_base_units(SOL_DECIMALS),
+            Err("Amount must be greater than zero".to_string())
+        );
+        assert_eq!(
+            lamports("0.0000000004"),
+            Err("Amount 0.0000000004 is less than one base unit".to_string())
+        );
+        // More fractional digits than a u128 power of ten can divide away
+        let tiny = format!("0.{}1", "0".repeat(40));
+        assert!(
+            lamports(&tiny)
+                .unwrap_err()
+                .contains("less than one base unit")
+        );
+    }
+
+    #[test]
+    fn rejects_amounts_beyond_u64() {
+        assert!(lamports("18446744074").unwrap_err().contains("too large"));
+        assert_eq!(lamports("18446744073.709551615"), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn rejects_anything_but_plain_decimals() {
+        for text in ["", ".", "-1", "1e3", "1.2.3", "one"] {
+            assert!(Amount::parse_decimal(text).is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn deserializes_every_form() {
+        assert_eq!(parse_yaml("{lamports: 5}"), Amount::BaseUnits(5));
+        assert_eq!(parse_yaml("2"), Amount::Decimal {
+            mantissa: 2,
+            scale: 0
+        });
+        assert_eq!(parse_yaml("0.1"), Amount::Decimal {
+            mantissa: 1,
+            scale: 1
+        });
+        assert_eq!(parse_yaml("'0.25'"), Amount::Decimal {
+            mantissa: 25,
+            scale: 2
+        });
+        assert_eq!(
+            parse_yaml("{lamports: 5}").to_base_units(SOL_DECIMALS),
+            Ok(5)
+        );
+        assert!(serde_yaml::from_str::<Amount>("'-1'").is_err());
+    }
+}
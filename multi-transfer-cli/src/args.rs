@@ -1,4 +1,6 @@
-use clap::Parser;
+use crate::amount::Amount;
+use clap::{Parser, ValueEnum};
+use solana_sdk::pubkey::Pubkey;
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
@@ -9,16 +11,269 @@ use std::path::PathBuf;
     long_about = "A utility for executing multiple SOL transfers in parallel. \
     Supports configurable concurrency, timeout settings, and batch processing from YAML configuration."
 )]
+#[command(after_help = "Exit codes:\n  \
+    0  all transfers succeeded (or were simulated)\n  \
+    1  some transfers failed, timed out or failed --verify, or the batch could not be run\n  \
+    2  every transfer failed or timed out\n  \
+    130  interrupted with ctrl-c before every transfer was started")]
 pub struct Args {
-    /// Path to the YAML configuration file
+    /// Path or http(s) URL of the YAML configuration file.
+    /// Remote configs are fetched with the bearer token from MULTI_TRANSFER_CONFIG_TOKEN, if set
     #[clap(short, long, default_value = "config.yaml")]
-    pub config: PathBuf,
+    pub config: String,
 
-    /// Maximum number of concurrent transfers
+    /// Send these transfers instead of reading a config file: a JSON array like
+    /// `[{"from":"keypair.json","to":"<pubkey>","sol":0.1}]`
+    #[clap(
+        long,
+        value_name = "JSON",
+        conflicts_with = "config",
+        requires = "rpc_url"
+    )]
+    pub transfers: Option<String>,
+
+    /// CSV file with `address,amount_sol,memo` columns to send to instead of
+    /// the config's `destination_wallets`. Every row is sent once, from the
+    /// only source wallet or its `from` column; empty amounts and memos fall
+    /// back to the config
+    #[clap(long, value_name = "CSV", conflicts_with = "transfers")]
+    pub recipients: Option<PathBuf>,
+
+    /// RPC endpoint, overrides `rpc_url` from the config. Repeat it or separate
+    /// several with commas to rotate through them. Required with `--transfers`
+    #[clap(long, value_name = "URL", value_delimiter = ',')]
+    pub rpc_url: Vec<String>,
+
+    /// Maximum number of concurrent transfers across all source wallets.
+    /// Per-source limits from the config (`max_concurrent_per_source`) apply on top of this
     #[clap(long, default_value = "10")]
     pub concurrent: usize,
 
     /// Timeout in seconds for transaction confirmation
     #[clap(short, long, default_value = "60")]
     pub timeout: u64,
+
+    /// Cap on the RPC requests per second of all transfers together, sends and
+    /// status polls included. Halved for 30s whenever the RPC rate limits anyway.
+    /// Unlimited by default
+    #[clap(long, value_name = "N")]
+    pub rps: Option<f64>,
+
+    /// Milliseconds between two status polls of an unconfirmed transaction
+    #[clap(long, default_value = "500")]
+    pub poll_interval_ms: u64,
+
+    /// How often to retry an RPC call that failed with a rate-limit or other
+    /// transient error, with exponential backoff between attempts
+    #[clap(long, default_value = "3")]
+    pub max_retries: u32,
+
+    /// How often to re-sign and resend a transfer with a fresh blockhash after
+    /// a transient send error or a confirmation timeout. A timed-out transaction
+    /// is only replaced once its blockhash expired without it landing
+    #[clap(long, default_value = "0")]
+    pub retries: u32,
+
+    /// Delay in milliseconds before the first retry, doubled for every further
+    /// attempt. Used for both `--max-retries` and `--retries`
+    #[clap(long, default_value = "250")]
+    pub retry_backoff_ms: u64,
+
+    /// Pack up to this many SOL transfers from the same source into one
+    /// transaction. Lowered automatically if that many don't fit into a packet
+    #[clap(long, default_value = "1")]
+    pub batch_per_tx: usize,
+
+    /// Timeout in seconds for a single RPC request, including sending a transaction
+    #[clap(long, default_value = "30")]
+    pub rpc_timeout: u64,
+
+    /// Interval in seconds for refreshing the recent blockhash during a batch
+    #[clap(long, default_value = "30")]
+    pub blockhash_refresh_secs: u64,
+
+    /// Estimated fee per transfer in lamports used by the balance pre-flight check.
+    /// Derived from the RPC's fee for a transfer message when not set
+    #[clap(long)]
+    pub fee_lamports: Option<u64>,
+
+    /// Compute unit limit for every transfer, overrides `compute_unit_limit` in the config
+    #[clap(long)]
+    pub compute_unit_limit: Option<u32>,
+
+    /// Priority fee in micro-lamports per compute unit, overrides `compute_unit_price` in the config
+    #[clap(long, visible_alias = "priority-fee-microlamports")]
+    pub compute_unit_price: Option<u64>,
+
+    /// `auto` picks the compute unit price per transaction from the recent
+    /// prioritization fees of its accounts, for transfers without a fixed price
+    #[clap(long, value_enum, conflicts_with = "compute_unit_price")]
+    pub priority_fee: Option<PriorityFeeMode>,
+
+    /// Percentile of the recent prioritization fees used by `--priority-fee auto`
+    #[clap(long, default_value = "75", value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub priority_fee_percentile: u8,
+
+    /// Upper bound in micro-lamports per compute unit for `--priority-fee auto`
+    #[clap(long, requires = "priority_fee")]
+    pub max_priority_fee: Option<u64>,
+
+    /// Send without checking that every source wallet can cover its transfers
+    #[clap(long)]
+    pub skip_balance_check: bool,
+
+    /// Don't print advisory warnings, such as a `--concurrent` the RPC likely can't handle
+    #[clap(long)]
+    pub no_warnings: bool,
+
+    /// Refuse to run if the SOL transfers add up to more than this many SOL
+    #[clap(long, value_parser = Amount::parse_decimal)]
+    pub max_total_sol: Option<Amount>,
+
+    /// Create missing destination token accounts for SPL token transfers
+    #[clap(long)]
+    pub create_ata: bool,
+
+    /// Close the source token account after a transfer of its whole balance
+    /// and reclaim its rent
+    #[clap(long)]
+    pub close_token_accounts: bool,
+
+    /// Receives the rent of closed token accounts instead of the source wallet
+    #[clap(long, value_name = "PUBKEY", requires = "close_token_accounts")]
+    pub rent_recipient: Option<Pubkey>,
+
+    /// What to do about SOL transfers to accounts that don't exist yet and
+    /// would receive less than the rent-exempt minimum, which the runtime rejects
+    #[clap(long, value_enum, default_value = "warn")]
+    pub new_account_policy: NewAccountPolicy,
+
+    /// Simulate every transfer instead of sending it
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Sign and send without asking for confirmation of the plan first.
+    /// Required when stdin isn't a terminal
+    #[clap(short, long)]
+    pub yes: bool,
+
+    /// Build and sign every transaction without sending it, writing them to
+    /// this file one JSON object per line: the base64 transaction, its
+    /// blockhash and the block height it's valid until. Send them with `--broadcast`
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["dry_run", "state_file", "verify", "confirm_with_history"]
+    )]
+    pub sign_only: Option<PathBuf>,
+
+    /// Send the transactions a `--sign-only` run wrote to this file instead of
+    /// building them from the config. Needs the RPC but no keypairs
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["sign_only", "dry_run", "transfers", "state_file", "verify"]
+    )]
+    pub broadcast: Option<PathBuf>,
+
+    /// `batched` confirms all in-flight transfers with one `getSignatureStatuses`
+    /// call per poll instead of polling every signature on its own
+    #[clap(long, value_enum, default_value = "individual")]
+    pub confirm_mode: ConfirmMode,
+
+    /// Before the summary, look up timed-out transfers once more with
+    /// `searchTransactionHistory` and report those that landed late as successful.
+    /// Needs an archival RPC for signatures older than the recent status cache
+    #[clap(long, conflicts_with = "dry_run")]
+    pub confirm_with_history: bool,
+
+    /// After the run, check that every destination balance changed by the
+    /// amount its successful transfers sent
+    #[clap(long, conflicts_with = "dry_run")]
+    pub verify: bool,
+
+    /// Allowed difference for `--verify`, in lamports or raw token units
+    #[clap(long, default_value = "0", requires = "verify")]
+    pub verify_tolerance: u64,
+
+    /// Format of the transfer results
+    #[clap(
+        long = "output",
+        alias = "output-format",
+        value_enum,
+        default_value = "table"
+    )]
+    pub output_format: OutputFormat,
+
+    /// Write the results to this file instead of stdout.
+    /// The human-readable summary then goes to stderr
+    #[clap(long)]
+    pub output_file: Option<PathBuf>,
+
+    /// Record every sent and finished transfer in this append-only JSONL
+    /// file, so a failed batch can be resumed safely. Transfers it lists as
+    /// sent are looked up before resending
+    #[clap(long, visible_alias = "checkpoint")]
+    pub state_file: Option<PathBuf>,
+
+    /// Send only the (source, recipient) pairs the state file doesn't list as
+    /// successful, rebuilt into new batches
+    #[clap(long, requires = "state_file", conflicts_with = "force")]
+    pub resume: bool,
+
+    /// Send transfers even if the state file lists them as successful.
+    /// Their new results are still recorded
+    #[clap(long, requires = "state_file")]
+    pub force: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PriorityFeeMode {
+    Auto,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfirmMode {
+    Individual,
+    Batched,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NewAccountPolicy {
+    /// Refuse to run the batch
+    Error,
+    /// List the transfers and send them anyway
+    Warn,
+    /// Raise their amounts to the rent-exempt minimum
+    AutoFundToRent,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_takes_the_format_and_output_format_still_works() {
+        let args = Args::try_parse_from(["multi-transfer-cli", "--output", "csv"]).unwrap();
+        assert_eq!(args.output_format, OutputFormat::Csv);
+        assert_eq!(args.output_file, None);
+
+        let args = Args::try_parse_from([
+            "multi-transfer-cli",
+            "--output-format",
+            "json",
+            "--output-file",
+            "results.json",
+        ])
+        .unwrap();
+        assert_eq!(args.output_format, OutputFormat::Json);
+        assert_eq!(args.output_file, Some(PathBuf::from("results.json")));
+    }
 }
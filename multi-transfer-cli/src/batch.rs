@@ -0,0 +1,114 @@
+use crate::memo;
+use crate::types::TransferSpec;
+use solana_sdk::{
+    compute_budget::ComputeBudgetInstruction, message::Message, packet::PACKET_DATA_SIZE,
+    pubkey::Pubkey, system_instruction,
+};
+use std::collections::HashMap;
+use tracing::warn;
+
+/// Splits the transfers into the groups that are sent as one transaction each.
+///
+/// SOL transfers from the same source with the same compute budget are packed
+/// up to `batch_per_tx` per transaction, or fewer if that many wouldn't fit
+/// into a packet, memos included. Token transfers are always sent on their own.
+pub fn group_transfers(
+    transfers: Vec<TransferSpec>,
+    batch_per_tx: usize,
+) -> Vec<Vec<TransferSpec>> {
+    if batch_per_tx <= 1 {
+        return transfers
+            .into_iter()
+            .map(|transfer| vec![transfer])
+            .collect();
+    }
+
+    let limit = max_sol_transfers_per_tx();
+    if limit < batch_per_tx {
+        warn!(
+            "Only {} transfers fit into one transaction, packing {} instead of {}",
+            limit, limit, batch_per_tx
+        );
+    }
+    let limit = limit.min(batch_per_tx);
+
+    // Groups in order of their first transfer
+    let mut groups: Vec<Vec<TransferSpec>> = Vec::new();
+    let mut open_groups: HashMap<_, usize> = HashMap::new();
+
+    for transfer in transfers {
+        if transfer.token.is_some() {
+            groups.push(vec![transfer]);
+            continue;
+        }
+
+        let budget = (transfer.compute_unit_limit, transfer.compute_unit_price);
+        let key = (transfer.from_keypair_path.clone(), budget);
+        match open_groups.get(&key) {
+            Some(&index) if groups[index].len() < limit && fits(&groups[index], &transfer) => {
+                groups[index].push(transfer)
+            }
+            _ => {
+                open_groups.insert(key, groups.len());
+                groups.push(vec![transfer]);
+            }
+        }
+    }
+
+    groups
+}
+
+/// Most SOL transfers without a memo a single-signer transaction can hold
+/// without exceeding the packet size
+fn max_sol_transfers_per_tx() -> usize {
+    let mut memos = Vec::new();
+    loop {
+        memos.push(None);
+        if transaction_size(&memos) > PACKET_DATA_SIZE {
+            return (memos.len() - 1).max(1);
+        }
+    }
+}
+
+/// Whether `transfer` still fits into the transaction of `group`. Only memos
+/// make transfers differ in size, `max_sol_transfers_per_tx` covers the rest
+fn fits(group: &[TransferSpec], transfer: &TransferSpec) -> bool {
+    if group
+        .iter()
+        .chain([transfer])
+        .all(|transfer| transfer.memo.is_none())
+    {
+        return true;
+    }
+    let memos: Vec<_> = group
+        .iter()
+        .chain([transfer])
+        .map(|transfer| transfer.memo.as_deref())
+        .collect();
+    transaction_size(&memos) <= PACKET_DATA_SIZE
+}
+
+/// Size of a single-signer transaction with one SOL transfer per entry of
+/// `memos`, each followed by its memo if any. Space for both compute budget
+/// instructions is always reserved, since a priority fee may be added when
+/// the transaction is built
+fn transaction_size(memos: &[Option<&str>]) -> usize {
+    let payer = Pubkey::new_unique();
+    let mut instructions = vec![
+        ComputeBudgetInstruction::set_compute_unit_limit(u32::MAX),
+        ComputeBudgetInstruction::set_compute_unit_price(u64::MAX),
+    ];
+    for memo in memos {
+        instructions.push(system_instruction::transfer(
+            &payer,
+            &Pubkey::new_unique(),
+            u64::MAX,
+        ));
+        if let Some(memo) = memo {
+            instructions.push(memo::instruction(memo, &payer));
+        }
+    }
+    let message = Message::new(&instructions, Some(&payer));
+    // Signature count prefix plus one signature
+    1 + 64 + message.serialize().len()
+}
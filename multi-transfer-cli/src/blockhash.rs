@@ -0,0 +1,184 @@
+use anyhow::{Context, Result};
+use common::SolanaRpc;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::hash::Hash;
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::RwLock, task::JoinHandle, time::interval};
+use tracing::{debug, warn};
+
+/// Shared recent blockhash that is refreshed in the background, so transfers
+/// that wait a long time for a semaphore permit don't sign with an expired hash.
+pub struct BlockhashCache<R = RpcClient> {
+    rpc_client: Arc<R>,
+    current: Arc<RwLock<Hash>>,
+}
+
+// Derived Clone would require `R: Clone`, only the Arcs are cloned
+impl<R> Clone for BlockhashCache<R> {
+    fn clone(&self) -> Self {
+        Self {
+            rpc_client: self.rpc_client.clone(),
+            current: self.current.clone(),
+        }
+    }
+}
+
+impl<R: SolanaRpc + 'static> BlockhashCache<R> {
+    pub async fn new(rpc_client: Arc<R>) -> Result<Self> {
+        let blockhash = rpc_client
+            .get_latest_blockhash()
+            .await
+            .context("Failed to get recent blockhash")?;
+
+        Ok(Self {
+            rpc_client,
+            current: Arc::new(RwLock::new(blockhash)),
+        })
+    }
+
+    /// Returns the most recently fetched blockhash
+    pub async fn get(&self) -> Hash {
+        *self.current.read().await
+    }
+
+    /// Fetches a new blockhash right away, e.g. after a send failed because the
+    /// cached one expired
+    pub async fn refresh(&self) -> Result<Hash> {
+        let blockhash = self
+            .rpc_client
+            .get_latest_blockhash()
+            .await
+            .context("Failed to refresh recent blockhash")?;
+        *self.current.write().await = blockhash;
+        Ok(blockhash)
+    }
+
+    /// Spawns a task that refreshes the blockhash every `period`.
+    /// A failed refresh keeps the previous value and is retried on the next tick.
+    pub fn spawn_refresh(&self, period: Duration) -> JoinHandle<()> {
+        let cache = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = interval(period);
+            ticker.tick().await; // first tick completes immediately
+
+            loop {
+                ticker.tick().await;
+                match cache.refresh().await {
+                    Ok(blockhash) => debug!("Refreshed recent blockhash: {}", blockhash),
+                    Err(e) => warn!("{:#}", e),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::mock::MockRpc;
+    use solana_client::client_error::ClientErrorKind;
+    use solana_sdk::commitment_config::CommitmentConfig;
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_the_blockhash_until_refreshed() {
+        let rpc = Arc::new(MockRpc::new());
+        let cache = BlockhashCache::new(rpc.clone()).await.unwrap();
+        let first = cache.get().await;
+
+        // Outliving its blockhash doesn't make the cache fetch on its own
+        tokio::time::advance(Duration::from_secs(120)).await;
+        assert_eq!(cache.get().await, first);
+        assert_eq!(rpc.calls("get_latest_blockhash"), 1);
+
+        let refreshed = cache.refresh().await.unwrap();
+        assert_ne!(refreshed, first);
+        assert_eq!(cache.get().await, refreshed);
+        assert!(
+            rpc.is_blockhash_valid(&refreshed, CommitmentConfig::processed())
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_refresh_keeps_the_previous_blockhash() {
+        let rpc = Arc::new(MockRpc::new());
+        let cache = BlockhashCache::new(rpc.clone()).await.unwrap();
+        let first = cache.get().await;
+
+        rpc.fail_next(
+            "get_latest_blockhash",
+            ClientErrorKind::Custom("connection reset".to_string()),
+        );
+        assert!(cache.refresh().await.is_err());
+        assert_eq!(cache.get().await, first);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clones_share_the_blockhash() {
+        let rpc = Arc::new(MockRpc::new());
+        let cache = BlockhashCache::new(rpc).await.unwrap();
+        let clone = cache.clone();
+        let refreshed = clone.refresh().await.unwrap();
+        assert_eq!(cache.get().await, refreshed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn new_fails_without_a_blockhash() {
+        let rpc = Arc::new(MockRpc::new());
+        rpc.fail_next(
+            "get_latest_blockhash",
+            ClientErrorKind::Custom("connection refused".to_string()),
+        );
+        assert!(BlockhashCache::new(rpc).await.is_err());
+    }
+
+    /// Lets the clock pass the next refresh tick, and the refresh run
+    async fn pass_tick(period: Duration) {
+        tokio::time::sleep(period + Duration::from_millis(1)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refreshes_every_period() {
+        let period = Duration::from_secs(10);
+        let rpc = Arc::new(MockRpc::new());
+        let cache = BlockhashCache::new(rpc.clone()).await.unwrap();
+        let refresh = cache.spawn_refresh(period);
+        let first = cache.get().await;
+
+        // The first tick completes right away and doesn't refresh
+        tokio::time::sleep(period / 2).await;
+        assert_eq!(cache.get().await, first);
+
+        pass_tick(period / 2).await;
+        let second = cache.get().await;
+        assert_ne!(second, first);
+        pass_tick(period).await;
+        assert_ne!(cache.get().await, second);
+        assert_eq!(rpc.calls("get_latest_blockhash"), 3);
+        refresh.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_background_refresh_retries_on_the_next_tick() {
+        let period = Duration::from_secs(10);
+        let rpc = Arc::new(MockRpc::new());
+        let cache = BlockhashCache::new(rpc.clone()).await.unwrap();
+        let first = cache.get().await;
+        rpc.fail_next(
+            "get_latest_blockhash",
+            ClientErrorKind::Custom("connection reset".to_string()),
+        );
+        let refresh = cache.spawn_refresh(period);
+
+        pass_tick(period).await;
+        assert_eq!(rpc.calls("get_latest_blockhash"), 2);
+        assert_eq!(cache.get().await, first);
+
+        pass_tick(period).await;
+        assert_eq!(rpc.calls("get_latest_blockhash"), 3);
+        assert_ne!(cache.get().await, first);
+        refresh.abort();
+    }
+}
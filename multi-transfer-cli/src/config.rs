@@ -0,0 +1,681 @@
+use crate::amount::{Amount, SOL_DECIMALS};
+use crate::memo;
+use crate::types::{Config, Mode, TransferPair};
+use anyhow::{Context, Result, anyhow, bail};
+use common::{RpcUrls, keypair};
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Environment variable holding an optional bearer token for remote configs
+pub const CONFIG_TOKEN_ENV: &str = "MULTI_TRANSFER_CONFIG_TOKEN";
+
+/// Remote configs larger than this are rejected
+const MAX_REMOTE_CONFIG_BYTES: usize = 1024 * 1024;
+
+const ALLOWED_CONTENT_TYPES: &[&str] = &[
+    "application/yaml",
+    "application/x-yaml",
+    "text/yaml",
+    "text/x-yaml",
+    "text/plain",
+    "application/octet-stream",
+];
+
+/// Loads the config from a local path or, if `source` is an http(s) URL,
+/// downloads it first
+pub async fn load_config(source: &str) -> Result<Config> {
+    let config_data = if is_url(source) {
+        fetch_remote_config(source).await?
+    } else {
+        std::fs::read_to_string(source)
+            .with_context(|| format!("Failed to read config file: {:?}", source))?
+    };
+
+    let config: Config =
+        serde_yaml::from_str(&config_data).context("Failed to parse config file")?;
+    validate(&config)?;
+    Ok(config)
+}
+
+/// One entry of `--transfers`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InlineTransfer {
+    #[serde(alias = "from_keypair_path")]
+    from: String,
+    #[serde(alias = "to_address")]
+    to: String,
+    #[serde(alias = "amount")]
+    sol: Amount,
+}
+
+/// Builds a config from a JSON array of `{"from", "to", "sol"}` transfers,
+/// given inline on the command line instead of a config file
+pub fn config_from_json(json: &str, rpc_urls: &[String]) -> Result<Config> {
+    let transfers: Vec<InlineTransfer> =
+        serde_json::from_str(json).context("Failed to parse --transfers")?;
+    if transfers.is_empty() {
+        bail!("--transfers doesn't list any transfer");
+    }
+
+    let config = Config {
+        rpc_url: RpcUrls::Many(rpc_urls.to_vec()),
+        amount: None,
+        mode: Some(Mode::Pairs),
+        source_wallets: Vec::new(),
+        destination_wallets: Vec::new(),
+        transfers: transfers
+            .into_iter()
+            .map(|transfer| TransferPair {
+                from_keypair_path: transfer.from,
+                to_address: transfer.to,
+                amount: Some(transfer.sol),
+                token_mint: None,
+                decimals: None,
+                memo: None,
+            })
+            .collect(),
+        token_mint: None,
+        decimals: None,
+        max_concurrent_per_source: None,
+        compute_unit_limit: None,
+        compute_unit_price: None,
+        memo: None,
+    };
+    validate(&config)?;
+    Ok(config)
+}
+
+/// One row of a `--recipients` CSV
+#[derive(Debug, Deserialize)]
+struct Recipient {
+    /// Keypair sending this row, needed when the config has several source wallets
+    #[serde(alias = "from_keypair_path", default)]
+    from: Option<String>,
+    address: String,
+    #[serde(alias = "amount", default)]
+    amount_sol: Option<String>,
+    #[serde(default)]
+    memo: Option<String>,
+}
+
+/// Replaces the config's destinations with the rows of a `--recipients` CSV.
+/// Every row is sent once, from its `from` keypair or the config's only
+/// source wallet, with the row's amount and memo overriding the configured ones
+pub fn add_recipients(config: &mut Config, path: &Path) -> Result<()> {
+    if !config.destination_wallets.is_empty() || !config.transfers.is_empty() {
+        bail!(
+            "--recipients replaces the config's destinations, remove its `destination_wallets` and `transfers`"
+        );
+    }
+    let only_source = match config.source_wallets.as_slice() {
+        [source] => Some(&source.from_keypair_path),
+        _ => None,
+    };
+
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .with_context(|| format!("Failed to read recipients file: {:?}", path))?;
+    let mut recipients = Vec::new();
+    for (row, record) in reader.deserialize::<Recipient>().enumerate() {
+        // Row 1 is the header
+        let line = row + 2;
+        let recipient =
+            record.with_context(|| format!("Failed to parse {:?} line {}", path, line))?;
+        let amount = recipient
+            .amount_sol
+            .map(|amount| Amount::parse_decimal(&amount))
+            .transpose()
+            .map_err(|e| anyhow!("{} in {:?} line {}", e, path, line))?;
+        let from = match (recipient.from, only_source) {
+            (Some(from), _) => from,
+            (None, Some(source)) => source.clone(),
+            (None, None) => bail!(
+                "{:?} line {} has no `from`, which is needed unless the config lists exactly one source wallet",
+                path,
+                line
+            ),
+        };
+        recipients.push(TransferPair {
+            from_keypair_path: from,
+            to_address: recipient.address,
+            amount,
+            token_mint: None,
+            decimals: None,
+            memo: recipient.memo,
+        });
+    }
+    if recipients.is_empty() {
+        bail!("{:?} doesn't list any recipient", path);
+    }
+    info!("Loaded {} recipients from {:?}", recipients.len(), path);
+
+    config.mode = Some(Mode::Pairs);
+    config.transfers = recipients;
+    validate(config)
+}
+
+/// Rejects configs that mix both ways of listing transfers, don't list the
+/// ones their `mode` sends, have missing, zero or sub-lamport amounts or
+/// malformed memo templates
+fn validate(config: &Config) -> Result<()> {
+    if config.rpc_url.urls().is_empty() {
+        bail!("No RPC endpoint in config, set rpc_url");
+    }
+    if !config.transfers.is_empty() && !config.destination_wallets.is_empty() {
+        bail!("Config can't combine `transfers` with `destination_wallets`, use one or the other");
+    }
+    match config.mode {
+        Some(Mode::Pairs) if config.transfers.is_empty() => {
+            bail!("Config sets `mode: pairs` but lists no `transfers`");
+        }
+        Some(Mode::Fanout) if !config.transfers.is_empty() => {
+            bail!(
+                "Config sets `mode: fanout`, which sends to `destination_wallets`, but lists `transfers`. Use `mode: pairs` to send those"
+            );
+        }
+        _ => {}
+    }
+
+    let source_memos = config
+        .source_wallets
+        .iter()
+        .filter_map(|source| source.memo.as_ref());
+    let pair_memos = config
+        .transfers
+        .iter()
+        .filter_map(|pair| pair.memo.as_ref());
+    for template in config.memo.iter().chain(source_memos).chain(pair_memos) {
+        memo::check_template(template)?;
+    }
+
+    for transfer in config.transfer_pairs() {
+        let (from, to) = (transfer.from, transfer.to);
+        let Some(amount) = transfer.amount else {
+            bail!(
+                "No amount set for transfer {} -> {} (set it on the transfer, the source wallet or globally)",
+                keypair::describe(from),
+                to
+            );
+        };
+
+        // Token amounts depend on the mint's decimals and are checked once those are known
+        if transfer.token_mint.is_none()
+            && let Err(e) = amount.to_base_units(SOL_DECIMALS)
+        {
+            bail!("{} for transfer {} -> {}", e, keypair::describe(from), to);
+        }
+    }
+
+    Ok(())
+}
+
+fn is_url(source: &str) -> bool {
+    source.starts_with("https://") || source.starts_with("http://")
+}
+
+async fn fetch_remote_config(url: &str) -> Result<String> {
+    info!("Fetching config from {}", url);
+
+    let mut request = reqwest::Client::new().get(url);
+    if let Ok(token) = std::env::var(CONFIG_TOKEN_ENV) {
+        request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+
+    let mut response = request
+        .send()
+        .await
+        .with_context(|| format!("Failed to fetch config from {}", url))?
+        .error_for_status()
+        .with_context(|| format!("Config server returned an error for {}", url))?;
+
+    if let Some(content_type) = response.headers().get(CONTENT_TYPE) {
+        let content_type = content_type.to_str().unwrap_or_default();
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        if !ALLOWED_CONTENT_TYPES.contains(&mime) {
+            bail!(
+                "Unexpected content type for remote config: {}",
+                content_type
+            );
+        }
+    }
+
+    if response
+        .content_length()
+        .is_some_and(|len| len as usize > MAX_REMOTE_CONFIG_BYTES)
+    {
+        bail!(
+            "Remote config exceeds the {} byte limit",
+            MAX_REMOTE_CONFIG_BYTES
+        );
+    }
+
+    // Checked as it arrives too, the length may be missing or wrong
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .context("Failed to read remote config body")?
+    {
+        if body.len() + chunk.len() > MAX_REMOTE_CONFIG_BYTES {
+            bail!(
+                "Remote config exceeds the {} byte limit",
+                MAX_REMOTE_CONFIG_BYTES
+            );
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let config_data = String::from_utf8(body).context("Remote config is not UTF-8")?;
+
+    // Keep a copy of exactly what was fetched for later reference
+    let cached_path = cache_path();
+    std::fs::write(&cached_path, &config_data)
+        .with_context(|| format!("Failed to cache remote config to {:?}", cached_path))?;
+    info!("Cached remote config to {:?}", cached_path);
+
+    Ok(config_data)
+}
+
+fn cache_path() -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "multi-transfer-config-{}.yaml",
+        chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// A file with `contents` in the temp dir, named uniquely per test
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "multi-transfer-cli-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::File::create(&path)
+            .and_then(|mut file| file.write_all(contents.as_bytes()))
+            .unwrap();
+        path
+    }
+
+    fn config(sources: &[&str]) -> Config {
+        let mut yaml = "rpc_url: http://localhost:8899\namount: 0.1\nsource_wallets:\n".to_string();
+        for source in sources {
+            yaml.push_str(&format!("  - from_keypair_path: {}\n", source));
+        }
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    fn sent(config: &Config) -> Vec<(String, String, Option<u64>)> {
+        config
+            .transfers
+            .iter()
+            .map(|pair| {
+                (
+                    pair.from_keypair_path.clone(),
+                    pair.to_address.clone(),
+                    pair.amount
+                        .map(|amount| amount.to_base_units(SOL_DECIMALS).unwrap()),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recipients_are_sent_once_from_the_only_source() {
+        let path = temp_file(
+            "recipients-one-source.csv",
+            "address,amount_sol,memo\nalice,0.5,hi\nbob,,\n",
+        );
+        let mut config = config(&["a.json"]);
+        add_recipients(&mut config, &path).unwrap();
+
+        assert_eq!(sent(&config), vec![
+            ("a.json".to_string(), "alice".to_string(), Some(500_000_000)),
+            ("a.json".to_string(), "bob".to_string(), None),
+        ]);
+        assert_eq!(config.transfers[0].memo.as_deref(), Some("hi"));
+        assert_eq!(config.transfers[1].memo, None);
+        assert_eq!(config.mode(), Mode::Pairs);
+    }
+
+    #[test]
+    fn recipients_without_from_are_rejected_with_several_sources() {
+        let path = temp_file("recipients-no-from.csv", "address,amount_sol\nalice,0.5\n");
+        let mut config = config(&["a.json", "b.json"]);
+        let error = add_recipients(&mut config, &path).unwrap_err();
+
+        assert!(
+            error.to_string().contains("line 2 has no `from`"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn recipients_are_sent_from_their_from_column() {
+        let path = temp_file(
+            "recipients-from.csv",
+            "from,address,amount_sol\nb.json,alice,0.5\na.json,bob,0.25\n",
+        );
+        let mut config = config(&["a.json", "b.json"]);
+        add_recipients(&mut config, &path).unwrap();
+
+        assert_eq!(sent(&config), vec![
+            ("b.json".to_string(), "alice".to_string(), Some(500_000_000)),
+            ("a.json".to_string(), "bob".to_string(), Some(250_000_000)),
+        ]);
+    }
+
+    /// Serves one request with `head` and then `chunks` in chunked encoding,
+    /// returns its URL
+    async fn serve_once(
+        head: &'static str,
+        chunks: impl IntoIterator<Item = Vec<u8>, IntoIter: Send> + 'static,
+    ) -> String {
+        let chunks = chunks.into_iter();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/config.yaml", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let _ = stream.write_all(head.as_bytes()).await;
+            for chunk in chunks {
+                let mut framed = format!("{:x}\r\n", chunk.len()).into_bytes();
+                framed.extend_from_slice(&chunk);
+                framed.extend_from_slice(b"\r\n");
+                // The client hangs up once it has seen enough
+                if stream.write_all(&framed).await.is_err() {
+                    return;
+                }
+            }
+            let _ = stream.write_all(b"0\r\n\r\n").await;
+        });
+        url
+    }
+
+    const CHUNKED: &str =
+        "HTTP/1.1 200 OK\r\nContent-Type: text/yaml\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+    #[tokio::test]
+    async fn fetches_a_remote_config() {
+        let url = serve_once(CHUNKED, vec![
+            b"rpc_url: ".to_vec(),
+            b"http://rpc\n".to_vec(),
+        ])
+        .await;
+        assert_eq!(
+            fetch_remote_config(&url).await.unwrap(),
+            "rpc_url: http://rpc\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_reading_a_remote_config_without_length_at_the_limit() {
+        // Never ends, reading it all first would never return
+        let url = serve_once(CHUNKED, std::iter::repeat(vec![b'#'; 64 * 1024])).await;
+        let error = fetch_remote_config(&url).await.unwrap_err();
+        assert!(error.to_string().contains("byte limit"), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_remote_config_declared_too_large() {
+        let url = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/yaml\r\nContent-Length: 2000000\r\n\r\n",
+            Vec::new(),
+        )
+        .await;
+        let error = fetch_remote_config(&url).await.unwrap_err();
+        assert!(error.to_string().contains("byte limit"), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn rejects_unexpected_content_types() {
+        let url = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nTransfer-Encoding: chunked\r\n\r\n",
+            vec![b"<html>".to_vec()],
+        )
+        .await;
+        let error = fetch_remote_config(&url).await.unwrap_err();
+        assert!(error.to_string().contains("content type"), "{:#}", error);
+    }
+
+    async fn load(name: &str, yaml: &str) -> Result<Config> {
+        let path = temp_file(name, yaml);
+        load_config(path.to_str().unwrap()).await
+    }
+
+    /// `(from, to, lamports, memo)` of every configured SOL transfer
+    fn resolved(config: &Config) -> Vec<(String, String, u64, Option<String>)> {
+        config
+            .transfer_pairs()
+            .iter()
+            .map(|transfer| {
+                (
+                    transfer.from.to_string(),
+                    transfer.to.to_string(),
+                    transfer
+                        .amount
+                        .unwrap()
+                        .to_base_units(SOL_DECIMALS)
+                        .unwrap(),
+                    transfer.memo.cloned(),
+                )
+            })
+            .collect()
+    }
+
+    fn transfer(
+        from: &str,
+        to: &str,
+        lamports: u64,
+        memo: &str,
+    ) -> (String, String, u64, Option<String>) {
+        (
+            from.to_string(),
+            to.to_string(),
+            lamports,
+            Some(memo.to_string()),
+        )
+    }
+
+    #[tokio::test]
+    async fn loads_the_example_config() {
+        let config = load_config("config.yaml").await.unwrap();
+        assert_eq!(config.mode(), Mode::Fanout);
+        assert_eq!(config.transfer_pairs().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fanout_amounts_and_memos_fall_back_from_source_to_global() {
+        let config = load(
+            "fanout.yaml",
+            "rpc_url: [http://one, http://two]
+amount: 0.01
+memo: global {index}
+source_wallets:
+  - from_keypair_path: a.json
+    amount: 0.02
+    memo: from a
+  - from_keypair_path: b.json
+destination_wallets: [x, y]
+",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(config.rpc_url.urls(), ["http://one", "http://two"]);
+        assert_eq!(config.mode(), Mode::Fanout);
+        assert_eq!(resolved(&config), vec![
+            transfer("a.json", "x", 20_000_000, "from a"),
+            transfer("a.json", "y", 20_000_000, "from a"),
+            transfer("b.json", "x", 10_000_000, "global {index}"),
+            transfer("b.json", "y", 10_000_000, "global {index}"),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn pair_settings_take_precedence() {
+        let config = load(
+            "pairs.yaml",
+            "rpc_url: http://rpc
+amount: 0.01
+memo: global
+source_wallets:
+  - from_keypair_path: a.json
+    amount: 0.02
+transfers:
+  - from_keypair_path: a.json
+    to_address: x
+    amount: {lamports: 5}
+    memo: pair
+  - from_keypair_path: a.json
+    to_address: y
+  - from_keypair_path: b.json
+    to_address: x
+",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(config.mode(), Mode::Pairs);
+        assert_eq!(resolved(&config), vec![
+            transfer("a.json", "x", 5, "pair"),
+            transfer("a.json", "y", 20_000_000, "global"),
+            transfer("b.json", "x", 10_000_000, "global"),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn decimals_come_from_the_level_that_set_the_mint() {
+        let config = load(
+            "mints.yaml",
+            "rpc_url: http://rpc
+amount: 1.5
+token_mint: global-mint
+decimals: 6
+source_wallets:
+  - from_keypair_path: a.json
+    mint: source-mint
+transfers:
+  - from_keypair_path: a.json
+    to_address: x
+  - from_keypair_path: a.json
+    to_address: y
+    token_mint: pair-mint
+    decimals: 2
+  - from_keypair_path: b.json
+    to_address: z
+",
+        )
+        .await
+        .unwrap();
+
+        let mints: Vec<(Option<&str>, Option<u8>)> = config
+            .transfer_pairs()
+            .iter()
+            .map(|transfer| (transfer.token_mint.map(String::as_str), transfer.decimals))
+            .collect();
+        assert_eq!(mints, vec![
+            (Some("source-mint"), None),
+            (Some("pair-mint"), Some(2)),
+            (Some("global-mint"), Some(6)),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_configs() {
+        let cases = [
+            (
+                "rpc_url: []\namount: 0.1\ndestination_wallets: [x]\n",
+                "No RPC endpoint",
+            ),
+            (
+                "rpc_url: http://rpc\namount: 0.1\ndestination_wallets: [x]\ntransfers:\n  - {from_keypair_path: a.json, to_address: x}\n",
+                "can't combine",
+            ),
+            (
+                "rpc_url: http://rpc\namount: 0.1\nmode: pairs\ndestination_wallets: [x]\n",
+                "lists no `transfers`",
+            ),
+            (
+                "rpc_url: http://rpc\namount: 0.1\nmode: fanout\ntransfers:\n  - {from_keypair_path: a.json, to_address: x}\n",
+                "`mode: fanout`",
+            ),
+            (
+                "rpc_url: http://rpc\ntransfers:\n  - {from_keypair_path: a.json, to_address: x}\n",
+                "No amount set",
+            ),
+            (
+                "rpc_url: http://rpc\namount: 0.0000000001\ntransfers:\n  - {from_keypair_path: a.json, to_address: x}\n",
+                "less than one base unit",
+            ),
+            (
+                "rpc_url: http://rpc\namount: 0\ntransfers:\n  - {from_keypair_path: a.json, to_address: x}\n",
+                "greater than zero",
+            ),
+            (
+                "rpc_url: http://rpc\namount: 0.1\nmemo: \"{name}\"\ntransfers:\n  - {from_keypair_path: a.json, to_address: x}\n",
+                "Unknown placeholder",
+            ),
+            (
+                "rpc_url: http://rpc\namount: 0.1\nmode: sideways\n",
+                "Failed to parse config file",
+            ),
+        ];
+        for (i, (yaml, expected)) in cases.into_iter().enumerate() {
+            let error = load(&format!("invalid-{}.yaml", i), yaml)
+                .await
+                .unwrap_err();
+            assert!(
+                format!("{:#}", error).contains(expected),
+                "{:?}: expected {:?}, got {:#}",
+                yaml,
+                expected,
+                error
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_a_missing_config_file() {
+        let error = load_config("/nonexistent/config.yaml").await.unwrap_err();
+        assert!(error.to_string().contains("Failed to read config file"));
+    }
+
+    #[test]
+    fn builds_a_config_from_inline_transfers() {
+        let config = config_from_json(
+            r#"[{"from": "a.json", "to": "x", "sol": 0.5}, {"from_keypair_path": "b.json", "to_address": "y", "amount": "0.25"}]"#,
+            &["http://rpc".to_string()],
+        )
+        .unwrap();
+        assert_eq!(config.mode(), Mode::Pairs);
+        let sent: Vec<(String, String, u64)> = resolved(&config)
+            .into_iter()
+            .map(|(from, to, lamports, _)| (from, to, lamports))
+            .collect();
+        assert_eq!(sent, vec![
+            ("a.json".to_string(), "x".to_string(), 500_000_000),
+            ("b.json".to_string(), "y".to_string(), 250_000_000),
+        ]);
+
+        assert!(config_from_json("[]", &["http://rpc".to_string()]).is_err());
+        assert!(
+            config_from_json(r#"[{"from": "a.json", "to": "x"}]"#, &[
+                "http://rpc".to_string()
+            ])
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use crate::retry;
+use common::SolanaRpc;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{
+    commitment_config::CommitmentConfig, signature::Signature, transaction::TransactionError,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::oneshot, task::JoinHandle, time::interval};
+use tracing::{debug, warn};
+
+/// Signatures per `getSignatureStatuses` request, the RPC's limit
+const MAX_SIGNATURES_PER_REQUEST: usize = 256;
+
+type Waiters = HashMap<Signature, Vec<oneshot::Sender<Result<(), TransactionError>>>>;
+
+/// Confirms the signatures of all in-flight transfers together, with one
+/// `getSignatureStatuses` call per poll instead of one poll loop per transfer
+pub struct BatchConfirmer<R = RpcClient> {
+    rpc_client: Arc<R>,
+    waiters: Arc<Mutex<Waiters>>,
+}
+
+// Derived Clone would require `R: Clone`, only the Arcs are cloned
+impl<R> Clone for BatchConfirmer<R> {
+    fn clone(&self) -> Self {
+        Self {
+            rpc_client: self.rpc_client.clone(),
+            waiters: self.waiters.clone(),
+        }
+    }
+}
+
+impl<R: SolanaRpc + 'static> BatchConfirmer<R> {
+    pub fn new(rpc_client: Arc<R>) -> Self {
+        Self {
+            rpc_client,
+            waiters: Arc::default(),
+        }
+    }
+
+    /// Waits until `signature` is confirmed or failed. `None` if it didn't
+    /// resolve within `timeout`
+    pub async fn wait(
+        &self,
+        signature: Signature,
+        timeout: Duration,
+    ) -> Option<Result<(), TransactionError>> {
+        let (sender, receiver) = oneshot::channel();
+        self.lock().entry(signature).or_default().push(sender);
+
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(status)) => Some(status),
+            _ => {
+                // Stop polling for it unless someone else still waits
+                let mut waiters = self.lock();
+                if let Some(senders) = waiters.get_mut(&signature) {
+                    senders.retain(|sender| !sender.is_closed());
+                    if senders.is_empty() {
+                        waiters.remove(&signature);
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    /// Spawns the task that polls the pending signatures every `period`
+    pub fn spawn_polling(
+        &self,
+        period: Duration,
+        max_retries: u32,
+        backoff: Duration,
+    ) -> JoinHandle<()> {
+        let confirmer = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = interval(period);
+            loop {
+                ticker.tick().await;
+                confirmer.poll(max_retries, backoff).await;
+            }
+        })
+    }
+
+    async fn poll(&self, max_retries: u32, backoff: Duration) {
+        let pending: Vec<Signature> = self.lock().keys().copied().collect();
+        if pending.is_empty() {
+            return;
+        }
+        debug!("Checking the status of {} signatures", pending.len());
+
+        for chunk in pending.chunks(MAX_SIGNATURES_PER_REQUEST) {
+            let statuses = match retry::with_backoff(
+                "Checking signature statuses",
+                max_retries,
+                backoff,
+                || self.rpc_client.get_signature_statuses(chunk),
+            )
+            .await
+            {
+                Ok(response) => response.value,
+                Err(e) => {
+                    warn!("Error checking signature statuses: {}", e);
+                    continue;
+                }
+            };
+
+            let mut waiters = self.lock();
+            for (signature, status) in chunk.iter().zip(statuses) {
+                // Processed isn't final yet, wait for the same commitment as a single poll
+                let Some(status) = status
+                    .filter(|status| status.satisfies_commitment(CommitmentConfig::confirmed()))
+                else {
+                    continue;
+                };
+                let result = match status.err {
+                    None => Ok(()),
+                    Some(e) => Err(e),
+                };
+                for sender in waiters.remove(signature).unwrap_or_default() {
+                    let _ = sender.send(result.clone());
+                }
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Waiters> {
+        self.waiters
+            .lock()
+            .expect("confirmation waiters lock poisoned")
+    }
+}
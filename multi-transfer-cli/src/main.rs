@@ -1,235 +1,498 @@
+mod amount;
 mod args;
+mod batch;
+mod blockhash;
+mod config;
+mod confirm;
+mod memo;
+mod offline;
+mod plan;
+mod preflight;
+mod priority_fee;
+mod rate_limit;
+mod report;
+mod retry;
+mod runner;
+mod state;
+mod token;
+mod transfer;
 mod types;
+mod verify;
 
-use anyhow::{Context, Result};
-use args::Args;
+use amount::SOL_DECIMALS;
+use anyhow::{Context, Result, anyhow, bail};
+use args::{Args, ConfirmMode, NewAccountPolicy, OutputFormat, PriorityFeeMode};
+use blockhash::BlockhashCache;
+use chrono::{DateTime, Utc};
 use clap::Parser;
-use solana_client::nonblocking::rpc_client::RpcClient;
-use solana_client::rpc_config::RpcSendTransactionConfig;
-use solana_sdk::{
-    commitment_config::CommitmentConfig,
-    pubkey::Pubkey,
-    signature::Signature,
-    signer::{Signer, keypair::read_keypair_file},
-    system_instruction,
-    transaction::Transaction,
-};
+use common::{RoundRobinClient, RpcUrls, keypair, rate_limit::RateLimiter};
+use confirm::BatchConfirmer;
+use plan::PlanSettings;
+use priority_fee::AutoPriorityFee;
+use rate_limit::RateLimited;
+use runner::{RunOptions, TransferEvent};
+use solana_sdk::commitment_config::CommitmentConfig;
+use state::StateFile;
 use std::{
-    str::FromStr,
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    process::ExitCode,
     sync::Arc,
     time::{Duration, Instant},
 };
-use tokio::{sync::Semaphore, time::sleep};
-use tracing::{info, warn};
-use types::{Config, TransferResult, TransferSpec, TransferStatus};
+use tokio::signal;
+use tracing::{debug, info, warn};
+use transfer::{ExecutorOptions, TransferExecutor};
+use types::{TokenSpec, TransferResult, TransferSpec};
+use verify::BalanceSnapshot;
+
+/// Some transfers failed, timed out or failed verification
+const EXIT_PARTIAL_FAILURE: u8 = 1;
+/// Every transfer failed or timed out
+const EXIT_ALL_FAILED: u8 = 2;
+/// Stopped by ctrl-c before every transfer was started
+const EXIT_INTERRUPTED: u8 = 130;
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize logging
-    tracing_subscriber::fmt::init();
+async fn main() -> Result<ExitCode> {
+    // Initialize logging on stderr so stdout only carries the results
+    tracing_subscriber::fmt().with_writer(io::stderr).init();
 
     // Parse command line arguments
     let args = Args::parse();
 
-    // Read the configuration file
-    let config_data = std::fs::read_to_string(&args.config)
-        .with_context(|| format!("Failed to read config file: {:?}", args.config))?;
+    if let Some(path) = &args.broadcast {
+        return broadcast(&args, path).await;
+    }
 
-    let config: Config =
-        serde_yaml::from_str(&config_data).context("Failed to parse config file")?;
+    // Read the configuration file, or the transfers given inline
+    let mut config = match &args.transfers {
+        Some(transfers) => config::config_from_json(transfers, &args.rpc_url)?,
+        None => config::load_config(&args.config).await?,
+    };
+    if let Some(path) = &args.recipients {
+        config::add_recipients(&mut config, path)?;
+    }
+    if !args.rpc_url.is_empty() {
+        config.rpc_url = RpcUrls::Many(args.rpc_url.clone());
+    }
 
-    // Create RPC client
-    let rpc_client = Arc::new(RpcClient::new_with_commitment(
-        config.rpc_url.clone(),
+    // Sends and confirmations rotate through all endpoints, the other
+    // lookups go to the first reachable one
+    let rpc_timeout = Duration::from_secs(args.rpc_timeout);
+    let rpc_urls = config.rpc_url.urls();
+    let endpoints = RoundRobinClient::new(rpc_urls, CommitmentConfig::confirmed(), rpc_timeout);
+    let rpc_client = Arc::new(common::make_rpc_client(
+        endpoints.first_reachable_url().await,
         CommitmentConfig::confirmed(),
+        rpc_timeout,
     ));
-
-    // Get recent blockhash
-    let recent_blockhash = rpc_client
-        .get_latest_blockhash()
-        .await
-        .context("Failed to get recent blockhash")?;
+    let rpc_pool = Arc::new(RateLimited::new(endpoints, args.rps.map(RateLimiter::new)));
+    if rpc_urls.len() > 1 {
+        info!("Sending through {} RPC endpoints", rpc_urls.len());
+    }
 
     // Generate all transfer combinations
     let mut transfers = Vec::new();
-    for source in &config.source_wallets {
-        let amount = source.amount.unwrap_or(config.amount); // if amount is not provided, use default amount
-        for dest in &config.destination_wallets {
-            transfers.push(TransferSpec {
-                from_keypair_path: source.from_keypair_path.clone(),
-                to_address: dest.clone(),
-                amount_sol: amount,
-            });
-        }
+    // Decimals looked up on chain for mints without configured decimals
+    let mut mint_decimals = HashMap::new();
+    for (index, transfer) in config.transfer_pairs().into_iter().enumerate() {
+        let (from, to) = (transfer.from, transfer.to);
+        let source = config.source_wallet(from);
+        let token = match transfer.token_mint {
+            Some(mint) => {
+                let decimals = match transfer.decimals {
+                    Some(decimals) => decimals,
+                    None => match mint_decimals.get(mint) {
+                        Some(decimals) => *decimals,
+                        None => {
+                            let decimals = token::fetch_mint_decimals(&rpc_client, mint)
+                                .await
+                                .with_context(|| {
+                                    format!(
+                                        "Failed to resolve decimals for {}",
+                                        keypair::describe(from)
+                                    )
+                                })?;
+                            *mint_decimals.entry(mint.clone()).or_insert(decimals)
+                        }
+                    },
+                };
+                Some(TokenSpec {
+                    mint: mint.clone(),
+                    decimals,
+                })
+            }
+            None => None,
+        };
+        let decimals = token.as_ref().map_or(SOL_DECIMALS, |token| token.decimals);
+        let amount = transfer
+            .amount
+            .context("amount was checked when the config was loaded")?
+            .to_base_units(decimals)
+            .map_err(|e| anyhow!("{} for transfer {} -> {}", e, keypair::describe(from), to))?;
+        let memo = transfer
+            .memo
+            .map(|template| memo::render(template, index + 1, to))
+            .transpose()?;
+        transfers.push(TransferSpec {
+            from_keypair_path: from.to_string(),
+            to_address: to.to_string(),
+            amount,
+            token,
+            compute_unit_limit: args
+                .compute_unit_limit
+                .or(source.and_then(|source| source.compute_unit_limit))
+                .or(config.compute_unit_limit),
+            compute_unit_price: args
+                .compute_unit_price
+                .or(source.and_then(|source| source.compute_unit_price))
+                .or(config.compute_unit_price),
+            new_account: false,
+            rent_top_up: 0,
+            memo,
+        });
     }
 
     info!("Generated {} transfers from configuration", transfers.len());
 
-    // Set up semaphore for controlling concurrency
-    let semaphore = Arc::new(Semaphore::new(args.concurrent));
+    // Every address and keypair is checked before anything touches the chain.
+    // A dry run reports the problems and simulates anyway, a real run sends
+    // nothing rather than the transfers that happen to be valid
+    let problems = preflight::validate_transfers(&transfers);
+    if problems.is_empty() {
+        info!("Pre-flight checks passed");
+    } else if args.dry_run {
+        eprintln!("\n{:-^80}", " PRE-FLIGHT PROBLEMS ");
+        for problem in &problems {
+            warn!("{}", problem);
+            eprintln!("  - {}", problem);
+        }
+    } else {
+        bail!(
+            "{} problems in the configuration, nothing was sent:\n  - {}",
+            problems.len(),
+            problems.join("\n  - ")
+        );
+    }
 
-    // Execute transfers in parallel
-    let start_time = Instant::now();
-    let transfer_futures = transfers.iter().map(|transfer| {
-        let rpc_client = rpc_client.clone();
-        let semaphore = semaphore.clone();
+    // Skip whatever a previous run of this batch already sent
+    let state = match &args.state_file {
+        Some(path) => Some(Arc::new(StateFile::load(path)?)),
+        None => None,
+    };
+    // A transfer an interrupted run sent may have landed after all
+    if let Some(state) = &state
+        && !args.dry_run
+    {
+        let landed = state.resolve_unfinished(&*rpc_client).await?;
+        if landed > 0 {
+            info!("{} unfinished transfers landed after all", landed);
+        }
+    }
+    if let Some(state) = &state
+        && !args.force
+    {
+        let total = transfers.len();
+        let mut pending = Vec::new();
+        for transfer in transfers {
+            if !state.is_done(&transfer).await {
+                pending.push(transfer);
+            }
+        }
+        // Silently skipping them could hide that the wrong state file was passed
+        if pending.len() < total && !args.resume {
+            bail!(
+                "The state file already records {} of these transfers as successful, pass \
+                 --resume to send only the outstanding ones or --force to send them all again",
+                total - pending.len()
+            );
+        }
+        transfers = pending;
 
-        let keypair_path = transfer.from_keypair_path.clone();
-        let to_address = transfer.to_address.clone();
-        let amount_sol = transfer.amount_sol;
-        let recent_blockhash = recent_blockhash;
-        let timeout = args.timeout;
+        if transfers.len() < total {
+            info!(
+                "Resuming: skipping {} transfers already recorded as successful, batching the {} outstanding ones",
+                total - transfers.len(),
+                transfers.len()
+            );
+        }
+        if transfers.is_empty() {
+            println!("All {} transfers already succeeded, nothing to do", total);
+            return Ok(ExitCode::SUCCESS);
+        }
+    }
 
-        async move {
-            // Acquire permit from semaphore
-            let _permit = semaphore.acquire().await.unwrap();
-
-            info!("Starting transfer: {} -> {}", keypair_path, to_address);
-
-            // Load keypair
-            let from_keypair = match read_keypair_file(&keypair_path) {
-                Ok(kp) => kp,
-                Err(e) => {
-                    warn!("Failed: Keypair loading error: {}", e);
-                    return TransferResult {
-                        from: keypair_path,
-                        to: to_address,
-                        amount: amount_sol,
-                        signature: Signature::default(),
-                        duration_ms: 0,
-                        status: TransferStatus::Failed(format!("Keypair loading error: {}", e)),
-                    };
+    // A new account has to receive at least its rent-exempt minimum. Every transfer to
+    // it is checked on its own since any of them may be the one that creates it
+    if let Some(rent) = preflight::mark_new_destinations(&rpc_client, &mut transfers).await? {
+        let mut below_rent: Vec<_> = transfers
+            .iter_mut()
+            .filter(|transfer| transfer.new_account && transfer.amount < rent)
+            .collect();
+        if !below_rent.is_empty() {
+            if args.new_account_policy == NewAccountPolicy::AutoFundToRent {
+                for transfer in &mut below_rent {
+                    transfer.rent_top_up = rent - transfer.amount;
+                    transfer.amount = rent;
                 }
-            };
-
-            let from_pubkey = from_keypair.pubkey();
-
-            // Parse destination address
-            let to_pubkey = match Pubkey::from_str(&to_address) {
-                Ok(pk) => pk,
-                Err(e) => {
-                    warn!("Failed: Invalid destination address: {}", e);
-                    return TransferResult {
-                        from: from_pubkey.to_string(),
-                        to: to_address,
-                        amount: amount_sol,
-                        signature: Signature::default(),
-                        duration_ms: 0,
-                        status: TransferStatus::Failed(format!(
-                            "Invalid destination address: {}",
-                            e
-                        )),
-                    };
+                info!(
+                    "Raised {} transfers to new accounts to the rent-exempt minimum of {} lamports",
+                    below_rent.len(),
+                    rent
+                );
+            } else {
+                let details = below_rent
+                    .iter()
+                    .map(|transfer| {
+                        format!(
+                            "  - {} -> {}: {} lamports",
+                            keypair::describe(&transfer.from_keypair_path),
+                            transfer.to_address,
+                            transfer.amount
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+
+                if args.new_account_policy == NewAccountPolicy::Error && !args.dry_run {
+                    bail!(
+                        "Transfers to new accounts below the rent-exempt minimum of {} lamports \
+                        (see --new-account-policy):\n{}",
+                        rent,
+                        details
+                    );
                 }
-            };
+                eprintln!(
+                    "\n{:-^80}",
+                    format!(" BELOW THE RENT-EXEMPT MINIMUM OF {} LAMPORTS ", rent)
+                );
+                eprintln!("{}", details);
+            }
+        }
+    }
 
-            info!("Creating transaction from {} to {}", from_pubkey, to_pubkey);
+    // Guard against typos in amounts before touching any balance
+    if let Some(max_total_sol) = args.max_total_sol {
+        let max_lamports = max_total_sol
+            .to_base_units(SOL_DECIMALS)
+            .map_err(|e| anyhow!("Invalid --max-total-sol: {}", e))?;
+        let total_lamports = preflight::total_lamports(&transfers)?;
+        if total_lamports > max_lamports {
+            let message = format!(
+                "Transfers add up to {:.9} SOL, more than --max-total-sol {} SOL",
+                amount::to_ui_amount(total_lamports, SOL_DECIMALS),
+                max_total_sol
+            );
+            if !args.dry_run {
+                bail!(message);
+            }
+            warn!("{}", message);
+        }
+    }
 
-            // Convert SOL to lamports
-            let lamports = (amount_sol * 1_000_000_000.0) as u64;
+    // Configured per-source limits, layered under the global one. Sources
+    // without one send a single transfer at a time
+    let mut source_limits = HashMap::new();
+    for transfer in &transfers {
+        let limit = config
+            .source_wallet(&transfer.from_keypair_path)
+            .and_then(|source| source.max_concurrent)
+            .or(config.max_concurrent_per_source);
+        if let Some(limit) = limit {
+            source_limits.insert(transfer.from_keypair_path.clone(), limit);
+        }
+    }
+    let sources = preflight::source_plans(
+        &rpc_client,
+        &transfers,
+        args.fee_lamports,
+        &source_limits,
+        args.create_ata,
+    )
+    .await?;
+
+    // Only as many transfers as there are, and the sources allow, can be in flight
+    let in_flight = args.concurrent.min(
+        sources
+            .iter()
+            .map(|source| source.max_concurrent.min(source.transfer_count as usize))
+            .sum(),
+    );
+    if !args.no_warnings
+        && let Some(warning) = preflight::concurrency_warning(rpc_urls, in_flight)
+    {
+        warn!("{} (silence with --no-warnings)", warning);
+    }
 
-            // Create transfer instruction
-            let instruction = system_instruction::transfer(&from_pubkey, &to_pubkey, lamports);
+    // Make sure every source wallet can cover all of its transfers before sending any
+    if args.skip_balance_check {
+        warn!("Skipping the source balance check");
+    } else {
+        let shortfalls = preflight::check_balances(&rpc_client, &sources).await?;
+        if !shortfalls.is_empty() {
+            let details = shortfalls
+                .iter()
+                .map(|shortfall| format!("  - {}", shortfall))
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            if !args.dry_run {
+                bail!("Insufficient balance in source wallets:\n{}", details);
+            }
+            eprintln!("\n{:-^80}", " UNDERFUNDED SOURCE WALLETS ");
+            eprintln!("{}", details);
+        }
+    }
 
-            // Create transaction
-            let tx = Transaction::new_signed_with_payer(
-                &[instruction],
-                Some(&from_pubkey),
-                &[&from_keypair],
-                recent_blockhash,
-            );
+    // Nothing is signed until the plan is confirmed, a dry run has nothing to confirm
+    let settings = PlanSettings {
+        concurrent: in_flight,
+        batch_per_tx: args.batch_per_tx,
+        timeout_secs: args.timeout,
+        max_retries: args.max_retries,
+    };
+    eprintln!("\n{}", plan::render_plan(&sources, &settings));
+    if !args.dry_run && !args.yes && !plan::confirm()? {
+        println!("aborted by user");
+        return Ok(ExitCode::SUCCESS);
+    }
 
-            info!("Sending transaction...");
-
-            // Send the transaction
-            let start = Instant::now();
-            let signature = match rpc_client
-                .send_transaction_with_config(&tx, RpcSendTransactionConfig {
-                    skip_preflight: false,
-                    preflight_commitment: Some(CommitmentConfig::confirmed().commitment),
-                    encoding: None,
-                    max_retries: Some(5),
-                    min_context_slot: None,
-                })
-                .await
-            {
-                Ok(sig) => sig,
-                Err(e) => {
-                    warn!("Failed to send transaction: {}", e);
-                    return TransferResult {
-                        from: from_pubkey.to_string(),
-                        to: to_pubkey.to_string(),
-                        amount: amount_sol,
-                        signature: Signature::default(),
-                        duration_ms: start.elapsed().as_millis() as u64,
-                        status: TransferStatus::Failed(format!("Send error: {}", e)),
-                    };
-                }
-            };
-
-            info!("Confirming transaction: {}", signature);
-
-            // Wait for confirmation
-            let timeout_duration = Duration::from_secs(timeout);
-            let mut status_result = None;
-            let end_time = Instant::now() + timeout_duration;
-
-            while Instant::now() < end_time {
-                match rpc_client.get_signature_status(&signature).await {
-                    Ok(Some(status)) => {
-                        status_result = Some(status);
-                        break;
-                    }
-                    Ok(None) => {
-                        sleep(Duration::from_millis(500)).await;
-                    }
-                    Err(e) => {
-                        warn!("Error checking signature status: {}", e);
-                        sleep(Duration::from_millis(1000)).await;
-                    }
-                }
-            }
+    // Destination balances to compare against once everything is sent
+    let snapshot = if args.verify {
+        Some(BalanceSnapshot::take(&rpc_client, &transfers).await)
+    } else {
+        None
+    };
+
+    // Fetch the initial blockhash and keep it fresh for long-running batches
+    let blockhash_cache = BlockhashCache::new(rpc_pool.clone()).await?;
+    let blockhash_refresh =
+        blockhash_cache.spawn_refresh(Duration::from_secs(args.blockhash_refresh_secs));
+
+    // In batched mode a single task confirms the signatures of all transfers
+    let batch_confirmer = match args.confirm_mode {
+        ConfirmMode::Batched => Some(BatchConfirmer::new(rpc_pool.clone())),
+        ConfirmMode::Individual => None,
+    };
+    let confirm_polling = batch_confirmer.as_ref().map(|confirmer| {
+        confirmer.spawn_polling(
+            Duration::from_millis(args.poll_interval_ms),
+            args.max_retries,
+            Duration::from_millis(args.retry_backoff_ms),
+        )
+    });
 
-            let duration_ms = start.elapsed().as_millis() as u64;
+    // Execute transfers in parallel
+    let executor = Arc::new(TransferExecutor::new(
+        rpc_pool.clone(),
+        blockhash_cache.clone(),
+        executor_options(&args, batch_confirmer),
+    ));
 
-            let status = match status_result {
-                Some(Ok(())) => {
-                    info!("Success: {} in {}ms", signature, duration_ms);
-                    TransferStatus::Success
-                }
-                Some(Err(e)) => {
-                    warn!("Failed: {}", e);
-                    TransferStatus::Failed(format!("Transaction error: {:?}", e))
-                }
-                None => {
-                    warn!("Timeout while confirming transaction");
-                    TransferStatus::Timeout
-                }
-            };
-
-            TransferResult {
-                from: from_pubkey.to_string(),
-                to: to_pubkey.to_string(),
-                amount: amount_sol,
-                signature,
-                duration_ms,
-                status,
+    if let Some(path) = &args.sign_only {
+        let (count, last_valid_block_height) =
+            offline::sign_all(&rpc_client, &executor, transfers, args.batch_per_tx, path).await?;
+        println!(
+            "Signed {} transactions into {:?}, broadcast them before block height {}",
+            count, path, last_valid_block_height
+        );
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let start_time = Instant::now();
+    let started_at = Utc::now();
+    let mut run = runner::run_transfers(transfers, executor, RunOptions {
+        concurrent: args.concurrent,
+        source_limits,
+        batch_per_tx: args.batch_per_tx,
+        state: state.clone(),
+        dry_run: args.dry_run,
+    });
+    let total = run.total;
+
+    // The first ctrl-c stops new transfers from starting, the second one aborts
+    let ctrl_c = tokio::spawn({
+        let stop = run.stopper();
+        async move {
+            if signal::ctrl_c().await.is_err() {
+                return;
+            }
+            warn!("Interrupted, waiting for in-flight transfers. Press ctrl-c again to abort");
+            stop();
+
+            if signal::ctrl_c().await.is_ok() {
+                warn!("Aborting");
+                std::process::exit(EXIT_INTERRUPTED.into());
             }
         }
     });
 
-    // Collect all futures and execute them
-    let handles: Vec<_> = transfer_futures.map(tokio::spawn).collect();
+    // Results in the order of the run, whatever order they finish in
+    let mut finished = Vec::new();
+    let mut not_started = 0;
+    while let Some(event) = run.events.recv().await {
+        let (index, result) = match event {
+            TransferEvent::Started { index, from, to } => {
+                info!("Starting transfer #{}: {} -> {}", index + 1, from, to);
+                continue;
+            }
+            TransferEvent::Sent { index, signature } => {
+                info!("Transfer #{} sent: {}", index + 1, signature);
+                continue;
+            }
+            TransferEvent::NotStarted { index } => {
+                debug!("Transfer #{} was not started", index + 1);
+                not_started += 1;
+                continue;
+            }
+            TransferEvent::Confirmed { index, result } => (index, result),
+            TransferEvent::Failed {
+                index,
+                reason,
+                result,
+            } => {
+                warn!("Transfer #{} failed: {}", index + 1, reason);
+                (index, result)
+            }
+            TransferEvent::TimedOut { index, result } => {
+                warn!("Transfer #{} timed out", index + 1);
+                (index, result)
+            }
+        };
+        finished.push((index, *result));
+        info!("Progress: {}/{} transfers completed", finished.len(), total);
+    }
+    finished.sort_by_key(|(index, _)| *index);
+    let mut results: Vec<_> = finished.into_iter().map(|(_, result)| result).collect();
 
-    let mut results = Vec::new();
-    let mut completed = 0;
-    let total = handles.len();
+    blockhash_refresh.abort();
+    if let Some(confirm_polling) = confirm_polling {
+        confirm_polling.abort();
+    }
+    ctrl_c.abort();
 
-    for handle in handles {
-        let result = handle.await?;
-        results.push(result);
-        completed += 1;
-        info!("Progress: {}/{} transfers completed", completed, total);
+    if args.confirm_with_history {
+        let updated = transfer::recheck_timeouts_with_history(&rpc_client, &mut results).await;
+        info!("Updated {} timed-out transfers from history", updated.len());
+        if !updated.is_empty() {
+            transfer::fetch_missing_fees(&rpc_client, &mut results).await;
+        }
+        if let Some(state) = &state {
+            for i in updated {
+                if let Err(e) = state.update_status(&results[i]).await {
+                    warn!("{:#}", e);
+                }
+            }
+        }
+    }
+
+    if let Some(snapshot) = &snapshot {
+        snapshot
+            .verify(&rpc_client, &mut results, args.verify_tolerance)
+            .await;
     }
 
     info!(
@@ -238,67 +501,176 @@ async fn main() -> Result<()> {
     );
 
     // Display results
-    println!("\n{:-^80}", " RESULTS SUMMARY ");
-    println!(
-        "{:<5} {:<12} {:<44} {:<10} {:<10} {:<20} {:<20}",
-        "No.", "Status", "Signature", "Amount", "Time (ms)", "From", "To"
-    );
-    println!("{:-^80}", "");
-
-    let mut success_count = 0;
-    let mut failed_count = 0;
-    let mut timeout_count = 0;
-    let mut total_duration = 0;
-
-    for (i, result) in results.iter().enumerate() {
-        let status_str = match &result.status {
-            TransferStatus::Success => {
-                success_count += 1;
-                "SUCCESS"
-            }
-            TransferStatus::Failed(err) => {
-                failed_count += 1;
-                println!("    Error details: {}", err);
-                "FAILED"
-            }
-            TransferStatus::Timeout => {
-                timeout_count += 1;
-                "TIMEOUT"
-            }
-        };
+    let total_time = start_time.elapsed();
+    write_results(&args, &results, &rpc_urls.join(","), started_at, total_time)?;
 
-        total_duration += result.duration_ms;
-
-        println!(
-            "{:<5} {:<12} {:<44} {:<10.4} {:<10} {:<20} {:<20}",
-            i + 1,
-            status_str,
-            result.signature.to_string(),
-            result.amount,
-            result.duration_ms,
-            result.from,
-            result.to
+    if not_started > 0 {
+        eprintln!(
+            "\nInterrupted: {} of {} transfers were never started",
+            not_started, total
         );
+        return Ok(ExitCode::from(EXIT_INTERRUPTED));
     }
 
-    // Print summary statistics
-    println!("\n{:-^80}", " STATISTICS ");
-    println!("Total transfers: {}", results.len());
-    println!("Successful: {}", success_count);
-    println!("Failed: {}", failed_count);
-    println!("Timeouts: {}", timeout_count);
-    println!(
-        "Average duration: {}ms",
-        if !results.is_empty() {
-            total_duration / results.len() as u64
-        } else {
-            0
-        }
+    Ok(exit_code(&results, total_time))
+}
+
+/// Client the transfers send and confirm through: every endpoint in turn,
+/// paced by `--rps`
+type SendClient = RateLimited<RoundRobinClient>;
+
+/// How the executor sends, from the command line
+fn executor_options(
+    args: &Args,
+    batch_confirmer: Option<BatchConfirmer<SendClient>>,
+) -> ExecutorOptions<SendClient> {
+    ExecutorOptions {
+        timeout: Duration::from_secs(args.timeout),
+        rpc_timeout: Duration::from_secs(args.rpc_timeout),
+        dry_run: args.dry_run,
+        create_ata: args.create_ata,
+        close_token_accounts: args.close_token_accounts.then_some(args.rent_recipient),
+        max_retries: args.max_retries,
+        retries: args.retries,
+        retry_backoff: Duration::from_millis(args.retry_backoff_ms),
+        poll_interval: Duration::from_millis(args.poll_interval_ms),
+        batch_confirmer,
+        auto_priority_fee: args
+            .priority_fee
+            .map(|PriorityFeeMode::Auto| AutoPriorityFee {
+                percentile: args.priority_fee_percentile,
+                max_micro_lamports: args.max_priority_fee,
+            }),
+    }
+}
+
+/// Sends the transactions of a `--sign-only` run and reports them like a
+/// normal run. The config is only read for its RPC URLs, if `--rpc-url` isn't set
+async fn broadcast(args: &Args, path: &Path) -> Result<ExitCode> {
+    let rpc_urls = if args.rpc_url.is_empty() {
+        config::load_config(&args.config)
+            .await?
+            .rpc_url
+            .urls()
+            .to_vec()
+    } else {
+        args.rpc_url.clone()
+    };
+    let signed = offline::read_signed(path)?;
+    info!(
+        "Broadcasting {} signed transactions from {:?}",
+        signed.len(),
+        path
     );
-    println!(
-        "Total execution time: {}ms",
-        start_time.elapsed().as_millis()
+
+    let endpoints = RoundRobinClient::new(
+        &rpc_urls,
+        CommitmentConfig::confirmed(),
+        Duration::from_secs(args.rpc_timeout),
     );
+    let rpc_client = Arc::new(common::make_rpc_client(
+        endpoints.first_reachable_url().await,
+        CommitmentConfig::confirmed(),
+        Duration::from_secs(args.rpc_timeout),
+    ));
+    let rpc_pool = Arc::new(RateLimited::new(endpoints, args.rps.map(RateLimiter::new)));
+    let batch_confirmer = match args.confirm_mode {
+        ConfirmMode::Batched => Some(BatchConfirmer::new(rpc_pool.clone())),
+        ConfirmMode::Individual => None,
+    };
+    let confirm_polling = batch_confirmer.as_ref().map(|confirmer| {
+        confirmer.spawn_polling(
+            Duration::from_millis(args.poll_interval_ms),
+            args.max_retries,
+            Duration::from_millis(args.retry_backoff_ms),
+        )
+    });
+    // Signed transactions keep their blockhash, the cache is never used to sign
+    let executor = Arc::new(TransferExecutor::new(
+        rpc_pool.clone(),
+        BlockhashCache::new(rpc_pool.clone()).await?,
+        executor_options(args, batch_confirmer),
+    ));
+
+    let start_time = Instant::now();
+    let started_at = Utc::now();
+    let mut results =
+        offline::broadcast_all(rpc_client.clone(), executor, signed, args.concurrent).await?;
+    if let Some(confirm_polling) = confirm_polling {
+        confirm_polling.abort();
+    }
+
+    if args.confirm_with_history {
+        let updated = transfer::recheck_timeouts_with_history(&rpc_client, &mut results).await;
+        info!("Updated {} timed-out transfers from history", updated.len());
+        if !updated.is_empty() {
+            transfer::fetch_missing_fees(&rpc_client, &mut results).await;
+        }
+    }
 
+    let total_time = start_time.elapsed();
+    write_results(args, &results, &rpc_urls.join(","), started_at, total_time)?;
+    Ok(exit_code(&results, total_time))
+}
+
+/// Writes the results in the requested format to stdout or `--output-file`,
+/// with the human-readable summary on stderr when that isn't the output
+fn write_results(
+    args: &Args,
+    results: &[TransferResult],
+    rpc_url: &str,
+    started_at: DateTime<Utc>,
+    total_time: Duration,
+) -> Result<()> {
+    let mut output: Box<dyn Write> = match &args.output_file {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).with_context(|| {
+                format!("Failed to create output file: {:?}", path)
+            })?))
+        }
+        None => Box::new(io::stdout().lock()),
+    };
+
+    if args.output_format == OutputFormat::Table && args.output_file.is_none() {
+        report::write_summary(&mut output, results, args.dry_run, total_time)?;
+    } else {
+        // Keep the machine-readable output clean, humans read stderr
+        report::write_summary(&mut io::stderr().lock(), results, args.dry_run, total_time)?;
+        match args.output_format {
+            OutputFormat::Table => {
+                report::write_summary(&mut output, results, args.dry_run, total_time)?
+            }
+            OutputFormat::Json => {
+                report::write_json(&mut output, results, rpc_url, started_at, total_time)?
+            }
+            OutputFormat::Csv => report::write_csv(&mut output, results, rpc_url)?,
+        }
+    }
+    output.flush()?;
     Ok(())
 }
+
+/// Lets CI jobs detect failed batches, see the exit codes in `--help`
+fn exit_code(results: &[TransferResult], total_time: Duration) -> ExitCode {
+    let stats = report::Statistics::from_results(results, total_time);
+    let unsuccessful = stats.failed + stats.timeouts;
+    if unsuccessful == 0 {
+        if stats.verification_mismatches > 0 {
+            warn!(
+                "{} of {} transfers failed verification",
+                stats.verification_mismatches, stats.total
+            );
+            return ExitCode::from(EXIT_PARTIAL_FAILURE);
+        }
+        return ExitCode::SUCCESS;
+    }
+    warn!(
+        "{} of {} transfers failed or timed out",
+        unsuccessful, stats.total
+    );
+    if unsuccessful == stats.total {
+        ExitCode::from(EXIT_ALL_FAILED)
+    } else {
+        ExitCode::from(EXIT_PARTIAL_FAILURE)
+    }
+}
@@ -0,0 +1,51 @@
+use anyhow::{Result, bail};
+use solana_sdk::{instruction::Instruction, pubkey::Pubkey};
+
+/// Rendered memos longer than this don't fit into a transaction next to a transfer
+pub const MAX_MEMO_BYTES: usize = 566;
+
+/// Placeholders a memo template may contain
+const PLACEHOLDERS: &[&str] = &["destination", "index"];
+
+/// Rejects templates with unknown or unclosed placeholders, so a typo shows
+/// up when the config is loaded instead of on chain
+pub fn check_template(template: &str) -> Result<()> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            bail!("Unclosed placeholder in memo {:?}", template);
+        };
+        let name = &rest[start + 1..start + len];
+        if !PLACEHOLDERS.contains(&name) {
+            bail!(
+                "Unknown placeholder {{{}}} in memo {:?}, expected one of {{destination}}, {{index}}",
+                name,
+                template
+            );
+        }
+        rest = &rest[start + len + 1..];
+    }
+    Ok(())
+}
+
+/// Fills in `{destination}` and `{index}`, the transfer's 1-based position
+/// in the config
+pub fn render(template: &str, index: usize, destination: &str) -> Result<String> {
+    let memo = template
+        .replace("{destination}", destination)
+        .replace("{index}", &index.to_string());
+    if memo.len() > MAX_MEMO_BYTES {
+        bail!(
+            "Memo for transfer #{} is {} bytes, at most {} fit into a transaction",
+            index,
+            memo.len(),
+            MAX_MEMO_BYTES
+        );
+    }
+    Ok(memo)
+}
+
+/// Memo instruction signed by the transfer's source, which signs anyway
+pub fn instruction(memo: &str, signer: &Pubkey) -> Instruction {
+    spl_memo::build_memo(memo.as_bytes(), &[signer])
+}
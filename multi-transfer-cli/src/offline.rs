@@ -0,0 +1,282 @@
+use crate::amount::{self, SOL_DECIMALS};
+use crate::batch;
+use crate::transfer::{self, Landed, TransferExecutor};
+use crate::types::{TransferResult, TransferSpec, TransferStatus};
+use anyhow::{Context, Result, bail};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use chrono::Utc;
+use common::{SolanaRpc, keypair};
+use serde::{Deserialize, Serialize};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{
+    commitment_config::CommitmentConfig, hash::Hash, signature::Signature, transaction::Transaction,
+};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::Instant,
+};
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+/// A transaction written by `--sign-only`, one JSON object per line
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignedTransaction {
+    /// Base64 of the serialized transaction, as `sendTransaction` takes it
+    pub transaction: String,
+    pub signature: String,
+    pub blockhash: String,
+    /// The transaction can't land once the chain is past this block height
+    pub last_valid_block_height: u64,
+    pub from: String,
+    pub transfers: Vec<SignedTransfer>,
+}
+
+/// One transfer of a signed transaction, to report it after broadcasting
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignedTransfer {
+    pub to: String,
+    /// Lamports, or raw token units when `token_mint` is set
+    pub lamports: u64,
+    pub token_mint: Option<String>,
+    pub decimals: u8,
+    #[serde(default)]
+    pub rent_top_up_lamports: Option<u64>,
+    #[serde(default)]
+    pub new_account: bool,
+    #[serde(default)]
+    pub memo: Option<String>,
+}
+
+impl SignedTransaction {
+    fn new(tx: &Transaction, last_valid_block_height: u64, batch: &[TransferSpec]) -> Result<Self> {
+        let serialized = bincode::serialize(tx).context("Failed to serialize transaction")?;
+        Ok(Self {
+            transaction: BASE64.encode(serialized),
+            signature: tx.signatures[0].to_string(),
+            blockhash: tx.message.recent_blockhash.to_string(),
+            last_valid_block_height,
+            from: tx.message.account_keys[0].to_string(),
+            transfers: batch
+                .iter()
+                .map(|transfer| SignedTransfer {
+                    to: transfer.to_address.clone(),
+                    lamports: transfer.amount,
+                    token_mint: transfer.token.as_ref().map(|token| token.mint.clone()),
+                    decimals: transfer
+                        .token
+                        .as_ref()
+                        .map_or(SOL_DECIMALS, |token| token.decimals),
+                    rent_top_up_lamports: (transfer.rent_top_up > 0)
+                        .then_some(transfer.rent_top_up),
+                    new_account: transfer.new_account,
+                    memo: transfer.memo.clone(),
+                })
+                .collect(),
+        })
+    }
+
+    /// The transaction, checked to be signed and to match the recorded signature
+    fn decode(&self) -> Result<Transaction> {
+        let serialized = BASE64
+            .decode(&self.transaction)
+            .context("Transaction is not valid base64")?;
+        let tx: Transaction =
+            bincode::deserialize(&serialized).context("Failed to deserialize transaction")?;
+        if tx.signatures.first().map(Signature::to_string).as_deref() != Some(&self.signature) {
+            bail!(
+                "Transaction doesn't carry the recorded signature {}",
+                self.signature
+            );
+        }
+        tx.verify()
+            .context("Transaction signature doesn't verify")?;
+        Ok(tx)
+    }
+}
+
+/// Builds and signs every transfer, packed like a normal run, with one fresh
+/// blockhash and writes the transactions to `path`. Nothing is sent. Returns
+/// the number of transactions and the block height they're valid until
+pub async fn sign_all<R: SolanaRpc + 'static>(
+    rpc_client: &RpcClient,
+    executor: &TransferExecutor<R>,
+    transfers: Vec<TransferSpec>,
+    batch_per_tx: usize,
+    path: &Path,
+) -> Result<(usize, u64)> {
+    let (blockhash, last_valid_block_height) = rpc_client
+        .get_latest_blockhash_with_commitment(CommitmentConfig::confirmed())
+        .await
+        .context("Failed to get recent blockhash")?;
+    info!(
+        "Signing with blockhash {}, valid until block height {}",
+        blockhash, last_valid_block_height
+    );
+
+    let mut signed = Vec::new();
+    for batch in batch::group_transfers(transfers, batch_per_tx) {
+        let tx = executor.sign(&batch, blockhash).await.map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to sign transfer {} -> {}: {}",
+                keypair::describe(&batch[0].from_keypair_path),
+                batch[0].to_address,
+                e
+            )
+        })?;
+        signed.push(SignedTransaction::new(
+            &tx,
+            last_valid_block_height,
+            &batch,
+        )?);
+    }
+
+    let file =
+        File::create(path).with_context(|| format!("Failed to create signed file {:?}", path))?;
+    let mut writer = BufWriter::new(file);
+    for transaction in &signed {
+        serde_json::to_writer(&mut writer, transaction)?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok((signed.len(), last_valid_block_height))
+}
+
+/// Reads the transactions a `--sign-only` run wrote to `path`
+pub fn read_signed(path: &Path) -> Result<Vec<SignedTransaction>> {
+    let file =
+        File::open(path).with_context(|| format!("Failed to open signed file {:?}", path))?;
+    let mut signed = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        signed.push(
+            serde_json::from_str(&line)
+                .with_context(|| format!("Invalid signed transaction on line {}", i + 1))?,
+        );
+    }
+    Ok(signed)
+}
+
+/// Sends the signed transactions, up to `concurrent` at once, and reports
+/// every transfer in them in file order. Transactions whose blockhash already
+/// expired are failed without sending, they have to be signed again
+pub async fn broadcast_all<R: SolanaRpc + 'static>(
+    rpc_client: Arc<RpcClient>,
+    executor: Arc<TransferExecutor<R>>,
+    signed: Vec<SignedTransaction>,
+    concurrent: usize,
+) -> Result<Vec<TransferResult>> {
+    let mut expired = HashMap::new();
+    for transaction in &signed {
+        if expired.contains_key(&transaction.blockhash) {
+            continue;
+        }
+        let blockhash = Hash::from_str(&transaction.blockhash)
+            .with_context(|| format!("Invalid blockhash {}", transaction.blockhash))?;
+        let valid = rpc_client
+            .is_blockhash_valid(&blockhash, CommitmentConfig::processed())
+            .await
+            .context("Failed to check the blockhash of the signed transactions")?;
+        if !valid {
+            warn!(
+                "Blockhash {} expired, its transactions have to be signed again",
+                transaction.blockhash
+            );
+        }
+        expired.insert(transaction.blockhash.clone(), !valid);
+    }
+
+    let semaphore = Arc::new(Semaphore::new(concurrent.max(1)));
+    let mut handles = Vec::new();
+    for transaction in signed {
+        let rpc_client = rpc_client.clone();
+        let executor = executor.clone();
+        let semaphore = semaphore.clone();
+        let expired = expired[&transaction.blockhash];
+        handles.push(tokio::spawn(async move {
+            let _permit = semaphore.acquire().await;
+            let started_at = Utc::now();
+            let start = Instant::now();
+            let (signature, status) = match transaction.decode() {
+                Ok(_) if expired => (
+                    transaction.signature.parse().unwrap_or_default(),
+                    TransferStatus::Failed(format!(
+                        "Blockhash {} expired before broadcasting",
+                        transaction.blockhash
+                    )),
+                ),
+                Ok(tx) => {
+                    info!("Broadcasting {}", tx.signatures[0]);
+                    (tx.signatures[0], executor.send_signed(&tx).await)
+                }
+                Err(e) => (
+                    Signature::default(),
+                    TransferStatus::Failed(format!("{:#}", e)),
+                ),
+            };
+            // Failed transactions that landed paid their fee too
+            let landed = match status {
+                TransferStatus::Success | TransferStatus::Failed(_)
+                    if signature != Signature::default() && !expired =>
+                {
+                    transfer::fetch_landed(&*rpc_client, &signature).await
+                }
+                _ => Landed::default(),
+            };
+            results_for(transaction, signature, status, landed, started_at, start)
+        }));
+    }
+
+    let mut results = Vec::new();
+    for handle in handles {
+        results.extend(handle.await.context("Broadcast task panicked")?);
+    }
+    Ok(results)
+}
+
+fn results_for(
+    transaction: SignedTransaction,
+    signature: Signature,
+    status: TransferStatus,
+    landed: Landed,
+    started_at: chrono::DateTime<Utc>,
+    start: Instant,
+) -> Vec<TransferResult> {
+    let duration_ms = start.elapsed().as_millis() as u64;
+    let finished_at = Utc::now();
+    let success = matches!(status, TransferStatus::Success);
+    let count = transaction.transfers.len();
+    transaction
+        .transfers
+        .into_iter()
+        .enumerate()
+        .map(|(i, transfer)| TransferResult {
+            from: transaction.from.clone(),
+            to: transfer.to,
+            amount: amount::to_ui_amount(transfer.lamports, transfer.decimals),
+            lamports: transfer.lamports,
+            token_mint: transfer.token_mint,
+            signature,
+            priority_fee_micro_lamports: None,
+            fee_lamports: landed.fee.map(|fee| transfer::fee_share(fee, count, i)),
+            slot: landed.slot,
+            duration_ms,
+            attempts: 1,
+            status: status.clone(),
+            reclaimed_rent_lamports: None,
+            created_account: transfer.new_account && success,
+            rent_top_up_lamports: transfer.rent_top_up_lamports,
+            memo: transfer.memo,
+            verification: None,
+            started_at,
+            finished_at,
+        })
+        .collect()
+}
@@ -0,0 +1,170 @@
+use crate::types::SourcePlan;
+use anyhow::{Context, Result, bail};
+use common::lamports_to_sol;
+use std::fmt::Write as _;
+use std::io::{self, IsTerminal, Write};
+
+/// How the transfers are going to be sent, shown with the plan
+pub struct PlanSettings {
+    /// Transfers in flight at once across all sources
+    pub concurrent: usize,
+    pub batch_per_tx: usize,
+    pub timeout_secs: u64,
+    pub max_retries: u32,
+}
+
+/// The plan grouped by source wallet: transfers, destinations, SOL sent and
+/// estimated fees per wallet, their totals and the send settings
+pub fn render_plan(sources: &[SourcePlan], settings: &PlanSettings) -> String {
+    let mut plan = format!("{:-^80}\n", " PLAN ");
+    for source in sources {
+        let _ = write!(
+            plan,
+            "  {}: {} transfers to {} destinations, {:.9} SOL",
+            source.pubkey,
+            source.transfer_count,
+            source.destination_count,
+            lamports_to_sol(source.lamports)
+        );
+        if source.token_transfer_count > 0 {
+            let _ = write!(plan, " and {} token transfers", source.token_transfer_count);
+        }
+        let _ = write!(
+            plan,
+            ", est. fees {:.9} SOL",
+            lamports_to_sol(source.fee_lamports)
+        );
+        if source.new_token_accounts > 0 {
+            let _ = write!(
+                plan,
+                ", {:.9} SOL rent for {} new token accounts",
+                lamports_to_sol(source.token_account_rent_lamports),
+                source.new_token_accounts
+            );
+        }
+        match source.max_concurrent {
+            1 => plan.push_str(", one at a time"),
+            max_concurrent => {
+                let _ = write!(plan, ", at most {} in flight", max_concurrent);
+            }
+        }
+        plan.push('\n');
+    }
+
+    let transfer_count: u64 = sources.iter().map(|source| source.transfer_count).sum();
+    let lamports: u64 = sources.iter().map(|source| source.lamports).sum();
+    let fee_lamports: u64 = sources.iter().map(|source| source.fee_lamports).sum();
+    let rent_lamports: u64 = sources
+        .iter()
+        .map(|source| source.token_account_rent_lamports)
+        .sum();
+    let _ = write!(
+        plan,
+        "  Total: {} transfers from {} wallets, {:.9} SOL, est. fees {:.9} SOL",
+        transfer_count,
+        sources.len(),
+        lamports_to_sol(lamports),
+        lamports_to_sol(fee_lamports)
+    );
+    if rent_lamports > 0 {
+        let _ = write!(
+            plan,
+            ", {:.9} SOL token account rent",
+            lamports_to_sol(rent_lamports)
+        );
+    }
+    plan.push('\n');
+    let _ = write!(
+        plan,
+        "  Up to {} transfers in flight, {} per transaction, {}s confirmation timeout, {} retries",
+        settings.concurrent, settings.batch_per_tx, settings.timeout_secs, settings.max_retries
+    );
+    plan
+}
+
+/// Asks on the terminal whether to go ahead. Fails right away when stdin isn't
+/// a terminal, since nobody could answer and waiting would hang the run
+pub fn confirm() -> Result<bool> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        bail!("stdin is not a terminal, pass --yes to send without confirmation");
+    }
+
+    eprint!("Proceed? [y/N] ");
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    stdin
+        .read_line(&mut answer)
+        .context("Failed to read the confirmation")?;
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::pubkey::Pubkey;
+
+    const SETTINGS: PlanSettings = PlanSettings {
+        concurrent: 8,
+        batch_per_tx: 1,
+        timeout_secs: 60,
+        max_retries: 3,
+    };
+
+    fn source(transfer_count: u64, lamports: u64, max_concurrent: usize) -> SourcePlan {
+        SourcePlan {
+            pubkey: Pubkey::new_unique(),
+            transfer_count,
+            destination_count: transfer_count as usize,
+            lamports,
+            token_transfer_count: 0,
+            fee_lamports: transfer_count * 5000,
+            new_token_accounts: 0,
+            token_account_rent_lamports: 0,
+            max_concurrent,
+        }
+    }
+
+    #[test]
+    fn renders_each_source_and_the_totals() {
+        let sol = source(2, 1_500_000_000, 1);
+        let mut token = source(3, 0, 4);
+        token.token_transfer_count = 3;
+        token.destination_count = 2;
+        token.new_token_accounts = 2;
+        token.token_account_rent_lamports = 4_078_560;
+
+        let (sol_pubkey, token_pubkey) = (sol.pubkey, token.pubkey);
+
+        let plan = render_plan(&[sol, token], &SETTINGS);
+        let lines: Vec<&str> = plan.lines().collect();
+        assert_eq!(lines[0], format!("{:-^80}", " PLAN "));
+        assert_eq!(
+            lines[1..],
+            [
+                format!(
+                    "  {}: 2 transfers to 2 destinations, 1.500000000 SOL, est. fees 0.000010000 SOL, one at a time",
+                    sol_pubkey
+                ),
+                format!(
+                    "  {}: 3 transfers to 2 destinations, 0.000000000 SOL and 3 token transfers, est. fees 0.000015000 SOL, 0.004078560 SOL rent for 2 new token accounts, at most 4 in flight",
+                    token_pubkey
+                ),
+                "  Total: 5 transfers from 2 wallets, 1.500000000 SOL, est. fees 0.000025000 SOL, 0.004078560 SOL token account rent".to_string(),
+                "  Up to 8 transfers in flight, 1 per transaction, 60s confirmation timeout, 3 retries".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_out_rent_when_no_token_accounts_are_created() {
+        let plan = render_plan(&[source(1, 1, 2)], &SETTINGS);
+        assert!(!plan.contains("rent"), "{}", plan);
+        assert!(plan.contains(
+            "  Total: 1 transfers from 1 wallets, 0.000000001 SOL, est. fees 0.000005000 SOL\n"
+        ));
+    }
+}
@@ -0,0 +1,529 @@
+use crate::runner::DEFAULT_SOURCE_LIMIT;
+use crate::types::{SourcePlan, SourceShortfall, TransferSpec};
+use anyhow::{Context, Result, anyhow};
+use common::keypair;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{
+    hash::Hash, message::Message, program_pack::Pack, pubkey::Pubkey, signer::Signer,
+    system_instruction,
+};
+use spl_associated_token_account::get_associated_token_address;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    str::FromStr,
+};
+
+/// Accounts per `getMultipleAccounts` request, the RPC's limit
+const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+
+/// Free endpoints run by Solana, limited to 100 requests per 10 seconds per IP
+const PUBLIC_RPC_HOSTS: &[&str] = &[
+    "api.mainnet-beta.solana.com",
+    "api.devnet.solana.com",
+    "api.testnet.solana.com",
+];
+/// Transfers in flight that a public endpoint still serves without throttling
+/// most of them, each one polls its status twice a second
+const PUBLIC_RPC_MAX_CONCURRENT: usize = 10;
+/// Transfers in flight beyond which even paid plans usually rate limit
+const RPC_MAX_CONCURRENT: usize = 200;
+
+/// Checks the generated transfers for problems that would make them fail
+/// regardless of on-chain state: unreadable keypair files and invalid
+/// destination addresses. Each path and address is checked once.
+/// Returns a human-readable description of every problem found.
+pub fn validate_transfers(transfers: &[TransferSpec]) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut checked_paths = BTreeSet::new();
+    let mut checked_addresses = BTreeSet::new();
+
+    for transfer in transfers {
+        if checked_addresses.insert(transfer.to_address.as_str())
+            && let Err(e) = Pubkey::from_str(&transfer.to_address)
+        {
+            problems.push(format!(
+                "Invalid destination address {}: {}",
+                transfer.to_address, e
+            ));
+        }
+
+        let path = transfer.from_keypair_path.as_str();
+        if let Some(token) = &transfer.token
+            && checked_addresses.insert(token.mint.as_str())
+            && let Err(e) = Pubkey::from_str(&token.mint)
+        {
+            problems.push(format!("Invalid token mint {}: {}", token.mint, e));
+        }
+
+        if !checked_paths.insert(path) {
+            continue;
+        }
+        if let Err(e) = keypair::load_keypair(path) {
+            problems.push(format!(
+                "Cannot load keypair {}: {}",
+                keypair::describe(path),
+                e
+            ));
+        }
+    }
+
+    problems
+}
+
+/// Sums what every source wallet sends and the base and priority fees of its
+/// transfers. Sources are grouped by pubkey, so the same keypair listed
+/// several times is planned once. Sources whose keypair can't be loaded are
+/// skipped, see [`validate_transfers`].
+///
+/// `fee_per_transfer` overrides the fee otherwise derived from `get_fee_for_message`.
+/// `source_limits` are the per-source concurrency limits by keypair path.
+/// With `create_ata`, the rent of the missing destination token accounts is
+/// added as well. Fails if what a source needs doesn't fit in a `u64` of lamports
+pub async fn source_plans(
+    rpc_client: &RpcClient,
+    transfers: &[TransferSpec],
+    fee_per_transfer: Option<u64>,
+    source_limits: &HashMap<String, usize>,
+    create_ata: bool,
+) -> Result<Vec<SourcePlan>> {
+    let mut sources: BTreeMap<Pubkey, SourcePlan> = BTreeMap::new();
+    let mut destinations: BTreeMap<Pubkey, BTreeSet<&str>> = BTreeMap::new();
+    // Destination token accounts per source, only collected with `create_ata`
+    let mut token_accounts: BTreeMap<Pubkey, BTreeSet<Pubkey>> = BTreeMap::new();
+    let mut pubkeys_by_path = BTreeMap::new();
+
+    for transfer in transfers {
+        let path = transfer.from_keypair_path.as_str();
+        let pubkey = match pubkeys_by_path.get(path) {
+            Some(pubkey) => *pubkey,
+            None => match keypair::load_keypair(path) {
+                Ok(keypair) => *pubkeys_by_path.entry(path).or_insert(keypair.pubkey()),
+                Err(_) => continue,
+            },
+        };
+
+        let source = sources.entry(pubkey).or_insert_with(|| SourcePlan {
+            pubkey,
+            transfer_count: 0,
+            destination_count: 0,
+            lamports: 0,
+            token_transfer_count: 0,
+            fee_lamports: 0,
+            new_token_accounts: 0,
+            token_account_rent_lamports: 0,
+            max_concurrent: source_limits
+                .get(path)
+                .copied()
+                .unwrap_or(DEFAULT_SOURCE_LIMIT),
+        });
+        source.transfer_count += 1;
+        source.fee_lamports = checked_sum(
+            source.fee_lamports,
+            transfer.priority_fee_lamports(),
+            &pubkey,
+        )?;
+        // Token transfers only cost SOL in fees
+        match transfer.token {
+            Some(_) => source.token_transfer_count += 1,
+            None => source.lamports = checked_sum(source.lamports, transfer.amount, &pubkey)?,
+        }
+        destinations
+            .entry(pubkey)
+            .or_default()
+            .insert(&transfer.to_address);
+        if create_ata
+            && let Some(token) = &transfer.token
+            && let (Ok(to), Ok(mint)) = (
+                Pubkey::from_str(&transfer.to_address),
+                Pubkey::from_str(&token.mint),
+            )
+        {
+            token_accounts
+                .entry(pubkey)
+                .or_default()
+                .insert(get_associated_token_address(&to, &mint));
+        }
+    }
+
+    let all_token_accounts: BTreeSet<Pubkey> = token_accounts.values().flatten().copied().collect();
+    if !all_token_accounts.is_empty() {
+        let missing = missing_accounts(rpc_client, all_token_accounts)
+            .await
+            .context("Failed to look up destination token accounts")?;
+        if !missing.is_empty() {
+            let rent = rpc_client
+                .get_minimum_balance_for_rent_exemption(spl_token::state::Account::LEN)
+                .await
+                .context("Failed to fetch the rent-exempt minimum of a token account")?;
+            add_token_account_rent(&mut sources, &token_accounts, &missing, rent)?;
+        }
+    }
+
+    // Only fetched if a fee has to be estimated
+    let mut recent_blockhash: Option<Hash> = None;
+    let mut plans = Vec::new();
+    for (pubkey, mut source) in sources {
+        let fee = match fee_per_transfer {
+            Some(fee) => fee,
+            None => {
+                let blockhash = match recent_blockhash {
+                    Some(blockhash) => blockhash,
+                    None => *recent_blockhash.insert(
+                        rpc_client
+                            .get_latest_blockhash()
+                            .await
+                            .context("Failed to get recent blockhash for fee estimation")?,
+                    ),
+                };
+                estimate_transfer_fee(rpc_client, &pubkey, blockhash)
+                    .await
+                    .with_context(|| format!("Failed to estimate fee for {}", pubkey))?
+            }
+        };
+        let base_fees = fee
+            .checked_mul(source.transfer_count)
+            .ok_or_else(|| too_large(&pubkey))?;
+        source.fee_lamports = checked_sum(source.fee_lamports, base_fees, &pubkey)?;
+        // Makes sure `required_lamports` fits as well
+        checked_sum(
+            checked_sum(source.lamports, source.fee_lamports, &pubkey)?,
+            source.token_account_rent_lamports,
+            &pubkey,
+        )?;
+        source.destination_count = destinations[&pubkey].len();
+        plans.push(source);
+    }
+
+    Ok(plans)
+}
+
+/// Charges every source the rent of each of its destination token accounts
+/// that is `missing`. Sources sending to the same missing account are all
+/// charged, whichever goes first creates it
+fn add_token_account_rent(
+    sources: &mut BTreeMap<Pubkey, SourcePlan>,
+    token_accounts: &BTreeMap<Pubkey, BTreeSet<Pubkey>>,
+    missing: &HashSet<Pubkey>,
+    rent: u64,
+) -> Result<()> {
+    for (pubkey, accounts) in token_accounts {
+        let Some(source) = sources.get_mut(pubkey) else {
+            continue;
+        };
+        source.new_token_accounts = accounts
+            .iter()
+            .filter(|account| missing.contains(account))
+            .count() as u64;
+        source.token_account_rent_lamports = rent
+            .checked_mul(source.new_token_accounts)
+            .ok_or_else(|| too_large(pubkey))?;
+    }
+    Ok(())
+}
+
+/// Which of `pubkeys` don't exist on chain
+async fn missing_accounts(
+    rpc_client: &RpcClient,
+    pubkeys: impl IntoIterator<Item = Pubkey>,
+) -> Result<HashSet<Pubkey>> {
+    let pubkeys: Vec<Pubkey> = pubkeys.into_iter().collect();
+    let mut missing = HashSet::new();
+    for chunk in pubkeys.chunks(MAX_MULTIPLE_ACCOUNTS) {
+        let accounts = rpc_client.get_multiple_accounts(chunk).await?;
+        for (pubkey, account) in chunk.iter().zip(accounts) {
+            if account.is_none() {
+                missing.insert(*pubkey);
+            }
+        }
+    }
+    Ok(missing)
+}
+
+fn checked_sum(a: u64, b: u64, source: &Pubkey) -> Result<u64> {
+    a.checked_add(b).ok_or_else(|| too_large(source))
+}
+
+fn too_large(source: &Pubkey) -> anyhow::Error {
+    anyhow!(
+        "Transfers from {} add up to more than {} lamports, check their amounts",
+        source,
+        u64::MAX
+    )
+}
+
+/// Compares what every source wallet needs for all of its transfers plus fees
+/// against its current balance
+pub async fn check_balances(
+    rpc_client: &RpcClient,
+    sources: &[SourcePlan],
+) -> Result<Vec<SourceShortfall>> {
+    let mut shortfalls = Vec::new();
+    for source in sources {
+        let balance_lamports = rpc_client
+            .get_balance(&source.pubkey)
+            .await
+            .with_context(|| format!("Failed to fetch balance of {}", source.pubkey))?;
+
+        let required_lamports = source.required_lamports();
+        if balance_lamports < required_lamports {
+            shortfalls.push(SourceShortfall {
+                pubkey: source.pubkey,
+                transfer_count: source.transfer_count,
+                balance_lamports,
+                required_lamports,
+            });
+        }
+    }
+
+    Ok(shortfalls)
+}
+
+/// Advice for a run that will likely be rate limited by `rpc_urls` with
+/// `concurrent` transfers in flight, `None` if it looks fine. Sends rotate
+/// through the endpoints, so each one sees its share. Only a heuristic based
+/// on the endpoints, the RPCs' real limits are unknown
+pub fn concurrency_warning(rpc_urls: &[String], concurrent: usize) -> Option<String> {
+    let hosts: Vec<String> = rpc_urls
+        .iter()
+        .filter_map(|url| reqwest::Url::parse(url).ok())
+        .filter_map(|url| url.host_str().map(str::to_string))
+        .collect();
+    if hosts.is_empty() {
+        return None;
+    }
+    let per_endpoint = concurrent.div_ceil(rpc_urls.len());
+
+    if let Some(host) = hosts
+        .iter()
+        .find(|host| PUBLIC_RPC_HOSTS.contains(&host.as_str()))
+    {
+        (per_endpoint > PUBLIC_RPC_MAX_CONCURRENT).then(|| {
+            format!(
+                "{} transfers in flight against the public endpoint {} will mostly be rate limited (HTTP 429). \
+                Use --concurrent {} or less, or a dedicated RPC",
+                per_endpoint,
+                host,
+                PUBLIC_RPC_MAX_CONCURRENT * rpc_urls.len()
+            )
+        })
+    } else {
+        (per_endpoint > RPC_MAX_CONCURRENT).then(|| {
+            format!(
+                "{} transfers in flight per endpoint exceed the rate limits of most RPC plans, \
+                consider a lower --concurrent or --confirm-mode batched",
+                per_endpoint
+            )
+        })
+    }
+}
+
+/// Lamports sent by all SOL transfers, token transfers are not counted.
+/// Fails if they don't fit in a `u64`
+pub fn total_lamports(transfers: &[TransferSpec]) -> Result<u64> {
+    transfers
+        .iter()
+        .filter(|transfer| transfer.token.is_none())
+        .try_fold(0u64, |total, transfer| total.checked_add(transfer.amount))
+        .ok_or_else(|| {
+            anyhow!(
+                "Transfers add up to more than {} lamports, check their amounts",
+                u64::MAX
+            )
+        })
+}
+
+/// Sets `new_account` on every SOL transfer whose destination doesn't exist on
+/// chain yet and returns the rent-exempt minimum such a transfer must send, or
+/// `None` if every destination exists. Invalid destinations are skipped, see
+/// [`validate_transfers`]
+pub async fn mark_new_destinations(
+    rpc_client: &RpcClient,
+    transfers: &mut [TransferSpec],
+) -> Result<Option<u64>> {
+    // Token transfers go to token accounts, which `--create-ata` takes care of
+    let destinations: BTreeSet<Pubkey> = transfers
+        .iter()
+        .filter(|transfer| transfer.token.is_none())
+        .filter_map(|transfer| Pubkey::from_str(&transfer.to_address).ok())
+        .collect();
+
+    let new_destinations: HashSet<String> = missing_accounts(rpc_client, destinations)
+        .await
+        .context("Failed to look up destination accounts")?
+        .iter()
+        .map(Pubkey::to_string)
+        .collect();
+    if new_destinations.is_empty() {
+        return Ok(None);
+    }
+
+    for transfer in transfers.iter_mut() {
+        transfer.new_account =
+            transfer.token.is_none() && new_destinations.contains(&transfer.to_address);
+    }
+    let rent = rpc_client
+        .get_minimum_balance_for_rent_exemption(0)
+        .await
+        .context("Failed to fetch the rent-exempt minimum")?;
+    Ok(Some(rent))
+}
+
+/// Fee for a single-signature SOL transfer paid by `payer`
+async fn estimate_transfer_fee(
+    rpc_client: &RpcClient,
+    payer: &Pubkey,
+    recent_blockhash: Hash,
+) -> Result<u64> {
+    let instruction = system_instruction::transfer(payer, payer, 0);
+    let message = Message::new_with_blockhash(&[instruction], Some(payer), &recent_blockhash);
+    Ok(rpc_client.get_fee_for_message(&message).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TokenSpec;
+    use solana_sdk::signature::Keypair;
+
+    fn transfer(from: &Keypair, amount: u64) -> TransferSpec {
+        TransferSpec {
+            from_keypair_path: format!("base58:{}", from.to_base58_string()),
+            to_address: Pubkey::new_unique().to_string(),
+            amount,
+            token: None,
+            compute_unit_limit: None,
+            compute_unit_price: None,
+            new_account: false,
+            rent_top_up: 0,
+            memo: None,
+        }
+    }
+
+    #[test]
+    fn reports_every_invalid_address_together() {
+        let from = Keypair::new();
+        let mut first = transfer(&from, 1);
+        first.to_address = "not-an-address".to_string();
+        let mut second = transfer(&from, 1);
+        second.to_address = "0OIl".to_string();
+        // Checked once, however many transfers go there
+        let mut repeated = transfer(&from, 1);
+        repeated.to_address = first.to_address.clone();
+        let mut token = transfer(&from, 1);
+        token.token = Some(TokenSpec {
+            mint: "bad-mint".to_string(),
+            decimals: 6,
+        });
+        let valid = transfer(&from, 1);
+
+        let problems = validate_transfers(&[first, valid, second, repeated, token]);
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].starts_with("Invalid destination address not-an-address"));
+        assert!(problems[1].starts_with("Invalid destination address 0OIl"));
+        assert!(problems[2].starts_with("Invalid token mint bad-mint"));
+    }
+
+    #[test]
+    fn reports_unloadable_keypairs_once() {
+        let mut first = transfer(&Keypair::new(), 1);
+        first.from_keypair_path = "/nonexistent/keypair.json".to_string();
+        let mut second = transfer(&Keypair::new(), 1);
+        second.from_keypair_path = first.from_keypair_path.clone();
+
+        let problems = validate_transfers(&[first, second]);
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("Cannot load keypair"));
+    }
+
+    /// Never called while the fee per transfer is given
+    fn offline_client() -> RpcClient {
+        RpcClient::new("http://127.0.0.1:1".to_string())
+    }
+
+    #[test]
+    fn total_lamports_fails_instead_of_overflowing() {
+        let from = Keypair::new();
+        assert_eq!(
+            total_lamports(&[transfer(&from, 1), transfer(&from, 2)]).unwrap(),
+            3
+        );
+        assert!(total_lamports(&[transfer(&from, u64::MAX), transfer(&from, 1)]).is_err());
+    }
+
+    #[tokio::test]
+    async fn source_plans_add_up_amounts_and_fees() {
+        let from = Keypair::new();
+        let transfers = [transfer(&from, 10), transfer(&from, 20)];
+        let plans = source_plans(
+            &offline_client(),
+            &transfers,
+            Some(5000),
+            &HashMap::new(),
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(plans.len(), 1);
+        assert_eq!(plans[0].lamports, 30);
+        assert_eq!(plans[0].fee_lamports, 10_000);
+        assert_eq!(plans[0].required_lamports(), 10_030);
+        assert_eq!(plans[0].destination_count, 2);
+    }
+
+    #[tokio::test]
+    async fn source_plans_fail_instead_of_overflowing() {
+        let from = Keypair::new();
+        let amounts = [transfer(&from, u64::MAX), transfer(&from, 1)];
+        let error = source_plans(&offline_client(), &amounts, Some(0), &HashMap::new(), false)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains(&from.pubkey().to_string()));
+
+        // Fits on its own, but not with the fees on top
+        let fees = [transfer(&from, u64::MAX)];
+        assert!(
+            source_plans(&offline_client(), &fees, Some(5000), &HashMap::new(), false)
+                .await
+                .is_err()
+        );
+    }
+
+    fn plan(pubkey: Pubkey) -> SourcePlan {
+        SourcePlan {
+            pubkey,
+            transfer_count: 2,
+            destination_count: 2,
+            lamports: 0,
+            token_transfer_count: 2,
+            fee_lamports: 10_000,
+            new_token_accounts: 0,
+            token_account_rent_lamports: 0,
+            max_concurrent: 1,
+        }
+    }
+
+    #[test]
+    fn charges_each_source_the_token_accounts_it_creates() {
+        const RENT: u64 = 2_039_280;
+        let (first, second) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (shared, existing, own) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let mut sources = BTreeMap::from([(first, plan(first)), (second, plan(second))]);
+        let token_accounts = BTreeMap::from([
+            (first, BTreeSet::from([shared, existing, own])),
+            (second, BTreeSet::from([shared, existing])),
+        ]);
+        let missing = HashSet::from([shared, own]);
+        add_token_account_rent(&mut sources, &token_accounts, &missing, RENT).unwrap();
+
+        assert_eq!(sources[&first].new_token_accounts, 2);
+        assert_eq!(sources[&first].token_account_rent_lamports, 2 * RENT);
+        assert_eq!(sources[&first].required_lamports(), 10_000 + 2 * RENT);
+        assert_eq!(sources[&second].new_token_accounts, 1);
+        assert_eq!(sources[&second].required_lamports(), 10_000 + RENT);
+    }
+}
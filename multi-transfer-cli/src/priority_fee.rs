@@ -0,0 +1,45 @@
+use common::SolanaRpc;
+use solana_client::client_error::ClientError;
+use solana_sdk::pubkey::Pubkey;
+
+/// How `--priority-fee auto` picks the compute unit price
+#[derive(Debug, Clone, Copy)]
+pub struct AutoPriorityFee {
+    /// Percentile of the recent fees paid for the involved accounts
+    pub percentile: u8,
+    /// Upper bound in micro-lamports per compute unit
+    pub max_micro_lamports: Option<u64>,
+}
+
+impl AutoPriorityFee {
+    /// Compute unit price in micro-lamports, based on the fees recently paid by
+    /// transactions that locked any of `accounts`
+    pub async fn estimate<R: SolanaRpc>(
+        &self,
+        rpc_client: &R,
+        accounts: &[Pubkey],
+    ) -> Result<u64, ClientError> {
+        let mut fees: Vec<u64> = rpc_client
+            .get_recent_prioritization_fees(accounts)
+            .await?
+            .into_iter()
+            .map(|fee| fee.prioritization_fee)
+            .collect();
+
+        let fee = percentile(&mut fees, self.percentile);
+        Ok(match self.max_micro_lamports {
+            Some(max) => fee.min(max),
+            None => fee,
+        })
+    }
+}
+
+/// Nearest-rank percentile, 0 for an empty list
+fn percentile(values: &mut [u64], percentile: u8) -> u64 {
+    if values.is_empty() {
+        return 0;
+    }
+    values.sort_unstable();
+    let rank = (values.len() * percentile.min(100) as usize).div_ceil(100);
+    values[rank.saturating_sub(1)]
+}
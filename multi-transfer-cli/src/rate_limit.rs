@@ -0,0 +1,179 @@
+use common::{SolanaRpc, rate_limit::RateLimiter, retry::is_rate_limited};
+use solana_client::{
+    client_error::Result as ClientResult,
+    rpc_config::{RpcSendTransactionConfig, RpcSimulateTransactionConfig, RpcTransactionConfig},
+    rpc_response::{RpcPrioritizationFee, RpcResult, RpcSimulateTransactionResult},
+};
+use solana_sdk::{
+    account::Account,
+    commitment_config::CommitmentConfig,
+    hash::Hash,
+    message::Message,
+    pubkey::Pubkey,
+    signature::Signature,
+    transaction::{self, Transaction},
+};
+use solana_transaction_status::{EncodedConfirmedTransactionWithStatusMeta, TransactionStatus};
+use std::future::Future;
+
+/// An RPC client whose calls all wait for the limiter first and report rate
+/// limits back to it. Without a limiter calls go straight through
+pub struct RateLimited<R> {
+    inner: R,
+    limiter: Option<RateLimiter>,
+}
+
+impl<R: SolanaRpc> RateLimited<R> {
+    pub fn new(inner: R, limiter: Option<RateLimiter>) -> Self {
+        Self { inner, limiter }
+    }
+
+    async fn call<T>(&self, request: impl Future<Output = ClientResult<T>>) -> ClientResult<T> {
+        let Some(limiter) = &self.limiter else {
+            return request.await;
+        };
+        limiter.acquire().await;
+        let result = request.await;
+        if let Err(e) = &result
+            && is_rate_limited(e)
+        {
+            limiter.on_rate_limited();
+        }
+        result
+    }
+}
+
+impl<R: SolanaRpc> SolanaRpc for RateLimited<R> {
+    fn url(&self) -> String {
+        self.inner.url()
+    }
+
+    fn commitment(&self) -> CommitmentConfig {
+        self.inner.commitment()
+    }
+
+    fn get_balance(&self, pubkey: &Pubkey) -> impl Future<Output = ClientResult<u64>> + Send {
+        self.call(self.inner.get_balance(pubkey))
+    }
+
+    fn get_latest_blockhash(&self) -> impl Future<Output = ClientResult<Hash>> + Send {
+        self.call(self.inner.get_latest_blockhash())
+    }
+
+    fn is_blockhash_valid(
+        &self,
+        blockhash: &Hash,
+        commitment: CommitmentConfig,
+    ) -> impl Future<Output = ClientResult<bool>> + Send {
+        self.call(self.inner.is_blockhash_valid(blockhash, commitment))
+    }
+
+    fn get_account_with_commitment(
+        &self,
+        pubkey: &Pubkey,
+        commitment: CommitmentConfig,
+    ) -> impl Future<Output = RpcResult<Option<Account>>> + Send {
+        self.call(self.inner.get_account_with_commitment(pubkey, commitment))
+    }
+
+    fn get_fee_for_message(
+        &self,
+        message: &Message,
+    ) -> impl Future<Output = ClientResult<u64>> + Send {
+        self.call(self.inner.get_fee_for_message(message))
+    }
+
+    fn get_recent_prioritization_fees(
+        &self,
+        addresses: &[Pubkey],
+    ) -> impl Future<Output = ClientResult<Vec<RpcPrioritizationFee>>> + Send {
+        self.call(self.inner.get_recent_prioritization_fees(addresses))
+    }
+
+    fn send_transaction_with_config(
+        &self,
+        transaction: &Transaction,
+        config: RpcSendTransactionConfig,
+    ) -> impl Future<Output = ClientResult<Signature>> + Send {
+        self.call(self.inner.send_transaction_with_config(transaction, config))
+    }
+
+    fn send_and_confirm_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> impl Future<Output = ClientResult<Signature>> + Send {
+        self.call(self.inner.send_and_confirm_transaction(transaction))
+    }
+
+    fn simulate_transaction_with_config(
+        &self,
+        transaction: &Transaction,
+        config: RpcSimulateTransactionConfig,
+    ) -> impl Future<Output = RpcResult<RpcSimulateTransactionResult>> + Send {
+        self.call(
+            self.inner
+                .simulate_transaction_with_config(transaction, config),
+        )
+    }
+
+    fn get_signature_status(
+        &self,
+        signature: &Signature,
+    ) -> impl Future<Output = ClientResult<Option<transaction::Result<()>>>> + Send {
+        self.call(self.inner.get_signature_status(signature))
+    }
+
+    fn get_signature_statuses(
+        &self,
+        signatures: &[Signature],
+    ) -> impl Future<Output = RpcResult<Vec<Option<TransactionStatus>>>> + Send {
+        self.call(self.inner.get_signature_statuses(signatures))
+    }
+
+    fn get_signature_statuses_with_history(
+        &self,
+        signatures: &[Signature],
+    ) -> impl Future<Output = RpcResult<Vec<Option<TransactionStatus>>>> + Send {
+        self.call(self.inner.get_signature_statuses_with_history(signatures))
+    }
+
+    fn get_transaction_with_config(
+        &self,
+        signature: &Signature,
+        config: RpcTransactionConfig,
+    ) -> impl Future<Output = ClientResult<EncodedConfirmedTransactionWithStatusMeta>> + Send {
+        self.call(self.inner.get_transaction_with_config(signature, config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::mock::MockRpc;
+    use solana_client::client_error::ClientErrorKind;
+    use std::time::Duration;
+    use tokio::time::advance;
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limited_rpc_calls_lower_the_rate() {
+        let rpc = MockRpc::new();
+        rpc.fail_next(
+            "get_balance",
+            ClientErrorKind::Custom("HTTP status client error (429 Too Many Requests)".to_string()),
+        );
+        rpc.fail_next(
+            "get_balance",
+            ClientErrorKind::Custom("Account not found".to_string()),
+        );
+        let client = RateLimited::new(rpc, Some(RateLimiter::new(8.0)));
+        let limiter = client.limiter.as_ref().unwrap();
+
+        assert!(client.get_balance(&Pubkey::new_unique()).await.is_err());
+        assert_eq!(limiter.rate(), 4.0);
+        // Past the interval in which rate limits only halve the rate once
+        advance(Duration::from_secs(1)).await;
+        assert!(client.get_balance(&Pubkey::new_unique()).await.is_err());
+        assert_eq!(limiter.rate(), 4.0);
+        assert_eq!(client.get_balance(&Pubkey::new_unique()).await.unwrap(), 0);
+    }
+}
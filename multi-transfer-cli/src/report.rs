@@ -0,0 +1,571 @@
+use crate::types::{TransferResult, TransferStatus, Verification};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use common::{lamport_change_to_sol, lamports_to_sol};
+use serde::Serialize;
+use std::{collections::BTreeMap, io::Write, time::Duration};
+
+/// Aggregate counts over a batch of transfers
+#[derive(Debug, Default, Serialize)]
+pub struct Statistics {
+    pub total: usize,
+    pub successful: usize,
+    /// Successful transfers that needed more than one attempt
+    pub successful_after_retry: usize,
+    pub failed: usize,
+    pub timeouts: usize,
+    pub simulated: usize,
+    /// Successful transfers whose destination balance didn't change as expected
+    pub verification_mismatches: usize,
+    /// Fees paid by the transactions that landed, as far as they're known, or
+    /// the estimated fees of a dry run
+    pub total_fees_lamports: u64,
+    /// Landed transfers whose fee couldn't be fetched, missing from the total
+    pub unknown_fees: usize,
+    /// Rent reclaimed by closing emptied token accounts
+    pub reclaimed_rent_lamports: u64,
+    /// Destination accounts created by successful transfers
+    pub created_accounts: Vec<String>,
+    pub average_duration_ms: u64,
+    pub total_duration_ms: u64,
+}
+
+impl Statistics {
+    pub fn from_results(results: &[TransferResult], total_time: Duration) -> Self {
+        let mut stats = Statistics {
+            total: results.len(),
+            total_duration_ms: total_time.as_millis() as u64,
+            ..Statistics::default()
+        };
+
+        let mut summed_duration = 0;
+        for result in results {
+            match result.status {
+                TransferStatus::Success => {
+                    stats.successful += 1;
+                    if result.attempts > 1 {
+                        stats.successful_after_retry += 1;
+                    }
+                }
+                TransferStatus::Failed(_) => stats.failed += 1,
+                TransferStatus::Timeout => stats.timeouts += 1,
+                TransferStatus::Simulated { .. } => stats.simulated += 1,
+            }
+            match result.fee_lamports {
+                Some(fee) => stats.total_fees_lamports += fee,
+                None if matches!(result.status, TransferStatus::Success) => stats.unknown_fees += 1,
+                None => {}
+            }
+            stats.reclaimed_rent_lamports += result.reclaimed_rent_lamports.unwrap_or_default();
+            if result.created_account && !stats.created_accounts.contains(&result.to) {
+                stats.created_accounts.push(result.to.clone());
+            }
+            if let Some(Verification::Mismatch { .. }) = result.verification {
+                stats.verification_mismatches += 1;
+            }
+            summed_duration += result.duration_ms;
+        }
+        if !results.is_empty() {
+            stats.average_duration_ms = summed_duration / results.len() as u64;
+        }
+
+        stats
+    }
+}
+
+/// Everything written by `--output json`
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    rpc_url: &'a str,
+    started_at: DateTime<Utc>,
+    finished_at: DateTime<Utc>,
+    statistics: Statistics,
+    results: &'a [TransferResult],
+}
+
+/// Writes the human-readable results table and statistics
+pub fn write_summary<W: Write>(
+    out: &mut W,
+    results: &[TransferResult],
+    dry_run: bool,
+    total_time: Duration,
+) -> Result<()> {
+    if dry_run {
+        writeln!(out, "\n{:=^80}", " DRY RUN - NO TRANSACTIONS WERE SENT ")?;
+    }
+    writeln!(out, "\n{:-^80}", " RESULTS SUMMARY ")?;
+    writeln!(
+        out,
+        "{:<5} {:<12} {:<44} {:<10} {:<12} {:<10} {:<10} {:<20} {:<20}",
+        "No.", "Status", "Signature", "Amount", "Asset", "Fee", "Time (ms)", "From", "To"
+    )?;
+    writeln!(out, "{:-^80}", "")?;
+
+    // source -> (lamports that would be spent including fees, balance before the batch)
+    let mut simulated_spend: BTreeMap<&str, (u64, Option<u64>)> = BTreeMap::new();
+    // signature -> (source, its balance after the transaction, lamports the whole transaction costs)
+    let mut simulated_transactions: BTreeMap<String, (&str, Option<u64>, u64)> = BTreeMap::new();
+    // (destination, asset) -> SOL or token units it would receive
+    let mut simulated_received: BTreeMap<(&str, Option<&str>), f64> = BTreeMap::new();
+
+    for (i, result) in results.iter().enumerate() {
+        match &result.status {
+            TransferStatus::Success | TransferStatus::Timeout => {}
+            TransferStatus::Failed(err) => writeln!(out, "    Error details: {}", err)?,
+            TransferStatus::Simulated {
+                fee_lamports,
+                post_balance_lamports,
+                units_consumed,
+                logs,
+            } => {
+                writeln!(
+                    out,
+                    "    Estimated transaction fee: {} lamports, compute units consumed: {}",
+                    fee_lamports,
+                    units_consumed.map_or("unknown".to_string(), |u| u.to_string())
+                )?;
+                for log in logs {
+                    writeln!(out, "    Log: {}", log)?;
+                }
+                let transferred_lamports = match result.token_mint {
+                    Some(_) => 0,
+                    None => result.lamports,
+                };
+                // The fee is shared by the transfers batched into one transaction
+                let cost = transferred_lamports + result.fee_lamports.unwrap_or(*fee_lamports);
+                simulated_spend.entry(&result.from).or_default().0 += cost;
+                simulated_transactions
+                    .entry(result.signature.to_string())
+                    .or_insert((&result.from, *post_balance_lamports, 0))
+                    .2 += cost;
+                *simulated_received
+                    .entry((&result.to, result.token_mint.as_deref()))
+                    .or_default() += result.amount;
+            }
+        }
+        if result.attempts > 1 {
+            writeln!(out, "    Attempts: {}", result.attempts)?;
+        }
+        if let Some(rent) = result.reclaimed_rent_lamports {
+            writeln!(
+                out,
+                "    Closed the source token account, reclaimed {} lamports",
+                rent
+            )?;
+        }
+        if let Some(top_up) = result.rent_top_up_lamports {
+            writeln!(
+                out,
+                "    Raised by {} lamports to the rent-exempt minimum of the new account",
+                top_up
+            )?;
+        }
+        if result.created_account {
+            writeln!(out, "    Created the destination account")?;
+        }
+        if let Some(memo) = &result.memo {
+            writeln!(out, "    Memo: {}", memo)?;
+        }
+        match &result.verification {
+            Some(Verification::Mismatch {
+                expected_delta,
+                actual_delta,
+            }) => writeln!(
+                out,
+                "    Verification: MISMATCH, destination changed by {:+} instead of {:+} base units",
+                actual_delta, expected_delta
+            )?,
+            Some(Verification::Unverified { reason }) => {
+                writeln!(out, "    Verification: UNVERIFIED, {}", reason)?
+            }
+            Some(Verification::Verified) | None => {}
+        }
+
+        writeln!(
+            out,
+            "{:<5} {:<12} {:<44} {:<10.4} {:<12} {:<10} {:<10} {:<20} {:<20}",
+            i + 1,
+            result.status.label(),
+            result.signature.to_string(),
+            result.amount,
+            asset_label(result.token_mint.as_deref()),
+            result
+                .fee_lamports
+                .map_or("-".to_string(), |fee| fee.to_string()),
+            result.duration_ms,
+            result.from,
+            result.to
+        )?;
+    }
+
+    // Print summary statistics
+    let stats = Statistics::from_results(results, total_time);
+    writeln!(out, "\n{:-^80}", " STATISTICS ")?;
+    writeln!(out, "Total transfers: {}", stats.total)?;
+    writeln!(
+        out,
+        "Successful: {} ({} on the first try, {} after retry)",
+        stats.successful,
+        stats.successful - stats.successful_after_retry,
+        stats.successful_after_retry
+    )?;
+    writeln!(out, "Failed: {}", stats.failed)?;
+    writeln!(out, "Timeouts: {}", stats.timeouts)?;
+    if results.iter().any(|result| result.verification.is_some()) {
+        writeln!(
+            out,
+            "Verification mismatches: {}",
+            stats.verification_mismatches
+        )?;
+    }
+    if dry_run {
+        writeln!(out, "Simulated: {}", stats.simulated)?;
+        writeln!(
+            out,
+            "Estimated fees: {:.9} SOL ({} lamports)",
+            lamports_to_sol(stats.total_fees_lamports),
+            stats.total_fees_lamports
+        )?;
+    }
+    if !dry_run {
+        writeln!(
+            out,
+            "Total fees paid: {:.9} SOL ({} lamports){}",
+            lamports_to_sol(stats.total_fees_lamports),
+            stats.total_fees_lamports,
+            if stats.unknown_fees > 0 {
+                format!(", unknown for {} successful transfers", stats.unknown_fees)
+            } else {
+                String::new()
+            }
+        )?;
+    }
+    if stats.reclaimed_rent_lamports > 0 {
+        writeln!(
+            out,
+            "Reclaimed rent: {:.9} SOL",
+            lamports_to_sol(stats.reclaimed_rent_lamports)
+        )?;
+    }
+    if !stats.created_accounts.is_empty() {
+        writeln!(
+            out,
+            "Created accounts: {} ({})",
+            stats.created_accounts.len(),
+            stats.created_accounts.join(", ")
+        )?;
+    }
+    writeln!(out, "Average duration: {}ms", stats.average_duration_ms)?;
+    writeln!(out, "Total execution time: {}ms", stats.total_duration_ms)?;
+
+    if dry_run {
+        // Each simulation runs against the current state, so a transaction's
+        // post-balance plus its whole cost is the balance before the batch
+        for (source, post_balance, cost) in simulated_transactions.into_values() {
+            if let Some(post_balance) = post_balance {
+                simulated_spend.entry(source).or_default().1 = Some(post_balance + cost);
+            }
+        }
+
+        writeln!(out, "\n{:-^80}", " DRY RUN SPEND PER SOURCE ")?;
+        for (source, (lamports, balance)) in &simulated_spend {
+            writeln!(
+                out,
+                "{:<44} would spend {:.9} SOL (incl. fees), balance afterwards: {}",
+                source,
+                lamports_to_sol(*lamports),
+                balance.map_or("unknown".to_string(), |b| format!(
+                    "{:.9} SOL",
+                    lamport_change_to_sol(b as i128 - *lamports as i128)
+                ))
+            )?;
+        }
+
+        writeln!(out, "\n{:-^80}", " DRY RUN RECEIVED PER DESTINATION ")?;
+        for ((destination, token_mint), amount) in &simulated_received {
+            writeln!(
+                out,
+                "{:<44} would receive {:.9} {}",
+                destination,
+                amount,
+                asset_label(*token_mint)
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes all results together with the run metadata and statistics as JSON
+pub fn write_json<W: Write>(
+    out: &mut W,
+    results: &[TransferResult],
+    rpc_url: &str,
+    started_at: DateTime<Utc>,
+    total_time: Duration,
+) -> Result<()> {
+    let report = JsonReport {
+        rpc_url,
+        started_at,
+        finished_at: Utc::now(),
+        statistics: Statistics::from_results(results, total_time),
+        results,
+    };
+    serde_json::to_writer_pretty(&mut *out, &report)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Writes one CSV row per transfer
+pub fn write_csv<W: Write>(out: W, results: &[TransferResult], rpc_url: &str) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record([
+        "no",
+        "status",
+        "error",
+        "signature",
+        "amount",
+        "lamports",
+        "asset",
+        "priority_fee_micro_lamports",
+        "fee_lamports",
+        "slot",
+        "verification",
+        "duration_ms",
+        "attempts",
+        "reclaimed_rent_lamports",
+        "created_account",
+        "rent_top_up_lamports",
+        "memo",
+        "from",
+        "to",
+        "rpc_url",
+        "started_at",
+        "finished_at",
+    ])?;
+
+    for (i, result) in results.iter().enumerate() {
+        writer.write_record([
+            (i + 1).to_string().as_str(),
+            result.status.label(),
+            result.status.error().unwrap_or_default(),
+            result.signature.to_string().as_str(),
+            result.amount.to_string().as_str(),
+            result.lamports.to_string().as_str(),
+            result.token_mint.as_deref().unwrap_or("SOL"),
+            result
+                .priority_fee_micro_lamports
+                .map(|fee| fee.to_string())
+                .unwrap_or_default()
+                .as_str(),
+            result
+                .fee_lamports
+                .map(|fee| fee.to_string())
+                .unwrap_or_default()
+                .as_str(),
+            result
+                .slot
+                .map(|slot| slot.to_string())
+                .unwrap_or_default()
+                .as_str(),
+            result.verification.as_ref().map_or("", Verification::label),
+            result.duration_ms.to_string().as_str(),
+            result.attempts.to_string().as_str(),
+            result
+                .reclaimed_rent_lamports
+                .map(|rent| rent.to_string())
+                .unwrap_or_default()
+                .as_str(),
+            result.created_account.to_string().as_str(),
+            result
+                .rent_top_up_lamports
+                .map(|top_up| top_up.to_string())
+                .unwrap_or_default()
+                .as_str(),
+            result.memo.as_deref().unwrap_or_default(),
+            &result.from,
+            &result.to,
+            rpc_url,
+            result.started_at.to_rfc3339().as_str(),
+            result.finished_at.to_rfc3339().as_str(),
+        ])?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// "SOL" for native transfers, otherwise the abbreviated token mint
+fn asset_label(token_mint: Option<&str>) -> String {
+    match token_mint {
+        Some(mint) if mint.len() > 11 => format!("{}..{}", &mint[..4], &mint[mint.len() - 4..]),
+        Some(mint) => mint.to_string(),
+        None => "SOL".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use solana_sdk::signature::Signature;
+
+    fn result(status: TransferStatus) -> TransferResult {
+        TransferResult {
+            from: "source".to_string(),
+            to: "destination".to_string(),
+            amount: 0.5,
+            lamports: 500_000_000,
+            token_mint: None,
+            signature: Signature::new_unique(),
+            priority_fee_micro_lamports: None,
+            fee_lamports: matches!(status, TransferStatus::Success).then_some(5000),
+            slot: None,
+            duration_ms: 100,
+            attempts: 1,
+            status,
+            reclaimed_rent_lamports: None,
+            created_account: false,
+            rent_top_up_lamports: None,
+            memo: None,
+            verification: None,
+            started_at: Utc::now(),
+            finished_at: Utc::now(),
+        }
+    }
+
+    fn results() -> Vec<TransferResult> {
+        let mut retried = result(TransferStatus::Success);
+        retried.attempts = 2;
+        retried.slot = Some(42);
+        vec![
+            result(TransferStatus::Success),
+            retried,
+            result(TransferStatus::Failed("insufficient funds".to_string())),
+            result(TransferStatus::Timeout),
+        ]
+    }
+
+    fn json(results: &[TransferResult]) -> String {
+        let mut out = Vec::new();
+        write_json(
+            &mut out,
+            results,
+            "http://rpc",
+            Utc::now(),
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn counts_outcomes() {
+        let stats = Statistics::from_results(&results(), Duration::from_millis(1500));
+        assert_eq!(
+            (stats.total, stats.successful, stats.successful_after_retry),
+            (4, 2, 1)
+        );
+        assert_eq!((stats.failed, stats.timeouts, stats.simulated), (1, 1, 0));
+        assert_eq!((stats.total_fees_lamports, stats.unknown_fees), (10_000, 0));
+        assert_eq!(stats.average_duration_ms, 100);
+        assert_eq!(stats.total_duration_ms, 1500);
+    }
+
+    #[test]
+    fn writes_every_result_as_json() {
+        let results = results();
+        let report: Value = serde_json::from_str(&json(&results)).unwrap();
+
+        assert_eq!(report["rpc_url"], "http://rpc");
+        assert_eq!(report["statistics"]["failed"], 1);
+        let written = report["results"].as_array().unwrap();
+        assert_eq!(written.len(), 4);
+        assert_eq!(written[0]["signature"], results[0].signature.to_string());
+        assert_eq!(written[0]["status"], "SUCCESS");
+        assert_eq!(written[0]["error"], Value::Null);
+        assert_eq!(written[1]["attempts"], 2);
+        assert_eq!(written[1]["slot"], 42);
+        assert_eq!(written[2]["status"], "FAILED");
+        assert_eq!(written[2]["error"], "insufficient funds");
+        assert_eq!(written[3]["status"], "TIMEOUT");
+        // What `jq '.results | map(select(.status == "FAILED")) | length'` counts
+        let failed = written
+            .iter()
+            .filter(|result| result.get("status") == Some(&Value::from("FAILED")))
+            .count();
+        assert_eq!(failed, 1);
+    }
+
+    #[test]
+    fn keeps_the_fee_share_of_a_simulated_transfer_apart() {
+        let mut simulated = result(TransferStatus::Simulated {
+            fee_lamports: 10_000,
+            post_balance_lamports: Some(1_000_000_000),
+            units_consumed: Some(450),
+            logs: vec!["Program log: ok".to_string()],
+        });
+        // Half of a transaction batching two transfers
+        simulated.fee_lamports = Some(5000);
+
+        let output = json(&[simulated]);
+        assert_eq!(output.matches("\"fee_lamports\"").count(), 1, "{}", output);
+        let report: Value = serde_json::from_str(&output).unwrap();
+        let written = &report["results"][0];
+        assert_eq!(written["status"], "SIMULATED");
+        assert_eq!(written["fee_lamports"], 5000);
+        assert_eq!(written["transaction_fee_lamports"], 10_000);
+        assert_eq!(written["units_consumed"], 450);
+        assert_eq!(written["logs"][0], "Program log: ok");
+    }
+
+    #[test]
+    fn writes_one_csv_row_per_transfer() {
+        let results = results();
+        let mut out = Vec::new();
+        write_csv(&mut out, &results, "http://rpc").unwrap();
+
+        let mut reader = csv::Reader::from_reader(out.as_slice());
+        let headers = reader.headers().unwrap().clone();
+        let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), 4);
+        let field = |row: usize, name: &str| {
+            let column = headers.iter().position(|header| header == name).unwrap();
+            rows[row][column].to_string()
+        };
+        assert_eq!(field(0, "no"), "1");
+        assert_eq!(field(0, "signature"), results[0].signature.to_string());
+        assert_eq!(field(0, "lamports"), "500000000");
+        assert_eq!(field(0, "asset"), "SOL");
+        assert_eq!(field(0, "fee_lamports"), "5000");
+        assert_eq!(field(1, "slot"), "42");
+        assert_eq!(field(2, "status"), "FAILED");
+        assert_eq!(field(2, "error"), "insufficient funds");
+        assert_eq!(field(2, "fee_lamports"), "");
+        assert_eq!(field(3, "rpc_url"), "http://rpc");
+    }
+
+    #[test]
+    fn summarizes_the_results_for_humans() {
+        let mut out = Vec::new();
+        write_summary(&mut out, &results(), false, Duration::from_secs(1)).unwrap();
+        let summary = String::from_utf8(out).unwrap();
+
+        assert!(summary.contains("    Error details: insufficient funds\n"));
+        assert!(summary.contains("    Attempts: 2\n"));
+        assert!(summary.contains("Total transfers: 4\n"));
+        assert!(summary.contains("Successful: 2 (1 on the first try, 1 after retry)\n"));
+        assert!(summary.contains("Total fees paid: 0.000010000 SOL (10000 lamports)\n"));
+        assert!(!summary.contains("DRY RUN"));
+    }
+
+    #[test]
+    fn abbreviates_token_mints() {
+        assert_eq!(asset_label(None), "SOL");
+        assert_eq!(asset_label(Some("USDC")), "USDC");
+        assert_eq!(
+            asset_label(Some("EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v")),
+            "EPjF..Dt1v"
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use common::retry::is_retryable;
+use rand::Rng;
+use solana_client::client_error::ClientError;
+use std::{future::Future, time::Duration};
+use tokio::time::sleep;
+use tracing::warn;
+
+/// Upper bound for a single backoff delay
+const MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// Runs `operation` and retries it up to `max_retries` times with exponential
+/// backoff starting at `base_delay` and jitter, as long as it fails with a
+/// retryable error
+pub async fn with_backoff<T, F, Fut>(
+    what: &str,
+    max_retries: u32,
+    base_delay: Duration,
+    mut operation: F,
+) -> Result<T, ClientError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ClientError>>,
+{
+    let mut attempt = 0;
+    loop {
+        match operation().await {
+            Err(e) if attempt < max_retries && is_retryable(&e) => {
+                let delay = backoff_delay(base_delay, attempt);
+                attempt += 1;
+                warn!(
+                    "{} failed ({}), retry {}/{} in {}ms",
+                    what,
+                    e,
+                    attempt,
+                    max_retries,
+                    delay.as_millis()
+                );
+                sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Exponential delay for the given attempt with up to 50% random jitter, so
+/// concurrent transfers don't all retry at the same moment
+pub fn backoff_delay(base_delay: Duration, attempt: u32) -> Duration {
+    let delay = base_delay
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_DELAY);
+    let jitter = rand::thread_rng().gen_range(0..=delay.as_millis() as u64 / 2);
+    delay + Duration::from_millis(jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum_with_jitter() {
+        let base = Duration::from_millis(100);
+        for (attempt, expected) in [(0, 100), (1, 200), (3, 800)] {
+            let delay = backoff_delay(base, attempt);
+            let expected = Duration::from_millis(expected);
+            assert!(
+                delay >= expected && delay <= expected * 3 / 2,
+                "{:?}",
+                delay
+            );
+        }
+        assert!(backoff_delay(base, 20) <= MAX_DELAY * 3 / 2);
+    }
+}
@@ -0,0 +1,278 @@
+use crate::batch;
+use crate::state::{StateFile, TransferKey};
+use crate::transfer::TransferExecutor;
+use crate::types::{TransferResult, TransferSpec, TransferStatus};
+use common::{SolanaRpc, keypair};
+use solana_sdk::{hash::Hash, signature::Signature};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::{Semaphore, mpsc};
+use tracing::{info, warn};
+
+/// What happened to a transfer, in the order it happens. `index` is the
+/// transfer's position in the run, results sorted by it keep that order
+#[derive(Debug)]
+pub enum TransferEvent {
+    /// Got its permits and is being built
+    Started {
+        index: usize,
+        from: String,
+        to: String,
+    },
+    /// Its transaction was sent, again with a new signature after a retry
+    Sent { index: usize, signature: Signature },
+    /// Landed successfully, or simulated successfully in a dry run
+    Confirmed {
+        index: usize,
+        result: Box<TransferResult>,
+    },
+    Failed {
+        index: usize,
+        reason: String,
+        result: Box<TransferResult>,
+    },
+    TimedOut {
+        index: usize,
+        result: Box<TransferResult>,
+    },
+    /// Never started because the run was stopped
+    NotStarted { index: usize },
+}
+
+impl TransferEvent {
+    fn finished(index: usize, result: TransferResult) -> Self {
+        let result = Box::new(result);
+        match &result.status {
+            TransferStatus::Success | TransferStatus::Simulated { .. } => {
+                TransferEvent::Confirmed { index, result }
+            }
+            TransferStatus::Failed(reason) => TransferEvent::Failed {
+                index,
+                reason: reason.clone(),
+                result,
+            },
+            TransferStatus::Timeout => TransferEvent::TimedOut { index, result },
+        }
+    }
+}
+
+/// Transfers in flight at once for a source without a configured limit. Its
+/// transactions go out one after the other, so two of them can never race
+/// each other with the same blockhash
+pub const DEFAULT_SOURCE_LIMIT: usize = 1;
+
+/// How `run_transfers` schedules and records the transfers
+pub struct RunOptions {
+    /// Transfers in flight at once across all sources
+    pub concurrent: usize,
+    /// Transfers in flight at once per source keypair, `DEFAULT_SOURCE_LIMIT`
+    /// for sources not listed
+    pub source_limits: HashMap<String, usize>,
+    /// SOL transfers packed into one transaction
+    pub batch_per_tx: usize,
+    /// Where finished transfers are recorded, never written in a dry run
+    pub state: Option<Arc<StateFile>>,
+    pub dry_run: bool,
+}
+
+/// A started run, its events end once every transfer finished or was skipped
+pub struct TransferRun {
+    pub events: mpsc::UnboundedReceiver<TransferEvent>,
+    /// Transfers in the run once packed, the number of indices in the events
+    pub total: usize,
+    semaphores: Vec<Arc<Semaphore>>,
+}
+
+impl TransferRun {
+    /// Returns a function that stops new transfers from starting, the ones in
+    /// flight still finish. Every transfer still waiting is reported as `NotStarted`
+    pub fn stopper(&self) -> impl Fn() + Send + 'static {
+        let semaphores = self.semaphores.clone();
+        move || {
+            for semaphore in &semaphores {
+                semaphore.close();
+            }
+        }
+    }
+}
+
+/// Spawns every transfer and returns right away. Progress and results are
+/// only reported through the run's events
+pub fn run_transfers<R: SolanaRpc + 'static>(
+    transfers: Vec<TransferSpec>,
+    executor: Arc<TransferExecutor<R>>,
+    options: RunOptions,
+) -> TransferRun {
+    // Each batch becomes one transaction, so the limits apply per transaction
+    let total = transfers.len();
+    let batches = batch::group_transfers(transfers, options.batch_per_tx);
+    if batches.len() < total {
+        info!(
+            "Packed {} transfers into {} transactions",
+            total,
+            batches.len()
+        );
+    }
+
+    let semaphore = Arc::new(Semaphore::new(options.concurrent));
+    // Per-source limits, layered under the global one
+    let mut source_semaphores: HashMap<String, Arc<Semaphore>> = HashMap::new();
+
+    let (events, receiver) = mpsc::unbounded_channel();
+    let mut first_index = 0;
+    for batch in batches {
+        let indices = first_index..first_index + batch.len();
+        first_index = indices.end;
+
+        let events = events.clone();
+        let executor = executor.clone();
+        let semaphore = semaphore.clone();
+        let source_semaphore = source_semaphores
+            .entry(batch[0].from_keypair_path.clone())
+            .or_insert_with_key(|source| {
+                let limit = options
+                    .source_limits
+                    .get(source)
+                    .copied()
+                    .unwrap_or(DEFAULT_SOURCE_LIMIT);
+                Arc::new(Semaphore::new(limit.max(1)))
+            })
+            .clone();
+        let state = options.state.clone();
+        let dry_run = options.dry_run;
+
+        tokio::spawn(async move {
+            let not_started = || {
+                for index in indices.clone() {
+                    let _ = events.send(TransferEvent::NotStarted { index });
+                }
+            };
+
+            // Wait for the source's own limit first so a busy source doesn't
+            // hold global permits that other sources could use
+            let Ok(_source_permit) = source_semaphore.acquire().await else {
+                return not_started();
+            };
+
+            // Acquire permit from semaphore, it's only closed when the run stops
+            let Ok(_permit) = semaphore.acquire().await else {
+                return not_started();
+            };
+            // The permit may have been handed out right before the stop
+            if semaphore.is_closed() {
+                return not_started();
+            }
+
+            let from_keypair_path = batch[0].from_keypair_path.clone();
+            // Simulations don't change anything on chain, so there's nothing to resume
+            let state = state.filter(|_| !dry_run);
+            let keys: Vec<TransferKey> = batch.iter().map(TransferKey::new).collect();
+            for (index, transfer) in indices.clone().zip(&batch) {
+                let _ = events.send(TransferEvent::Started {
+                    index,
+                    from: keypair::describe(&transfer.from_keypair_path),
+                    to: transfer.to_address.clone(),
+                });
+            }
+
+            // Sends are recorded as they happen, before the transfer is recorded as finished
+            let (sent_tx, mut sent_rx) = mpsc::unbounded_channel::<(Signature, Hash)>();
+            let sent_indices = indices.clone();
+            let sent_events = &events;
+            // Owns the sender, so the channel closes once the transfer finished
+            let execute = executor.execute(batch, move |signature, blockhash| {
+                for index in sent_indices.clone() {
+                    let _ = sent_events.send(TransferEvent::Sent { index, signature });
+                }
+                let _ = sent_tx.send((signature, blockhash));
+            });
+            let record_sends = async {
+                while let Some((signature, blockhash)) = sent_rx.recv().await {
+                    if let Some(state) = &state
+                        && let Err(e) = state.record_sent(&keys, &signature, &blockhash).await
+                    {
+                        warn!("{:#}", e);
+                    }
+                }
+            };
+            let (results, ()) = tokio::join!(execute, record_sends);
+
+            for (index, result) in indices.zip(results) {
+                if let Some(state) = &state
+                    && let Err(e) = state.record(&from_keypair_path, &result).await
+                {
+                    warn!("{:#}", e);
+                }
+                let _ = events.send(TransferEvent::finished(index, result));
+            }
+        });
+    }
+
+    TransferRun {
+        events: receiver,
+        total,
+        semaphores: std::iter::once(semaphore)
+            .chain(source_semaphores.into_values())
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::tests::{executor, transfer};
+    use common::mock::{MockRpc, SentTransaction};
+    use solana_sdk::{signature::Keypair, signer::Signer};
+    use std::time::Duration;
+
+    fn overlap(a: &SentTransaction, b: &SentTransaction) -> bool {
+        a.started < b.finished && b.started < a.finished
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transfers_of_one_source_never_overlap() {
+        let rpc = Arc::new(MockRpc::new());
+        rpc.state().send_delay = Duration::from_secs(1);
+        let executor = Arc::new(executor(&rpc, 0).await);
+        let (first, second) = (Keypair::new(), Keypair::new());
+        let transfers = vec![
+            transfer(&first),
+            transfer(&first),
+            transfer(&second),
+            transfer(&second),
+        ];
+
+        let mut run = run_transfers(transfers, executor, RunOptions {
+            concurrent: 10,
+            source_limits: HashMap::new(),
+            batch_per_tx: 1,
+            state: None,
+            dry_run: false,
+        });
+        let mut confirmed = 0;
+        while let Some(event) = run.events.recv().await {
+            if let TransferEvent::Confirmed { .. } = event {
+                confirmed += 1;
+            }
+        }
+        assert_eq!(confirmed, 4);
+
+        let state = rpc.state();
+        let sends_from = |source: &Keypair| -> Vec<&SentTransaction> {
+            state
+                .sends
+                .iter()
+                .filter(|sent| sent.transaction.message.account_keys[0] == source.pubkey())
+                .collect()
+        };
+        let (from_first, from_second) = (sends_from(&first), sends_from(&second));
+        assert_eq!(from_first.len(), 2);
+        assert_eq!(from_second.len(), 2);
+        assert!(!overlap(from_first[0], from_first[1]));
+        assert!(!overlap(from_second[0], from_second[1]));
+        assert!(
+            from_first
+                .iter()
+                .any(|a| from_second.iter().any(|b| overlap(a, b)))
+        );
+    }
+}
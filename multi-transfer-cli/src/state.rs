@@ -0,0 +1,618 @@
+use crate::types::{TransferResult, TransferSpec, TransferStatus};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use common::{SolanaRpc, keypair};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{commitment_config::CommitmentConfig, hash::Hash, signature::Signature};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex, time::sleep};
+use tracing::{info, warn};
+
+/// Status of a transfer whose transaction was sent but hadn't finished yet
+const SENT: &str = "SENT";
+
+/// Most signatures `getSignatureStatuses` accepts per call
+const MAX_SIGNATURE_STATUSES: usize = 256;
+
+/// What identifies a transfer across runs
+#[derive(Debug, Clone)]
+pub struct TransferKey {
+    /// As given by [`keypair::describe`], so inline secrets aren't written to disk
+    from_keypair_path: String,
+    to: String,
+    /// The configured amount, before any rent top-up
+    lamports: u64,
+    token_mint: Option<String>,
+}
+
+impl TransferKey {
+    pub fn new(transfer: &TransferSpec) -> Self {
+        Self {
+            from_keypair_path: keypair::describe(&transfer.from_keypair_path),
+            to: transfer.to_address.clone(),
+            lamports: transfer.amount - transfer.rent_top_up,
+            token_mint: transfer.token.as_ref().map(|token| token.mint.clone()),
+        }
+    }
+}
+
+/// Outcome of one transfer as stored in the state file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateRecord {
+    /// As given by [`keypair::describe`], so inline secrets aren't written to disk
+    pub from_keypair_path: String,
+    pub to: String,
+    /// Lamports, or raw token units when `token_mint` is set
+    pub lamports: u64,
+    /// `None` for SOL transfers
+    pub token_mint: Option<String>,
+    pub signature: String,
+    /// Blockhash a `SENT` transaction was signed with, to tell when it can no longer land
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blockhash: Option<String>,
+    pub status: String,
+    pub error: Option<String>,
+    pub updated_at: DateTime<Utc>,
+    /// Attempts that didn't succeed after this transfer already had, e.g. on a
+    /// `--force` re-run. A successful record is never replaced by them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub later_attempts: Vec<Attempt>,
+}
+
+/// An attempt recorded on a transfer that had already succeeded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attempt {
+    pub signature: String,
+    pub status: String,
+    pub error: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl StateRecord {
+    fn matches(
+        &self,
+        from_keypair_path: &str,
+        to: &str,
+        lamports: u64,
+        token_mint: Option<&str>,
+    ) -> bool {
+        self.from_keypair_path == from_keypair_path
+            && self.to == to
+            && self.lamports == lamports
+            && self.token_mint.as_deref() == token_mint
+    }
+}
+
+/// Progress of a batch, persisted after every send and every finished transfer
+/// so a re-run can skip the transfers that already succeeded.
+///
+/// The file is append-only JSONL: every change appends the full updated record
+/// as one line, and loading replays them so the last line of a transfer wins
+pub struct StateFile {
+    path: PathBuf,
+    records: Mutex<Vec<StateRecord>>,
+}
+
+impl StateFile {
+    /// Loads the state file, or starts an empty one if it doesn't exist yet.
+    /// A state file written as a single JSON array by an older version is
+    /// converted to JSONL
+    pub fn load(path: &Path) -> Result<Self> {
+        let records = if path.exists() {
+            let data = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read state file: {:?}", path))?;
+            if data.trim_start().starts_with('[') {
+                let records: Vec<StateRecord> = serde_json::from_str(&data)
+                    .with_context(|| format!("Failed to parse state file: {:?}", path))?;
+                convert_legacy(path, &records)?;
+                records
+            } else {
+                replay(&data).with_context(|| format!("Failed to parse state file: {:?}", path))?
+            }
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            records: Mutex::new(records),
+        })
+    }
+
+    /// Whether this transfer is already recorded as successful
+    pub async fn is_done(&self, transfer: &TransferSpec) -> bool {
+        let token_mint = transfer.token.as_ref().map(|token| token.mint.as_str());
+        self.records.lock().await.iter().any(|record| {
+            record.status == TransferStatus::Success.label()
+                && record.matches(
+                    &keypair::describe(&transfer.from_keypair_path),
+                    &transfer.to_address,
+                    transfer.amount,
+                    token_mint,
+                )
+        })
+    }
+
+    /// Adds or updates the record for a finished transfer and appends it to the file
+    pub async fn record(&self, from_keypair_path: &str, result: &TransferResult) -> Result<()> {
+        let record = StateRecord {
+            from_keypair_path: keypair::describe(from_keypair_path),
+            to: result.to.clone(),
+            // The configured amount, which is what `is_done` compares against
+            lamports: result.requested_lamports(),
+            token_mint: result.token_mint.clone(),
+            signature: result.signature.to_string(),
+            blockhash: None,
+            status: result.status.label().to_string(),
+            error: result.status.error().map(str::to_string),
+            updated_at: Utc::now(),
+            later_attempts: Vec::new(),
+        };
+
+        // Holding the lock while writing keeps the lines of concurrent
+        // updates in the order they were applied
+        let mut records = self.records.lock().await;
+        let i = upsert(&mut records, record);
+        self.append(&records[i..=i]).await
+    }
+
+    /// Records the transfers of a transaction as `SENT` as soon as it was
+    /// sent, so a crash before it confirmed isn't mistaken for never sending it
+    pub async fn record_sent(
+        &self,
+        transfers: &[TransferKey],
+        signature: &Signature,
+        blockhash: &Hash,
+    ) -> Result<()> {
+        let mut records = self.records.lock().await;
+        let mut changed = Vec::with_capacity(transfers.len());
+        for key in transfers {
+            let i = upsert(&mut records, StateRecord {
+                from_keypair_path: key.from_keypair_path.clone(),
+                to: key.to.clone(),
+                lamports: key.lamports,
+                token_mint: key.token_mint.clone(),
+                signature: signature.to_string(),
+                blockhash: Some(blockhash.to_string()),
+                status: SENT.to_string(),
+                error: None,
+                updated_at: Utc::now(),
+                later_attempts: Vec::new(),
+            });
+            changed.push(records[i].clone());
+        }
+        self.append(&changed).await
+    }
+
+    /// Settles transfers a previous run sent but never saw finish, `SENT` or
+    /// timed out, by their signature instead of sending them again blindly.
+    /// Waits until their blockhash expired so a late landing is not missed.
+    /// Those that landed become successful and are skipped, the rest failed
+    /// and are sent again. Returns how many landed
+    pub async fn resolve_unfinished<R: SolanaRpc>(&self, rpc_client: &R) -> Result<usize> {
+        let mut records = self.records.lock().await;
+        let unfinished: Vec<usize> = records
+            .iter()
+            .enumerate()
+            .filter(|(_, record)| {
+                (record.status == SENT || record.status == TransferStatus::Timeout.label())
+                    && Signature::from_str(&record.signature)
+                        .is_ok_and(|signature| signature != Signature::default())
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if unfinished.is_empty() {
+            return Ok(0);
+        }
+        info!(
+            "Checking {} transfers the previous run didn't see finish",
+            unfinished.len()
+        );
+
+        let mut blockhashes: Vec<Hash> = unfinished
+            .iter()
+            .filter_map(|&i| records[i].blockhash.as_deref())
+            .filter_map(|blockhash| Hash::from_str(blockhash).ok())
+            .collect();
+        blockhashes.sort_unstable();
+        blockhashes.dedup();
+        for blockhash in blockhashes {
+            while rpc_client
+                .is_blockhash_valid(&blockhash, CommitmentConfig::processed())
+                .await
+                .context("Failed to check the blockhash of an unfinished transfer")?
+            {
+                info!(
+                    "Waiting for blockhash {} to expire before checking its transfers",
+                    blockhash
+                );
+                sleep(Duration::from_secs(2)).await;
+            }
+        }
+
+        let mut signatures: Vec<Signature> = unfinished
+            .iter()
+            .filter_map(|&i| Signature::from_str(&records[i].signature).ok())
+            .collect();
+        // Transfers packed into one transaction share a signature
+        signatures.sort_unstable();
+        signatures.dedup();
+        let mut statuses = std::collections::HashMap::new();
+        for chunk in signatures.chunks(MAX_SIGNATURE_STATUSES) {
+            let response = rpc_client
+                .get_signature_statuses_with_history(chunk)
+                .await
+                .context("Failed to look up unfinished transfers")?;
+            for (signature, status) in chunk.iter().zip(response.value) {
+                statuses.insert(signature.to_string(), status);
+            }
+        }
+
+        let mut landed = 0;
+        let mut changed = Vec::with_capacity(unfinished.len());
+        for i in unfinished {
+            let record = &mut records[i];
+            let status = match statuses.get(&record.signature).cloned().flatten() {
+                Some(status) => match status.err {
+                    None => {
+                        landed += 1;
+                        TransferStatus::Success
+                    }
+                    Some(e) => TransferStatus::Failed(format!("Transaction error: {:?}", e)),
+                },
+                None => TransferStatus::Failed(format!("{} never landed", record.signature)),
+            };
+            info!(
+                "Unfinished transfer {} -> {} resolved as {}",
+                keypair::describe(&record.from_keypair_path),
+                record.to,
+                status.label()
+            );
+            record.status = status.label().to_string();
+            record.error = status.error().map(str::to_string);
+            record.updated_at = Utc::now();
+            changed.push(record.clone());
+        }
+
+        self.append(&changed).await?;
+        Ok(landed)
+    }
+
+    /// Updates the status of an already recorded transfer, found by its
+    /// signature, e.g. after a timed-out transfer turned out to have landed
+    pub async fn update_status(&self, result: &TransferResult) -> Result<()> {
+        let signature = result.signature.to_string();
+        let mut records = self.records.lock().await;
+        let Some(existing) = records.iter_mut().find(|existing| {
+            existing.to == result.to
+                && existing.lamports == result.requested_lamports()
+                && existing.token_mint == result.token_mint
+                && (existing.signature == signature
+                    || existing
+                        .later_attempts
+                        .iter()
+                        .any(|attempt| attempt.signature == signature))
+        }) else {
+            return Ok(());
+        };
+        let status = result.status.label().to_string();
+        let error = result.status.error().map(str::to_string);
+        match existing
+            .later_attempts
+            .iter_mut()
+            .find(|attempt| attempt.signature == signature)
+        {
+            Some(attempt) => {
+                attempt.status = status;
+                attempt.error = error;
+                attempt.updated_at = Utc::now();
+            }
+            None => {
+                existing.status = status;
+                existing.error = error;
+                existing.updated_at = Utc::now();
+            }
+        }
+
+        let updated = existing.clone();
+        self.append(&[updated]).await
+    }
+
+    /// Appends the records as one line each. They are written with a single
+    /// call so a crash leaves at most the last line truncated, which `load` skips
+    async fn append(&self, records: &[StateRecord]) -> Result<()> {
+        let mut data = String::new();
+        for record in records {
+            data.push_str(&serde_json::to_string(record)?);
+            data.push('\n');
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .with_context(|| format!("Failed to open state file: {:?}", self.path))?;
+        file.write_all(data.as_bytes())
+            .await
+            .with_context(|| format!("Failed to write state file: {:?}", self.path))?;
+        file.sync_data()
+            .await
+            .with_context(|| format!("Failed to write state file: {:?}", self.path))?;
+
+        Ok(())
+    }
+}
+
+/// Rebuilds the records from the lines of a JSONL state file, the last line
+/// of each transfer replacing the earlier ones. A last line that doesn't parse
+/// was cut off by a crash and is skipped
+fn replay(data: &str) -> Result<Vec<StateRecord>> {
+    let mut records: Vec<StateRecord> = Vec::new();
+    let lines: Vec<&str> = data
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    for (i, line) in lines.iter().enumerate() {
+        let record: StateRecord = match serde_json::from_str(line) {
+            Ok(record) => record,
+            Err(e) if i + 1 == lines.len() => {
+                warn!("Skipping the truncated last line of the state file: {}", e);
+                break;
+            }
+            Err(e) => return Err(e).with_context(|| format!("Invalid line {}", i + 1)),
+        };
+        match records.iter_mut().find(|existing| {
+            existing.matches(
+                &record.from_keypair_path,
+                &record.to,
+                record.lamports,
+                record.token_mint.as_deref(),
+            )
+        }) {
+            Some(existing) => *existing = record,
+            None => records.push(record),
+        }
+    }
+    Ok(records)
+}
+
+/// Rewrites a state file from the old single JSON array as JSONL, through a
+/// temporary file so a crash never leaves it half converted
+fn convert_legacy(path: &Path, records: &[StateRecord]) -> Result<()> {
+    let mut data = String::new();
+    for record in records {
+        data.push_str(&serde_json::to_string(record)?);
+        data.push('\n');
+    }
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, data)
+        .with_context(|| format!("Failed to write state file: {:?}", tmp_path))?;
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to replace state file: {:?}", path))?;
+    info!("Converted state file {:?} to JSONL", path);
+    Ok(())
+}
+
+/// Replaces the record of the same transfer, or adds it. A successful record
+/// is kept when the new one didn't succeed, which goes into its
+/// `later_attempts` instead, updating the attempt with the same signature.
+/// A new status of the same transaction keeps the blockhash it was sent with.
+/// Returns the index of the updated record
+fn upsert(records: &mut Vec<StateRecord>, mut record: StateRecord) -> usize {
+    let Some(i) = records.iter().position(|existing| {
+        existing.matches(
+            &record.from_keypair_path,
+            &record.to,
+            record.lamports,
+            record.token_mint.as_deref(),
+        )
+    }) else {
+        records.push(record);
+        return records.len() - 1;
+    };
+    let existing = &mut records[i];
+    if record.blockhash.is_none() && record.signature == existing.signature {
+        record.blockhash = existing.blockhash.clone();
+    }
+
+    let success = TransferStatus::Success.label();
+    if existing.status != success || record.status == success {
+        *existing = record;
+        return i;
+    }
+    let attempt = Attempt {
+        signature: record.signature,
+        status: record.status,
+        error: record.error,
+        updated_at: record.updated_at,
+    };
+    match existing
+        .later_attempts
+        .iter_mut()
+        .find(|existing| existing.signature == attempt.signature)
+    {
+        Some(existing) => *existing = attempt,
+        None => existing.later_attempts.push(attempt),
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::mock::MockRpc;
+    use tokio::time::Instant;
+
+    fn record(to: &str, signature: &str, status: &str) -> StateRecord {
+        StateRecord {
+            from_keypair_path: "source.json".to_string(),
+            to: to.to_string(),
+            lamports: 1000,
+            token_mint: None,
+            signature: signature.to_string(),
+            blockhash: None,
+            status: status.to_string(),
+            error: (status == "FAILED").then(|| "Send error: boom".to_string()),
+            updated_at: Utc::now(),
+            later_attempts: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn replaces_unsuccessful_records() {
+        let mut records = vec![record("alice", "first", "FAILED")];
+        upsert(&mut records, record("alice", "second", SENT));
+        upsert(&mut records, record("alice", "second", "SUCCESS"));
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].signature, "second");
+        assert_eq!(records[0].status, "SUCCESS");
+        assert!(records[0].later_attempts.is_empty());
+    }
+
+    #[test]
+    fn never_downgrades_a_successful_record() {
+        let mut records = vec![record("alice", "first", "SUCCESS")];
+        upsert(&mut records, record("alice", "second", SENT));
+        upsert(&mut records, record("alice", "second", "FAILED"));
+        upsert(&mut records, record("alice", "third", "TIMEOUT"));
+
+        assert_eq!(records.len(), 1);
+        let kept = &records[0];
+        assert_eq!(kept.signature, "first");
+        assert_eq!(kept.status, "SUCCESS");
+        assert_eq!(kept.error, None);
+        let attempts: Vec<(&str, &str)> = kept
+            .later_attempts
+            .iter()
+            .map(|attempt| (attempt.signature.as_str(), attempt.status.as_str()))
+            .collect();
+        assert_eq!(attempts, vec![("second", "FAILED"), ("third", "TIMEOUT")]);
+    }
+
+    #[test]
+    fn keeps_other_transfers_apart() {
+        let mut records = vec![record("alice", "first", "SUCCESS")];
+        upsert(&mut records, record("bob", "second", "FAILED"));
+        let mut other_amount = record("alice", "third", "FAILED");
+        other_amount.lamports = 2000;
+        upsert(&mut records, other_amount);
+
+        assert_eq!(records.len(), 3);
+        assert!(records[0].later_attempts.is_empty());
+    }
+
+    #[test]
+    fn reads_records_without_later_attempts() {
+        let json = r#"[{"from_keypair_path":"source.json","to":"alice","lamports":1000,
+            "token_mint":null,"signature":"first","status":"SUCCESS","error":null,
+            "updated_at":"2025-01-01T00:00:00Z"}]"#;
+        let records: Vec<StateRecord> = serde_json::from_str(json).unwrap();
+        assert!(records[0].later_attempts.is_empty());
+        assert!(
+            !serde_json::to_string(&records)
+                .unwrap()
+                .contains("later_attempts")
+        );
+    }
+    #[tokio::test(start_paused = true)]
+    async fn resolves_a_timed_out_transfer_once_its_blockhash_expired() {
+        let rpc = MockRpc::new();
+        let blockhash = rpc.get_latest_blockhash().await.unwrap();
+        let signature = Signature::new_unique();
+        rpc.state().statuses.insert(signature, Ok(()));
+
+        let mut records = Vec::new();
+        let mut sent = record("alice", &signature.to_string(), SENT);
+        sent.blockhash = Some(blockhash.to_string());
+        upsert(&mut records, sent);
+        upsert(
+            &mut records,
+            record("alice", &signature.to_string(), "TIMEOUT"),
+        );
+        assert_eq!(records[0].status, "TIMEOUT");
+        assert_eq!(records[0].blockhash, Some(blockhash.to_string()));
+
+        let path = std::env::temp_dir().join(format!(
+            "multi-transfer-cli-{}-timeout.state",
+            std::process::id()
+        ));
+        let state = StateFile {
+            path: path.clone(),
+            records: Mutex::new(records),
+        };
+        let started = Instant::now();
+        assert_eq!(state.resolve_unfinished(&rpc).await.unwrap(), 1);
+        assert!(started.elapsed() >= rpc.state().blockhash_ttl);
+        let records = state.records.lock().await;
+        assert_eq!(records[0].status, "SUCCESS");
+        assert_eq!(records[0].error, None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn line(record: &StateRecord) -> String {
+        serde_json::to_string(record).unwrap() + "\n"
+    }
+
+    #[test]
+    fn replays_the_last_line_of_each_transfer() {
+        let data = line(&record("alice", "first", SENT))
+            + &line(&record("bob", "second", "FAILED"))
+            + &line(&record("alice", "first", "SUCCESS"));
+        let records = replay(&data).unwrap();
+
+        let statuses: Vec<(&str, &str)> = records
+            .iter()
+            .map(|record| (record.to.as_str(), record.status.as_str()))
+            .collect();
+        assert_eq!(statuses, vec![("alice", "SUCCESS"), ("bob", "FAILED")]);
+    }
+
+    #[test]
+    fn skips_only_a_truncated_last_line() {
+        let complete = line(&record("alice", "first", "SUCCESS"));
+        let cut_off = &line(&record("bob", "second", SENT))[..20];
+
+        let records = replay(&format!("{}{}", complete, cut_off)).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].to, "alice");
+
+        assert!(replay(&format!("{}\n{}", cut_off, complete)).is_err());
+    }
+
+    #[tokio::test]
+    async fn appends_to_a_converted_legacy_file() {
+        let path = std::env::temp_dir().join(format!(
+            "multi-transfer-cli-{}-legacy.state",
+            std::process::id()
+        ));
+        let legacy = serde_json::to_string_pretty(&[record("alice", "first", "SUCCESS")]).unwrap();
+        std::fs::write(&path, legacy).unwrap();
+
+        let state = StateFile::load(&path).unwrap();
+        assert_eq!(state.records.lock().await.len(), 1);
+        state
+            .append(&[
+                record("bob", "second", SENT),
+                record("bob", "second", "SUCCESS"),
+            ])
+            .await
+            .unwrap();
+
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(data.lines().count(), 3);
+        let reloaded = StateFile::load(&path).unwrap();
+        let records = reloaded.records.lock().await;
+        let statuses: Vec<(&str, &str)> = records
+            .iter()
+            .map(|record| (record.to.as_str(), record.status.as_str()))
+            .collect();
+        assert_eq!(statuses, vec![("alice", "SUCCESS"), ("bob", "SUCCESS")]);
+        drop(records);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
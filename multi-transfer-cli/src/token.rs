@@ -0,0 +1,122 @@
+use crate::types::TokenSpec;
+use anyhow::Context;
+use common::SolanaRpc;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{
+    instruction::Instruction, program_option::COption, program_pack::Pack, pubkey::Pubkey,
+};
+use spl_associated_token_account::{
+    get_associated_token_address, instruction::create_associated_token_account_idempotent,
+};
+use std::str::FromStr;
+
+/// Reads the decimals from the mint account on chain
+pub async fn fetch_mint_decimals(rpc_client: &RpcClient, mint: &str) -> anyhow::Result<u8> {
+    let mint_pubkey =
+        Pubkey::from_str(mint).with_context(|| format!("Invalid token mint {}", mint))?;
+    let account = rpc_client
+        .get_account(&mint_pubkey)
+        .await
+        .with_context(|| format!("Failed to fetch token mint {}", mint))?;
+    let mint_state = spl_token::state::Mint::unpack(&account.data)
+        .with_context(|| format!("{} is not an SPL token mint", mint))?;
+    Ok(mint_state.decimals)
+}
+
+/// Instructions of a single token transfer
+pub struct TokenTransfer {
+    pub instructions: Vec<Instruction>,
+    /// Lamports returned by closing the emptied source account, if it's closed
+    pub reclaimed_rent: Option<u64>,
+}
+
+/// Builds the instructions for an SPL token transfer between the associated
+/// token accounts of `from` and `to`, `amount` is in raw token units. When
+/// `create_ata` is set, the destination account is created if needed;
+/// otherwise a missing destination account is an error. With `close_to`, a
+/// transfer of the source account's whole balance also closes that account
+/// and sends its rent to `close_to`.
+pub async fn build_transfer_instructions<R: SolanaRpc>(
+    rpc_client: &R,
+    token: &TokenSpec,
+    from: &Pubkey,
+    to: &Pubkey,
+    amount: u64,
+    create_ata: bool,
+    close_to: Option<&Pubkey>,
+) -> Result<TokenTransfer, String> {
+    let mint = Pubkey::from_str(&token.mint).map_err(|e| format!("Invalid token mint: {}", e))?;
+    let source_ata = get_associated_token_address(from, &mint);
+    let destination_ata = get_associated_token_address(to, &mint);
+
+    let mut instructions = Vec::new();
+    if create_ata {
+        instructions.push(create_associated_token_account_idempotent(
+            from,
+            to,
+            &mint,
+            &spl_token::id(),
+        ));
+    } else {
+        let destination_account = rpc_client
+            .get_account_with_commitment(&destination_ata, rpc_client.commitment())
+            .await
+            .map_err(|e| format!("Failed to look up destination token account: {}", e))?;
+        if destination_account.value.is_none() {
+            return Err(format!(
+                "Destination token account {} does not exist (pass --create-ata to create it)",
+                destination_ata
+            ));
+        }
+    }
+
+    instructions.push(
+        spl_token::instruction::transfer_checked(
+            &spl_token::id(),
+            &source_ata,
+            &mint,
+            &destination_ata,
+            from,
+            &[],
+            amount,
+            token.decimals,
+        )
+        .map_err(|e| format!("Failed to build token transfer: {}", e))?,
+    );
+
+    let mut reclaimed_rent = None;
+    if let Some(close_to) = close_to {
+        let source_account = rpc_client
+            .get_account_with_commitment(&source_ata, rpc_client.commitment())
+            .await
+            .map_err(|e| format!("Failed to look up source token account: {}", e))?
+            .value;
+        // A missing source account fails the transfer itself
+        if let Some(source_account) = source_account {
+            let state = spl_token::state::Account::unpack(&source_account.data)
+                .map_err(|e| format!("Invalid source token account {}: {}", source_ata, e))?;
+            if state.amount == amount {
+                instructions.push(
+                    spl_token::instruction::close_account(
+                        &spl_token::id(),
+                        &source_ata,
+                        close_to,
+                        from,
+                        &[],
+                    )
+                    .map_err(|e| format!("Failed to build close account: {}", e))?,
+                );
+                // Wrapped SOL accounts hold the transferred amount as lamports too
+                reclaimed_rent = Some(match state.is_native {
+                    COption::Some(rent_exempt_reserve) => rent_exempt_reserve,
+                    COption::None => source_account.lamports,
+                });
+            }
+        }
+    }
+
+    Ok(TokenTransfer {
+        instructions,
+        reclaimed_rent,
+    })
+}
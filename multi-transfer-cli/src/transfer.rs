@@ -0,0 +1,1071 @@
+use crate::amount::{self, SOL_DECIMALS};
+use crate::blockhash::BlockhashCache;
+use crate::confirm::BatchConfirmer;
+use crate::memo;
+use crate::priority_fee::AutoPriorityFee;
+use crate::retry;
+use crate::token;
+use crate::types::{TransferResult, TransferSpec, TransferStatus};
+use chrono::Utc;
+use common::{SolanaRpc, keypair};
+use solana_client::client_error::{ClientError, ClientErrorKind};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_client::rpc_config::{
+    RpcSendTransactionConfig, RpcSimulateTransactionAccountsConfig, RpcSimulateTransactionConfig,
+    RpcTransactionConfig,
+};
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    compute_budget::ComputeBudgetInstruction,
+    hash::Hash,
+    instruction::Instruction,
+    pubkey::Pubkey,
+    signature::{Keypair, Signature},
+    signer::Signer,
+    system_instruction,
+    transaction::{Transaction, TransactionError},
+};
+use solana_transaction_status::UiTransactionEncoding;
+use std::{collections::HashMap, io, str::FromStr, sync::Arc, time::Duration};
+use tokio::time::{Instant, sleep, timeout};
+use tracing::{info, warn};
+
+/// What happened to a transaction, before timing and amounts are attached
+/// to each of its transfers
+struct Outcome {
+    from: String,
+    signature: Signature,
+    status: TransferStatus,
+    priority_fee: Option<u64>,
+    attempts: u32,
+    /// Per transfer of the batch, rent of the token account it closes
+    reclaimed_rent: Vec<Option<u64>>,
+}
+
+impl Outcome {
+    fn failed(from: String, error: String) -> Self {
+        Self {
+            from,
+            signature: Signature::default(),
+            status: TransferStatus::Failed(error),
+            priority_fee: None,
+            attempts: 1,
+            reclaimed_rent: Vec::new(),
+        }
+    }
+}
+
+/// The keypair and instructions of a transaction, ready to be signed
+struct Prepared {
+    from_keypair: Keypair,
+    instructions: Vec<Instruction>,
+    priority_fee: Option<u64>,
+    /// Per transfer of the batch, rent of the token account it closes
+    reclaimed_rent: Vec<Option<u64>>,
+}
+
+/// Settings shared by every transfer of a run
+pub struct ExecutorOptions<R = RpcClient> {
+    /// How long to wait for a sent transaction to confirm
+    pub timeout: Duration,
+    /// Timeout of a single send request
+    pub rpc_timeout: Duration,
+    pub dry_run: bool,
+    pub create_ata: bool,
+    /// Close source token accounts a transfer empties, sending the rent here.
+    /// `None` in the inner option means to the source wallet
+    pub close_token_accounts: Option<Option<Pubkey>>,
+    pub max_retries: u32,
+    /// Re-attempts of a whole transfer with a fresh blockhash
+    pub retries: u32,
+    /// First delay of both kinds of retries
+    pub retry_backoff: Duration,
+    /// Estimate a compute unit price for transfers without a fixed one
+    pub auto_priority_fee: Option<AutoPriorityFee>,
+    /// Time between two status polls when confirming on its own
+    pub poll_interval: Duration,
+    /// Confirm through the shared poller instead of polling every signature on its own
+    pub batch_confirmer: Option<BatchConfirmer<R>>,
+}
+
+/// Builds, signs and either sends or simulates a single transfer
+pub struct TransferExecutor<R = RpcClient> {
+    rpc_client: Arc<R>,
+    blockhash_cache: BlockhashCache<R>,
+    options: ExecutorOptions<R>,
+}
+
+impl<R: SolanaRpc + 'static> TransferExecutor<R> {
+    pub fn new(
+        rpc_client: Arc<R>,
+        blockhash_cache: BlockhashCache<R>,
+        options: ExecutorOptions<R>,
+    ) -> Self {
+        Self {
+            rpc_client,
+            blockhash_cache,
+            options,
+        }
+    }
+
+    /// Sends `batch` as a single transaction. All transfers must come from the
+    /// same source and share one result status and signature. `on_sent` is
+    /// called with every signature that was sent and the blockhash it was
+    /// signed with, more than once on retries
+    pub async fn execute(
+        &self,
+        batch: Vec<TransferSpec>,
+        on_sent: impl Fn(Signature, Hash) + Sync,
+    ) -> Vec<TransferResult> {
+        let started_at = Utc::now();
+        let start = Instant::now();
+
+        let outcome = self.run(&batch, &on_sent).await;
+        // Failed transactions that landed paid their fee too
+        let landed = match outcome.status {
+            TransferStatus::Success | TransferStatus::Failed(_)
+                if outcome.signature != Signature::default() =>
+            {
+                fetch_landed(&*self.rpc_client, &outcome.signature).await
+            }
+            // Split over the batch like a paid fee, so per-transfer estimates add up
+            TransferStatus::Simulated { fee_lamports, .. } => Landed {
+                slot: None,
+                fee: Some(fee_lamports),
+            },
+            _ => Landed::default(),
+        };
+
+        let duration_ms = start.elapsed().as_millis() as u64;
+        let finished_at = Utc::now();
+        let batch_len = batch.len();
+        batch
+            .into_iter()
+            .enumerate()
+            .map(|(i, transfer)| {
+                let decimals = transfer
+                    .token
+                    .as_ref()
+                    .map_or(SOL_DECIMALS, |token| token.decimals);
+
+                TransferResult {
+                    from: outcome.from.clone(),
+                    to: transfer.to_address,
+                    amount: amount::to_ui_amount(transfer.amount, decimals),
+                    lamports: transfer.amount,
+                    token_mint: transfer.token.map(|token| token.mint),
+                    signature: outcome.signature,
+                    priority_fee_micro_lamports: outcome.priority_fee,
+                    fee_lamports: landed.fee.map(|fee| fee_share(fee, batch_len, i)),
+                    slot: landed.slot,
+                    duration_ms,
+                    attempts: outcome.attempts,
+                    status: outcome.status.clone(),
+                    // Only reclaimed if the transaction went through
+                    reclaimed_rent_lamports: match outcome.status {
+                        TransferStatus::Success | TransferStatus::Simulated { .. } => {
+                            outcome.reclaimed_rent.get(i).copied().flatten()
+                        }
+                        TransferStatus::Failed(_) | TransferStatus::Timeout => None,
+                    },
+                    created_account: transfer.new_account
+                        && matches!(outcome.status, TransferStatus::Success),
+                    rent_top_up_lamports: (transfer.rent_top_up > 0)
+                        .then_some(transfer.rent_top_up),
+                    memo: transfer.memo,
+                    verification: None,
+                    started_at,
+                    finished_at,
+                }
+            })
+            .collect()
+    }
+
+    /// Loads the keypair and builds the instructions of `batch`. Fails with
+    /// the outcome to report if any transfer can't be built
+    async fn prepare(&self, batch: &[TransferSpec]) -> Result<Prepared, Outcome> {
+        let Some(first) = batch.first() else {
+            return Err(Outcome::failed(
+                String::new(),
+                "Empty transfer batch".to_string(),
+            ));
+        };
+        // Inline secrets must not end up in logs or results
+        let keypair_source = keypair::describe(&first.from_keypair_path);
+
+        // Load keypair
+        let from_keypair = match keypair::load_keypair(&first.from_keypair_path) {
+            Ok(kp) => kp,
+            Err(e) => {
+                warn!("Failed: Keypair loading error: {}", e);
+                return Err(Outcome::failed(
+                    keypair_source,
+                    format!("Keypair loading error: {}", e),
+                ));
+            }
+        };
+
+        let from_pubkey = from_keypair.pubkey();
+
+        // Parse destination addresses
+        let mut to_pubkeys = Vec::new();
+        for transfer in batch {
+            match Pubkey::from_str(&transfer.to_address) {
+                Ok(pk) => to_pubkeys.push(pk),
+                Err(e) => {
+                    warn!("Failed: Invalid destination address: {}", e);
+                    return Err(Outcome::failed(
+                        from_pubkey.to_string(),
+                        format!("Invalid destination address {}: {}", transfer.to_address, e),
+                    ));
+                }
+            }
+        }
+
+        let priority_fee = match (first.compute_unit_price, &self.options.auto_priority_fee) {
+            (Some(price), _) => Some(price),
+            (None, Some(auto)) => {
+                let accounts: Vec<_> = std::iter::once(from_pubkey)
+                    .chain(to_pubkeys.iter().copied())
+                    .collect();
+                match auto.estimate(&*self.rpc_client, &accounts).await {
+                    Ok(price) => Some(price),
+                    Err(e) => {
+                        warn!(
+                            "Failed to estimate priority fee, sending without one: {}",
+                            e
+                        );
+                        None
+                    }
+                }
+            }
+            (None, None) => None,
+        };
+
+        // Compute budget instructions go first, followed by the transfers
+        let mut instructions = Vec::new();
+        let compute_unit_limit = first.compute_unit_limit.or_else(|| {
+            // Only pay the priority fee for the units the transfers actually need
+            priority_fee.map(|_| {
+                batch
+                    .iter()
+                    .map(TransferSpec::default_compute_unit_limit)
+                    .sum()
+            })
+        });
+        if let Some(limit) = compute_unit_limit {
+            instructions.push(ComputeBudgetInstruction::set_compute_unit_limit(limit));
+        }
+        if let Some(price) = priority_fee {
+            info!(
+                "Using priority fee of {} micro-lamports per CU for {}",
+                price, from_pubkey
+            );
+            instructions.push(ComputeBudgetInstruction::set_compute_unit_price(price));
+        }
+
+        let rent_recipient = self
+            .options
+            .close_token_accounts
+            .map(|recipient| recipient.unwrap_or(from_pubkey));
+        let mut reclaimed_rent = Vec::with_capacity(batch.len());
+        for (transfer, to_pubkey) in batch.iter().zip(to_pubkeys) {
+            // Create transfer instructions
+            match &transfer.token {
+                Some(token) => {
+                    match token::build_transfer_instructions(
+                        &*self.rpc_client,
+                        token,
+                        &from_pubkey,
+                        &to_pubkey,
+                        transfer.amount,
+                        self.options.create_ata,
+                        rent_recipient.as_ref(),
+                    )
+                    .await
+                    {
+                        Ok(token_transfer) => {
+                            if let Some(rent) = token_transfer.reclaimed_rent {
+                                info!(
+                                    "Transfer empties the {} account of {}, closing it to reclaim {} lamports",
+                                    token.mint, from_pubkey, rent
+                                );
+                            }
+                            instructions.extend(token_transfer.instructions);
+                            reclaimed_rent.push(token_transfer.reclaimed_rent);
+                        }
+                        Err(e) => {
+                            warn!("Failed: {}", e);
+                            return Err(Outcome::failed(from_pubkey.to_string(), e));
+                        }
+                    }
+                }
+                None => {
+                    instructions.push(system_instruction::transfer(
+                        &from_pubkey,
+                        &to_pubkey,
+                        transfer.amount,
+                    ));
+                    reclaimed_rent.push(None);
+                }
+            }
+            if let Some(memo) = &transfer.memo {
+                instructions.push(memo::instruction(memo, &from_pubkey));
+            }
+        }
+
+        info!(
+            "Creating transaction from {} with {} transfers",
+            from_pubkey,
+            batch.len()
+        );
+
+        Ok(Prepared {
+            from_keypair,
+            instructions,
+            priority_fee,
+            reclaimed_rent,
+        })
+    }
+
+    async fn run(
+        &self,
+        batch: &[TransferSpec],
+        on_sent: &(dyn Fn(Signature, Hash) + Sync),
+    ) -> Outcome {
+        let prepared = match self.prepare(batch).await {
+            Ok(prepared) => prepared,
+            Err(outcome) => return outcome,
+        };
+        let from_pubkey = prepared.from_keypair.pubkey();
+
+        let sign = |recent_blockhash| {
+            Transaction::new_signed_with_payer(
+                &prepared.instructions,
+                Some(&from_pubkey),
+                &[&prepared.from_keypair],
+                recent_blockhash,
+            )
+        };
+
+        let (signature, status, attempts) = if self.options.dry_run {
+            // Create transaction with the latest cached blockhash
+            let tx = sign(self.blockhash_cache.get().await);
+            (tx.signatures[0], self.simulate(&tx, &from_pubkey).await, 1)
+        } else {
+            self.send_and_confirm(&sign, on_sent).await
+        };
+
+        Outcome {
+            from: from_pubkey.to_string(),
+            signature,
+            status,
+            priority_fee: prepared.priority_fee,
+            attempts,
+            reclaimed_rent: prepared.reclaimed_rent,
+        }
+    }
+
+    /// Builds `batch` and signs it with `blockhash` without sending it
+    pub async fn sign(
+        &self,
+        batch: &[TransferSpec],
+        blockhash: Hash,
+    ) -> Result<Transaction, String> {
+        let prepared = match self.prepare(batch).await {
+            Ok(prepared) => prepared,
+            Err(outcome) => return Err(outcome.status.error().unwrap_or_default().to_string()),
+        };
+        let from_pubkey = prepared.from_keypair.pubkey();
+        Ok(Transaction::new_signed_with_payer(
+            &prepared.instructions,
+            Some(&from_pubkey),
+            &[&prepared.from_keypair],
+            blockhash,
+        ))
+    }
+
+    /// Sends a transaction signed elsewhere and waits for it to confirm.
+    /// Without the keypair it can't be re-signed, so `retries` don't apply
+    pub async fn send_signed(&self, tx: &Transaction) -> TransferStatus {
+        match self.send(tx).await {
+            Ok(signature) => self.confirm(&signature).await,
+            Err(e) => {
+                warn!("Failed to send transaction: {}", e);
+                TransferStatus::Failed(format!("Send error: {}", e))
+            }
+        }
+    }
+
+    /// Sends the transaction signed with the latest cached blockhash and waits
+    /// for it to confirm. After a transient send error or a confirmation timeout
+    /// it is re-signed with a fresh blockhash, up to `retries` more times, once
+    /// the previous one can no longer land.
+    /// Returns the signature, its status and the number of attempts
+    async fn send_and_confirm(
+        &self,
+        sign: &impl Fn(Hash) -> Transaction,
+        on_sent: &(dyn Fn(Signature, Hash) + Sync),
+    ) -> (Signature, TransferStatus, u32) {
+        let max_attempts = self.options.retries + 1;
+        let mut recent_blockhash = self.blockhash_cache.get().await;
+        let mut attempt = 1;
+
+        loop {
+            let tx = sign(recent_blockhash);
+            let signature = tx.signatures[0];
+            let reason = match self.send(&tx).await {
+                Ok(signature) => {
+                    on_sent(signature, recent_blockhash);
+                    match self.confirm(&signature).await {
+                        TransferStatus::Timeout if attempt < max_attempts => {
+                            if let Err(status) = self
+                                .wait_until_replaceable(&signature, &recent_blockhash)
+                                .await
+                            {
+                                return (signature, status, attempt);
+                            }
+                            format!("{} never landed", signature)
+                        }
+                        status => return (signature, status, attempt),
+                    }
+                }
+                // The node may have taken it and only the response got lost
+                Err(e) if may_have_been_sent(&e) => {
+                    warn!("Failed to send transaction {}: {}", signature, e);
+                    on_sent(signature, recent_blockhash);
+                    if attempt >= max_attempts {
+                        return (signature, TransferStatus::Timeout, attempt);
+                    }
+                    if let Err(status) = self
+                        .wait_until_replaceable(&signature, &recent_blockhash)
+                        .await
+                    {
+                        return (signature, status, attempt);
+                    }
+                    format!("send error: {}, {} never landed", e, signature)
+                }
+                Err(e) => {
+                    warn!("Failed to send transaction: {}", e);
+                    // An expired cached blockhash always gets one fresh attempt
+                    let retryable = if is_blockhash_expired(&e) {
+                        attempt < max_attempts.max(2)
+                    } else {
+                        attempt < max_attempts && common::retry::is_retryable(&e)
+                    };
+                    if !retryable {
+                        return (
+                            Signature::default(),
+                            TransferStatus::Failed(format!("Send error: {}", e)),
+                            attempt,
+                        );
+                    }
+                    format!("send error: {}", e)
+                }
+            };
+
+            let delay = retry::backoff_delay(self.options.retry_backoff, attempt - 1);
+            attempt += 1;
+            warn!(
+                "Retrying transfer with a fresh blockhash in {}ms ({}), attempt {}",
+                delay.as_millis(),
+                reason,
+                attempt
+            );
+            sleep(delay).await;
+
+            recent_blockhash = match self.blockhash_cache.refresh().await {
+                Ok(blockhash) => blockhash,
+                Err(e) => {
+                    warn!("{:#}", e);
+                    self.blockhash_cache.get().await
+                }
+            };
+        }
+    }
+
+    /// Resending while a transaction can still land could pay twice, so this
+    /// waits until `signature` can no longer land. `Err` carries the status to
+    /// return instead of retrying, the one it landed with or `Timeout` if that
+    /// can't be told
+    async fn wait_until_replaceable(
+        &self,
+        signature: &Signature,
+        blockhash: &Hash,
+    ) -> Result<(), TransferStatus> {
+        match self.status_after_expiry(signature, blockhash).await {
+            Ok(None) => Ok(()),
+            Ok(Some(status)) => {
+                info!("{} landed without being confirmed in time", signature);
+                Err(status)
+            }
+            Err(e) => {
+                warn!(
+                    "Cannot tell whether {} landed, not retrying: {}",
+                    signature, e
+                );
+                Err(TransferStatus::Timeout)
+            }
+        }
+    }
+
+    /// Waits until `blockhash` expired, so a transaction signed with it can no
+    /// longer land, then looks up the final status of `signature`.
+    /// `None` means it never landed and is safe to replace
+    async fn status_after_expiry(
+        &self,
+        signature: &Signature,
+        blockhash: &Hash,
+    ) -> Result<Option<TransferStatus>, ClientError> {
+        info!(
+            "Waiting for the blockhash of {} to expire before retrying",
+            signature
+        );
+        loop {
+            let valid = retry::with_backoff(
+                "Checking blockhash validity",
+                self.options.max_retries,
+                self.options.retry_backoff,
+                || {
+                    self.rpc_client
+                        .is_blockhash_valid(blockhash, CommitmentConfig::processed())
+                },
+            )
+            .await?;
+            if !valid {
+                break;
+            }
+            sleep(Duration::from_secs(2)).await;
+        }
+
+        let signatures = [*signature];
+        let statuses = retry::with_backoff(
+            "Checking signature status",
+            self.options.max_retries,
+            self.options.retry_backoff,
+            || self.rpc_client.get_signature_statuses(&signatures),
+        )
+        .await?;
+
+        Ok(statuses
+            .value
+            .into_iter()
+            .next()
+            .flatten()
+            .map(|status| match status.err {
+                None => TransferStatus::Success,
+                Some(e) => TransferStatus::Failed(format!("Transaction error: {:?}", e)),
+            }))
+    }
+
+    async fn send(&self, tx: &Transaction) -> Result<Signature, ClientError> {
+        info!("Sending transaction...");
+
+        retry::with_backoff(
+            "Sending transaction",
+            self.options.max_retries,
+            self.options.retry_backoff,
+            || async {
+                let send =
+                    self.rpc_client
+                        .send_transaction_with_config(tx, RpcSendTransactionConfig {
+                            skip_preflight: false,
+                            preflight_commitment: Some(CommitmentConfig::confirmed().commitment),
+                            encoding: None,
+                            max_retries: Some(5),
+                            min_context_slot: None,
+                        });
+
+                // Don't let a stuck endpoint hold the transfer forever. Re-sending the
+                // same signed transaction after a timeout can't double-spend
+                match timeout(self.options.rpc_timeout, send).await {
+                    Ok(result) => result,
+                    Err(_) => Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!(
+                            "send timed out after {}s",
+                            self.options.rpc_timeout.as_secs()
+                        ),
+                    )
+                    .into()),
+                }
+            },
+        )
+        .await
+    }
+
+    async fn confirm(&self, signature: &Signature) -> TransferStatus {
+        info!("Confirming transaction: {}", signature);
+
+        // Wait for confirmation
+        let start = Instant::now();
+        let status_result = match &self.options.batch_confirmer {
+            Some(confirmer) => confirmer.wait(*signature, self.options.timeout).await,
+            None => {
+                self.poll_status(signature, start + self.options.timeout)
+                    .await
+            }
+        };
+
+        match status_result {
+            Some(Ok(())) => {
+                info!(
+                    "Success: {} in {}ms",
+                    signature,
+                    start.elapsed().as_millis()
+                );
+                TransferStatus::Success
+            }
+            Some(Err(e)) => {
+                warn!("Failed: {}", e);
+                TransferStatus::Failed(format!("Transaction error: {:?}", e))
+            }
+            None => {
+                warn!("Timeout while confirming transaction");
+                TransferStatus::Timeout
+            }
+        }
+    }
+
+    /// Polls the status of `signature` on its own until it resolves or `end_time` passes
+    async fn poll_status(
+        &self,
+        signature: &Signature,
+        end_time: Instant,
+    ) -> Option<Result<(), TransactionError>> {
+        while Instant::now() < end_time {
+            let status = retry::with_backoff(
+                "Checking signature status",
+                self.options.max_retries,
+                self.options.retry_backoff,
+                || self.rpc_client.get_signature_status(signature),
+            )
+            .await;
+            match status {
+                Ok(Some(status)) => return Some(status),
+                Ok(None) => {
+                    sleep(self.options.poll_interval).await;
+                }
+                Err(e) => {
+                    warn!("Error checking signature status: {}", e);
+                    sleep(self.options.poll_interval * 2).await;
+                }
+            }
+        }
+        None
+    }
+
+    /// Simulates the transaction instead of sending it. No confirmation loop is
+    /// needed, so the timeout does not apply here.
+    async fn simulate(&self, tx: &Transaction, from_pubkey: &Pubkey) -> TransferStatus {
+        info!("Simulating transaction...");
+
+        let fee_lamports = match self.rpc_client.get_fee_for_message(&tx.message).await {
+            Ok(fee) => fee,
+            Err(e) => {
+                warn!("Failed to estimate fee: {}", e);
+                return TransferStatus::Failed(format!("Fee estimation error: {}", e));
+            }
+        };
+
+        let simulation = match self
+            .rpc_client
+            .simulate_transaction_with_config(tx, RpcSimulateTransactionConfig {
+                sig_verify: false,
+                commitment: Some(CommitmentConfig::confirmed()),
+                accounts: Some(RpcSimulateTransactionAccountsConfig {
+                    encoding: None,
+                    addresses: vec![from_pubkey.to_string()],
+                }),
+                ..RpcSimulateTransactionConfig::default()
+            })
+            .await
+        {
+            Ok(response) => response.value,
+            Err(e) => {
+                warn!("Failed to simulate transaction: {}", e);
+                return TransferStatus::Failed(format!("Simulation error: {}", e));
+            }
+        };
+
+        let logs = simulation.logs.unwrap_or_default();
+
+        if let Some(e) = simulation.err {
+            warn!("Simulation failed: {}", e);
+            return TransferStatus::Failed(format!(
+                "Simulated transaction error: {:?}; logs: [{}]",
+                e,
+                logs.join(" | ")
+            ));
+        }
+
+        let post_balance_lamports = simulation
+            .accounts
+            .and_then(|accounts| accounts.into_iter().next().flatten())
+            .map(|account| account.lamports);
+
+        info!(
+            "Simulation succeeded, estimated fee: {} lamports, compute units: {:?}",
+            fee_lamports, simulation.units_consumed
+        );
+
+        TransferStatus::Simulated {
+            fee_lamports,
+            post_balance_lamports,
+            units_consumed: simulation.units_consumed,
+            logs,
+        }
+    }
+}
+
+/// Most signatures `getSignatureStatuses` accepts per call
+const MAX_SIGNATURE_STATUSES: usize = 256;
+
+/// Looks up timed-out transfers in the transaction history, which needs an
+/// archival RPC for older signatures. Transfers found confirmed become
+/// successful, those found failed get the transaction error. Returns the
+/// indices of the updated results
+pub async fn recheck_timeouts_with_history(
+    rpc_client: &RpcClient,
+    results: &mut [TransferResult],
+) -> Vec<usize> {
+    let mut signatures: Vec<Signature> = results
+        .iter()
+        .filter(|result| {
+            matches!(result.status, TransferStatus::Timeout)
+                && result.signature != Signature::default()
+        })
+        .map(|result| result.signature)
+        .collect();
+    // Transfers packed into one transaction share a signature
+    signatures.sort_unstable();
+    signatures.dedup();
+
+    let mut statuses = HashMap::new();
+    for chunk in signatures.chunks(MAX_SIGNATURE_STATUSES) {
+        match rpc_client.get_signature_statuses_with_history(chunk).await {
+            Ok(response) => {
+                for (signature, status) in chunk.iter().zip(response.value) {
+                    // Only trust statuses the cluster won't roll back
+                    if let Some(status) = status
+                        && status.satisfies_commitment(CommitmentConfig::confirmed())
+                    {
+                        statuses.insert(*signature, status.err);
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to look up timed-out transfers in history: {}", e),
+        }
+    }
+
+    let mut updated = Vec::new();
+    for (i, result) in results.iter_mut().enumerate() {
+        if !matches!(result.status, TransferStatus::Timeout) {
+            continue;
+        }
+        let Some(err) = statuses.get(&result.signature) else {
+            continue;
+        };
+        result.status = match err {
+            None => TransferStatus::Success,
+            Some(e) => TransferStatus::Failed(format!("Transaction error: {:?}", e)),
+        };
+        info!(
+            "Timed-out transfer {} -> {} found in history: {}",
+            result.from,
+            result.to,
+            result.status.label()
+        );
+        updated.push(i);
+    }
+
+    updated
+}
+
+/// Where a transaction landed and what it paid, as recorded on chain
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Landed {
+    pub slot: Option<u64>,
+    pub fee: Option<u64>,
+}
+
+/// Slot and fee of `signature` from its transaction, both `None` if the
+/// transaction can't be found
+pub async fn fetch_landed<R: SolanaRpc>(rpc_client: &R, signature: &Signature) -> Landed {
+    let config = RpcTransactionConfig {
+        encoding: Some(UiTransactionEncoding::Base64),
+        commitment: Some(CommitmentConfig::confirmed()),
+        max_supported_transaction_version: Some(0),
+    };
+    match rpc_client
+        .get_transaction_with_config(signature, config)
+        .await
+    {
+        Ok(transaction) => Landed {
+            slot: Some(transaction.slot),
+            fee: transaction.transaction.meta.map(|meta| meta.fee),
+        },
+        Err(e) => {
+            warn!("Failed to look up the fee and slot of {}: {}", signature, e);
+            Landed::default()
+        }
+    }
+}
+
+/// Fetches the fees and slots of transfers that only turned out to have
+/// landed after the run, e.g. found in history, splitting each fee like
+/// `execute` does
+pub async fn fetch_missing_fees(rpc_client: &RpcClient, results: &mut [TransferResult]) {
+    let mut signatures: Vec<Signature> = results
+        .iter()
+        .filter(|result| {
+            result.fee_lamports.is_none()
+                && result.signature != Signature::default()
+                && matches!(
+                    result.status,
+                    TransferStatus::Success | TransferStatus::Failed(_)
+                )
+        })
+        .map(|result| result.signature)
+        .collect();
+    signatures.sort_unstable();
+    signatures.dedup();
+
+    for signature in signatures {
+        let landed = fetch_landed(rpc_client, &signature).await;
+        let Some(fee) = landed.fee else {
+            continue;
+        };
+        let rows: Vec<&mut TransferResult> = results
+            .iter_mut()
+            .filter(|result| result.signature == signature)
+            .collect();
+        let count = rows.len();
+        for (i, result) in rows.into_iter().enumerate() {
+            result.fee_lamports = Some(fee_share(fee, count, i));
+            result.slot = landed.slot;
+        }
+    }
+}
+
+/// Share of a transaction's fee for its `i`th of `count` transfers. The first
+/// one takes the remainder, so the shares add up to the fee
+pub fn fee_share(fee: u64, count: usize, i: usize) -> u64 {
+    let count = count.max(1) as u64;
+    fee / count + if i == 0 { fee % count } else { 0 }
+}
+
+fn is_blockhash_expired(e: &ClientError) -> bool {
+    e.get_transaction_error() == Some(TransactionError::BlockhashNotFound)
+}
+
+/// Whether a failed send may still have reached the node, because the request
+/// timed out rather than being refused or answered with an error
+fn may_have_been_sent(e: &ClientError) -> bool {
+    match e.kind() {
+        ClientErrorKind::Io(e) => e.kind() == io::ErrorKind::TimedOut,
+        ClientErrorKind::Reqwest(e) => e.is_timeout() && !e.is_connect(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use common::mock::MockRpc;
+    use std::sync::Mutex;
+
+    fn options(retries: u32) -> ExecutorOptions<MockRpc> {
+        ExecutorOptions {
+            timeout: Duration::from_secs(5),
+            rpc_timeout: Duration::from_secs(5),
+            dry_run: false,
+            create_ata: false,
+            close_token_accounts: None,
+            max_retries: 0,
+            retries,
+            retry_backoff: Duration::from_millis(10),
+            auto_priority_fee: None,
+            poll_interval: Duration::from_millis(500),
+            batch_confirmer: None,
+        }
+    }
+
+    pub(crate) async fn executor(rpc: &Arc<MockRpc>, retries: u32) -> TransferExecutor<MockRpc> {
+        let cache = BlockhashCache::new(rpc.clone()).await.unwrap();
+        TransferExecutor::new(rpc.clone(), cache, options(retries))
+    }
+
+    pub(crate) fn transfer(from: &Keypair) -> TransferSpec {
+        TransferSpec {
+            from_keypair_path: format!("base58:{}", from.to_base58_string()),
+            to_address: Pubkey::new_unique().to_string(),
+            amount: 1_000_000,
+            token: None,
+            compute_unit_limit: None,
+            compute_unit_price: None,
+            new_account: false,
+            rent_top_up: 0,
+            memo: None,
+        }
+    }
+
+    async fn execute(executor: &TransferExecutor<MockRpc>) -> TransferResult {
+        let mut results = executor
+            .execute(vec![transfer(&Keypair::new())], |_, _| {})
+            .await;
+        assert_eq!(results.len(), 1);
+        results.remove(0)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lands_on_the_first_attempt() {
+        let rpc = Arc::new(MockRpc::new());
+        let result = execute(&executor(&rpc, 2).await).await;
+
+        assert!(matches!(result.status, TransferStatus::Success));
+        assert_eq!(result.attempts, 1);
+        assert_eq!(
+            result.signature,
+            rpc.state().sends[0].transaction.signatures[0]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_without_retries() {
+        let rpc = Arc::new(MockRpc::new());
+        rpc.state().land_sent = false;
+        let result = execute(&executor(&rpc, 0).await).await;
+
+        assert!(matches!(result.status, TransferStatus::Timeout));
+        assert_eq!(result.attempts, 1);
+        assert_eq!(rpc.state().sends.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resends_a_timed_out_transfer_only_after_its_blockhash_expired() {
+        let rpc = Arc::new(MockRpc::new());
+        rpc.state().land_sent = false;
+        let result = execute(&executor(&rpc, 1).await).await;
+
+        assert!(matches!(result.status, TransferStatus::Timeout));
+        assert_eq!(result.attempts, 2);
+        let state = rpc.state();
+        assert_eq!(state.sends.len(), 2);
+        let (first, second) = (&state.sends[0], &state.sends[1]);
+        assert!(second.started - first.started >= state.blockhash_ttl);
+        assert_ne!(
+            first.transaction.message.recent_blockhash,
+            second.transaction.message.recent_blockhash
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_a_rejected_send_with_a_fresh_blockhash() {
+        let rpc = Arc::new(MockRpc::new());
+        rpc.fail_next(
+            "send_transaction",
+            ClientErrorKind::Custom("HTTP status client error (429 Too Many Requests)".to_string()),
+        );
+        let result = execute(&executor(&rpc, 1).await).await;
+
+        assert!(matches!(result.status, TransferStatus::Success));
+        assert_eq!(result.attempts, 2);
+        let state = rpc.state();
+        assert_eq!(state.sends.len(), 2);
+        assert_ne!(
+            state.sends[0].transaction.message.recent_blockhash,
+            state.sends[1].transaction.message.recent_blockhash
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_a_transaction_error() {
+        let rpc = Arc::new(MockRpc::new());
+        rpc.fail_next(
+            "send_transaction",
+            TransactionError::InsufficientFundsForFee,
+        );
+        let result = execute(&executor(&rpc, 3).await).await;
+
+        assert!(matches!(result.status, TransferStatus::Failed(_)));
+        assert_eq!(result.attempts, 1);
+        assert_eq!(result.signature, Signature::default());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_cached_blockhash_gets_a_fresh_attempt_even_without_retries() {
+        let rpc = Arc::new(MockRpc::new());
+        let executor = executor(&rpc, 0).await;
+        tokio::time::advance(Duration::from_secs(61)).await;
+        let result = execute(&executor).await;
+
+        assert!(matches!(result.status, TransferStatus::Success));
+        assert_eq!(result.attempts, 2);
+        let state = rpc.state();
+        assert!(!state.sends[0].accepted);
+        assert!(state.sends[1].accepted);
+    }
+
+    fn send_timed_out() -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, "send timed out after 5s")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn does_not_resign_a_send_that_timed_out_but_landed() {
+        let rpc = Arc::new(MockRpc::new());
+        rpc.lose_next_send_response(send_timed_out());
+        let executor = executor(&rpc, 3).await;
+        let sent = Mutex::new(Vec::new());
+        let result = executor
+            .execute(vec![transfer(&Keypair::new())], |signature, _| {
+                sent.lock().unwrap().push(signature)
+            })
+            .await
+            .remove(0);
+
+        assert!(matches!(result.status, TransferStatus::Success));
+        assert_eq!(result.attempts, 1);
+        let state = rpc.state();
+        assert_eq!(state.sends.len(), 1);
+        assert_eq!(result.signature, state.sends[0].transaction.signatures[0]);
+        assert_eq!(*sent.lock().unwrap(), vec![result.signature]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resends_a_timed_out_send_once_its_blockhash_expired() {
+        let rpc = Arc::new(MockRpc::new());
+        rpc.state().land_sent = false;
+        rpc.lose_next_send_response(send_timed_out());
+        let executor = executor(&rpc, 1).await;
+        tokio::spawn({
+            let rpc = rpc.clone();
+            async move {
+                // Only sends after the first one land
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                rpc.state().land_sent = true;
+            }
+        });
+        let result = execute(&executor).await;
+
+        assert!(matches!(result.status, TransferStatus::Success));
+        assert_eq!(result.attempts, 2);
+        let state = rpc.state();
+        assert_eq!(state.sends.len(), 2);
+        assert!(state.sends[1].started - state.sends[0].started >= state.blockhash_ttl);
+        assert_eq!(result.signature, state.sends[1].transaction.signatures[0]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_the_signature_of_a_timed_out_send_without_retries() {
+        let rpc = Arc::new(MockRpc::new());
+        rpc.lose_next_send_response(send_timed_out());
+        let result = execute(&executor(&rpc, 0).await).await;
+
+        assert!(matches!(result.status, TransferStatus::Timeout));
+        assert_eq!(
+            result.signature,
+            rpc.state().sends[0].transaction.signatures[0]
+        );
+    }
+}
@@ -1,40 +1,413 @@
-use serde::Deserialize;
-use solana_sdk::signature::Signature;
+use crate::amount::Amount;
+use chrono::{DateTime, Utc};
+use common::{RpcUrls, lamports_to_sol};
+use serde::{Deserialize, Serialize, Serializer, ser::SerializeMap};
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+use std::fmt;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
-    pub rpc_url: String,
-    pub amount: f64,
+    /// One endpoint or a list, sends and confirmations rotate through them
+    pub rpc_url: RpcUrls,
+    /// Default amount for every transfer
+    pub amount: Option<Amount>,
+    /// Whether transfers come from `destination_wallets` or `transfers`,
+    /// inferred from which of them is set when omitted
+    pub mode: Option<Mode>,
+    #[serde(default)]
     pub source_wallets: Vec<SourceWallet>,
+    /// Every source sends to every destination in `fanout` mode. Can't be combined with `transfers`
+    #[serde(default)]
     pub destination_wallets: Vec<String>,
+    /// Explicit source/destination pairs sent in `pairs` mode
+    #[serde(default)]
+    pub transfers: Vec<TransferPair>,
+    /// Send this SPL token instead of SOL, `amount` is then in UI units
+    #[serde(alias = "mint")]
+    pub token_mint: Option<String>,
+    /// Decimals of `token_mint`, read from the mint account when not set
+    pub decimals: Option<u8>,
+    /// Maximum in-flight transfers per source wallet, on top of `--concurrent`
+    pub max_concurrent_per_source: Option<usize>,
+    /// Compute unit limit requested by every transfer
+    pub compute_unit_limit: Option<u32>,
+    /// Priority fee in micro-lamports per compute unit
+    pub compute_unit_price: Option<u64>,
+    /// Memo attached to every transfer, may contain `{destination}` and `{index}`
+    pub memo: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct SourceWallet {
     pub from_keypair_path: String,
-    pub amount: Option<f64>,
+    pub amount: Option<Amount>,
+    /// Overrides the global `token_mint` for this wallet
+    #[serde(alias = "mint")]
+    pub token_mint: Option<String>,
+    /// Overrides the global `decimals` for this wallet
+    pub decimals: Option<u8>,
+    /// Overrides the global `max_concurrent_per_source` for this wallet
+    pub max_concurrent: Option<usize>,
+    /// Overrides the global `compute_unit_limit` for this wallet
+    pub compute_unit_limit: Option<u32>,
+    /// Overrides the global `compute_unit_price` for this wallet
+    pub compute_unit_price: Option<u64>,
+    /// Overrides the global `memo` for this wallet
+    pub memo: Option<String>,
+}
+
+/// How the config turns its wallets into transfers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Every source wallet sends to every destination wallet
+    Fanout,
+    /// Only the source/destination pairs listed in `transfers`
+    Pairs,
+}
+
+/// A single transfer listed explicitly in the config
+#[derive(Debug, Deserialize)]
+pub struct TransferPair {
+    pub from_keypair_path: String,
+    pub to_address: String,
+    pub amount: Option<Amount>,
+    /// Overrides the source wallet's and the global `token_mint` for this transfer
+    #[serde(alias = "mint")]
+    pub token_mint: Option<String>,
+    /// Decimals of this transfer's `token_mint`, read from the mint account when not set
+    pub decimals: Option<u8>,
+    /// Overrides the source wallet's and the global `memo` for this transfer
+    pub memo: Option<String>,
+}
+
+/// One transfer of the config with its amount and token resolved
+pub struct ConfiguredTransfer<'a> {
+    pub from: &'a str,
+    pub to: &'a str,
+    pub amount: Option<Amount>,
+    /// `None` for a SOL transfer
+    pub token_mint: Option<&'a String>,
+    /// Configured decimals of `token_mint`, `None` to read them from the mint
+    pub decimals: Option<u8>,
+    /// Memo template, `None` to send no memo
+    pub memo: Option<&'a String>,
+}
+
+impl Config {
+    pub fn mode(&self) -> Mode {
+        self.mode.unwrap_or(if self.transfers.is_empty() {
+            Mode::Fanout
+        } else {
+            Mode::Pairs
+        })
+    }
+
+    /// Settings for a source wallet, if it's listed in `source_wallets`
+    pub fn source_wallet(&self, from_keypair_path: &str) -> Option<&SourceWallet> {
+        self.source_wallets
+            .iter()
+            .find(|source| source.from_keypair_path == from_keypair_path)
+    }
+
+    /// Every source/destination combination the config describes. Amounts,
+    /// token mints and memos resolve from the pair, then the source wallet,
+    /// then the global setting. Decimals come from wherever the mint was set
+    pub fn transfer_pairs<'a>(&'a self) -> Vec<ConfiguredTransfer<'a>> {
+        let resolve = |from: &'a str, to: &'a str, pair: Option<&'a TransferPair>| {
+            let source = self.source_wallet(from);
+            let (token_mint, decimals) = match pair.and_then(|pair| pair.token_mint.as_ref()) {
+                Some(mint) => (Some(mint), pair.and_then(|pair| pair.decimals)),
+                None => match source.and_then(|source| source.token_mint.as_ref()) {
+                    Some(mint) => (Some(mint), source.and_then(|source| source.decimals)),
+                    None => (self.token_mint.as_ref(), self.decimals),
+                },
+            };
+            ConfiguredTransfer {
+                from,
+                to,
+                amount: pair
+                    .and_then(|pair| pair.amount)
+                    .or_else(|| source.and_then(|source| source.amount))
+                    .or(self.amount),
+                token_mint,
+                decimals,
+                memo: pair
+                    .and_then(|pair| pair.memo.as_ref())
+                    .or_else(|| source.and_then(|source| source.memo.as_ref()))
+                    .or(self.memo.as_ref()),
+            }
+        };
+
+        match self.mode() {
+            Mode::Fanout => self
+                .source_wallets
+                .iter()
+                .flat_map(|source| {
+                    self.destination_wallets
+                        .iter()
+                        .map(|dest| resolve(source.from_keypair_path.as_str(), dest.as_str(), None))
+                })
+                .collect(),
+            Mode::Pairs => self
+                .transfers
+                .iter()
+                .map(|pair| {
+                    resolve(
+                        pair.from_keypair_path.as_str(),
+                        pair.to_address.as_str(),
+                        Some(pair),
+                    )
+                })
+                .collect(),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct TransferSpec {
     pub from_keypair_path: String,
     pub to_address: String,
-    pub amount_sol: f64,
+    /// Lamports, or raw token units when `token` is set
+    pub amount: u64,
+    pub token: Option<TokenSpec>,
+    pub compute_unit_limit: Option<u32>,
+    /// Micro-lamports per compute unit
+    pub compute_unit_price: Option<u64>,
+    /// SOL transfer to a destination that doesn't exist on chain yet
+    pub new_account: bool,
+    /// Lamports added to `amount` to reach the rent-exempt minimum of a new account
+    pub rent_top_up: u64,
+    /// Rendered memo, sent in a memo instruction after the transfer
+    pub memo: Option<String>,
 }
 
-#[derive(Debug)]
+/// Compute units budgeted for a SOL transfer, the transfer itself plus the
+/// compute budget instructions with some headroom
+const SOL_TRANSFER_COMPUTE_UNITS: u32 = 1_000;
+/// Compute units budgeted for a token transfer including a possible ATA creation
+const TOKEN_TRANSFER_COMPUTE_UNITS: u32 = 50_000;
+/// Compute units budgeted for a memo instruction, which logs the memo and
+/// verifies its signer, plus the per-byte cost of the memo text
+const MEMO_COMPUTE_UNITS: u32 = 10_000;
+const MEMO_COMPUTE_UNITS_PER_BYTE: u32 = 100;
+
+impl TransferSpec {
+    /// Compute units requested for this transfer when a priority fee is paid
+    /// but no `compute_unit_limit` is configured. Without an explicit limit the
+    /// runtime would reserve 200k units per instruction and charge the fee on all of them
+    pub fn default_compute_unit_limit(&self) -> u32 {
+        let transfer = match self.token {
+            Some(_) => TOKEN_TRANSFER_COMPUTE_UNITS,
+            None => SOL_TRANSFER_COMPUTE_UNITS,
+        };
+        let memo = self.memo.as_ref().map_or(0, |memo| {
+            MEMO_COMPUTE_UNITS + MEMO_COMPUTE_UNITS_PER_BYTE * memo.len() as u32
+        });
+        transfer + memo
+    }
+
+    /// Upper bound of the priority fee in lamports this transfer pays on top of the base fee
+    pub fn priority_fee_lamports(&self) -> u64 {
+        let Some(price) = self.compute_unit_price else {
+            return 0;
+        };
+        let units = self
+            .compute_unit_limit
+            .unwrap_or_else(|| self.default_compute_unit_limit());
+        (price as u128 * units as u128).div_ceil(1_000_000) as u64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenSpec {
+    pub mint: String,
+    pub decimals: u8,
+}
+
+#[derive(Debug, Serialize)]
 pub struct TransferResult {
     pub from: String,
     pub to: String,
+    /// `lamports` in SOL or token units, for display
     pub amount: f64,
+    /// Lamports, or raw token units when `token_mint` is set
+    pub lamports: u64,
+    /// `None` for SOL transfers
+    pub token_mint: Option<String>,
+    #[serde(serialize_with = "serialize_display")]
     pub signature: Signature,
+    /// Compute unit price the transaction paid, in micro-lamports
+    pub priority_fee_micro_lamports: Option<u64>,
+    /// This transfer's share of the fee its transaction paid, as recorded on
+    /// chain or estimated by a dry run. `None` if it never landed or the fee
+    /// couldn't be fetched
+    pub fee_lamports: Option<u64>,
+    /// Slot the transaction landed in, `None` if it never did or couldn't be looked up
+    pub slot: Option<u64>,
     pub duration_ms: u64,
+    /// Times the transaction was signed and sent, more than 1 after `--retries` kicked in
+    pub attempts: u32,
+    #[serde(flatten)]
     pub status: TransferStatus,
+    /// Rent returned by closing the emptied source token account
+    pub reclaimed_rent_lamports: Option<u64>,
+    /// The transfer created the destination account
+    pub created_account: bool,
+    /// Lamports added to the configured amount to make a new account rent-exempt
+    pub rent_top_up_lamports: Option<u64>,
+    /// Memo sent along with the transfer
+    pub memo: Option<String>,
+    /// Outcome of `--verify`, `None` when not verified or not successful
+    pub verification: Option<Verification>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
 }
 
-#[derive(Debug)]
+impl TransferResult {
+    /// `lamports` as configured, before any rent top-up
+    pub fn requested_lamports(&self) -> u64 {
+        self.lamports - self.rent_top_up_lamports.unwrap_or_default()
+    }
+}
+
+/// Whether the destination balance changed by what the transfers sent
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Verification {
+    Verified,
+    /// Changes are in lamports, or raw token units for token transfers
+    Mismatch {
+        expected_delta: i64,
+        actual_delta: i64,
+    },
+    Unverified {
+        reason: String,
+    },
+}
+
+impl Verification {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Verification::Verified => "VERIFIED",
+            Verification::Mismatch { .. } => "MISMATCH",
+            Verification::Unverified { .. } => "UNVERIFIED",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum TransferStatus {
     Success,
     Failed(String),
     Timeout,
+    /// Dry-run only: the transaction simulated successfully
+    Simulated {
+        fee_lamports: u64,
+        post_balance_lamports: Option<u64>,
+        units_consumed: Option<u64>,
+        logs: Vec<String>,
+    },
+}
+
+impl TransferStatus {
+    pub fn error(&self) -> Option<&str> {
+        match self {
+            TransferStatus::Failed(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            TransferStatus::Success => "SUCCESS",
+            TransferStatus::Failed(_) => "FAILED",
+            TransferStatus::Timeout => "TIMEOUT",
+            TransferStatus::Simulated { .. } => "SIMULATED",
+        }
+    }
+}
+
+impl Serialize for TransferStatus {
+    /// Serialized as a flat `status` label plus an `error` message, so the
+    /// status is easy to filter on in JSON and maps onto plain CSV columns
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("status", self.label())?;
+        match self {
+            TransferStatus::Failed(err) => map.serialize_entry("error", err)?,
+            TransferStatus::Success | TransferStatus::Timeout => {
+                map.serialize_entry("error", &None::<String>)?
+            }
+            TransferStatus::Simulated {
+                fee_lamports,
+                post_balance_lamports,
+                units_consumed,
+                logs,
+            } => {
+                map.serialize_entry("error", &None::<String>)?;
+                // `fee_lamports` of the result is this transfer's share
+                map.serialize_entry("transaction_fee_lamports", fee_lamports)?;
+                map.serialize_entry("post_balance_lamports", post_balance_lamports)?;
+                map.serialize_entry("units_consumed", units_consumed)?;
+                map.serialize_entry("logs", logs)?;
+            }
+        }
+        map.end()
+    }
+}
+
+fn serialize_display<T: fmt::Display, S: Serializer>(
+    value: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+/// What a source wallet is about to send and roughly pay for it
+#[derive(Debug)]
+pub struct SourcePlan {
+    pub pubkey: Pubkey,
+    pub transfer_count: u64,
+    pub destination_count: usize,
+    /// SOL sent, token transfers only cost fees
+    pub lamports: u64,
+    pub token_transfer_count: u64,
+    /// Estimated base fees plus the priority fees of all transfers
+    pub fee_lamports: u64,
+    /// Missing destination token accounts its transfers create with `--create-ata`
+    pub new_token_accounts: u64,
+    /// Rent the source pays to create them
+    pub token_account_rent_lamports: u64,
+    /// Transfers in flight at once, from the per-source limits
+    pub max_concurrent: usize,
+}
+
+impl SourcePlan {
+    pub fn required_lamports(&self) -> u64 {
+        self.lamports + self.fee_lamports + self.token_account_rent_lamports
+    }
+}
+
+/// A source wallet that can't cover all of its transfers plus fees
+#[derive(Debug)]
+pub struct SourceShortfall {
+    pub pubkey: Pubkey,
+    pub transfer_count: u64,
+    pub balance_lamports: u64,
+    pub required_lamports: u64,
+}
+
+impl fmt::Display for SourceShortfall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} has {:.9} SOL but needs {:.9} SOL for {} transfers (short by {:.9} SOL)",
+            self.pubkey,
+            lamports_to_sol(self.balance_lamports),
+            lamports_to_sol(self.required_lamports),
+            self.transfer_count,
+            lamports_to_sol(self.required_lamports - self.balance_lamports)
+        )
+    }
 }
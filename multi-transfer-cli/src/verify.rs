@@ -0,0 +1,146 @@
+use crate::types::{TransferResult, TransferSpec, TransferStatus, Verification};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{program_pack::Pack, pubkey::Pubkey};
+use spl_associated_token_account::get_associated_token_address;
+use std::{collections::HashMap, str::FromStr};
+use tracing::{info, warn};
+
+/// A destination wallet and the asset it receives, `None` for SOL
+type BalanceKey = (String, Option<String>);
+
+/// Destination balances taken before any transfer was sent
+pub struct BalanceSnapshot {
+    before: HashMap<BalanceKey, u64>,
+}
+
+impl BalanceSnapshot {
+    /// Fetches the current balance of every destination. Destinations whose
+    /// balance can't be fetched are left out and reported as unverified later
+    pub async fn take(rpc_client: &RpcClient, transfers: &[TransferSpec]) -> Self {
+        let mut before = HashMap::new();
+        for transfer in transfers {
+            let key = (
+                transfer.to_address.clone(),
+                transfer.token.as_ref().map(|token| token.mint.clone()),
+            );
+            if before.contains_key(&key) {
+                continue;
+            }
+            match fetch_balance(rpc_client, &key).await {
+                Ok(balance) => {
+                    before.insert(key, balance);
+                }
+                Err(e) => warn!("Cannot verify transfers to {}: {}", key.0, e),
+            }
+        }
+
+        info!("Recorded the balances of {} destinations", before.len());
+        Self { before }
+    }
+
+    /// Compares every destination's balance change against the successful
+    /// transfers to it and records the outcome on those transfers.
+    ///
+    /// Transfers to one destination are checked together, so concurrent
+    /// transfers to the same wallet add up instead of looking like mismatches.
+    /// Destinations that also sent in this run have their outgoing amounts
+    /// subtracted; the fees they paid must fit within `tolerance`.
+    pub async fn verify(
+        &self,
+        rpc_client: &RpcClient,
+        results: &mut [TransferResult],
+        tolerance: u64,
+    ) {
+        // key -> (expected change, whether a timed-out transfer may still land)
+        let mut expected: HashMap<BalanceKey, (i128, bool)> = HashMap::new();
+        for result in results.iter() {
+            let incoming = (result.to.clone(), result.token_mint.clone());
+            let outgoing = (result.from.clone(), result.token_mint.clone());
+            match result.status {
+                TransferStatus::Success => {
+                    expected.entry(incoming).or_default().0 += result.lamports as i128;
+                    if self.before.contains_key(&outgoing) {
+                        expected.entry(outgoing).or_default().0 -= result.lamports as i128;
+                    }
+                }
+                TransferStatus::Timeout => {
+                    expected.entry(incoming).or_default().1 = true;
+                    if self.before.contains_key(&outgoing) {
+                        expected.entry(outgoing).or_default().1 = true;
+                    }
+                }
+                TransferStatus::Failed(_) | TransferStatus::Simulated { .. } => {}
+            }
+        }
+
+        let mut verifications = HashMap::new();
+        for (key, (expected_delta, pending)) in expected {
+            let verification = match self.before.get(&key) {
+                None => Verification::Unverified {
+                    reason: "balance before the transfer is unknown".to_string(),
+                },
+                Some(_) if pending => Verification::Unverified {
+                    reason: "a timed-out transfer to this destination may still land".to_string(),
+                },
+                Some(&before) => match fetch_balance(rpc_client, &key).await {
+                    Ok(after) => {
+                        let actual_delta = after as i128 - before as i128;
+                        if (actual_delta - expected_delta).unsigned_abs() <= tolerance as u128 {
+                            Verification::Verified
+                        } else {
+                            warn!(
+                                "Balance of {} changed by {} instead of {}",
+                                key.0, actual_delta, expected_delta
+                            );
+                            Verification::Mismatch {
+                                expected_delta: expected_delta as i64,
+                                actual_delta: actual_delta as i64,
+                            }
+                        }
+                    }
+                    Err(e) => Verification::Unverified { reason: e },
+                },
+            };
+            verifications.insert(key, verification);
+        }
+
+        for result in results.iter_mut() {
+            if matches!(result.status, TransferStatus::Success) {
+                let key = (result.to.clone(), result.token_mint.clone());
+                result.verification = verifications.get(&key).cloned();
+            }
+        }
+    }
+}
+
+/// Lamports of a wallet, or raw units in its associated token account.
+/// Accounts that don't exist yet hold nothing
+async fn fetch_balance(
+    rpc_client: &RpcClient,
+    (address, mint): &BalanceKey,
+) -> Result<u64, String> {
+    let owner =
+        Pubkey::from_str(address).map_err(|e| format!("Invalid address {}: {}", address, e))?;
+    let account = match mint {
+        None => owner,
+        Some(mint) => {
+            let mint = Pubkey::from_str(mint)
+                .map_err(|e| format!("Invalid token mint {}: {}", mint, e))?;
+            get_associated_token_address(&owner, &mint)
+        }
+    };
+
+    let account = rpc_client
+        .get_account_with_commitment(&account, rpc_client.commitment())
+        .await
+        .map_err(|e| format!("Failed to fetch balance of {}: {}", account, e))?
+        .value;
+
+    match (account, mint) {
+        (None, _) => Ok(0),
+        (Some(account), None) => Ok(account.lamports),
+        (Some(account), Some(_)) => spl_token::state::Account::unpack(&account.data)
+            .map(|token_account| token_account.amount)
+            .map_err(|e| format!("Invalid token account of {}: {}", address, e)),
+    }
+}
@@ -0,0 +1,216 @@
+use crate::config::{Config, TransactionType};
+use crate::geyser::GeyserSubscriber;
+use crate::transaction::{self, RPC_TIMEOUT, TransactionKind};
+use anyhow::{Context, Result, bail};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{message::Message, pubkey::Pubkey, signer::Signer};
+use std::time::Duration;
+use tracing::{error, info};
+use yellowstone_grpc_client::GeyserGrpcClient;
+
+/// Outcome of a single check, with what was found either way
+pub struct Check {
+    name: &'static str,
+    outcome: Result<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, outcome: Result<String>) -> Self {
+        Self { name, outcome }
+    }
+}
+
+/// Checks that need neither the RPC nor the geyser endpoint: the keypair
+/// loads, the transaction can be built and both endpoints are well-formed
+pub fn offline_checks(config: &Config) -> Vec<Check> {
+    vec![
+        Check::new(
+            "keypair",
+            common::load_keypair(&config.keypair_path)
+                .map(|keypair| format!("loaded, wallet {}", keypair.pubkey())),
+        ),
+        Check::new("transaction", check_transaction(config)),
+        Check::new("geyser endpoint", check_geyser_endpoint(config)),
+        Check::new("rpc url", check_rpc_urls(config)),
+    ]
+}
+
+/// Everything `offline_checks` covers, plus the RPC, the destinations and
+/// funding wallet on chain and a short-lived geyser subscription. Sends nothing
+pub async fn all_checks(
+    config: &Config,
+    subscriber: &GeyserSubscriber,
+    geyser_timeout: Duration,
+) -> Vec<Check> {
+    let mut checks = offline_checks(config);
+
+    // Every endpoint is checked, the first reachable one looks up the rest
+    let mut reachable = None;
+    for url in config.rpc_urls().unwrap_or_default() {
+        let rpc_client = common::make_rpc_client(
+            url.clone(),
+            config.commitment.commitment_config(),
+            RPC_TIMEOUT,
+        );
+        let rpc = rpc_client
+            .get_latest_blockhash()
+            .await
+            .with_context(|| format!("{} is not reachable", url));
+        if rpc.is_ok() && reachable.is_none() {
+            reachable = Some(rpc_client);
+        }
+        checks.push(Check::new(
+            "rpc",
+            rpc.map(|blockhash| format!("{} reachable, latest blockhash {}", url, blockhash)),
+        ));
+    }
+
+    // Without an RPC there's nothing to look up on chain
+    if let Some(rpc_client) = reachable {
+        if config.transaction_type == TransactionType::Transfer {
+            checks.push(Check::new(
+                "destinations on chain",
+                check_destinations(config, &rpc_client).await,
+            ));
+        }
+        checks.push(Check::new(
+            "funding balance",
+            check_balance(config, &rpc_client).await,
+        ));
+    }
+
+    checks.push(Check::new(
+        "geyser connection",
+        subscriber
+            .check_connection(geyser_timeout)
+            .await
+            .map(|slot| format!("subscribed, first block at slot {}", slot)),
+    ));
+    checks
+}
+
+/// Logs every check, fails if any of them did
+pub fn report(checks: &[Check]) -> Result<()> {
+    let mut failed = 0;
+    for check in checks {
+        match &check.outcome {
+            Ok(details) => info!("PASS {}: {}", check.name, details),
+            Err(e) => {
+                failed += 1;
+                error!("FAIL {}: {:#}", check.name, e);
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, checks.len());
+    }
+    info!("All {} checks passed", checks.len());
+    Ok(())
+}
+
+fn check_rpc_urls(config: &Config) -> Result<String> {
+    let urls = config.rpc_urls()?;
+    for url in &urls {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            bail!("{:?} is not an http(s) URL", url);
+        }
+    }
+    Ok(urls.join(", "))
+}
+
+/// The configured transaction parses and the wallet can sign it
+fn check_transaction(config: &Config) -> Result<String> {
+    let kind = TransactionKind::from_config(config)?;
+    let payer = common::load_keypair(&config.keypair_path)?.pubkey();
+    kind.check_signers(&payer)?;
+    Ok(kind.to_string())
+}
+
+fn check_geyser_endpoint(config: &Config) -> Result<String> {
+    GeyserGrpcClient::build_from_shared(config.geyser_endpoint.clone())
+        .with_context(|| format!("Invalid geyser endpoint {:?}", config.geyser_endpoint))?
+        .x_token(Some(&config.geyser_token))
+        .context("Invalid geyser token")?;
+    Ok(config.geyser_endpoint.clone())
+}
+
+/// Destinations that don't exist yet are fine as long as a single send covers
+/// their rent-exempt minimum, otherwise that send would fail
+async fn check_destinations(config: &Config, rpc_client: &RpcClient) -> Result<String> {
+    let destinations = config.destination_pubkeys()?;
+    let accounts = rpc_client
+        .get_multiple_accounts(&destinations)
+        .await
+        .context("Failed to look up the destinations")?;
+    let missing: Vec<Pubkey> = destinations
+        .iter()
+        .zip(&accounts)
+        .filter(|(_, account)| account.is_none())
+        .map(|(destination, _)| *destination)
+        .collect();
+    if missing.is_empty() {
+        return Ok(format!("all {} exist", destinations.len()));
+    }
+
+    let rent = rpc_client
+        .get_minimum_balance_for_rent_exemption(0)
+        .await
+        .context("Failed to fetch the rent-exempt minimum")?;
+    let lamports = config.amount_lamports()?;
+    if lamports < rent {
+        bail!(
+            "{:?} don't exist yet and {} lamports per send is below their rent-exempt minimum of {}",
+            missing,
+            lamports,
+            rent
+        );
+    }
+    Ok(format!(
+        "{} of {} exist, the first send creates {:?}",
+        destinations.len() - missing.len(),
+        destinations.len(),
+        missing
+    ))
+}
+
+/// The funding wallet must cover at least one send plus its fee, at the
+/// initial priority fee if one is configured
+async fn check_balance(config: &Config, rpc_client: &RpcClient) -> Result<String> {
+    let payer = common::load_keypair(&config.keypair_path)?.pubkey();
+    let kind = TransactionKind::from_config(config)?;
+    let lamports = kind.lamports();
+
+    let instruction = kind.instruction(&payer, 0, 0, None);
+    let price = config
+        .priority_fee
+        .as_ref()
+        .map_or(0, |priority_fee| priority_fee.initial_micro_lamports);
+    let mut instructions = transaction::compute_budget(&instruction, price);
+    instructions.push(instruction);
+    let blockhash = rpc_client.get_latest_blockhash().await?;
+    let message = Message::new_with_blockhash(&instructions, Some(&payer), &blockhash);
+    let fee = rpc_client
+        .get_fee_for_message(&message)
+        .await
+        .context("Failed to estimate the fee")?;
+
+    let balance = rpc_client
+        .get_balance(&payer)
+        .await
+        .with_context(|| format!("Failed to fetch the balance of {}", payer))?;
+    let required = lamports + fee;
+    if balance < required {
+        bail!(
+            "{} holds {} SOL, a send needs {} SOL including the fee",
+            payer,
+            common::lamports_to_sol(balance),
+            common::lamports_to_sol(required)
+        );
+    }
+    Ok(format!(
+        "{} holds {} SOL, enough for {} sends",
+        payer,
+        common::lamports_to_sol(balance),
+        balance / required
+    ))
+}
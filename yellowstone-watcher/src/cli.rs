@@ -1,3 +1,4 @@
+use crate::config::ConfigFormat;
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
@@ -16,16 +17,45 @@ pub struct Cli {
 pub enum Commands {
     /// Start watching for new blocks and sending transactions
     Start {
-        /// Path to config.yaml file
+        /// Path to config.yaml or config.toml file, or `-` to read YAML from stdin
         #[arg(short, long, default_value = "config.yaml")]
         config: PathBuf,
     },
 
+    /// Check that the geyser endpoint and token work by waiting for the first
+    /// block, without sending any transaction
+    CheckConnection {
+        /// Path to config.yaml or config.toml file, or `-` to read YAML from stdin
+        #[arg(short, long, default_value = "config.yaml")]
+        config: PathBuf,
+
+        /// Seconds to wait for the first block
+        #[arg(long, default_value_t = 30)]
+        timeout: u64,
+    },
+
+    /// Check the config end-to-end without sending any funds: keypair,
+    /// transaction, RPC, funding balance and the geyser connection
+    Check {
+        /// Path to config.yaml or config.toml file, or `-` to read YAML from stdin
+        #[arg(short, long, default_value = "config.yaml")]
+        config: PathBuf,
+
+        /// Seconds to wait for the first block from geyser
+        #[arg(long, default_value_t = 15)]
+        timeout: u64,
+    },
+
     /// Generate a sample config file
     GenerateConfig {
-        /// Path to output config file
-        #[arg(short, long, default_value = "config.yaml")]
-        output: PathBuf,
+        /// Path to output config file, `config.yaml` or `config.toml` per the format
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Format of the config file, taken from the output's extension if
+        /// unset and YAML if there's none
+        #[arg(long, value_enum)]
+        format: Option<ConfigFormat>,
     },
 }
 
@@ -1,30 +1,754 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
+use common::RpcUrls;
 use serde::{Deserialize, Serialize};
-use solana_sdk::pubkey::Pubkey;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+};
 use std::fs::File;
-use std::path::Path;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use yellowstone_grpc_proto::geyser::CommitmentLevel;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub geyser_endpoint: String,
     pub geyser_token: String,
+    /// A keypair file, `base58:<secret>` or `env:<VAR>`
     pub keypair_path: String,
-    pub destination_wallet: String,
-    pub sol_amount: f64,
-    pub solana_rpc_url: String,
+    /// What every send does, a SOL transfer unless set
+    #[serde(default)]
+    pub transaction_type: TransactionType,
+    /// A single address, or a list that is paid round-robin. Transfers only
+    #[serde(default)]
+    pub destination_wallet: Option<DestinationWallets>,
+    /// Text of the memo transaction type, `{slot}` and `{timestamp}` are
+    /// replaced with the triggering slot and the unix time of the send
+    #[serde(default)]
+    pub memo: Option<String>,
+    /// The instruction of the custom transaction type
+    #[serde(default)]
+    pub custom_instruction: Option<CustomInstructionConfig>,
+    /// Amount sent per block in SOL, rounded to the nearest lamport
+    #[serde(default)]
+    pub sol_amount: Option<f64>,
+    /// Exact amount sent per block, instead of `sol_amount`
+    #[serde(default)]
+    pub lamports: Option<u64>,
+    /// Where transactions are sent. A list fails over to the next endpoint
+    /// while the preferred ones are unhealthy
+    #[serde(alias = "solana_rpc_urls")]
+    pub solana_rpc_url: RpcUrls,
+    /// How long an RPC endpoint that failed is skipped
+    #[serde(default = "default_rpc_cooldown_secs")]
+    pub rpc_cooldown_secs: u64,
+    /// Escalate the priority fee when transactions repeatedly fail to land
+    #[serde(default)]
+    pub priority_fee: Option<PriorityFeeConfig>,
+    /// Give up on a send that hasn't confirmed after this many seconds
+    #[serde(default = "default_send_timeout_secs")]
+    pub send_timeout_secs: u64,
+    /// Don't send to the same destination again within this many seconds
+    #[serde(default)]
+    pub destination_cooldown_secs: Option<u64>,
+    /// Give up after this many failed reconnects in a row, retry forever if unset
+    #[serde(default)]
+    pub max_reconnect_attempts: Option<u32>,
+    /// Delay before the first reconnect, doubled after every failed attempt
+    #[serde(default = "default_reconnect_delay_secs")]
+    pub reconnect_delay_secs: u64,
+    /// Upper bound for the delay between reconnects
+    #[serde(default = "default_max_reconnect_delay_secs")]
+    pub max_reconnect_delay_secs: u64,
+    /// What triggers a send, every streamed block if unset
+    #[serde(default)]
+    pub trigger: Option<TriggerConfig>,
+    /// What the geyser subscription streams in the blocks trigger mode, every
+    /// update triggers a send
+    #[serde(default)]
+    pub subscription_mode: SubscriptionMode,
+    /// Commitment of the streamed blocks and of the RPC calls made when sending
+    #[serde(default)]
+    pub commitment: Commitment,
+    /// Only stream blocks touching one of these accounts or programs
+    #[serde(default, alias = "program_filter")]
+    pub account_include: Vec<String>,
+    /// Accounts whose transactions trigger a send, reported with each block
+    #[serde(default)]
+    pub watch_accounts: Vec<String>,
+    /// Programs whose transactions trigger a send, reported with each block
+    #[serde(default)]
+    pub watch_programs: Vec<String>,
+    /// Conditions a streamed block must all meet to trigger a send, every
+    /// block does if unset
+    #[serde(default)]
+    pub rules: Option<RulesConfig>,
+    /// Send workers, each with one send in flight
+    #[serde(default = "default_max_concurrent_sends")]
+    pub max_concurrent_sends: usize,
+    /// Blocks waiting for a free worker, further blocks are handled per `send_overflow`
+    #[serde(default = "default_send_queue_size")]
+    pub send_queue_size: usize,
+    #[serde(default)]
+    pub send_overflow: SendOverflow,
+    /// Skip blocks arriving sooner than this after the last send, unlimited if unset
+    #[serde(default, alias = "min_send_interval_ms")]
+    pub min_interval_ms: Option<u64>,
+    /// Skip blocks once this many sends happened within the last minute, unlimited if unset
+    #[serde(default, alias = "max_sends_per_minute")]
+    pub max_transactions_per_minute: Option<usize>,
+    /// How often to log block and send counters
+    #[serde(default = "default_stats_interval_secs")]
+    pub stats_interval_secs: u64,
+    /// Serve Prometheus metrics on this address, e.g. `127.0.0.1:9090`
+    #[serde(default, alias = "metrics_addr")]
+    pub metrics_listen: Option<String>,
+    /// How often to fetch the sending wallet's balance for the metrics
+    #[serde(default = "default_balance_refresh_secs")]
+    pub balance_refresh_secs: u64,
+    /// Reuse a fetched blockhash for this many seconds instead of fetching one per block
+    #[serde(default = "default_blockhash_ttl_secs")]
+    pub blockhash_ttl_secs: u64,
+}
+
+/// File format of a config, picked by its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// TOML for a `.toml` file, YAML for anything else
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ConfigFormat::Yaml => "yaml",
+            ConfigFormat::Toml => "toml",
+        }
+    }
+
+    /// `config.yaml` or `config.toml`
+    pub fn default_path(self) -> PathBuf {
+        PathBuf::from(format!("config.{}", self.extension()))
+    }
+}
+
+/// Which block is dropped when one arrives while the send queue is full
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SendOverflow {
+    /// Drop the longest waiting block, sends stay close to the chain tip.
+    /// `queue` is read as this, it used to wait without a bound
+    #[default]
+    #[serde(alias = "queue")]
+    DropOldest,
+    /// Drop the new block
+    #[serde(alias = "drop")]
+    DropNewest,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Commitment {
+    /// Fastest, the block may still be skipped
+    Processed,
+    #[default]
+    Confirmed,
+    /// Slowest, the block can no longer be rolled back
+    Finalized,
+}
+
+impl Commitment {
+    pub fn geyser_level(self) -> CommitmentLevel {
+        match self {
+            Commitment::Processed => CommitmentLevel::Processed,
+            Commitment::Confirmed => CommitmentLevel::Confirmed,
+            Commitment::Finalized => CommitmentLevel::Finalized,
+        }
+    }
+
+    pub fn commitment_config(self) -> CommitmentConfig {
+        match self {
+            Commitment::Processed => CommitmentConfig::processed(),
+            Commitment::Confirmed => CommitmentConfig::confirmed(),
+            Commitment::Finalized => CommitmentConfig::finalized(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionMode {
+    /// Full blocks including their transactions
+    #[default]
+    Blocks,
+    /// Only slot and blockhash metadata, far less bandwidth
+    BlocksMeta,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TriggerConfig {
+    #[serde(default)]
+    pub mode: TriggerMode,
+    /// Accounts whose updates trigger a send in the account_update mode
+    #[serde(default)]
+    pub accounts: Vec<String>,
+}
+
+/// Conditions on the streamed transactions of a block, see `rules::evaluate`
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RulesConfig {
+    /// At least this many streamed transactions
+    #[serde(default)]
+    pub min_transactions_in_block: Option<usize>,
+    /// A streamed transaction changed the balance of a watched account by at
+    /// least this many lamports
+    #[serde(default)]
+    pub watched_address_min_lamports: Option<u64>,
+    /// Leave vote transactions out of both rules
+    #[serde(default)]
+    pub only_vote_excluded: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerMode {
+    /// Every streamed block, per `subscription_mode` and the watched accounts
+    #[default]
+    Blocks,
+    /// Every change to the data or lamports of one of the trigger accounts
+    AccountUpdate,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionType {
+    /// The configured amount to the next destination
+    #[default]
+    Transfer,
+    /// A Memo program transaction with the `memo` text
+    Memo,
+    /// The instruction in `custom_instruction`
+    Custom,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CustomInstructionConfig {
+    pub program_id: String,
+    #[serde(default)]
+    pub accounts: Vec<AccountMetaConfig>,
+    /// Instruction data, encoded per `data_encoding`
+    #[serde(default)]
+    pub data: String,
+    #[serde(default)]
+    pub data_encoding: DataEncoding,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountMetaConfig {
+    pub pubkey: String,
+    /// Only the sending wallet can sign
+    #[serde(default)]
+    pub is_signer: bool,
+    #[serde(default)]
+    pub is_writable: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DataEncoding {
+    #[default]
+    Base58,
+    /// With or without a leading `0x`
+    Hex,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DestinationWallets {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn default_rpc_cooldown_secs() -> u64 {
+    30
+}
+
+fn default_send_timeout_secs() -> u64 {
+    60
+}
+
+fn default_reconnect_delay_secs() -> u64 {
+    1
+}
+
+fn default_max_reconnect_delay_secs() -> u64 {
+    60
+}
+
+fn default_blockhash_ttl_secs() -> u64 {
+    20
+}
+
+fn default_max_concurrent_sends() -> usize {
+    1
+}
+
+fn default_stats_interval_secs() -> u64 {
+    60
+}
+
+fn default_balance_refresh_secs() -> u64 {
+    300
+}
+
+fn default_send_queue_size() -> usize {
+    16
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriorityFeeConfig {
+    /// Compute unit price used while transactions are landing
+    #[serde(default)]
+    pub initial_micro_lamports: u64,
+    /// Added to the compute unit price after every dropped transaction
+    pub step_micro_lamports: u64,
+    /// Upper bound for the escalated compute unit price
+    pub max_micro_lamports: u64,
 }
 
 impl Config {
+    /// Loads the config from `path`, as TOML if it ends in `.toml` and YAML
+    /// otherwise. Or as YAML from stdin if `path` is `-`, so secrets like the
+    /// geyser token don't have to be written to disk
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let file = File::open(path).context("Failed to open config file")?;
-        let config: Config =
-            serde_yaml::from_reader(file).context("Failed to parse config file")?;
+        let path = path.as_ref();
+        if path == Path::new("-") {
+            let mut yaml = String::new();
+            io::stdin()
+                .read_to_string(&mut yaml)
+                .context("Failed to read config from stdin")?;
+            let config: Config =
+                serde_yaml::from_str(&yaml).context("Failed to parse config from stdin")?;
+            config.validate()?;
+            return Ok(config);
+        }
+
+        let config: Config = match ConfigFormat::from_path(path) {
+            ConfigFormat::Yaml => {
+                let file = File::open(path).context("Failed to open config file")?;
+                serde_yaml::from_reader(file).context("Failed to parse config file")?
+            }
+            ConfigFormat::Toml => {
+                let toml = std::fs::read_to_string(path).context("Failed to open config file")?;
+                toml::from_str(&toml).context("Failed to parse TOML config file")?
+            }
+        };
+        config.validate()?;
         Ok(config)
     }
 
-    pub fn destination_pubkey(&self) -> Result<Pubkey> {
-        self.destination_wallet
-            .parse::<Pubkey>()
-            .context("Invalid destination wallet pubkey in config")
+    /// Checks what serde can't, so mistakes show up when the config is loaded
+    fn validate(&self) -> Result<()> {
+        self.rpc_urls()?;
+        match self.transaction_type {
+            TransactionType::Transfer => {
+                self.destination_pubkeys()?;
+                self.amount_lamports()?;
+            }
+            TransactionType::Memo => {
+                self.memo_template()?;
+            }
+            TransactionType::Custom => {
+                self.custom_instruction()?;
+            }
+        }
+        self.watched()?;
+        self.trigger_accounts()?;
+        self.validate_rules()?;
+        Ok(())
+    }
+
+    /// Rules only see the transactions of full blocks
+    fn validate_rules(&self) -> Result<()> {
+        let Some(rules) = &self.rules else {
+            return Ok(());
+        };
+        if self.trigger_mode() != TriggerMode::Blocks
+            || !matches!(self.subscription_mode, SubscriptionMode::Blocks)
+        {
+            bail!("rules need the blocks trigger mode and subscription_mode: blocks");
+        }
+        if rules.watched_address_min_lamports.is_some() && self.watched()?.is_empty() {
+            bail!("rules.watched_address_min_lamports needs watch_accounts to compare against");
+        }
+        Ok(())
+    }
+
+    pub fn trigger_mode(&self) -> TriggerMode {
+        self.trigger
+            .as_ref()
+            .map_or(TriggerMode::default(), |trigger| trigger.mode)
+    }
+
+    /// Accounts whose updates trigger a send, empty unless the trigger mode is
+    /// account_update, which needs at least one
+    pub fn trigger_accounts(&self) -> Result<Vec<Pubkey>> {
+        let Some(trigger) = &self.trigger else {
+            return Ok(Vec::new());
+        };
+        if trigger.mode != TriggerMode::AccountUpdate {
+            return Ok(Vec::new());
+        }
+        if trigger.accounts.is_empty() {
+            bail!("trigger mode account_update needs at least one account in trigger.accounts");
+        }
+
+        let mut accounts = Vec::new();
+        for account in &trigger.accounts {
+            let pubkey = account.parse::<Pubkey>().with_context(|| {
+                format!("Invalid trigger account pubkey in config: {}", account)
+            })?;
+            if !accounts.contains(&pubkey) {
+                accounts.push(pubkey);
+            }
+        }
+        Ok(accounts)
+    }
+
+    /// Lamports sent per block, from either `lamports` or `sol_amount`
+    pub fn amount_lamports(&self) -> Result<u64> {
+        match (self.lamports, self.sol_amount) {
+            (Some(0), None) => bail!("lamports must be more than 0"),
+            (Some(lamports), None) => Ok(lamports),
+            (None, Some(sol)) if sol.is_finite() && sol > 0.0 => Ok(common::sol_to_lamports(sol)),
+            (None, Some(sol)) => bail!("sol_amount must be a positive number, got {}", sol),
+            (Some(_), Some(_)) => bail!("Set either sol_amount or lamports in config, not both"),
+            (None, None) => bail!("No amount in config, set sol_amount or lamports"),
+        }
+    }
+
+    /// Every account and program a streamed block must touch, from
+    /// `account_include`, `watch_accounts` and `watch_programs` without
+    /// duplicates. Empty if none is configured
+    pub fn watched(&self) -> Result<Vec<Pubkey>> {
+        let lists = [
+            ("account_include", &self.account_include),
+            ("watch_accounts", &self.watch_accounts),
+            ("watch_programs", &self.watch_programs),
+        ];
+
+        let mut watched = Vec::new();
+        for (name, accounts) in lists {
+            for account in accounts {
+                let pubkey = account
+                    .parse::<Pubkey>()
+                    .with_context(|| format!("Invalid {} pubkey in config: {}", name, account))?;
+                if !watched.contains(&pubkey) {
+                    watched.push(pubkey);
+                }
+            }
+        }
+        Ok(watched)
+    }
+
+    /// RPC endpoints in order of preference
+    pub fn rpc_urls(&self) -> Result<Vec<String>> {
+        let urls = self.solana_rpc_url.urls().to_vec();
+        if urls.is_empty() {
+            bail!("No RPC endpoint in config, set solana_rpc_url");
+        }
+        Ok(urls)
+    }
+
+    /// Destinations in rotation order
+    pub fn destination_pubkeys(&self) -> Result<Vec<Pubkey>> {
+        let wallets = match &self.destination_wallet {
+            Some(DestinationWallets::One(wallet)) => std::slice::from_ref(wallet),
+            Some(DestinationWallets::Many(wallets)) => wallets.as_slice(),
+            None => &[],
+        };
+        if wallets.is_empty() {
+            anyhow::bail!("No destination wallet in config");
+        }
+
+        wallets
+            .iter()
+            .map(|wallet| {
+                wallet.parse::<Pubkey>().with_context(|| {
+                    format!("Invalid destination wallet pubkey in config: {}", wallet)
+                })
+            })
+            .collect()
+    }
+
+    /// The memo text before its placeholders are filled in
+    pub fn memo_template(&self) -> Result<&str> {
+        match self.memo.as_deref() {
+            Some(memo) if !memo.trim().is_empty() => Ok(memo),
+            _ => bail!("transaction_type memo needs the text to send in memo"),
+        }
+    }
+
+    /// The instruction of the custom transaction type with its pubkeys and
+    /// data decoded. Whether its signers can sign is up to the sender
+    pub fn custom_instruction(&self) -> Result<Instruction> {
+        let Some(custom) = &self.custom_instruction else {
+            bail!("transaction_type custom needs a custom_instruction");
+        };
+        let program_id = custom.program_id.parse::<Pubkey>().with_context(|| {
+            format!(
+                "Invalid custom_instruction program_id in config: {}",
+                custom.program_id
+            )
+        })?;
+
+        let mut accounts = Vec::new();
+        for account in &custom.accounts {
+            let pubkey = account.pubkey.parse::<Pubkey>().with_context(|| {
+                format!(
+                    "Invalid custom_instruction account pubkey in config: {}",
+                    account.pubkey
+                )
+            })?;
+            accounts.push(if account.is_writable {
+                AccountMeta::new(pubkey, account.is_signer)
+            } else {
+                AccountMeta::new_readonly(pubkey, account.is_signer)
+            });
+        }
+
+        let data = match custom.data_encoding {
+            DataEncoding::Base58 => bs58::decode(&custom.data)
+                .into_vec()
+                .context("custom_instruction data is not valid base58")?,
+            DataEncoding::Hex => {
+                decode_hex(&custom.data).context("custom_instruction data is not valid hex")?
+            }
+        };
+        Ok(Instruction {
+            program_id,
+            accounts,
+            data,
+        })
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    if !hex.is_ascii() {
+        bail!("{:?} has characters other than hex digits", hex);
+    }
+    if !hex.len().is_multiple_of(2) {
+        bail!("odd number of digits");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .with_context(|| format!("{:?} is not a hex byte", &hex[i..i + 2]))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TransactionKind;
+    use solana_sdk::system_instruction;
+
+    const DESTINATION: &str = "11111111111111111111111111111112";
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "yellowstone-watcher-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// A transfer config with `amount` as its amount lines
+    fn transfer_config(name: &str, amount: &str) -> Result<Config> {
+        Config::load(temp_file(
+            name,
+            &format!(
+                "geyser_endpoint: http://geyser\ngeyser_token: token\nkeypair_path: env:KEYPAIR\nsolana_rpc_url: http://rpc\ndestination_wallet: \"{}\"\n{}",
+                DESTINATION, amount
+            ),
+        ))
+    }
+
+    /// The instruction a send of `config` would carry
+    fn transfer_instruction(config: &Config) -> Instruction {
+        let payer = Pubkey::new_unique();
+        TransactionKind::from_config(config)
+            .unwrap()
+            .instruction(&payer, 1, 0, None)
+    }
+
+    #[test]
+    fn exact_lamports_reach_the_transfer_instruction_unchanged() {
+        // More than f64 holds exactly
+        let lamports = 9_007_199_254_740_993;
+        let config =
+            transfer_config("lamports.yaml", &format!("lamports: {}\n", lamports)).unwrap();
+        assert_eq!(config.amount_lamports().unwrap(), lamports);
+
+        let instruction = transfer_instruction(&config);
+        let expected = system_instruction::transfer(
+            &Pubkey::new_unique(),
+            &DESTINATION.parse().unwrap(),
+            lamports,
+        );
+        assert_eq!(instruction.data, expected.data);
+
+        // The same amount in SOL is off by one lamport
+        let config = transfer_config("sol_amount.yaml", "sol_amount: 9007199.254740993\n").unwrap();
+        assert_ne!(config.amount_lamports().unwrap(), lamports);
+    }
+
+    #[test]
+    fn needs_exactly_one_amount() {
+        let error = transfer_config("both.yaml", "sol_amount: 0.1\nlamports: 100\n").unwrap_err();
+        assert!(error.to_string().contains("not both"), "{:#}", error);
+        let error = transfer_config("none.yaml", "").unwrap_err();
+        assert!(
+            error.to_string().contains("No amount in config"),
+            "{:#}",
+            error
+        );
+        let error = transfer_config("zero.yaml", "lamports: 0\n").unwrap_err();
+        assert!(error.to_string().contains("more than 0"), "{:#}", error);
+        let error = transfer_config("negative.yaml", "sol_amount: -1\n").unwrap_err();
+        assert!(error.to_string().contains("positive number"), "{:#}", error);
+        // Lamports have no fractions
+        assert!(transfer_config("fraction.yaml", "lamports: 1.5\n").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_destinations_when_loading() {
+        let error = Config::load(temp_file(
+            "bad-destination.yaml",
+            "geyser_endpoint: http://geyser\ngeyser_token: token\nkeypair_path: env:KEYPAIR\nsolana_rpc_url: http://rpc\ndestination_wallet: nope\nlamports: 1\n",
+        ))
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("Invalid destination wallet pubkey"),
+            "{:#}",
+            error
+        );
+    }
+
+    /// `config` in a format-independent form to compare configs by
+    fn canonical(config: &Config) -> String {
+        serde_yaml::to_string(config).unwrap()
+    }
+
+    #[test]
+    fn picks_the_format_by_extension() {
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("config.toml")),
+            ConfigFormat::Toml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("config.TOML")),
+            ConfigFormat::Toml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("config.yaml")),
+            ConfigFormat::Yaml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("config.yml")),
+            ConfigFormat::Yaml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("config")),
+            ConfigFormat::Yaml
+        );
+    }
+
+    #[test]
+    fn sample_configs_agree() {
+        // With the placeholder destinations filled in
+        let filled_in = |sample: &str| {
+            sample
+                .replace(
+                    "YOUR_DESTINATION_WALLET_ADDRESS",
+                    "11111111111111111111111111111112",
+                )
+                .replace(
+                    "ANOTHER_DESTINATION_WALLET_ADDRESS",
+                    "11111111111111111111111111111113",
+                )
+        };
+        let yaml = Config::load(temp_file(
+            "sample.yaml",
+            &filled_in(crate::SAMPLE_YAML_CONFIG),
+        ))
+        .unwrap();
+        let toml = Config::load(temp_file(
+            "sample.toml",
+            &filled_in(crate::SAMPLE_TOML_CONFIG),
+        ))
+        .unwrap();
+        assert_eq!(canonical(&yaml), canonical(&toml));
+    }
+
+    #[test]
+    fn round_trips_through_toml_and_yaml() {
+        let config = Config::load(temp_file(
+            "full.yaml",
+            "geyser_endpoint: http://geyser
+geyser_token: token
+keypair_path: env:KEYPAIR
+solana_rpc_urls: [http://one, http://two]
+destination_wallet: [\"11111111111111111111111111111112\", \"11111111111111111111111111111113\"]
+lamports: 1234567
+priority_fee:
+  initial_micro_lamports: 100
+  step_micro_lamports: 50
+  max_micro_lamports: 1000
+commitment: finalized
+watch_accounts: [\"11111111111111111111111111111114\"]
+rules:
+  min_transactions_in_block: 10
+max_transactions_per_minute: 30
+metrics_listen: 127.0.0.1:9090
+",
+        ))
+        .unwrap();
+
+        // Through a `Value`, which puts plain values ahead of tables as TOML needs
+        let toml = toml::Value::try_from(&config).unwrap().to_string();
+        let from_toml = Config::load(temp_file("round-trip.toml", &toml)).unwrap();
+        assert_eq!(canonical(&from_toml), canonical(&config));
+
+        let from_yaml = Config::load(temp_file("round-trip.yaml", &canonical(&config))).unwrap();
+        assert_eq!(canonical(&from_yaml), canonical(&config));
+        assert_eq!(from_toml.amount_lamports().unwrap(), 1234567);
+        assert_eq!(from_toml.rpc_urls().unwrap(), ["http://one", "http://two"]);
+    }
+
+    #[test]
+    fn reports_toml_syntax_errors() {
+        let error = Config::load(temp_file("broken.toml", "geyser_endpoint = ")).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("Failed to parse TOML config file")
+        );
     }
 }
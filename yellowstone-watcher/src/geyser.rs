@@ -1,24 +1,235 @@
-use anyhow::Result;
+use crate::config::{Commitment, SubscriptionMode};
+use crate::metrics::Metrics;
+use crate::rules::{BlockSummary, TransactionSummary};
+use anyhow::{Context, Result, bail};
+use futures::SinkExt;
+use rand::Rng;
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use yellowstone_grpc_client::ClientTlsConfig;
 use yellowstone_grpc_client::GeyserGrpcClient;
 use yellowstone_grpc_proto::geyser::{
-    CommitmentLevel, SubscribeRequest, SubscribeRequestFilterBlocks, subscribe_update::UpdateOneof,
+    SubscribeRequest, SubscribeRequestFilterAccounts, SubscribeRequestFilterBlocks,
+    SubscribeRequestFilterBlocksMeta, SubscribeRequestFilterSlots, SubscribeRequestPing,
+    SubscribeUpdateAccount, SubscribeUpdateBlock, SubscribeUpdateTransactionInfo,
+    subscribe_update::UpdateOneof,
 };
 use yellowstone_grpc_proto::tonic::codegen::tokio_stream::StreamExt;
 use yellowstone_grpc_proto::tonic::service::Interceptor;
 
+/// Matched by every block that contains a transaction, used when nothing is
+/// watched since geyser requires a filter
+const SYSTEM_PROGRAM: &str = "11111111111111111111111111111111";
+
+/// What the subscription streams, each streamed update triggers a send
+#[derive(Debug, Clone)]
+pub enum Trigger {
+    /// Blocks or block metadata per `mode`. In `Blocks` mode only blocks
+    /// touching one of `watched` are streamed, every block if it's empty
+    Blocks {
+        mode: SubscriptionMode,
+        watched: Vec<Pubkey>,
+    },
+    /// Every write to one of `accounts`
+    AccountUpdate { accounts: Vec<Pubkey> },
+}
+
+impl Trigger {
+    /// What is streamed, for log messages
+    fn updates(&self) -> &'static str {
+        match self {
+            Trigger::Blocks { .. } => "block updates",
+            Trigger::AccountUpdate { .. } => "account updates",
+        }
+    }
+}
+
+/// An update from the subscription, passed on to the handler
+#[derive(Debug, Clone)]
+pub enum GeyserEvent {
+    Block(BlockEvent),
+    AccountUpdate(AccountUpdate),
+}
+
+impl GeyserEvent {
+    pub fn slot(&self) -> u64 {
+        match self {
+            GeyserEvent::Block(block) => block.slot,
+            GeyserEvent::AccountUpdate(update) => update.slot,
+        }
+    }
+}
+
+/// A write to a trigger account
+#[derive(Debug, Clone)]
+pub struct AccountUpdate {
+    pub pubkey: Pubkey,
+    pub slot: u64,
+    pub lamports: u64,
+    /// Increases with every write, the same write can be notified twice
+    pub write_version: u64,
+}
+
+impl AccountUpdate {
+    fn from_update(update: &SubscribeUpdateAccount) -> Option<Self> {
+        let account = update.account.as_ref()?;
+        Some(Self {
+            pubkey: Pubkey::try_from(account.pubkey.as_slice()).ok()?,
+            slot: update.slot,
+            lamports: account.lamports,
+            write_version: account.write_version,
+        })
+    }
+}
+
+/// A streamed block and what made it match
+#[derive(Debug, Clone)]
+pub struct BlockEvent {
+    pub slot: u64,
+    /// Watched accounts and programs touched by the block, empty when nothing
+    /// is watched or in `BlocksMeta` mode
+    pub matched: Vec<Pubkey>,
+    /// Transactions of the block touching a watched account or program
+    pub signatures: Vec<Signature>,
+    /// The streamed transactions, for the send rules. Empty in `BlocksMeta` mode
+    pub summary: BlockSummary,
+}
+
+impl BlockEvent {
+    fn from_block(block: &SubscribeUpdateBlock, watched: &[Pubkey]) -> Self {
+        let mut matched = Vec::new();
+        let mut signatures = Vec::new();
+        let mut summary = BlockSummary::default();
+        for transaction in &block.transactions {
+            summary.transactions.push(TransactionSummary {
+                is_vote: transaction.is_vote,
+                watched_lamports: watched_lamports(transaction, watched),
+            });
+            let mut hit = false;
+            for pubkey in watched {
+                if touches(transaction, pubkey) {
+                    hit = true;
+                    if !matched.contains(pubkey) {
+                        matched.push(*pubkey);
+                    }
+                }
+            }
+            if hit && let Ok(signature) = Signature::try_from(transaction.signature.as_slice()) {
+                signatures.push(signature);
+            }
+        }
+
+        Self {
+            slot: block.slot,
+            matched,
+            signatures,
+            summary,
+        }
+    }
+}
+
+/// Whether `pubkey` is one of the transaction's accounts, including those
+/// loaded from address lookup tables. Programs are accounts of the
+/// transactions invoking them, so this covers both
+fn touches(transaction: &SubscribeUpdateTransactionInfo, pubkey: &Pubkey) -> bool {
+    let pubkey = pubkey.as_ref();
+    account_keys(transaction).any(|key| key == pubkey)
+}
+
+/// Largest balance change of any of `watched` in the transaction, from the
+/// balances before and after it, which are in the order of its account keys
+fn watched_lamports(transaction: &SubscribeUpdateTransactionInfo, watched: &[Pubkey]) -> u64 {
+    let Some(meta) = &transaction.meta else {
+        return 0;
+    };
+    account_keys(transaction)
+        .zip(meta.pre_balances.iter().zip(&meta.post_balances))
+        .filter(|(key, _)| watched.iter().any(|pubkey| pubkey.as_ref() == *key))
+        .map(|(_, (pre, post))| pre.abs_diff(*post))
+        .max()
+        .unwrap_or(0)
+}
+
+/// The transaction's static account keys followed by the writable and
+/// readonly ones loaded from address lookup tables
+fn account_keys(transaction: &SubscribeUpdateTransactionInfo) -> impl Iterator<Item = &[u8]> {
+    let static_keys = transaction
+        .transaction
+        .as_ref()
+        .and_then(|tx| tx.message.as_ref())
+        .map(|message| message.account_keys.as_slice())
+        .unwrap_or_default();
+    let (writable, readonly) = transaction
+        .meta
+        .as_ref()
+        .map(|meta| {
+            (
+                meta.loaded_writable_addresses.as_slice(),
+                meta.loaded_readonly_addresses.as_slice(),
+            )
+        })
+        .unwrap_or_default();
+
+    static_keys
+        .iter()
+        .chain(writable)
+        .chain(readonly)
+        .map(Vec::as_slice)
+}
+
+/// When and how often to reconnect after the stream dropped
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    /// Failed reconnects in a row before giving up, `None` retries forever
+    pub max_attempts: Option<u32>,
+    /// Delay before the first reconnect, doubled after every failed attempt
+    pub base_delay: Duration,
+    /// Upper bound for the delay between reconnects
+    pub max_delay: Duration,
+}
+
+impl ReconnectPolicy {
+    /// Backoff for the given failure in a row, starting at 1, with jitter so
+    /// several watchers don't reconnect in lockstep
+    fn delay(&self, failures: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+            .min(self.max_delay);
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
 pub struct GeyserSubscriber {
     endpoint: String,
     token: String,
+    reconnect: ReconnectPolicy,
+    trigger: Trigger,
+    commitment: Commitment,
+    metrics: Arc<Metrics>,
 }
 
 impl GeyserSubscriber {
-    pub fn new(endpoint: String, token: String) -> Self {
-        Self { endpoint, token }
+    pub fn new(
+        endpoint: String,
+        token: String,
+        reconnect: ReconnectPolicy,
+        trigger: Trigger,
+        commitment: Commitment,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            endpoint,
+            token,
+            reconnect,
+            trigger,
+            commitment,
+            metrics,
+        }
     }
 
     async fn create_client(&self) -> Result<GeyserGrpcClient<impl Interceptor>> {
@@ -35,64 +246,278 @@ impl GeyserSubscriber {
             .map_err(Into::into)
     }
 
-    pub async fn subscribe(&self, block_tx: mpsc::Sender<u64>) -> Result<()> {
-        // Create client on demand
-        let mut client = self.create_client().await?;
+    /// Streams updates into `event_tx`, reconnecting with backoff whenever the
+    /// connection fails or the stream ends. Resubscriptions continue after the
+    /// last received slot, so updates from the outage are still delivered.
+    /// Returns once the receiver is dropped, or with the last error after
+    /// `max_attempts` failures in a row
+    pub async fn subscribe(&self, event_tx: mpsc::Sender<GeyserEvent>) -> Result<()> {
+        let mut failures = 0;
+        let mut reconnects: u64 = 0;
+        let mut last_slot = None;
+
+        loop {
+            let from_slot = last_slot.map(|slot: u64| slot + 1);
+            let connected = match self.create_client().await {
+                Ok(client) => {
+                    match self.stream_updates(client, &event_tx, &mut last_slot).await {
+                        Ok(received) => {
+                            if event_tx.is_closed() {
+                                info!("Update handler stopped, closing subscription");
+                                return Ok(());
+                            }
+                            // A stream that delivered updates counts as a healthy connection
+                            if received {
+                                failures = 0;
+                            }
+                            warn!("Subscription stream ended");
+                        }
+                        Err(e) => error!("Geyser subscription error: {:#}", e),
+                    }
+                    true
+                }
+                Err(e) => {
+                    error!("Failed to connect to geyser: {:#}", e);
+                    false
+                }
+            };
+
+            // The endpoint may not keep slots that far back, so a resubscription
+            // that didn't deliver anything starts from the tip next time
+            if connected
+                && let Some(from_slot) = from_slot
+                && last_slot < Some(from_slot)
+            {
+                warn!(
+                    "Could not resume from slot {}, the next subscription starts at the latest slot",
+                    from_slot
+                );
+                last_slot = None;
+            }
+
+            failures += 1;
+            if let Some(max) = self.reconnect.max_attempts
+                && failures > max
+            {
+                bail!(
+                    "Giving up after {} failed reconnect attempts ({} reconnects in total)",
+                    max,
+                    reconnects
+                );
+            }
+
+            let delay = self.reconnect.delay(failures);
+            reconnects += 1;
+            Metrics::increment(&self.metrics.geyser_reconnects);
+            warn!(
+                "Reconnecting to {} in {:.1}s (attempt {}{}, reconnect #{})",
+                self.endpoint,
+                delay.as_secs_f64(),
+                failures,
+                self.reconnect
+                    .max_attempts
+                    .map_or(String::new(), |max| format!("/{}", max)),
+                reconnects
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
 
+    /// Block, block meta or account subscription for the trigger, starting at
+    /// `from_slot` if set
+    fn subscribe_request(&self, from_slot: Option<u64>) -> SubscribeRequest {
         // Create subscription request with block filter
+        let mut accounts = HashMap::new();
         let mut blocks = HashMap::new();
-        blocks.insert("blocks".to_string(), SubscribeRequestFilterBlocks {
-            account_include: vec!["11111111111111111111111111111111".to_string()], // just a system program to bypass the filter requirements
-            include_transactions: Some(true),
-            include_accounts: Some(false),
-            include_entries: Some(false),
-        });
-
-        // Create subscription request
-        let subscribe_request = SubscribeRequest {
+        let mut blocks_meta = HashMap::new();
+        match &self.trigger {
+            Trigger::Blocks {
+                mode: SubscriptionMode::Blocks,
+                watched,
+            } => {
+                let account_include = if watched.is_empty() {
+                    vec![SYSTEM_PROGRAM.to_string()]
+                } else {
+                    watched.iter().map(Pubkey::to_string).collect()
+                };
+                blocks.insert("blocks".to_string(), SubscribeRequestFilterBlocks {
+                    account_include,
+                    include_transactions: Some(true),
+                    include_accounts: Some(false),
+                    include_entries: Some(false),
+                });
+            }
+            Trigger::Blocks {
+                mode: SubscriptionMode::BlocksMeta,
+                ..
+            } => {
+                blocks_meta.insert(
+                    "blocks_meta".to_string(),
+                    SubscribeRequestFilterBlocksMeta {},
+                );
+            }
+            Trigger::AccountUpdate { accounts: pubkeys } => {
+                accounts.insert("accounts".to_string(), SubscribeRequestFilterAccounts {
+                    account: pubkeys.iter().map(Pubkey::to_string).collect(),
+                    owner: vec![],
+                    filters: vec![],
+                    nonempty_txn_signature: None,
+                });
+            }
+        }
+
+        SubscribeRequest {
             slots: HashMap::new(),
-            accounts: HashMap::new(),
+            accounts,
             transactions: HashMap::new(),
             blocks,
-            blocks_meta: HashMap::new(),
+            blocks_meta,
             accounts_data_slice: vec![],
-            commitment: Some(CommitmentLevel::Confirmed as i32),
+            commitment: Some(self.commitment.geyser_level() as i32),
             entry: HashMap::new(),
             transactions_status: HashMap::new(),
             ping: None,
-            from_slot: None,
+            from_slot,
+        }
+    }
+
+    /// Connects, subscribes and waits up to `timeout` for the first block,
+    /// returning its slot. Nothing is sent and the connection is closed again
+    pub async fn check_connection(&self, timeout: Duration) -> Result<u64> {
+        let mut client = self.create_client().await?;
+        let mut request = self.subscribe_request(None);
+        // Trigger accounts may not change for a long time, slots always do
+        if let Trigger::AccountUpdate { .. } = self.trigger {
+            request
+                .slots
+                .insert("slots".to_string(), SubscribeRequestFilterSlots {
+                    filter_by_commitment: Some(true),
+                    interslot_updates: None,
+                });
+        }
+        let (_subscribe_tx, mut subscription_stream) =
+            client.subscribe_with_request(Some(request)).await?;
+        info!("Subscription established, waiting for the first block...");
+
+        let first_slot = async {
+            while let Some(message) = subscription_stream.next().await {
+                match message?.update_oneof {
+                    Some(UpdateOneof::Block(block)) => return Ok(block.slot),
+                    Some(UpdateOneof::BlockMeta(block_meta)) => return Ok(block_meta.slot),
+                    Some(UpdateOneof::Slot(slot)) => return Ok(slot.slot),
+                    Some(UpdateOneof::Account(update)) => return Ok(update.slot),
+                    _ => continue,
+                }
+            }
+            bail!("Subscription stream ended before the first block")
         };
+        tokio::time::timeout(timeout, first_slot)
+            .await
+            .with_context(|| format!("No block received within {}s", timeout.as_secs()))?
+    }
 
-        info!("Subscribing to block updates...");
-        let (_, mut subscription_stream) = client
+    /// Runs a single subscription until its stream ends, starting after
+    /// `last_slot` if set and updating it for every update. Returns whether
+    /// any update was received
+    async fn stream_updates<I: Interceptor>(
+        &self,
+        mut client: GeyserGrpcClient<I>,
+        event_tx: &mpsc::Sender<GeyserEvent>,
+        last_slot: &mut Option<u64>,
+    ) -> Result<bool> {
+        let subscribe_request = self.subscribe_request(last_slot.map(|slot| slot + 1));
+        let updates = self.trigger.updates();
+        match last_slot {
+            Some(slot) => info!("Subscribing to {} after slot {}...", updates, slot),
+            None => info!("Subscribing to {}...", updates),
+        }
+        let (mut subscribe_tx, mut subscription_stream) = client
             .subscribe_with_request(Some(subscribe_request))
             .await?;
         info!("Subscription established successfully");
 
+        let mut received = false;
         while let Some(message) = subscription_stream.next().await {
             if let Ok(message) = message {
-                match message.update_oneof {
+                // Ignore other update types
+                let event = match message.update_oneof {
                     Some(UpdateOneof::Block(block)) => {
-                        info!("Received block update for slot: {}", block.slot);
-                        if let Err(e) = block_tx.send(block.slot).await {
-                            error!("Failed to send block update to handler: {}", e);
+                        let watched = match &self.trigger {
+                            Trigger::Blocks { watched, .. } => watched.as_slice(),
+                            Trigger::AccountUpdate { .. } => &[],
+                        };
+                        GeyserEvent::Block(BlockEvent::from_block(&block, watched))
+                    }
+                    Some(UpdateOneof::BlockMeta(block_meta)) => GeyserEvent::Block(BlockEvent {
+                        slot: block_meta.slot,
+                        matched: Vec::new(),
+                        signatures: Vec::new(),
+                        summary: BlockSummary::default(),
+                    }),
+                    // Startup notifications replay the current state, not a change
+                    Some(UpdateOneof::Account(update)) if !update.is_startup => {
+                        match AccountUpdate::from_update(&update) {
+                            Some(update) => GeyserEvent::AccountUpdate(update),
+                            None => {
+                                warn!("Skipping malformed account update in slot {}", update.slot);
+                                continue;
+                            }
+                        }
+                    }
+                    // The server drops subscriptions that stop answering its pings
+                    Some(UpdateOneof::Ping(_)) => {
+                        info!("Received ping from geyser, answering");
+                        let pong = SubscribeRequest {
+                            ping: Some(SubscribeRequestPing { id: 1 }),
+                            ..Default::default()
+                        };
+                        if let Err(e) = subscribe_tx.send(pong).await {
+                            error!("Failed to answer geyser ping: {}", e);
                         }
+                        continue;
                     }
-                    _ => {} // Ignore other update types
+                    _ => continue,
+                };
+                let slot = event.slot();
+                match &event {
+                    GeyserEvent::Block(_) => {
+                        Metrics::increment(&self.metrics.blocks_received);
+                        info!("Received block update for slot: {}", slot)
+                    }
+                    GeyserEvent::AccountUpdate(update) => {
+                        Metrics::increment(&self.metrics.account_updates);
+                        info!(
+                            "Received account update for {} in slot: {}",
+                            update.pubkey, slot
+                        )
+                    }
+                }
+                received = true;
+                *last_slot = Some(last_slot.map_or(slot, |last| last.max(slot)));
+                if let Err(e) = event_tx.send(event).await {
+                    error!("Failed to send {} to handler: {}", updates, e);
+                    break;
                 }
             } else {
                 error!("Error receiving message: {:?}", message.err());
             }
         }
 
-        error!("Subscription stream ended");
-        Ok(())
+        Ok(received)
     }
 }
 
-pub async fn start_subscription(endpoint: String, token: String) -> Result<mpsc::Receiver<u64>> {
+pub async fn start_subscription(
+    endpoint: String,
+    token: String,
+    reconnect: ReconnectPolicy,
+    trigger: Trigger,
+    commitment: Commitment,
+    metrics: Arc<Metrics>,
+) -> Result<mpsc::Receiver<GeyserEvent>> {
     let (tx, rx) = mpsc::channel(100); // Buffer size of 100
-    let subscriber = GeyserSubscriber::new(endpoint, token);
+    let subscriber =
+        GeyserSubscriber::new(endpoint, token, reconnect, trigger, commitment, metrics);
 
     tokio::spawn(async move {
         if let Err(e) = subscriber.subscribe(tx).await {
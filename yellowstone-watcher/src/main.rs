@@ -1,15 +1,63 @@
+mod check;
 mod cli;
 mod config;
 mod geyser;
+mod metrics;
+mod priority_fee;
+mod rate_limit;
+mod rpc_pool;
+mod rules;
+mod send_queue;
+mod slots;
 mod transaction;
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use cli::{Commands, parse_args};
-use config::Config;
+use config::{Config, ConfigFormat, SubscriptionMode, TriggerMode};
+use geyser::{GeyserEvent, GeyserSubscriber, ReconnectPolicy, Trigger};
+use metrics::Metrics;
+use rate_limit::{BlockStats, SendRateLimiter};
+use rpc_pool::RpcPool;
+use send_queue::{SendQueue, SendRequest};
+use slots::{LatestWrites, RecentSlots};
 use std::fs::File;
 use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::signal;
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
+
+/// Slots remembered to skip re-notifications, far more than a reconnect replays
+const RECENT_SLOTS_CAPACITY: usize = 1024;
+
+/// Subscriber for a one-off connection check, it never reconnects
+fn check_subscriber(config: &Config) -> Result<GeyserSubscriber> {
+    Ok(GeyserSubscriber::new(
+        config.geyser_endpoint.clone(),
+        config.geyser_token.clone(),
+        ReconnectPolicy {
+            max_attempts: Some(0),
+            base_delay: Duration::from_secs(config.reconnect_delay_secs),
+            max_delay: Duration::from_secs(config.max_reconnect_delay_secs),
+        },
+        trigger(config)?,
+        config.commitment,
+        Arc::new(Metrics::new()),
+    ))
+}
+
+/// What the subscription streams, per the config's trigger mode
+fn trigger(config: &Config) -> Result<Trigger> {
+    Ok(match config.trigger_mode() {
+        TriggerMode::Blocks => Trigger::Blocks {
+            mode: config.subscription_mode,
+            watched: config.watched()?,
+        },
+        TriggerMode::AccountUpdate => Trigger::AccountUpdate {
+            accounts: config.trigger_accounts()?,
+        },
+    })
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -24,48 +72,208 @@ async fn main() -> Result<()> {
             let config = Config::load(&config_path)
                 .context(format!("Failed to load config from {:?}", config_path))?;
             info!("Configuration loaded successfully");
+            // Catch a missing keypair or malformed endpoint before connecting anywhere
+            check::report(&check::offline_checks(&config))
+                .context("Config check failed, see `check` for the full checks")?;
 
-            let destination = config.destination_pubkey()?;
-            let tx_sender = transaction::TransactionSender::new(
-                &config.keypair_path,
-                destination,
-                config.sol_amount,
-                &config.solana_rpc_url,
-            )?;
+            let metrics = Arc::new(Metrics::new());
+            if let Some(listen) = &config.metrics_listen {
+                metrics::serve(listen, Arc::clone(&metrics)).await?;
+            }
 
-            info!(
-                "Transaction sender initialized with destination: {}",
-                destination
+            let kind = transaction::TransactionKind::from_config(&config)?;
+            info!("Transaction sender initialized, sending a {}", kind);
+            let tx_sender = Arc::new(transaction::TransactionSender::new(
+                &config.keypair_path,
+                kind,
+                RpcPool::new(
+                    &config.rpc_urls()?,
+                    config.commitment.commitment_config(),
+                    Duration::from_secs(config.rpc_cooldown_secs),
+                ),
+                config.priority_fee.clone(),
+                Duration::from_secs(config.send_timeout_secs),
+                config.destination_cooldown_secs.map(Duration::from_secs),
+                Duration::from_secs(config.blockhash_ttl_secs),
+                Arc::clone(&metrics),
+            )?);
+            // Sends refresh the balance too, this keeps it current while none happen
+            let balance_sender = Arc::clone(&tx_sender);
+            let balance_refresh = Duration::from_secs(config.balance_refresh_secs.max(1));
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(balance_refresh);
+                loop {
+                    ticker.tick().await;
+                    if let Err(e) = balance_sender.refresh_balance().await {
+                        warn!("Failed to refresh the wallet balance: {:#}", e);
+                    }
+                }
+            });
+            let send_queue_size = config.send_queue_size.max(1);
+            let send_queue = Arc::new(SendQueue::new(send_queue_size, config.send_overflow));
+            let mut send_results = send_queue::spawn_workers(
+                config.max_concurrent_sends,
+                Arc::clone(&send_queue),
+                Arc::clone(&tx_sender),
             );
 
+            let trigger = trigger(&config)?;
+            match &trigger {
+                Trigger::Blocks {
+                    mode: SubscriptionMode::Blocks,
+                    watched,
+                } if watched.is_empty() && config.rules.is_none() => warn!(
+                    "No watch_accounts or watch_programs configured, every block triggers a send"
+                ),
+                Trigger::Blocks {
+                    mode: SubscriptionMode::BlocksMeta,
+                    watched,
+                } if !watched.is_empty() => warn!(
+                    "Watched accounts and programs only apply to subscription_mode: blocks, ignoring them"
+                ),
+                Trigger::AccountUpdate { accounts } => {
+                    info!("Sending on every update of {:?}", accounts)
+                }
+                _ => {}
+            }
+            if let Some(rules) = &config.rules {
+                info!("Only blocks meeting every rule trigger a send: {:?}", rules);
+            }
+
             // Start geyser subscription
-            let mut block_rx = geyser::start_subscription(
+            let mut event_rx = geyser::start_subscription(
                 config.geyser_endpoint.clone(),
                 config.geyser_token.clone(),
+                ReconnectPolicy {
+                    max_attempts: config.max_reconnect_attempts,
+                    base_delay: Duration::from_secs(config.reconnect_delay_secs),
+                    max_delay: Duration::from_secs(config.max_reconnect_delay_secs),
+                },
+                trigger,
+                config.commitment,
+                Arc::clone(&metrics),
             )
             .await?;
 
-            info!("Started subscription to Yellowstone Geyser, listening for blocks...");
+            info!("Started subscription to Yellowstone Geyser, listening for updates...");
 
-            // Process block notifications
+            // Process block and account notifications
+            let mut recent_slots = RecentSlots::new(RECENT_SLOTS_CAPACITY);
+            let mut latest_writes = LatestWrites::default();
+            let mut rate_limiter = SendRateLimiter::new(
+                config.min_interval_ms.map(Duration::from_millis),
+                config.max_transactions_per_minute,
+            );
+            let mut stats = BlockStats::default();
+            let stats_interval = Duration::from_secs(config.stats_interval_secs.max(1));
+            let mut stats_tick = tokio::time::interval_at(
+                tokio::time::Instant::now() + stats_interval,
+                stats_interval,
+            );
             loop {
                 tokio::select! {
-                    Some(slot) = block_rx.recv() => {
-                        info!("Received new block: slot {}", slot);
-
-                        // Send transaction for new block
-                        match tx_sender.send_transaction().await {
-                            Ok(signature) => {
-                                info!("Transaction sent successfully: {}", signature);
+                    event = event_rx.recv() => {
+                        // The subscription only stops once it ran out of reconnects
+                        let Some(event) = event else {
+                            info!("Stats: {}, {}", stats, metrics);
+                            bail!("Geyser subscription stopped, exiting");
+                        };
+                        let slot = event.slot();
+                        match event {
+                            GeyserEvent::Block(event) => {
+                                stats.received += 1;
+                                // The same slot can arrive again, e.g. after a reconnect
+                                if !recent_slots.insert(slot) {
+                                    debug!("Skipping already handled slot {}", slot);
+                                    stats.duplicates += 1;
+                                    continue;
+                                }
+                                if event.matched.is_empty() {
+                                    info!("Received new block: slot {}", slot);
+                                } else {
+                                    info!(
+                                        "Received new block: slot {}, {} transactions touching {:?}",
+                                        slot,
+                                        event.signatures.len(),
+                                        event.matched
+                                    );
+                                    debug!("Matching transactions in slot {}: {:?}", slot, event.signatures);
+                                }
+                                if let Some(rules) = &config.rules
+                                    && let Err(reason) = rules::evaluate(rules, &event.summary)
+                                {
+                                    debug!("Block in slot {} filtered out by rules: {}", slot, reason);
+                                    stats.filtered += 1;
+                                    continue;
+                                }
                             }
-                            Err(e) => {
-                                error!("Failed to send transaction: {}", e);
+                            GeyserEvent::AccountUpdate(update) => {
+                                stats.account_updates += 1;
+                                // Geyser may notify the same write twice, e.g. after a reconnect
+                                if !latest_writes.insert(update.pubkey, update.write_version) {
+                                    debug!(
+                                        "Skipping already handled write {} of {}",
+                                        update.write_version, update.pubkey
+                                    );
+                                    stats.duplicates += 1;
+                                    continue;
+                                }
+                                info!(
+                                    "Account {} updated in slot {} ({} lamports, write version {}), triggering a send",
+                                    update.pubkey, slot, update.lamports, update.write_version
+                                );
                             }
                         }
+
+                        // Checked first, so sends the cooldown suppresses don't use up the rate limit
+                        if tx_sender.cooling_down() {
+                            info!("Every destination is cooling down, skipping the send for slot {}", slot);
+                            stats.cooled_down += 1;
+                            continue;
+                        }
+                        let now = Instant::now();
+                        if !rate_limiter.try_acquire(now) {
+                            info!("Rate limit reached, skipping the send for slot {}", slot);
+                            stats.rate_limited += 1;
+                            continue;
+                        }
+
+                        // Only enqueue here, the workers send so slow confirmations don't hold up new blocks
+                        let request = SendRequest { slot, queued_at: now };
+                        if let Some(dropped) = send_queue.push(request) {
+                            stats.overflowed += 1;
+                            warn!(
+                                "Send queue full ({} waiting), dropping the send for slot {}",
+                                send_queue_size, dropped.slot
+                            );
+                        }
                     }
 
+                    Some(result) = send_results.recv() => {
+                        let latency_ms = result.queue_latency.as_millis();
+                        match result.outcome {
+                            Ok(Some(signature)) => {
+                                stats.sent += 1;
+                                info!(
+                                    "Transaction for slot {} sent successfully: {} (queued {}ms)",
+                                    result.slot, signature, latency_ms
+                                );
+                            }
+                            Ok(None) => {}
+                            // Already reported as a structured `insufficient_balance` event
+                            Err(e) if e.is::<transaction::InsufficientBalance>() => {}
+                            Err(e) => error!(
+                                "Failed to send transaction for slot {}: {} (queued {}ms)",
+                                result.slot, e, latency_ms
+                            ),
+                        }
+                    }
+
+                    _ = stats_tick.tick() => info!("Stats: {}, {}", stats, metrics),
+
                     // Handle program termination
                     _ = signal::ctrl_c() => {
+                        info!("Stats: {}, {}", stats, metrics);
                         info!("Received shutdown signal, exiting...");
                         break;
                     }
@@ -73,19 +281,51 @@ async fn main() -> Result<()> {
             }
         }
 
-        Commands::GenerateConfig { output } => {
-            let sample_config = r#"# Yellowstone Geyser gRPC configuration
-geyser_endpoint: "https://grpc.ny.shyft.to"
-geyser_token: "YOUR_GEYSER_TOKEN"
+        Commands::CheckConnection {
+            config: config_path,
+            timeout,
+        } => {
+            let config = Config::load(&config_path)
+                .context(format!("Failed to load config from {:?}", config_path))?;
 
-# Solana RPC endpoint for sending transactions
-solana_rpc_url: "https://api.mainnet-beta.solana.com"
+            let subscriber = check_subscriber(&config)?;
+            let slot = subscriber
+                .check_connection(Duration::from_secs(timeout))
+                .await
+                .with_context(|| {
+                    format!("Connection check against {} failed", config.geyser_endpoint)
+                })?;
+            info!(
+                "Connection to {} works, first block at slot {}",
+                config.geyser_endpoint, slot
+            );
+        }
 
-# Solana transaction configuration
-keypair_path: "/path/to/your/keypair.json"
-destination_wallet: "YOUR_DESTINATION_WALLET_ADDRESS"
-sol_amount: 0.001
-"#;
+        Commands::Check {
+            config: config_path,
+            timeout,
+        } => {
+            let config = Config::load(&config_path)
+                .context(format!("Failed to load config from {:?}", config_path))?;
+            info!("Configuration loaded successfully");
+
+            let subscriber = check_subscriber(&config)?;
+            let checks =
+                check::all_checks(&config, &subscriber, Duration::from_secs(timeout)).await;
+            check::report(&checks)?;
+        }
+
+        Commands::GenerateConfig { output, format } => {
+            let format = format.unwrap_or_else(|| {
+                output
+                    .as_deref()
+                    .map_or(ConfigFormat::Yaml, ConfigFormat::from_path)
+            });
+            let output = output.unwrap_or_else(|| format.default_path());
+            let sample_config = match format {
+                ConfigFormat::Yaml => SAMPLE_YAML_CONFIG,
+                ConfigFormat::Toml => SAMPLE_TOML_CONFIG,
+            };
 
             let mut file = File::create(&output)
                 .context(format!("Failed to create config file at {:?}", output))?;
@@ -100,3 +340,210 @@ sol_amount: 0.001
 
     Ok(())
 }
+
+/// Written by `generate-config` for YAML
+const SAMPLE_YAML_CONFIG: &str = r#"# Yellowstone Geyser gRPC configuration
+geyser_endpoint: "https://grpc.ny.shyft.to"
+geyser_token: "YOUR_GEYSER_TOKEN"
+
+# Solana RPC endpoint for sending transactions, or a list to fail over to the next while one is failing
+solana_rpc_url: "https://api.mainnet-beta.solana.com"
+# solana_rpc_urls:
+#   - "https://api.mainnet-beta.solana.com"
+#   - "https://YOUR_BACKUP_RPC"
+# Skip a failing endpoint for this many seconds before trying it again
+rpc_cooldown_secs: 30
+
+# Solana transaction configuration
+# A keypair file, `base58:<secret key>` or `env:<VAR>` naming a variable with the secret
+keypair_path: "/path/to/your/keypair.json"
+# What every send does: transfer (the amount below to the destinations), memo or custom
+transaction_type: transfer
+# A single address, or a list that is paid round-robin, one per block
+destination_wallet:
+  - "YOUR_DESTINATION_WALLET_ADDRESS"
+  - "ANOTHER_DESTINATION_WALLET_ADDRESS"
+# Amount per block in SOL, or exactly in lamports instead (set only one)
+sol_amount: 0.001
+# lamports: 1000000
+# For memo: the text, {slot} and {timestamp} are replaced with the triggering slot and the unix time
+# memo: "triggered by slot {slot} at {timestamp}"
+# For custom: the instruction to send, only the wallet above can be a signer
+# custom_instruction:
+#   program_id: "YOUR_PROGRAM_ID"
+#   accounts:
+#     - pubkey: "YOUR_ACCOUNT_ADDRESS"
+#       is_signer: false
+#       is_writable: true
+#   data: "3Bxs4h24hBtQy9rw" # base58, or hex with data_encoding: hex
+
+# Give up on a send that hasn't confirmed after this many seconds
+send_timeout_secs: 60
+
+# blocks streams full blocks, blocks_meta only their slot and blockhash (much less bandwidth)
+subscription_mode: blocks
+
+# Optional: send whenever one of these accounts changes (data or lamports) instead of per block
+# trigger:
+#   mode: account_update
+#   accounts:
+#     - "YOUR_ORACLE_ACCOUNT_ADDRESS"
+
+# processed, confirmed or finalized; used for both the block stream and sending
+commitment: confirmed
+
+# Optional: only react to blocks with transactions touching one of these accounts or programs
+# (blocks mode only). The matches are logged with each block. When both are empty every block triggers a send
+# watch_accounts:
+#   - "YOUR_WATCHED_ACCOUNT_ADDRESS"
+# watch_programs:
+#   - "675kPX9MHTjS2zt1qfr1NYHuzeLXfQM9H24wFSUt1Mp8"
+
+# Optional: only send when the streamed transactions of a block meet all of these (blocks mode only)
+# rules:
+#   min_transactions_in_block: 5
+#   # A transaction changed the balance of a watched account by at least this much
+#   watched_address_min_lamports: 1000000000
+#   # Leave vote transactions out of both
+#   only_vote_excluded: true
+
+# Optional: give up after this many failed reconnects to the geyser endpoint in a row (retries forever if unset)
+# max_reconnect_attempts: 10
+
+# Reconnect delay in seconds, doubled per failed attempt up to the maximum and randomized by up to half
+reconnect_delay_secs: 1
+max_reconnect_delay_secs: 60
+
+# Reuse a fetched blockhash for this many seconds (it stays valid for about a minute)
+blockhash_ttl_secs: 20
+
+# Optional: skip blocks instead of sending more often than this
+# min_interval_ms: 2000
+# max_transactions_per_minute: 20
+
+# Log counters of received, sent and skipped blocks, failed sends, reconnects, latencies and the balance this often
+stats_interval_secs: 60
+
+# Optional: serve the same in Prometheus text format over HTTP
+# metrics_listen: "127.0.0.1:9090"
+# Fetch the wallet balance for the metrics this often, sends fetch it as well
+balance_refresh_secs: 300
+
+# Send workers, each with one transaction in flight. Blocks wait in a queue of send_queue_size;
+# when it's full the oldest or the new block is dropped (send_overflow: drop_oldest | drop_newest)
+max_concurrent_sends: 1
+send_queue_size: 16
+send_overflow: drop_oldest
+
+# Optional: skip blocks until this many seconds passed since the last send to the destination
+# destination_cooldown_secs: 30
+
+# Optional: escalate the priority fee while transactions keep getting dropped
+# priority_fee:
+#   initial_micro_lamports: 0
+#   step_micro_lamports: 10000
+#   max_micro_lamports: 1000000
+"#;
+
+/// The same as TOML
+const SAMPLE_TOML_CONFIG: &str = r#"# Yellowstone Geyser gRPC configuration
+geyser_endpoint = "https://grpc.ny.shyft.to"
+geyser_token = "YOUR_GEYSER_TOKEN"
+
+# Solana RPC endpoint for sending transactions, or a list to fail over to the next while one is failing
+solana_rpc_url = "https://api.mainnet-beta.solana.com"
+# solana_rpc_urls = ["https://api.mainnet-beta.solana.com", "https://YOUR_BACKUP_RPC"]
+# Skip a failing endpoint for this many seconds before trying it again
+rpc_cooldown_secs = 30
+
+# Solana transaction configuration
+# A keypair file, `base58:<secret key>` or `env:<VAR>` naming a variable with the secret
+keypair_path = "/path/to/your/keypair.json"
+# What every send does: transfer (the amount below to the destinations), memo or custom
+transaction_type = "transfer"
+# A single address, or a list that is paid round-robin, one per block
+destination_wallet = [
+  "YOUR_DESTINATION_WALLET_ADDRESS",
+  "ANOTHER_DESTINATION_WALLET_ADDRESS",
+]
+# Amount per block in SOL, or exactly in lamports instead (set only one)
+sol_amount = 0.001
+# lamports = 1000000
+# For memo: the text, {slot} and {timestamp} are replaced with the triggering slot and the unix time
+# memo = "triggered by slot {slot} at {timestamp}"
+
+# Give up on a send that hasn't confirmed after this many seconds
+send_timeout_secs = 60
+
+# blocks streams full blocks, blocks_meta only their slot and blockhash (much less bandwidth)
+subscription_mode = "blocks"
+
+# processed, confirmed or finalized; used for both the block stream and sending
+commitment = "confirmed"
+
+# Optional: only react to blocks with transactions touching one of these accounts or programs
+# (blocks mode only). The matches are logged with each block. When both are empty every block triggers a send
+# watch_accounts = ["YOUR_WATCHED_ACCOUNT_ADDRESS"]
+# watch_programs = ["675kPX9MHTjS2zt1qfr1NYHuzeLXfQM9H24wFSUt1Mp8"]
+
+# Optional: give up after this many failed reconnects to the geyser endpoint in a row (retries forever if unset)
+# max_reconnect_attempts = 10
+
+# Reconnect delay in seconds, doubled per failed attempt up to the maximum and randomized by up to half
+reconnect_delay_secs = 1
+max_reconnect_delay_secs = 60
+
+# Reuse a fetched blockhash for this many seconds (it stays valid for about a minute)
+blockhash_ttl_secs = 20
+
+# Optional: skip blocks instead of sending more often than this
+# min_interval_ms = 2000
+# max_transactions_per_minute = 20
+
+# Log counters of received, sent and skipped blocks, failed sends, reconnects, latencies and the balance this often
+stats_interval_secs = 60
+
+# Optional: serve the same in Prometheus text format over HTTP
+# metrics_listen = "127.0.0.1:9090"
+# Fetch the wallet balance for the metrics this often, sends fetch it as well
+balance_refresh_secs = 300
+
+# Send workers, each with one transaction in flight. Blocks wait in a queue of send_queue_size;
+# when it's full the oldest or the new block is dropped (send_overflow = "drop_oldest" | "drop_newest")
+max_concurrent_sends = 1
+send_queue_size = 16
+send_overflow = "drop_oldest"
+
+# Optional: skip blocks until this many seconds passed since the last send to the destination
+# destination_cooldown_secs = 30
+
+# Tables go last, keys after them would belong to the table
+
+# Optional: send whenever one of these accounts changes (data or lamports) instead of per block
+# [trigger]
+# mode = "account_update"
+# accounts = ["YOUR_ORACLE_ACCOUNT_ADDRESS"]
+
+# Optional: only send when the streamed transactions of a block meet all of these (blocks mode only)
+# [rules]
+# min_transactions_in_block = 5
+# # A transaction changed the balance of a watched account by at least this much
+# watched_address_min_lamports = 1000000000
+# # Leave vote transactions out of both
+# only_vote_excluded = true
+
+# Optional: escalate the priority fee while transactions keep getting dropped
+# [priority_fee]
+# initial_micro_lamports = 0
+# step_micro_lamports = 10000
+# max_micro_lamports = 1000000
+
+# For transaction_type custom: the instruction to send, only the wallet can be a signer
+# [custom_instruction]
+# program_id = "YOUR_PROGRAM_ID"
+# data = "3Bxs4h24hBtQy9rw" # base58, or hex with data_encoding = "hex"
+# [[custom_instruction.accounts]]
+# pubkey = "YOUR_ACCOUNT_ADDRESS"
+# is_signer = false
+# is_writable = true
+"#;
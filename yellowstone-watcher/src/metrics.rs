@@ -0,0 +1,290 @@
+use anyhow::{Context, Result};
+use std::fmt::{self, Write as _};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+/// Upper bounds in seconds of the latency histogram buckets
+const LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Counts of observed durations per bucket, rendered as a Prometheus histogram
+#[derive(Default)]
+pub struct Histogram {
+    /// Observations per bucket, not cumulative. The last one is `+Inf`
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Mean of the observed durations, `None` before the first one
+    fn mean(&self) -> Option<Duration> {
+        let count = self.count.load(Ordering::Relaxed);
+        (count > 0).then(|| Duration::from_micros(self.sum_micros.load(Ordering::Relaxed) / count))
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// Counters shared by the subscriber, the send workers and the block loop,
+/// logged with the periodic stats and served to Prometheus
+#[derive(Default)]
+pub struct Metrics {
+    pub blocks_received: AtomicU64,
+    pub account_updates: AtomicU64,
+    pub geyser_reconnects: AtomicU64,
+    pub transactions_sent: AtomicU64,
+    /// Sends the RPC rejected or that didn't confirm in time
+    pub transactions_failed: AtomicU64,
+    /// From the block or account update that triggered a send until the
+    /// transaction was submitted
+    pub block_to_send: Histogram,
+    /// From submitting a transaction until it confirmed
+    pub confirmation: Histogram,
+    /// Balance of the sending wallet as last fetched, `u64::MAX` before that
+    wallet_balance_lamports: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            wallet_balance_lamports: AtomicU64::new(u64::MAX),
+            ..Default::default()
+        }
+    }
+
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_wallet_balance(&self, lamports: u64) {
+        self.wallet_balance_lamports
+            .store(lamports, Ordering::Relaxed);
+    }
+
+    fn wallet_balance(&self) -> Option<u64> {
+        Some(self.wallet_balance_lamports.load(Ordering::Relaxed)).filter(|b| *b != u64::MAX)
+    }
+
+    /// Everything in the Prometheus text exposition format
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "yellowstone_watcher_blocks_received_total",
+                "Blocks received from geyser",
+                &self.blocks_received,
+            ),
+            (
+                "yellowstone_watcher_account_updates_total",
+                "Trigger account updates received from geyser",
+                &self.account_updates,
+            ),
+            (
+                "yellowstone_watcher_geyser_reconnects_total",
+                "Reconnects to the geyser endpoint",
+                &self.geyser_reconnects,
+            ),
+            (
+                "yellowstone_watcher_transactions_sent_total",
+                "Transactions sent and confirmed",
+                &self.transactions_sent,
+            ),
+            (
+                "yellowstone_watcher_transactions_failed_total",
+                "Transactions that failed or timed out",
+                &self.transactions_failed,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+
+        self.block_to_send.render(
+            &mut out,
+            "yellowstone_watcher_block_to_send_seconds",
+            "Time from the triggering update until the transaction was submitted",
+        );
+        self.confirmation.render(
+            &mut out,
+            "yellowstone_watcher_confirmation_seconds",
+            "Time from submitting a transaction until it confirmed",
+        );
+
+        // A gauge without a value yet is left out rather than reported as 0
+        if let Some(balance) = self.wallet_balance() {
+            let name = "yellowstone_watcher_wallet_balance_lamports";
+            let _ = writeln!(out, "# HELP {} Balance of the sending wallet", name);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, balance);
+        }
+        out
+    }
+}
+
+/// What the block loop's stats line doesn't already count
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed sends: {}, geyser reconnects: {}",
+            self.transactions_failed.load(Ordering::Relaxed),
+            self.geyser_reconnects.load(Ordering::Relaxed)
+        )?;
+        if let Some(mean) = self.block_to_send.mean() {
+            write!(f, ", mean block to send: {}ms", mean.as_millis())?;
+        }
+        if let Some(mean) = self.confirmation.mean() {
+            write!(f, ", mean confirmation: {}ms", mean.as_millis())?;
+        }
+        if let Some(balance) = self.wallet_balance() {
+            write!(
+                f,
+                ", wallet balance: {} SOL",
+                common::lamports_to_sol(balance)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Binds `listen` and serves the metrics to every request on it, whatever
+/// its path. Binding fails right away, requests are served in the background
+pub async fn serve(listen: &str, metrics: Arc<Metrics>) -> Result<()> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("Failed to listen for metrics on {}", listen))?;
+    info!("Serving Prometheus metrics on http://{}/metrics", listen);
+
+    tokio::spawn(async move {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept a metrics connection: {}", e);
+                    continue;
+                }
+            };
+            let metrics = Arc::clone(&metrics);
+            tokio::spawn(async move {
+                // Only the request line matters, and not even that
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let body = metrics.render_prometheus();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                if let Err(e) = stream.write_all(response.as_bytes()).await {
+                    warn!("Failed to serve metrics: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lines of `output` that aren't comments
+    fn samples(output: &str) -> Vec<&str> {
+        output
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect()
+    }
+
+    #[test]
+    fn renders_every_metric_after_events() {
+        let metrics = Metrics::new();
+        Metrics::increment(&metrics.blocks_received);
+        Metrics::increment(&metrics.blocks_received);
+        Metrics::increment(&metrics.account_updates);
+        Metrics::increment(&metrics.geyser_reconnects);
+        Metrics::increment(&metrics.transactions_sent);
+        Metrics::increment(&metrics.transactions_failed);
+        metrics.block_to_send.observe(Duration::from_millis(80));
+        metrics.confirmation.observe(Duration::from_millis(400));
+        metrics.confirmation.observe(Duration::from_secs(90));
+        metrics.set_wallet_balance(1_500_000_000);
+
+        let output = metrics.render_prometheus();
+        let samples = samples(&output);
+        for expected in [
+            "yellowstone_watcher_blocks_received_total 2",
+            "yellowstone_watcher_account_updates_total 1",
+            "yellowstone_watcher_geyser_reconnects_total 1",
+            "yellowstone_watcher_transactions_sent_total 1",
+            "yellowstone_watcher_transactions_failed_total 1",
+            "yellowstone_watcher_block_to_send_seconds_bucket{le=\"0.05\"} 0",
+            "yellowstone_watcher_block_to_send_seconds_bucket{le=\"0.1\"} 1",
+            "yellowstone_watcher_block_to_send_seconds_count 1",
+            "yellowstone_watcher_confirmation_seconds_bucket{le=\"0.25\"} 0",
+            "yellowstone_watcher_confirmation_seconds_bucket{le=\"0.5\"} 1",
+            "yellowstone_watcher_confirmation_seconds_bucket{le=\"60\"} 1",
+            "yellowstone_watcher_confirmation_seconds_bucket{le=\"+Inf\"} 2",
+            "yellowstone_watcher_confirmation_seconds_sum 90.4",
+            "yellowstone_watcher_confirmation_seconds_count 2",
+            "yellowstone_watcher_wallet_balance_lamports 1500000000",
+        ] {
+            assert!(
+                samples.contains(&expected),
+                "{} missing from\n{}",
+                expected,
+                output
+            );
+        }
+        assert!(output.contains("# TYPE yellowstone_watcher_confirmation_seconds histogram"));
+        assert!(output.contains("# TYPE yellowstone_watcher_wallet_balance_lamports gauge"));
+    }
+
+    #[test]
+    fn leaves_out_the_balance_before_it_was_fetched() {
+        let output = Metrics::new().render_prometheus();
+        assert!(!output.contains("wallet_balance"));
+        assert!(samples(&output).contains(&"yellowstone_watcher_blocks_received_total 0"));
+    }
+
+    #[test]
+    fn stats_line_shows_means_once_observed() {
+        let metrics = Metrics::new();
+        assert_eq!(metrics.to_string(), "failed sends: 0, geyser reconnects: 0");
+        metrics.confirmation.observe(Duration::from_millis(300));
+        metrics.confirmation.observe(Duration::from_millis(500));
+        assert!(metrics.to_string().ends_with(", mean confirmation: 400ms"));
+    }
+}
@@ -0,0 +1,102 @@
+use crate::config::PriorityFeeConfig;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{info, warn};
+
+/// Escalates the compute unit price while transactions keep failing to land
+/// and resets it once one lands again
+pub struct PriorityFeeController {
+    config: PriorityFeeConfig,
+    current_price: AtomicU64,
+    consecutive_drops: AtomicU64,
+}
+
+impl PriorityFeeController {
+    pub fn new(config: PriorityFeeConfig) -> Self {
+        let initial = config.initial_micro_lamports.min(config.max_micro_lamports);
+        Self {
+            config,
+            current_price: AtomicU64::new(initial),
+            consecutive_drops: AtomicU64::new(0),
+        }
+    }
+
+    /// Compute unit price in micro-lamports to use for the next transaction
+    pub fn current_price(&self) -> u64 {
+        self.current_price.load(Ordering::Relaxed)
+    }
+
+    pub fn record_drop(&self) {
+        let drops = self.consecutive_drops.fetch_add(1, Ordering::Relaxed) + 1;
+        let previous = self.current_price();
+        let escalated = previous
+            .saturating_add(self.config.step_micro_lamports)
+            .min(self.config.max_micro_lamports);
+
+        if escalated > previous {
+            self.current_price.store(escalated, Ordering::Relaxed);
+            warn!(
+                "Transaction dropped {} time(s) in a row, escalating priority fee: {} -> {} micro-lamports",
+                drops, previous, escalated
+            );
+        } else {
+            warn!(
+                "Transaction dropped {} time(s) in a row, priority fee already at cap of {} micro-lamports",
+                drops, self.config.max_micro_lamports
+            );
+        }
+    }
+
+    pub fn record_success(&self) {
+        let drops = self.consecutive_drops.swap(0, Ordering::Relaxed);
+        let initial = self
+            .config
+            .initial_micro_lamports
+            .min(self.config.max_micro_lamports);
+        if drops > 0 && self.current_price.swap(initial, Ordering::Relaxed) != initial {
+            info!(
+                "Transaction landed, resetting priority fee to {} micro-lamports",
+                initial
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller(initial: u64, step: u64, max: u64) -> PriorityFeeController {
+        PriorityFeeController::new(PriorityFeeConfig {
+            initial_micro_lamports: initial,
+            step_micro_lamports: step,
+            max_micro_lamports: max,
+        })
+    }
+
+    #[test]
+    fn escalates_per_drop_up_to_the_cap() {
+        let controller = controller(100, 50, 220);
+        assert_eq!(controller.current_price(), 100);
+        controller.record_drop();
+        assert_eq!(controller.current_price(), 150);
+        controller.record_drop();
+        controller.record_drop();
+        assert_eq!(controller.current_price(), 220);
+    }
+
+    #[test]
+    fn resets_once_a_transaction_lands() {
+        let controller = controller(100, 50, 1000);
+        controller.record_drop();
+        controller.record_drop();
+        controller.record_success();
+        assert_eq!(controller.current_price(), 100);
+        controller.record_drop();
+        assert_eq!(controller.current_price(), 150);
+    }
+
+    #[test]
+    fn initial_price_is_capped() {
+        assert_eq!(controller(500, 50, 300).current_price(), 300);
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// Caps how often blocks trigger a send. Both limits are off when unset
+pub struct SendRateLimiter {
+    min_interval: Option<Duration>,
+    max_per_minute: Option<usize>,
+    /// Sends allowed within the last minute, oldest first
+    recent: VecDeque<Instant>,
+}
+
+impl SendRateLimiter {
+    pub fn new(min_interval: Option<Duration>, max_per_minute: Option<usize>) -> Self {
+        Self {
+            min_interval,
+            max_per_minute,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Records a send at `now` if both limits allow it, returns `false` if the
+    /// block should be skipped
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        while let Some(oldest) = self.recent.front()
+            && now.duration_since(*oldest) >= MINUTE
+        {
+            self.recent.pop_front();
+        }
+
+        if let Some(min_interval) = self.min_interval
+            && let Some(last) = self.recent.back()
+            && now.duration_since(*last) < min_interval
+        {
+            return false;
+        }
+        if let Some(max) = self.max_per_minute
+            && self.recent.len() >= max
+        {
+            return false;
+        }
+
+        // Only the last minute is needed, the interval just looks at the newest
+        self.recent.push_back(now);
+        if self.max_per_minute.is_none() && self.recent.len() > 1 {
+            self.recent.pop_front();
+        }
+        true
+    }
+}
+
+/// Counters for the periodic stats line
+#[derive(Debug, Default)]
+pub struct BlockStats {
+    pub received: u64,
+    /// Writes to trigger accounts, in the account_update trigger mode
+    pub account_updates: u64,
+    pub sent: u64,
+    pub rate_limited: u64,
+    /// Skipped while every destination was cooling down
+    pub cooled_down: u64,
+    pub duplicates: u64,
+    /// Didn't meet the send rules
+    pub filtered: u64,
+    /// Dropped because the send queue was full
+    pub overflowed: u64,
+}
+
+impl fmt::Display for BlockStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.account_updates > 0 {
+            write!(f, "account updates received: {}, ", self.account_updates)?;
+        }
+        write!(
+            f,
+            "blocks received: {}, transactions sent: {}, skipped by rate limit: {}, \
+            skipped as duplicates: {}, ",
+            self.received, self.sent, self.rate_limited, self.duplicates
+        )?;
+        if self.cooled_down > 0 {
+            write!(
+                f,
+                "skipped during destination cooldowns: {}, ",
+                self.cooled_down
+            )?;
+        }
+        if self.filtered > 0 {
+            write!(f, "filtered out by rules: {}, ", self.filtered)?;
+        }
+        write!(f, "dropped by a full send queue: {}", self.overflowed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enforces_the_minimum_interval() {
+        let mut limiter = SendRateLimiter::new(Some(Duration::from_millis(500)), None);
+        let start = Instant::now();
+        assert!(limiter.try_acquire(start));
+        assert!(!limiter.try_acquire(start + Duration::from_millis(499)));
+        assert!(limiter.try_acquire(start + Duration::from_millis(500)));
+        // Skipped blocks don't count as sends
+        assert!(limiter.try_acquire(start + Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn allows_at_most_the_sends_per_minute() {
+        let mut limiter = SendRateLimiter::new(None, Some(2));
+        let start = Instant::now();
+        assert!(limiter.try_acquire(start));
+        assert!(limiter.try_acquire(start + Duration::from_secs(1)));
+        assert!(!limiter.try_acquire(start + Duration::from_secs(59)));
+        // The first send falls out of the window
+        assert!(limiter.try_acquire(start + MINUTE));
+        assert!(!limiter.try_acquire(start + MINUTE));
+    }
+
+    #[test]
+    fn reports_cooldown_skips_once_there_are_any() {
+        let mut stats = BlockStats {
+            received: 3,
+            rate_limited: 1,
+            ..BlockStats::default()
+        };
+        assert!(!stats.to_string().contains("cooldowns"));
+        stats.cooled_down = 2;
+        assert!(
+            stats
+                .to_string()
+                .contains("skipped during destination cooldowns: 2, ")
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use crate::transaction::RPC_TIMEOUT;
+use common::SolanaRpc;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::commitment_config::CommitmentConfig;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+struct Endpoint<R> {
+    client: R,
+    /// Skipped until then after a failure, `None` while healthy
+    unhealthy_until: Mutex<Option<Instant>>,
+}
+
+/// RPC endpoints to send through, in order of preference. One that fails is
+/// skipped for `cooldown`, then tried again
+pub struct RpcPool<R = RpcClient> {
+    endpoints: Vec<Endpoint<R>>,
+    cooldown: Duration,
+}
+
+impl RpcPool {
+    pub fn new(urls: &[String], commitment: CommitmentConfig, cooldown: Duration) -> Self {
+        Self::from_clients(
+            urls.iter()
+                .map(|url| common::make_rpc_client(url, commitment, RPC_TIMEOUT))
+                .collect(),
+            cooldown,
+        )
+    }
+}
+
+impl<R: SolanaRpc> RpcPool<R> {
+    pub fn from_clients(clients: Vec<R>, cooldown: Duration) -> Self {
+        Self {
+            endpoints: clients
+                .into_iter()
+                .map(|client| Endpoint {
+                    client,
+                    unhealthy_until: Mutex::new(None),
+                })
+                .collect(),
+            cooldown,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    /// The first healthy endpoint, or the one whose cooldown ends first if
+    /// none is, since sending through a failing endpoint beats not sending
+    pub fn pick(&self) -> usize {
+        let now = Instant::now();
+        let mut soonest: Option<(usize, Instant)> = None;
+        for (i, endpoint) in self.endpoints.iter().enumerate() {
+            match *endpoint.unhealthy_until.lock().unwrap() {
+                Some(until) if until > now => {
+                    if soonest.is_none_or(|(_, soonest)| until < soonest) {
+                        soonest = Some((i, until));
+                    }
+                }
+                _ => return i,
+            }
+        }
+        soonest.map_or(0, |(i, _)| i)
+    }
+
+    pub fn client(&self, i: usize) -> &R {
+        &self.endpoints[i].client
+    }
+
+    pub fn url(&self, i: usize) -> String {
+        self.endpoints[i].client.url()
+    }
+
+    /// Takes the endpoint out of rotation for the cooldown
+    pub fn mark_failed(&self, i: usize, error: impl fmt::Display) {
+        *self.endpoints[i].unhealthy_until.lock().unwrap() = Some(Instant::now() + self.cooldown);
+        if self.endpoints.len() > 1 {
+            warn!(
+                "RPC {} marked unhealthy for {}s: {}",
+                self.url(i),
+                self.cooldown.as_secs(),
+                error
+            );
+        }
+    }
+
+    /// Puts an endpoint that served a request back into rotation
+    pub fn mark_healthy(&self, i: usize) {
+        if self.endpoints[i]
+            .unhealthy_until
+            .lock()
+            .unwrap()
+            .take()
+            .is_some()
+            && self.endpoints.len() > 1
+        {
+            info!("RPC {} is healthy again", self.url(i));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::mock::MockRpc;
+    use tokio::time::advance;
+
+    const COOLDOWN: Duration = Duration::from_secs(30);
+
+    fn pool(endpoints: usize) -> RpcPool<MockRpc> {
+        RpcPool::from_clients((0..endpoints).map(|_| MockRpc::new()).collect(), COOLDOWN)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn prefers_the_first_healthy_endpoint() {
+        let pool = pool(3);
+        assert_eq!(pool.pick(), 0);
+
+        pool.mark_failed(0, "connection refused");
+        assert_eq!(pool.pick(), 1);
+        pool.mark_failed(1, "connection refused");
+        assert_eq!(pool.pick(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_endpoints_return_after_the_cooldown() {
+        let pool = pool(2);
+        pool.mark_failed(0, "connection refused");
+
+        advance(COOLDOWN - Duration::from_secs(1)).await;
+        assert_eq!(pool.pick(), 1);
+        advance(Duration::from_secs(1)).await;
+        assert_eq!(pool.pick(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn mark_healthy_ends_the_cooldown() {
+        let pool = pool(2);
+        pool.mark_failed(0, "connection refused");
+        pool.mark_healthy(0);
+        assert_eq!(pool.pick(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn picks_the_soonest_back_when_none_is_healthy() {
+        let pool = pool(3);
+        pool.mark_failed(1, "connection refused");
+        advance(Duration::from_secs(1)).await;
+        pool.mark_failed(0, "connection refused");
+        pool.mark_failed(2, "connection refused");
+        assert_eq!(pool.pick(), 1);
+    }
+}
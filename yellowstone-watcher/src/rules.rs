@@ -0,0 +1,150 @@
+use crate::config::RulesConfig;
+
+/// What the send rules look at in a streamed block
+#[derive(Debug, Clone, Default)]
+pub struct BlockSummary {
+    /// The streamed transactions of the block, those touching a watched
+    /// account or program, or the system program when nothing is watched
+    pub transactions: Vec<TransactionSummary>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TransactionSummary {
+    pub is_vote: bool,
+    /// Largest balance change of a watched account in the transaction, 0 if
+    /// it touched none or nothing is watched
+    pub watched_lamports: u64,
+}
+
+/// Whether `block` passes every configured rule, or the first one it fails.
+/// Rules that aren't set always pass
+pub fn evaluate(rules: &RulesConfig, block: &BlockSummary) -> Result<(), String> {
+    let transactions = block
+        .transactions
+        .iter()
+        .filter(|transaction| !(rules.only_vote_excluded && transaction.is_vote));
+
+    if let Some(min) = rules.min_transactions_in_block {
+        let count = transactions.clone().count();
+        if count < min {
+            return Err(format!(
+                "{} transactions, min_transactions_in_block is {}",
+                count, min
+            ));
+        }
+    }
+
+    if let Some(min) = rules.watched_address_min_lamports {
+        let largest = transactions
+            .map(|transaction| transaction.watched_lamports)
+            .max()
+            .unwrap_or(0);
+        if largest < min {
+            return Err(format!(
+                "largest watched balance change is {} lamports, watched_address_min_lamports is {}",
+                largest, min
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(transactions: &[(bool, u64)]) -> BlockSummary {
+        BlockSummary {
+            transactions: transactions
+                .iter()
+                .map(|&(is_vote, watched_lamports)| TransactionSummary {
+                    is_vote,
+                    watched_lamports,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn no_rules_pass_every_block() {
+        let rules = RulesConfig::default();
+        assert_eq!(evaluate(&rules, &BlockSummary::default()), Ok(()));
+        assert_eq!(evaluate(&rules, &block(&[(true, 0)])), Ok(()));
+    }
+
+    #[test]
+    fn min_transactions_in_block() {
+        let rules = RulesConfig {
+            min_transactions_in_block: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(evaluate(&rules, &block(&[(false, 0), (true, 0)])), Ok(()));
+        assert_eq!(
+            evaluate(&rules, &block(&[(false, 0)])),
+            Err("1 transactions, min_transactions_in_block is 2".to_string())
+        );
+    }
+
+    #[test]
+    fn watched_address_min_lamports() {
+        let rules = RulesConfig {
+            watched_address_min_lamports: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!(
+            evaluate(&rules, &block(&[(false, 10), (false, 1000)])),
+            Ok(())
+        );
+        assert_eq!(
+            evaluate(&rules, &block(&[(false, 999)])),
+            Err(
+                "largest watched balance change is 999 lamports, watched_address_min_lamports is 1000"
+                    .to_string()
+            )
+        );
+        assert!(evaluate(&rules, &BlockSummary::default()).is_err());
+    }
+
+    #[test]
+    fn only_vote_excluded_leaves_votes_out_of_the_count() {
+        let rules = RulesConfig {
+            min_transactions_in_block: Some(2),
+            only_vote_excluded: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            evaluate(&rules, &block(&[(false, 0), (true, 0), (true, 0)])),
+            Err("1 transactions, min_transactions_in_block is 2".to_string())
+        );
+        assert_eq!(
+            evaluate(&rules, &block(&[(false, 0), (false, 0), (true, 0)])),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn only_vote_excluded_leaves_votes_out_of_the_balance_changes() {
+        let rules = RulesConfig {
+            watched_address_min_lamports: Some(1000),
+            only_vote_excluded: true,
+            ..Default::default()
+        };
+        assert!(evaluate(&rules, &block(&[(false, 10), (true, 5000)])).is_err());
+        assert_eq!(
+            evaluate(&rules, &block(&[(false, 1000), (true, 5000)])),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn the_first_failing_rule_is_reported() {
+        let rules = RulesConfig {
+            min_transactions_in_block: Some(3),
+            watched_address_min_lamports: Some(1000),
+            only_vote_excluded: false,
+        };
+        let failure = evaluate(&rules, &block(&[(false, 0)])).unwrap_err();
+        assert!(failure.contains("min_transactions_in_block"), "{}", failure);
+    }
+}
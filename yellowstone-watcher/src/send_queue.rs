@@ -0,0 +1,227 @@
+use crate::config::SendOverflow;
+use crate::transaction::TransactionSender;
+use anyhow::Result;
+use common::SolanaRpc;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, mpsc};
+
+/// A block waiting for its send
+#[derive(Debug)]
+pub struct SendRequest {
+    pub slot: u64,
+    pub queued_at: Instant,
+}
+
+/// What became of a send, reported back to the block loop
+pub struct SendResult {
+    pub slot: u64,
+    /// Time the request waited for a free worker
+    pub queue_latency: Duration,
+    /// The signature, `None` if nothing was sent, e.g. during a destination cooldown
+    pub outcome: Result<Option<String>>,
+}
+
+/// Bounded queue between the block loop and the send workers, so a burst of
+/// blocks neither stalls the loop nor grows memory without limit
+pub struct SendQueue {
+    requests: Mutex<VecDeque<SendRequest>>,
+    available: Notify,
+    capacity: usize,
+    overflow: SendOverflow,
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize, overflow: SendOverflow) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            requests: Mutex::new(VecDeque::with_capacity(capacity)),
+            available: Notify::new(),
+            capacity,
+            overflow,
+        }
+    }
+
+    /// Queues `request` without waiting. On a full queue the oldest or the new
+    /// request is dropped per the overflow policy and returned
+    pub fn push(&self, request: SendRequest) -> Option<SendRequest> {
+        let mut requests = self.requests.lock().expect("send queue lock poisoned");
+        let dropped = if requests.len() < self.capacity {
+            None
+        } else {
+            match self.overflow {
+                SendOverflow::DropOldest => requests.pop_front(),
+                SendOverflow::DropNewest => return Some(request),
+            }
+        };
+        requests.push_back(request);
+        drop(requests);
+
+        self.available.notify_one();
+        dropped
+    }
+
+    /// Waits for the oldest queued request
+    async fn pop(&self) -> SendRequest {
+        loop {
+            if let Some(request) = self
+                .requests
+                .lock()
+                .expect("send queue lock poisoned")
+                .pop_front()
+            {
+                return request;
+            }
+            // Pushes in between are not lost, `notify_one` keeps a permit
+            self.available.notified().await;
+        }
+    }
+}
+
+/// Starts `workers` tasks that send a transaction for every queued request
+/// and report the results. They run until the results receiver is dropped
+pub fn spawn_workers<R: SolanaRpc + 'static>(
+    workers: usize,
+    queue: Arc<SendQueue>,
+    sender: Arc<TransactionSender<R>>,
+) -> mpsc::UnboundedReceiver<SendResult> {
+    let (results_tx, results_rx) = mpsc::unbounded_channel();
+    for _ in 0..workers.max(1) {
+        let queue = Arc::clone(&queue);
+        let sender = Arc::clone(&sender);
+        let results_tx = results_tx.clone();
+        tokio::spawn(async move {
+            loop {
+                let request = queue.pop().await;
+                let queue_latency = request.queued_at.elapsed();
+                let outcome = sender
+                    .send_transaction(request.slot, request.queued_at)
+                    .await;
+                let result = SendResult {
+                    slot: request.slot,
+                    queue_latency,
+                    outcome,
+                };
+                if results_tx.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    results_rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use crate::rpc_pool::RpcPool;
+    use crate::transaction::TransactionKind;
+    use common::mock::MockRpc;
+    use solana_sdk::{pubkey::Pubkey, signature::Keypair, signer::Signer};
+
+    fn request(slot: u64) -> SendRequest {
+        SendRequest {
+            slot,
+            queued_at: Instant::now(),
+        }
+    }
+
+    fn queued_slots(queue: &SendQueue) -> Vec<u64> {
+        queue
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|request| request.slot)
+            .collect()
+    }
+
+    /// A sender whose every send takes `send_delay`
+    fn slow_sender(send_delay: Duration) -> TransactionSender<MockRpc> {
+        let keypair = Keypair::new();
+        let rpc = MockRpc::new();
+        {
+            let mut state = rpc.state();
+            state.balances.insert(keypair.pubkey(), 1_000_000_000);
+            state.send_delay = send_delay;
+        }
+        TransactionSender::new(
+            &format!("base58:{}", keypair.to_base58_string()),
+            TransactionKind::Transfer {
+                destinations: vec![Pubkey::new_unique()],
+                lamports: 1000,
+            },
+            RpcPool::from_clients(vec![rpc], Duration::from_secs(30)),
+            None,
+            send_delay * 2,
+            None,
+            Duration::from_secs(30),
+            Arc::new(Metrics::new()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn drop_oldest_makes_room_for_the_new_block() {
+        let queue = SendQueue::new(2, SendOverflow::DropOldest);
+        assert!(queue.push(request(1)).is_none());
+        assert!(queue.push(request(2)).is_none());
+
+        assert_eq!(queue.push(request(3)).map(|dropped| dropped.slot), Some(1));
+        assert_eq!(queued_slots(&queue), vec![2, 3]);
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_queued_blocks() {
+        let queue = SendQueue::new(2, SendOverflow::DropNewest);
+        assert!(queue.push(request(1)).is_none());
+        assert!(queue.push(request(2)).is_none());
+
+        assert_eq!(queue.push(request(3)).map(|dropped| dropped.slot), Some(3));
+        assert_eq!(queued_slots(&queue), vec![1, 2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_sends_do_not_hold_up_block_events() {
+        let send_delay = Duration::from_secs(3);
+        let queue = Arc::new(SendQueue::new(8, SendOverflow::DropOldest));
+        let mut results = spawn_workers(1, Arc::clone(&queue), Arc::new(slow_sender(send_delay)));
+        let start = tokio::time::Instant::now();
+
+        // Stands in for the block loop, which only queues the sends
+        let (blocks_tx, mut blocks_rx) = mpsc::unbounded_channel();
+        let block_loop = tokio::spawn({
+            let queue = Arc::clone(&queue);
+            async move {
+                let mut consumed_at = Vec::new();
+                while let Some(slot) = blocks_rx.recv().await {
+                    assert!(queue.push(request(slot)).is_none());
+                    consumed_at.push(start.elapsed());
+                }
+                consumed_at
+            }
+        });
+        for slot in 1..=4 {
+            blocks_tx.send(slot).unwrap();
+        }
+        drop(blocks_tx);
+        let consumed_at = block_loop.await.unwrap();
+        assert_eq!(consumed_at, vec![Duration::ZERO; 4]);
+
+        let mut sent = Vec::new();
+        for _ in 0..4 {
+            let result = results.recv().await.unwrap();
+            assert!(result.outcome.unwrap().is_some());
+            sent.push((result.slot, start.elapsed()));
+        }
+        // One worker sends them one after another
+        assert_eq!(
+            sent,
+            (1..=4)
+                .map(|slot| (slot, send_delay * slot as u32))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,56 @@
+use solana_sdk::pubkey::Pubkey;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// The most recently handled slots, bounded so a long-running watcher doesn't
+/// grow without limit. The oldest slot is forgotten once `capacity` is reached
+pub struct RecentSlots {
+    seen: HashSet<u64>,
+    order: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl RecentSlots {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            seen: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Records `slot`, returns `false` if it was already handled
+    pub fn insert(&mut self, slot: u64) -> bool {
+        if !self.seen.insert(slot) {
+            return false;
+        }
+        if self.order.len() == self.capacity
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.seen.remove(&oldest);
+        }
+        self.order.push_back(slot);
+        true
+    }
+}
+
+/// The newest write handled per account. Bounded by the configured trigger
+/// accounts, so nothing is ever forgotten
+#[derive(Default)]
+pub struct LatestWrites {
+    versions: HashMap<Pubkey, u64>,
+}
+
+impl LatestWrites {
+    /// Records the write, returns `false` if it or a newer one of the same
+    /// account was already handled
+    pub fn insert(&mut self, pubkey: Pubkey, write_version: u64) -> bool {
+        match self.versions.get(&pubkey) {
+            Some(&latest) if latest >= write_version => false,
+            _ => {
+                self.versions.insert(pubkey, write_version);
+                true
+            }
+        }
+    }
+}
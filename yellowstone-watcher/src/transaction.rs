@@ -1,82 +1,1043 @@
-use anyhow::Result;
-use solana_client::rpc_client::RpcClient;
+use crate::config::{Config, PriorityFeeConfig, TransactionType};
+use crate::metrics::Metrics;
+use crate::priority_fee::PriorityFeeController;
+use crate::rpc_pool::RpcPool;
+use anyhow::{Result, bail};
+use common::SolanaRpc;
+use solana_client::{client_error::ClientError, nonblocking::rpc_client::RpcClient};
 use solana_sdk::{
-    commitment_config::CommitmentConfig,
+    compute_budget::ComputeBudgetInstruction,
+    hash::Hash,
+    instruction::{AccountMeta, Instruction},
+    pubkey,
     pubkey::Pubkey,
-    signature::{Keypair, Signer, read_keypair_file},
-    system_instruction,
-    transaction::Transaction,
+    signature::{Keypair, Signer},
+    system_instruction, system_program,
+    transaction::{Transaction, TransactionError},
 };
-use std::path::Path;
-use tracing::{debug, info};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, warn};
 
-pub struct TransactionSender {
-    keypair: Keypair,
-    rpc_client: RpcClient,
-    destination: Pubkey,
-    lamports: u64,
+/// Timeout of every RPC request, the RPC client's default
+pub const RPC_TIMEOUT: Duration = Duration::from_secs(30);
+
+const MEMO_PROGRAM_ID: Pubkey = pubkey!("MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr");
+
+/// Base fee of a transaction with a single signature, before any priority fee
+const LAMPORTS_PER_SIGNATURE: u64 = 5000;
+
+/// Compute units budgeted for a SOL transfer, the transfer itself plus the
+/// compute budget instructions with some headroom
+const TRANSFER_COMPUTE_UNITS: u32 = 1_000;
+/// Compute units budgeted for a memo instruction, which logs the memo and
+/// verifies its signer, plus the per-byte cost of the memo text
+const MEMO_COMPUTE_UNITS: u32 = 10_000;
+const MEMO_COMPUTE_UNITS_PER_BYTE: u32 = 100;
+
+/// Compute budget instructions for sending `instruction` at `price`
+/// micro-lamports per CU, none without a price. Transfers and memos request
+/// only the units they need, otherwise the runtime reserves 200k and the price
+/// is charged on all of them. What a custom instruction needs isn't known, so
+/// it keeps the default
+pub fn compute_budget(instruction: &Instruction, price: u64) -> Vec<Instruction> {
+    if price == 0 {
+        return Vec::new();
+    }
+    let limit = if instruction.program_id == system_program::id() {
+        Some(TRANSFER_COMPUTE_UNITS)
+    } else if instruction.program_id == MEMO_PROGRAM_ID {
+        Some(MEMO_COMPUTE_UNITS + MEMO_COMPUTE_UNITS_PER_BYTE * instruction.data.len() as u32)
+    } else {
+        None
+    };
+    limit
+        .map(ComputeBudgetInstruction::set_compute_unit_limit)
+        .into_iter()
+        .chain([ComputeBudgetInstruction::set_compute_unit_price(price)])
+        .collect()
 }
 
-impl TransactionSender {
-    pub fn new<P: AsRef<Path>>(
-        keypair_path: P,
-        destination: Pubkey,
-        sol_amount: f64,
-        rpc_url: &str,
-    ) -> Result<Self> {
-        let keypair = read_keypair_file(keypair_path)
-            .map_err(|e| anyhow::anyhow!("Failed to read keypair file: {}", e))?;
+/// What every send does, per the config's `transaction_type`
+#[derive(Debug, Clone)]
+pub enum TransactionKind {
+    /// `lamports` to the destinations in turn
+    Transfer {
+        destinations: Vec<Pubkey>,
+        lamports: u64,
+    },
+    /// A memo with `{slot}` and `{timestamp}` filled in per send
+    Memo { template: String },
+    /// The same instruction every time
+    Custom(Instruction),
+}
+
+impl TransactionKind {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(match config.transaction_type {
+            TransactionType::Transfer => TransactionKind::Transfer {
+                destinations: config.destination_pubkeys()?,
+                lamports: config.amount_lamports()?,
+            },
+            TransactionType::Memo => TransactionKind::Memo {
+                template: config.memo_template()?.to_string(),
+            },
+            TransactionType::Custom => TransactionKind::Custom(config.custom_instruction()?),
+        })
+    }
 
-        // Convert SOL to lamports (1 SOL = 10^9 lamports)
-        let lamports = (sol_amount * 1_000_000_000.0) as u64;
+    /// Fails if the instruction needs a signature from anyone but `payer`,
+    /// the only keypair there is to sign with
+    pub fn check_signers(&self, payer: &Pubkey) -> Result<()> {
+        if let TransactionKind::Custom(instruction) = self {
+            for account in &instruction.accounts {
+                if account.is_signer && account.pubkey != *payer {
+                    bail!(
+                        "custom_instruction account {} is a signer, but only the wallet {} can sign",
+                        account.pubkey,
+                        payer
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lamports a send moves out of the wallet, besides the fee
+    pub fn lamports(&self) -> u64 {
+        match self {
+            TransactionKind::Transfer { lamports, .. } => *lamports,
+            TransactionKind::Memo { .. } | TransactionKind::Custom(_) => 0,
+        }
+    }
 
-        let rpc_client =
-            RpcClient::new_with_commitment(rpc_url.to_string(), CommitmentConfig::confirmed());
+    /// The instruction sent for the update in `slot`, at `timestamp` in unix
+    /// seconds. A transfer goes to `destination`, the first one if unset
+    pub fn instruction(
+        &self,
+        payer: &Pubkey,
+        slot: u64,
+        timestamp: u64,
+        destination: Option<&Pubkey>,
+    ) -> Instruction {
+        match self {
+            TransactionKind::Transfer {
+                destinations,
+                lamports,
+            } => system_instruction::transfer(
+                payer,
+                destination.unwrap_or(&destinations[0]),
+                *lamports,
+            ),
+            TransactionKind::Memo { template } => {
+                let memo = template
+                    .replace("{slot}", &slot.to_string())
+                    .replace("{timestamp}", &timestamp.to_string());
+                Instruction {
+                    program_id: MEMO_PROGRAM_ID,
+                    accounts: vec![AccountMeta::new_readonly(*payer, true)],
+                    data: memo.into_bytes(),
+                }
+            }
+            TransactionKind::Custom(instruction) => instruction.clone(),
+        }
+    }
+}
+
+impl fmt::Display for TransactionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionKind::Transfer {
+                destinations,
+                lamports,
+            } => write!(
+                f,
+                "transfer of {} SOL to {} destinations: {:?}",
+                common::lamports_to_sol(*lamports),
+                destinations.len(),
+                destinations
+            ),
+            TransactionKind::Memo { template } => write!(f, "memo {:?}", template),
+            TransactionKind::Custom(instruction) => write!(
+                f,
+                "custom instruction to program {} with {} accounts and {} bytes of data",
+                instruction.program_id,
+                instruction.accounts.len(),
+                instruction.data.len()
+            ),
+        }
+    }
+}
+
+/// The sending wallet can't cover the transfer. Kept apart from network and
+/// RPC errors so the wallet-empty condition can be alerted on by itself
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "Insufficient balance: {} SOL (need at least {} SOL)",
+    common::lamports_to_sol(*balance_lamports),
+    common::lamports_to_sol(*required_lamports)
+)]
+pub struct InsufficientBalance {
+    pub wallet: Pubkey,
+    pub balance_lamports: u64,
+    pub required_lamports: u64,
+}
+
+impl InsufficientBalance {
+    pub fn shortfall_lamports(&self) -> u64 {
+        self.required_lamports.saturating_sub(self.balance_lamports)
+    }
+}
+
+pub struct TransactionSender<R = RpcClient> {
+    keypair: Keypair,
+    rpc: RpcPool<R>,
+    kind: TransactionKind,
+    /// Index of the next transfer destination, they're paid in turn
+    next_destination: AtomicUsize,
+    priority_fee: Option<PriorityFeeController>,
+    send_timeout: Duration,
+    destination_cooldown: Option<Duration>,
+    /// When each destination was last picked or received a confirmed transfer
+    last_sent: Mutex<HashMap<Pubkey, tokio::time::Instant>>,
+    /// Reused for every send through the same endpoint until it's older than
+    /// `blockhash_ttl`, sends through another endpoint fetch their own
+    blockhash: Mutex<Option<(Hash, tokio::time::Instant, usize)>>,
+    blockhash_ttl: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl<R: SolanaRpc> TransactionSender<R> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        keypair_source: &str,
+        kind: TransactionKind,
+        rpc: RpcPool<R>,
+        priority_fee: Option<PriorityFeeConfig>,
+        send_timeout: Duration,
+        destination_cooldown: Option<Duration>,
+        blockhash_ttl: Duration,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        let keypair = common::load_keypair(keypair_source)?;
+        kind.check_signers(&keypair.pubkey())?;
 
         Ok(Self {
             keypair,
-            rpc_client,
-            destination,
-            lamports,
+            rpc,
+            kind,
+            next_destination: AtomicUsize::new(0),
+            priority_fee: priority_fee.map(PriorityFeeController::new),
+            send_timeout,
+            destination_cooldown,
+            last_sent: Mutex::new(HashMap::new()),
+            blockhash: Mutex::new(None),
+            blockhash_ttl,
+            metrics,
         })
     }
 
-    pub async fn send_transaction(&self) -> Result<String> {
-        debug!(
-            "Preparing to send {} lamports to {}",
-            self.lamports, self.destination
-        );
+    /// Fetches the sending wallet's balance into the metrics
+    pub async fn refresh_balance(&self) -> Result<u64> {
+        let endpoint = self.rpc.pick();
+        self.balance(endpoint).await
+    }
 
-        let balance = self.rpc_client.get_balance(&self.keypair.pubkey())?;
-        if balance < self.lamports {
-            return Err(anyhow::anyhow!(
-                "Insufficient balance: {} SOL (need at least {} SOL)",
-                balance as f64 / 1_000_000_000.0,
-                self.lamports as f64 / 1_000_000_000.0
-            ));
+    async fn balance(&self, endpoint: usize) -> Result<u64> {
+        let balance = self
+            .rpc
+            .client(endpoint)
+            .get_balance(&self.keypair.pubkey())
+            .await?;
+        self.metrics.set_wallet_balance(balance);
+        Ok(balance)
+    }
+
+    /// The cached blockhash of `endpoint`, fetched again once it's older than
+    /// the TTL or came from another endpoint
+    async fn recent_blockhash(&self, endpoint: usize) -> Result<Hash> {
+        if let Some((blockhash, fetched_at, fetched_from)) = *self.blockhash.lock().unwrap()
+            && fetched_from == endpoint
+            && fetched_at.elapsed() < self.blockhash_ttl
+        {
+            return Ok(blockhash);
         }
 
-        let instruction =
-            system_instruction::transfer(&self.keypair.pubkey(), &self.destination, self.lamports);
+        let blockhash = self.rpc.client(endpoint).get_latest_blockhash().await?;
+        debug!("Fetched new blockhash {}", blockhash);
+        *self.blockhash.lock().unwrap() = Some((blockhash, tokio::time::Instant::now(), endpoint));
+        Ok(blockhash)
+    }
+
+    /// Lamports the wallet needs for a send: what it moves plus the base fee
+    fn required_lamports(&self) -> u64 {
+        self.kind.lamports() + LAMPORTS_PER_SIGNATURE
+    }
+
+    /// Checks the balance and fetches the blockhash through the first healthy
+    /// endpoint, failing over to the next one on RPC errors. Nothing is sent
+    /// yet, so trying another endpoint can't pay twice. Returns the endpoint
+    /// the transaction is to be sent through and its blockhash
+    async fn prepare(&self) -> Result<(usize, Hash)> {
+        let mut attempts = 0;
+        loop {
+            let endpoint = self.rpc.pick();
+            attempts += 1;
+            let prepared = async {
+                let balance = self.balance(endpoint).await?;
+                let required = self.required_lamports();
+                if balance < required {
+                    let insufficient = InsufficientBalance {
+                        wallet: self.keypair.pubkey(),
+                        balance_lamports: balance,
+                        required_lamports: required,
+                    };
+                    warn!(
+                        target: "insufficient_balance",
+                        wallet = %insufficient.wallet,
+                        balance_lamports = insufficient.balance_lamports,
+                        required_lamports = insufficient.required_lamports,
+                        shortfall_lamports = insufficient.shortfall_lamports(),
+                        "Insufficient balance to send"
+                    );
+                    return Err(insufficient.into());
+                }
+                self.recent_blockhash(endpoint).await
+            }
+            .await;
+
+            match prepared {
+                Ok(blockhash) => return Ok((endpoint, blockhash)),
+                Err(e) if e.is::<InsufficientBalance>() => return Err(e),
+                Err(e) => {
+                    self.rpc.mark_failed(endpoint, &e);
+                    if attempts >= self.rpc.len() {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Next destination in the rotation that isn't cooling down, `None` if all
+    /// are. The pick starts its cooldown right away, so concurrent sends can't
+    /// pick the same destination while this one is in flight
+    fn next_destination(&self, destinations: &[Pubkey]) -> Option<Pubkey> {
+        let mut last_sent = self.last_sent.lock().unwrap();
+        for _ in 0..destinations.len() {
+            let index = self.next_destination.fetch_add(1, Ordering::Relaxed);
+            let destination = destinations[index % destinations.len()];
+            let remaining = self.destination_cooldown.and_then(|cooldown| {
+                let sent_at = last_sent.get(&destination)?;
+                cooldown.checked_sub(sent_at.elapsed())
+            });
+            match remaining {
+                Some(remaining) => info!(
+                    "Skipping {}, cooldown has {:.1}s left",
+                    destination,
+                    remaining.as_secs_f64()
+                ),
+                None => {
+                    last_sent.insert(destination, tokio::time::Instant::now());
+                    return Some(destination);
+                }
+            }
+        }
+        None
+    }
+
+    /// Whether every transfer destination is still cooling down, so a send
+    /// now would be skipped. Reserves nothing, unlike `next_destination`
+    pub fn cooling_down(&self) -> bool {
+        let (TransactionKind::Transfer { destinations, .. }, Some(cooldown)) =
+            (&self.kind, self.destination_cooldown)
+        else {
+            return false;
+        };
+        let last_sent = self.last_sent.lock().unwrap();
+        destinations.iter().all(|destination| {
+            last_sent
+                .get(destination)
+                .is_some_and(|sent_at| sent_at.elapsed() <= cooldown)
+        })
+    }
+
+    /// Ends the cooldown `next_destination` started for a send that certainly
+    /// didn't land
+    fn release_destination(&self, destination: Option<&Pubkey>) {
+        if let Some(destination) = destination {
+            self.last_sent.lock().unwrap().remove(destination);
+        }
+    }
+
+    /// The instruction sent for the update in `slot`, transfers go to
+    /// `destination`. Needs no RPC, the rest of the transaction is up to the
+    /// send
+    pub fn build_instruction(&self, slot: u64, destination: Option<&Pubkey>) -> Instruction {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        self.kind
+            .instruction(&self.keypair.pubkey(), slot, timestamp, destination)
+    }
+
+    /// Sends the configured transaction for the update in `slot` received at
+    /// `triggered_at`, transfers to the next destination in the rotation.
+    /// Returns `None` without sending anything while every destination is
+    /// still in its cooldown window
+    pub async fn send_transaction(
+        &self,
+        slot: u64,
+        triggered_at: Instant,
+    ) -> Result<Option<String>> {
+        let destination = match &self.kind {
+            TransactionKind::Transfer { destinations, .. } => {
+                let Some(destination) = self.next_destination(destinations) else {
+                    info!("Skipping send, every destination is cooling down");
+                    return Ok(None);
+                };
+                debug!(
+                    "Preparing to send {} lamports to {}",
+                    self.kind.lamports(),
+                    destination
+                );
+                Some(destination)
+            }
+            TransactionKind::Memo { .. } | TransactionKind::Custom(_) => None,
+        };
+
+        let (endpoint, recent_blockhash) = match self.prepare().await {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                self.release_destination(destination.as_ref());
+                return Err(e);
+            }
+        };
+        let rpc_client = self.rpc.client(endpoint);
+
+        let instruction = self.build_instruction(slot, destination.as_ref());
+        let price = self
+            .priority_fee
+            .as_ref()
+            .map_or(0, PriorityFeeController::current_price);
+        if price > 0 {
+            debug!("Using priority fee of {} micro-lamports per CU", price);
+        }
+        let mut instructions = compute_budget(&instruction, price);
+        instructions.push(instruction);
 
-        let recent_blockhash = self.rpc_client.get_latest_blockhash()?;
         let transaction = Transaction::new_signed_with_payer(
-            &[instruction],
+            &instructions,
             Some(&self.keypair.pubkey()),
             &[&self.keypair],
             recent_blockhash,
         );
 
+        match &destination {
+            Some(destination) => info!(
+                "Sending {} SOL from {} to {} through {}",
+                common::lamports_to_sol(self.kind.lamports()),
+                self.keypair.pubkey(),
+                destination,
+                rpc_client.url()
+            ),
+            None => info!(
+                "Sending a {} from {} through {}",
+                self.kind,
+                self.keypair.pubkey(),
+                rpc_client.url()
+            ),
+        }
+
+        // Bound the send so an unresponsive RPC can't stall the block loop
+        let start = Instant::now();
+        self.metrics
+            .block_to_send
+            .observe(start.duration_since(triggered_at));
+        let result = tokio::time::timeout(
+            self.send_timeout,
+            rpc_client.send_and_confirm_transaction(&transaction),
+        )
+        .await;
+
+        let signature = match result {
+            Ok(Ok(signature)) => {
+                self.rpc.mark_healthy(endpoint);
+                Metrics::increment(&self.metrics.transactions_sent);
+                self.metrics.confirmation.observe(start.elapsed());
+                if let Some(priority_fee) = &self.priority_fee {
+                    priority_fee.record_success();
+                }
+                signature
+            }
+            Ok(Err(e)) => {
+                Metrics::increment(&self.metrics.transactions_failed);
+                // A transaction the cluster rejected, e.g. for insufficient
+                // funds, wasn't dropped and a higher fee won't make it land
+                if let Some(priority_fee) = &self.priority_fee
+                    && (e.get_transaction_error().is_none() || is_blockhash_expired(&e))
+                {
+                    priority_fee.record_drop();
+                }
+                if e.get_transaction_error().is_some() {
+                    // Rejected, so it never reached the destination
+                    self.release_destination(destination.as_ref());
+                }
+                if is_blockhash_expired(&e) {
+                    debug!("Blockhash {} expired, fetching a new one", recent_blockhash);
+                    *self.blockhash.lock().unwrap() = None;
+                } else if e.get_transaction_error().is_none() {
+                    // The transaction may have been submitted, so the next
+                    // send fails over rather than this one being sent again
+                    self.rpc.mark_failed(endpoint, &e);
+                }
+                return Err(e.into());
+            }
+            // The destination stays reserved, the transaction may still land
+            Err(_) => {
+                Metrics::increment(&self.metrics.transactions_failed);
+                if let Some(priority_fee) = &self.priority_fee {
+                    priority_fee.record_drop();
+                }
+                self.rpc.mark_failed(endpoint, "send timed out");
+                error!(
+                    "Send stalled: no response from RPC {} after {:.1}s, pending signature: {}",
+                    rpc_client.url(),
+                    start.elapsed().as_secs_f64(),
+                    transaction.signatures[0]
+                );
+                return Err(anyhow::anyhow!(
+                    "Timed out after {}s waiting for transaction {} to confirm",
+                    self.send_timeout.as_secs(),
+                    transaction.signatures[0]
+                ));
+            }
+        };
+
+        // The cooldown runs from the confirmation
+        if let Some(destination) = destination {
+            self.last_sent
+                .lock()
+                .unwrap()
+                .insert(destination, tokio::time::Instant::now());
+        }
+
         info!(
-            "Sending {} SOL from {} to {}",
-            self.lamports as f64 / 1_000_000_000.0,
-            self.keypair.pubkey(),
-            self.destination
+            "Transaction confirmed with signature: {} (sent through {})",
+            signature,
+            rpc_client.url()
         );
+        Ok(Some(signature.to_string()))
+    }
+}
 
-        let signature = self.rpc_client.send_and_confirm_transaction(&transaction)?;
+/// Whether the send failed because its blockhash is no longer valid
+fn is_blockhash_expired(error: &ClientError) -> bool {
+    matches!(
+        error.get_transaction_error(),
+        Some(TransactionError::BlockhashNotFound)
+    ) || error.to_string().contains("transaction expiration")
+}
 
-        info!("Transaction confirmed with signature: {}", signature);
-        Ok(signature.to_string())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::mock::MockRpc;
+    use solana_client::client_error::ClientErrorKind;
+
+    const LAMPORTS: u64 = 1_000_000;
+
+    fn sender(keypair: &Keypair, rpc: MockRpc) -> TransactionSender<MockRpc> {
+        pooled_sender(keypair, vec![rpc])
+    }
+
+    fn pooled_sender(keypair: &Keypair, rpcs: Vec<MockRpc>) -> TransactionSender<MockRpc> {
+        fee_sender(keypair, rpcs, None)
+    }
+
+    fn fee_sender(
+        keypair: &Keypair,
+        rpcs: Vec<MockRpc>,
+        priority_fee: Option<PriorityFeeConfig>,
+    ) -> TransactionSender<MockRpc> {
+        let kind = TransactionKind::Transfer {
+            destinations: vec![Pubkey::new_unique()],
+            lamports: LAMPORTS,
+        };
+        new_sender(keypair, kind, rpcs, priority_fee).unwrap()
+    }
+
+    fn new_sender(
+        keypair: &Keypair,
+        kind: TransactionKind,
+        rpcs: Vec<MockRpc>,
+        priority_fee: Option<PriorityFeeConfig>,
+    ) -> Result<TransactionSender<MockRpc>> {
+        TransactionSender::new(
+            &format!("base58:{}", keypair.to_base58_string()),
+            kind,
+            RpcPool::from_clients(rpcs, Duration::from_secs(30)),
+            priority_fee,
+            Duration::from_secs(5),
+            None,
+            Duration::from_secs(30),
+            Arc::new(Metrics::new()),
+        )
+    }
+
+    /// Sender paying one destination from a funded wallet, at most once per `cooldown`
+    fn cooldown_sender(rpc: MockRpc, cooldown: Duration) -> TransactionSender<MockRpc> {
+        let keypair = Keypair::new();
+        rpc.state().balances.insert(keypair.pubkey(), 1_000_000_000);
+        TransactionSender::new(
+            &format!("base58:{}", keypair.to_base58_string()),
+            TransactionKind::Transfer {
+                destinations: vec![Pubkey::new_unique()],
+                lamports: LAMPORTS,
+            },
+            RpcPool::from_clients(vec![rpc], Duration::from_secs(30)),
+            None,
+            Duration::from_secs(5),
+            Some(cooldown),
+            Duration::from_secs(30),
+            Arc::new(Metrics::new()),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_sends_never_share_a_destination_within_its_cooldown() {
+        let rpc = MockRpc::new();
+        rpc.state().send_delay = Duration::from_secs(1);
+        let sender = cooldown_sender(rpc, Duration::from_secs(60));
+
+        let (first, second) = tokio::join!(
+            sender.send_transaction(1, Instant::now()),
+            sender.send_transaction(2, Instant::now())
+        );
+        assert!(first.unwrap().is_some());
+        assert_eq!(second.unwrap(), None);
+        assert_eq!(sender.rpc.client(0).calls("send_transaction"), 1);
+
+        // The cooldown runs from the confirmation a second later
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert!(
+            sender
+                .send_transaction(3, Instant::now())
+                .await
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_timed_out_send_keeps_its_destination_cooling_down() {
+        let rpc = MockRpc::new();
+        // Past the 5s send timeout
+        rpc.state().send_delay = Duration::from_secs(10);
+        let sender = cooldown_sender(rpc, Duration::from_secs(60));
+
+        assert!(!sender.cooling_down());
+        assert!(sender.send_transaction(1, Instant::now()).await.is_err());
+        assert!(sender.cooling_down());
+        assert_eq!(
+            sender.send_transaction(2, Instant::now()).await.unwrap(),
+            None
+        );
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert!(!sender.cooling_down());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_rejected_send_frees_its_destination() {
+        let rpc = MockRpc::new();
+        rpc.fail_next(
+            "send_transaction",
+            TransactionError::InsufficientFundsForFee,
+        );
+        let sender = cooldown_sender(rpc, Duration::from_secs(60));
+
+        assert!(sender.send_transaction(1, Instant::now()).await.is_err());
+        assert!(
+            sender
+                .send_transaction(2, Instant::now())
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(
+            sender.send_transaction(3, Instant::now()).await.unwrap(),
+            None
+        );
+    }
+
+    /// The kind of transaction `yaml`, added to a minimal config, describes
+    fn kind_from_config(yaml: &str) -> Result<TransactionKind> {
+        let config: Config = serde_yaml::from_str(&format!(
+            "geyser_endpoint: http://geyser\ngeyser_token: token\nkeypair_path: env:KEYPAIR\nsolana_rpc_url: http://rpc\n{}",
+            yaml
+        ))
+        .unwrap();
+        TransactionKind::from_config(&config)
+    }
+
+    #[test]
+    fn builds_transfers_to_the_given_destination() {
+        let keypair = Keypair::new();
+        let destinations = vec![Pubkey::new_unique(), Pubkey::new_unique()];
+        let kind = TransactionKind::Transfer {
+            destinations: destinations.clone(),
+            lamports: LAMPORTS,
+        };
+        let sender = new_sender(&keypair, kind, vec![MockRpc::new()], None).unwrap();
+
+        assert_eq!(
+            sender.build_instruction(1, Some(&destinations[1])),
+            system_instruction::transfer(&keypair.pubkey(), &destinations[1], LAMPORTS)
+        );
+        assert_eq!(
+            sender.build_instruction(1, None),
+            system_instruction::transfer(&keypair.pubkey(), &destinations[0], LAMPORTS)
+        );
+    }
+
+    #[test]
+    fn fills_in_the_memo_template() {
+        let keypair = Keypair::new();
+        let kind = TransactionKind::Memo {
+            template: "slot {slot} at {timestamp}, slot {slot} again".to_string(),
+        };
+        let sender = new_sender(&keypair, kind, vec![MockRpc::new()], None).unwrap();
+
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let instruction = sender.build_instruction(42, None);
+        let after = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        assert_eq!(instruction.program_id, MEMO_PROGRAM_ID);
+        assert_eq!(instruction.accounts, vec![AccountMeta::new_readonly(
+            keypair.pubkey(),
+            true
+        )]);
+        let memo = String::from_utf8(instruction.data).unwrap();
+        let timestamp: u64 = memo
+            .strip_prefix("slot 42 at ")
+            .and_then(|rest| rest.strip_suffix(", slot 42 again"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((before..=after).contains(&timestamp), "{}", memo);
+    }
+
+    #[test]
+    fn sends_the_custom_instruction_as_configured() {
+        let keypair = Keypair::new();
+        let program_id = Pubkey::new_unique();
+        let account = Pubkey::new_unique();
+        let kind = kind_from_config(&format!(
+            "transaction_type: custom
+custom_instruction:
+  program_id: \"{}\"
+  accounts:
+    - {{pubkey: \"{}\", is_signer: true, is_writable: true}}
+    - {{pubkey: \"{}\"}}
+  data: 0a0b
+  data_encoding: hex
+",
+            program_id,
+            keypair.pubkey(),
+            account
+        ))
+        .unwrap();
+        let sender = new_sender(&keypair, kind, vec![MockRpc::new()], None).unwrap();
+
+        assert_eq!(sender.build_instruction(1, None), Instruction {
+            program_id,
+            accounts: vec![
+                AccountMeta::new(keypair.pubkey(), true),
+                AccountMeta::new_readonly(account, false),
+            ],
+            data: vec![0x0a, 0x0b],
+        });
+    }
+
+    #[test]
+    fn rejects_invalid_pubkeys_when_loading() {
+        let cases = [
+            (
+                "destination_wallet: [\"not-a-pubkey\"]\nlamports: 1\n".to_string(),
+                "Invalid destination wallet pubkey in config: not-a-pubkey",
+            ),
+            (
+                "transaction_type: custom\ncustom_instruction: {program_id: nope}\n".to_string(),
+                "Invalid custom_instruction program_id in config: nope",
+            ),
+            (
+                format!(
+                    "transaction_type: custom\ncustom_instruction: {{program_id: \"{}\", accounts: [{{pubkey: nope}}]}}\n",
+                    Pubkey::new_unique()
+                ),
+                "Invalid custom_instruction account pubkey in config: nope",
+            ),
+        ];
+        for (yaml, expected) in cases {
+            let error = kind_from_config(&yaml).unwrap_err();
+            assert!(error.to_string().contains(expected), "{:#}", error);
+        }
+    }
+
+    #[test]
+    fn rejects_signers_without_a_keypair() {
+        let keypair = Keypair::new();
+        let stranger = Pubkey::new_unique();
+        let kind = TransactionKind::Custom(Instruction {
+            program_id: Pubkey::new_unique(),
+            accounts: vec![
+                AccountMeta::new(keypair.pubkey(), true),
+                AccountMeta::new_readonly(stranger, true),
+            ],
+            data: Vec::new(),
+        });
+
+        let error = match new_sender(&keypair, kind, vec![MockRpc::new()], None) {
+            Ok(_) => panic!("a sender that can't sign its instruction was created"),
+            Err(error) => error,
+        };
+        assert!(
+            error
+                .to_string()
+                .contains(&format!("account {} is a signer", stranger)),
+            "{:#}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_to_send_on_insufficient_balance() {
+        let keypair = Keypair::new();
+        let rpc = MockRpc::new();
+        rpc.state()
+            .balances
+            .insert(keypair.pubkey(), LAMPORTS + LAMPORTS_PER_SIGNATURE - 1);
+        let sender = sender(&keypair, rpc);
+
+        let error = sender
+            .send_transaction(1, Instant::now())
+            .await
+            .unwrap_err();
+        let insufficient = error.downcast_ref::<InsufficientBalance>().unwrap();
+        assert_eq!(insufficient.wallet, keypair.pubkey());
+        assert_eq!(insufficient.shortfall_lamports(), 1);
+        let rpc = sender.rpc.client(0);
+        assert_eq!(rpc.calls("send_transaction"), 0);
+        assert_eq!(rpc.calls("get_latest_blockhash"), 0);
+    }
+
+    #[tokio::test]
+    async fn sends_with_enough_balance() {
+        let keypair = Keypair::new();
+        let rpc = MockRpc::new();
+        rpc.state()
+            .balances
+            .insert(keypair.pubkey(), LAMPORTS + LAMPORTS_PER_SIGNATURE);
+        let sender = sender(&keypair, rpc);
+
+        let signature = sender.send_transaction(1, Instant::now()).await.unwrap();
+        let output = sender.metrics.render_prometheus();
+        assert!(output.contains("yellowstone_watcher_transactions_sent_total 1\n"));
+        assert!(output.contains("yellowstone_watcher_confirmation_seconds_count 1\n"));
+        assert!(output.contains(&format!(
+            "yellowstone_watcher_wallet_balance_lamports {}\n",
+            LAMPORTS + LAMPORTS_PER_SIGNATURE
+        )));
+        let state = sender.rpc.client(0).state();
+        assert_eq!(state.sends.len(), 1);
+        assert_eq!(
+            signature,
+            Some(state.sends[0].transaction.signatures[0].to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_endpoint_before_sending() {
+        let keypair = Keypair::new();
+        let rpcs: Vec<MockRpc> = (0..2).map(|_| MockRpc::new()).collect();
+        for rpc in &rpcs {
+            rpc.state().balances.insert(keypair.pubkey(), 1_000_000_000);
+        }
+        rpcs[0].fail_next(
+            "get_balance",
+            ClientErrorKind::Custom("connection reset".to_string()),
+        );
+        let sender = pooled_sender(&keypair, rpcs);
+
+        sender.send_transaction(1, Instant::now()).await.unwrap();
+        assert_eq!(sender.rpc.client(0).calls("send_transaction"), 0);
+        assert_eq!(sender.rpc.client(1).calls("send_transaction"), 1);
+        // The failed endpoint sits out its cooldown
+        assert_eq!(sender.rpc.pick(), 1);
+    }
+
+    /// Price after one failed send that failed with `error`
+    async fn price_after_failure(error: ClientError) -> u64 {
+        let keypair = Keypair::new();
+        let rpc = MockRpc::new();
+        rpc.state().balances.insert(keypair.pubkey(), 1_000_000_000);
+        rpc.fail_next("send_transaction", error);
+        let sender = fee_sender(
+            &keypair,
+            vec![rpc],
+            Some(PriorityFeeConfig {
+                initial_micro_lamports: 100,
+                step_micro_lamports: 50,
+                max_micro_lamports: 1000,
+            }),
+        );
+
+        assert!(sender.send_transaction(1, Instant::now()).await.is_err());
+        sender.priority_fee.as_ref().unwrap().current_price()
+    }
+
+    #[test]
+    fn limits_the_compute_units_a_priority_fee_is_paid_on() {
+        let payer = Pubkey::new_unique();
+        let price = ComputeBudgetInstruction::set_compute_unit_price(100);
+
+        let transfer = system_instruction::transfer(&payer, &Pubkey::new_unique(), LAMPORTS);
+        assert_eq!(compute_budget(&transfer, 100), vec![
+            ComputeBudgetInstruction::set_compute_unit_limit(1_000),
+            price.clone()
+        ]);
+
+        let memo = TransactionKind::Memo {
+            template: "slot {slot}".to_string(),
+        }
+        .instruction(&payer, 7, 0, None);
+        assert_eq!(compute_budget(&memo, 100), vec![
+            ComputeBudgetInstruction::set_compute_unit_limit(10_600),
+            price.clone()
+        ]);
+
+        // Nothing to size a custom instruction by
+        let custom = Instruction {
+            program_id: Pubkey::new_unique(),
+            accounts: Vec::new(),
+            data: Vec::new(),
+        };
+        assert_eq!(compute_budget(&custom, 100), vec![price]);
+        assert!(compute_budget(&transfer, 0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn sends_the_compute_unit_limit_with_the_price() {
+        let keypair = Keypair::new();
+        let rpc = MockRpc::new();
+        rpc.state().balances.insert(keypair.pubkey(), 1_000_000_000);
+        let sender = fee_sender(
+            &keypair,
+            vec![rpc],
+            Some(PriorityFeeConfig {
+                initial_micro_lamports: 100,
+                step_micro_lamports: 50,
+                max_micro_lamports: 1000,
+            }),
+        );
+
+        sender.send_transaction(1, Instant::now()).await.unwrap();
+        let state = sender.rpc.client(0).state();
+        let message = &state.sends[0].transaction.message;
+        let data: Vec<&[u8]> = message
+            .instructions
+            .iter()
+            .map(|instruction| instruction.data.as_slice())
+            .collect();
+        assert_eq!(data[..2], [
+            ComputeBudgetInstruction::set_compute_unit_limit(1_000)
+                .data
+                .as_slice(),
+            ComputeBudgetInstruction::set_compute_unit_price(100)
+                .data
+                .as_slice(),
+        ]);
+        assert_eq!(data.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn escalates_the_fee_only_for_dropped_transactions() {
+        let not_confirmed = ClientErrorKind::Custom("not confirmed".to_string()).into();
+        assert_eq!(price_after_failure(not_confirmed).await, 150);
+        let expired = TransactionError::BlockhashNotFound.into();
+        assert_eq!(price_after_failure(expired).await, 150);
+        let rejected = TransactionError::InsufficientFundsForFee.into();
+        assert_eq!(price_after_failure(rejected).await, 100);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reuses_the_blockhash_until_its_ttl_passes() {
+        let keypair = Keypair::new();
+        let rpc = MockRpc::new();
+        rpc.state().balances.insert(keypair.pubkey(), 1_000_000_000);
+        let sender = sender(&keypair, rpc);
+        let rpc = sender.rpc.client(0);
+        let blockhashes = || {
+            rpc.state()
+                .sends
+                .iter()
+                .map(|sent| sent.transaction.message.recent_blockhash)
+                .collect::<Vec<_>>()
+        };
+
+        sender.send_transaction(1, Instant::now()).await.unwrap();
+        tokio::time::advance(Duration::from_secs(29)).await;
+        sender.send_transaction(2, Instant::now()).await.unwrap();
+        assert_eq!(rpc.calls("get_latest_blockhash"), 1);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        sender.send_transaction(3, Instant::now()).await.unwrap();
+        assert_eq!(rpc.calls("get_latest_blockhash"), 2);
+        let sent = blockhashes();
+        assert_eq!(sent[0], sent[1]);
+        assert_ne!(sent[1], sent[2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fetches_a_new_blockhash_after_it_expired() {
+        let keypair = Keypair::new();
+        let rpc = MockRpc::new();
+        rpc.state().balances.insert(keypair.pubkey(), 1_000_000_000);
+        // The node forgets blockhashes sooner than the cache does
+        rpc.state().blockhash_ttl = Duration::from_secs(10);
+        let sender = sender(&keypair, rpc);
+        let rpc = sender.rpc.client(0);
+
+        sender.send_transaction(1, Instant::now()).await.unwrap();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let error = sender
+            .send_transaction(2, Instant::now())
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("Blockhash not found"));
+        assert_eq!(rpc.calls("get_latest_blockhash"), 1);
+
+        sender.send_transaction(3, Instant::now()).await.unwrap();
+        assert_eq!(rpc.calls("get_latest_blockhash"), 2);
+        let state = rpc.state();
+        assert_eq!(
+            state
+                .sends
+                .iter()
+                .map(|sent| sent.accepted)
+                .collect::<Vec<_>>(),
+            vec![true, false, true]
+        );
     }
 }